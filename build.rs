@@ -0,0 +1,18 @@
+use std::env;
+
+// Turns the target OS into cfgs naming what the platform supports, so the sources check for
+// features instead of repeating lists of systems. `Capabilities` documents each of them.
+fn main() {
+    println!("cargo::rustc-check-cfg=cfg(robust_mutex, shared_rwlock)");
+
+    let os = env::var("CARGO_CFG_TARGET_OS").unwrap_or_default();
+    // Android's bionic only gained robust mutexes recently, so it always gets the fallback,
+    // which the pid-mutex feature also forces elsewhere.
+    let pid_mutex = env::var_os("CARGO_FEATURE_PID_MUTEX").is_some();
+    if matches!(os.as_str(), "linux" | "freebsd") && !pid_mutex {
+        println!("cargo::rustc-cfg=robust_mutex");
+    }
+    if matches!(os.as_str(), "linux" | "freebsd" | "macos") {
+        println!("cargo::rustc-cfg=shared_rwlock");
+    }
+}
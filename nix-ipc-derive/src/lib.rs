@@ -0,0 +1,172 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{Data, DeriveInput, Error, Fields, parse_macro_input, spanned::Spanned};
+
+/// Derives `nix_ipc::ShmSafe` for a `#[repr(C)]` struct.
+///
+/// The generated code checks at compile time that every field is `ShmSafe` and that the
+/// struct has no padding bytes other than those aligning fields such as `CachePadded`, and
+/// computes a layout fingerprint from the struct name, field names, field fingerprints and
+/// offsets.
+#[proc_macro_derive(ShmSafe)]
+pub fn derive_shm_safe(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// Derives `nix_ipc::ShmAtomic` for a `#[repr(C)]` struct whose fields are all `ShmAtomic`,
+/// such as a struct of atomic counters. The struct must also implement `ShmSafe`.
+#[proc_macro_derive(ShmAtomic)]
+pub fn derive_shm_atomic(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_atomic(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn expand_atomic(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+
+    if !input.generics.params.is_empty() {
+        return Err(Error::new(
+            input.generics.span(),
+            "ShmAtomic cannot be derived for generic structs",
+        ));
+    }
+
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        _ => {
+            return Err(Error::new(
+                name.span(),
+                "ShmAtomic can only be derived for structs",
+            ));
+        }
+    };
+    let types: Vec<&syn::Type> = fields.iter().map(|f| &f.ty).collect();
+
+    Ok(quote! {
+        const _: () = {
+            const fn assert_field_is_shm_atomic<T: ::nix_ipc::ShmAtomic>() {}
+            #(assert_field_is_shm_atomic::<#types>();)*
+        };
+
+        unsafe impl ::nix_ipc::ShmAtomic for #name {}
+    })
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+
+    if !input.generics.params.is_empty() {
+        return Err(Error::new(
+            input.generics.span(),
+            "ShmSafe cannot be derived for generic structs",
+        ));
+    }
+
+    if !is_repr_c(&input)? {
+        return Err(Error::new(
+            name.span(),
+            "ShmSafe requires the struct to be #[repr(C)]",
+        ));
+    }
+
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        _ => {
+            return Err(Error::new(
+                name.span(),
+                "ShmSafe can only be derived for structs",
+            ));
+        }
+    };
+
+    let members: Vec<TokenStream2> = match fields {
+        Fields::Named(named) => named
+            .named
+            .iter()
+            .map(|f| {
+                let ident = f.ident.as_ref().unwrap();
+                quote!(#ident)
+            })
+            .collect(),
+        Fields::Unnamed(unnamed) => (0..unnamed.unnamed.len())
+            .map(|i| {
+                let index = syn::Index::from(i);
+                quote!(#index)
+            })
+            .collect(),
+        Fields::Unit => Vec::new(),
+    };
+    let member_names: Vec<String> = members.iter().map(|m| m.to_string()).collect();
+    let types: Vec<&syn::Type> = fields.iter().map(|f| &f.ty).collect();
+    let name_str = name.to_string();
+
+    Ok(quote! {
+        const _: () = {
+            const fn assert_field_is_shm_safe<T: ::nix_ipc::ShmSafe>() {}
+            #(assert_field_is_shm_safe::<#types>();)*
+
+            assert!(
+                ::nix_ipc::__private::padding_free(
+                    &[#((
+                        ::core::mem::offset_of!(#name, #members),
+                        ::core::mem::size_of::<#types>(),
+                        ::core::mem::align_of::<#types>(),
+                    )),*],
+                    ::core::mem::size_of::<#name>(),
+                    ::core::mem::align_of::<#name>(),
+                ),
+                concat!("ShmSafe struct `", #name_str, "` must not contain padding"),
+            );
+        };
+
+        unsafe impl ::nix_ipc::ShmSafe for #name {
+            const FINGERPRINT: u64 = {
+                let mut hash = ::nix_ipc::__private::fnv1a(
+                    ::nix_ipc::__private::FNV_OFFSET,
+                    #name_str.as_bytes(),
+                );
+                hash = ::nix_ipc::__private::fnv1a_u64(
+                    hash,
+                    ::core::mem::size_of::<#name>() as u64,
+                );
+                #(
+                    hash = ::nix_ipc::__private::fnv1a(hash, #member_names.as_bytes());
+                    hash = ::nix_ipc::__private::fnv1a_u64(
+                        hash,
+                        ::core::mem::offset_of!(#name, #members) as u64,
+                    );
+                    hash = ::nix_ipc::__private::fnv1a_u64(
+                        hash,
+                        <#types as ::nix_ipc::ShmSafe>::FINGERPRINT,
+                    );
+                )*
+                hash
+            };
+        }
+    })
+}
+
+fn is_repr_c(input: &DeriveInput) -> syn::Result<bool> {
+    let mut repr_c = false;
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("repr")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("C") {
+                repr_c = true;
+            } else if meta.path.is_ident("packed") {
+                return Err(meta.error("ShmSafe does not support #[repr(packed)]"));
+            }
+            if meta.input.peek(syn::token::Paren) {
+                let _content;
+                syn::parenthesized!(_content in meta.input);
+            }
+            Ok(())
+        })?;
+    }
+    Ok(repr_c)
+}
@@ -0,0 +1,448 @@
+#[cfg(feature = "futures")]
+use std::pin::Pin;
+use std::{
+    future::poll_fn,
+    mem::size_of,
+    num::NonZeroUsize,
+    os::fd::{AsFd, OwnedFd},
+    sync::atomic::Ordering,
+    task::{Context, Poll, ready},
+};
+
+#[cfg(feature = "futures")]
+use futures_core::Stream;
+#[cfg(feature = "futures")]
+use futures_sink::Sink;
+use nix::{
+    fcntl::OFlag,
+    libc::{EBUSY, EOWNERDEAD, c_int},
+};
+use tokio::io::{Interest, unix::AsyncFd};
+
+use crate::{
+    builder::Builder,
+    cleanup::CleanupPolicy,
+    error::{Error, Result},
+    futex,
+    futex_bridge::FutexBridge,
+    liveness::LIVENESS_POLL,
+    map::Mapping,
+    namespace::Namespace,
+    pid_mutex::PidMutex,
+    r_mtx::LockResult,
+    raw_lock,
+};
+
+pub(crate) mod sealed {
+    use crate::futex::EventCount;
+
+    /// The event count a channel notifies, opaque outside of the crate.
+    pub struct Notified<'a>(pub(crate) &'a EventCount);
+
+    /// The sending end of a shared memory channel.
+    pub trait Sender {
+        type Item;
+
+        fn try_send(&mut self, value: Self::Item) -> Result<(), Self::Item>;
+
+        /// Notified when a receiver makes room.
+        fn not_full(&self) -> Notified<'_>;
+    }
+
+    /// The receiving end of a shared memory channel.
+    pub trait Receiver {
+        type Item;
+
+        fn try_recv(&mut self) -> Option<Self::Item>;
+
+        /// Notified when a sender adds a value.
+        fn not_empty(&self) -> Notified<'_>;
+    }
+}
+
+use sealed::{Receiver, Sender};
+
+/// The eventfd of a futex bridge, registered with the tokio reactor.
+pub(crate) struct Notifier {
+    fd: AsyncFd<OwnedFd>,
+    bridge: FutexBridge,
+}
+
+impl Notifier {
+    pub(crate) fn new(bridge: FutexBridge) -> Result<Self> {
+        let fd = bridge.event().as_fd().try_clone_to_owned()?;
+        Ok(Self {
+            fd: AsyncFd::with_interest(fd, Interest::READABLE)?,
+            bridge,
+        })
+    }
+
+    /// Polls until `poll` returns `Some`, waiting for the bridge between attempts.
+    pub(crate) async fn until<R>(&self, mut poll: impl FnMut() -> Result<Option<R>>) -> Result<R> {
+        loop {
+            if let Some(result) = poll()? {
+                return Ok(result);
+            }
+            let mut guard = self.fd.readable().await?;
+            self.bridge.event().try_wait()?;
+            guard.clear_ready();
+        }
+    }
+
+    /// Like `until`, for use in `poll` functions. Only the task that polled last is woken.
+    fn poll_until<R>(
+        &self,
+        cx: &mut Context<'_>,
+        mut poll: impl FnMut() -> Option<R>,
+    ) -> Poll<Result<R>> {
+        loop {
+            if let Some(result) = poll() {
+                return Poll::Ready(Ok(result));
+            }
+            let mut guard = ready!(self.fd.poll_read_ready(cx))?;
+            self.bridge.event().try_wait()?;
+            guard.clear_ready();
+        }
+    }
+}
+
+/// Waits until `fd` is readable, for eventfds that aren't bridges. `poll` reads from `fd`
+/// without blocking, returning `None` if there was nothing to read.
+pub(crate) async fn readable<R>(
+    fd: &impl AsFd,
+    mut poll: impl FnMut() -> Result<Option<R>>,
+) -> Result<R> {
+    let fd = AsyncFd::with_interest(fd.as_fd().try_clone_to_owned()?, Interest::READABLE)?;
+    loop {
+        if let Some(result) = poll()? {
+            return Ok(result);
+        }
+        fd.readable().await?.clear_ready();
+    }
+}
+
+/// An interprocess mutex for async code, locked with `lock().await` instead of blocking the
+/// thread. Only available on Linux with the `tokio` feature.
+///
+/// The mutex records the pid of its owner, like the fallback of `RMtx` on targets without
+/// robust mutexes, and waiters take it over when that process is gone. Each handle runs a
+/// helper thread waiting on the lock word, which wakes waiting tasks through an eventfd
+/// registered with tokio. The helper also wakes them every 10 milliseconds, so they notice a
+/// dead owner, which never unlocks.
+///
+/// The owner is a process, so the mutex is not reentrant: a task locking it while another
+/// task of the same process holds it waits like any other.
+pub struct AsyncMtx {
+    notifier: Notifier,
+    map: Mapping,
+}
+
+impl AsyncMtx {
+    /// Opens the mutex in /dev/shm, creating it unlocked if it doesn't exist.
+    /// Must be called within a tokio runtime.
+    pub fn new(name: &str) -> Result<Self> {
+        Self::builder(name).build()
+    }
+
+    /// Returns a builder for setting the namespace and the permissions the mutex is created with.
+    pub fn builder(name: &str) -> Builder<Self> {
+        Builder::new(name)
+    }
+
+    /// Unlinks (deletes) the mutex file from /dev/shm.
+    pub fn unlink(name: &str) -> Result<()> {
+        Self::unlink_in(&Namespace::default(), name)
+    }
+
+    /// Unlinks the mutex called `name` in `namespace`.
+    pub fn unlink_in(namespace: &Namespace, name: &str) -> Result<()> {
+        namespace.path(name, ".amx").unlink()
+    }
+
+    /// Locks the mutex, waiting without blocking the thread while it is held elsewhere.
+    /// Returns a guard that unlocks it when dropped.
+    pub async fn lock(&self) -> Result<AsyncMtxGuard<'_>> {
+        let result = self
+            .notifier
+            .until(|| self.acquired(self.mtx().try_lock()))
+            .await?;
+        Ok(AsyncMtxGuard { mtx: self, result })
+    }
+
+    /// Locks the mutex, blocking the thread while it is held elsewhere, for code outside of
+    /// async tasks.
+    pub fn blocking_lock(&self) -> Result<AsyncMtxGuard<'_>> {
+        let err = self.mtx().lock(None)?;
+        let result = self
+            .acquired(err)?
+            .expect("Locking without a deadline never times out");
+        Ok(AsyncMtxGuard { mtx: self, result })
+    }
+
+    /// Attempts to lock the mutex without waiting, returning `None` if it is held elsewhere.
+    pub fn try_lock(&self) -> Result<Option<AsyncMtxGuard<'_>>> {
+        let result = self.acquired(self.mtx().try_lock())?;
+        Ok(result.map(|result| AsyncMtxGuard { mtx: self, result }))
+    }
+
+    /// Interprets the return code of an attempt to acquire the mutex, marking it consistent
+    /// again if the previous owner died. Returns `None` if it is held elsewhere.
+    fn acquired(&self, err: c_int) -> Result<Option<LockResult>> {
+        match err {
+            EBUSY => Ok(None),
+            EOWNERDEAD => {
+                raw_lock::check(self.mtx().consistent())
+                    .map_err(Error::lock("AsyncMtx::consistent"))?;
+                Ok(Some(LockResult::OwnerDiedRecovered))
+            }
+            _ => raw_lock::check(err)
+                .map(|()| Some(LockResult::Acquired))
+                .map_err(Error::lock("AsyncMtx::lock")),
+        }
+    }
+
+    fn unlock(&self) -> Result<()> {
+        let err = self.mtx().unlock();
+        // The helper threads of other handles don't count as waiters, so unlocking alone
+        // wouldn't wake them.
+        futex::wake(self.mtx().owner(), u32::MAX)?;
+        raw_lock::check(err).map_err(Error::lock("AsyncMtx::unlock"))
+    }
+
+    fn mtx(&self) -> &PidMutex {
+        unsafe { &*(self.map.ptr() as *const PidMutex) }
+    }
+}
+
+impl Builder<AsyncMtx> {
+    /// Opens the mutex like `AsyncMtx::new`.
+    pub fn build(self) -> Result<AsyncMtx> {
+        let path = self.namespace.path(&self.name, ".amx");
+        let len = NonZeroUsize::new(size_of::<PidMutex>()).expect("PidMutex has nonzero size");
+
+        // Zeroed memory is an unlocked mutex.
+        let map = Mapping::open_init(
+            &path,
+            OFlag::O_CREAT,
+            &self.permissions,
+            len,
+            CleanupPolicy::Never,
+            |_| Ok(()),
+        )?;
+        map.inherit_in_forks(None);
+
+        let mtx = unsafe { &*(map.ptr() as *const PidMutex) };
+        let owner = mtx.owner().load(Ordering::SeqCst);
+        let bridge = unsafe { FutexBridge::new(mtx.owner(), owner, None, Some(LIVENESS_POLL))? };
+        Ok(AsyncMtx {
+            notifier: Notifier::new(bridge)?,
+            map,
+        })
+    }
+}
+
+/// RAII guard returned by `AsyncMtx::lock`, unlocking the mutex on drop.
+pub struct AsyncMtxGuard<'a> {
+    mtx: &'a AsyncMtx,
+    result: LockResult,
+}
+
+impl AsyncMtxGuard<'_> {
+    /// How the mutex was acquired.
+    pub fn lock_result(&self) -> &LockResult {
+        &self.result
+    }
+
+    /// Returns true if the previous owner died while holding the mutex.
+    pub fn owner_died_recovered(&self) -> bool {
+        matches!(self.result, LockResult::OwnerDiedRecovered)
+    }
+}
+
+impl Drop for AsyncMtxGuard<'_> {
+    fn drop(&mut self) {
+        self.mtx.unlock().ok();
+    }
+}
+
+/// The sending end of a shared memory channel, a `spsc::Producer` or an `mpmc::Queue`, that
+/// waits without blocking the thread while the channel is full. Only available on Linux with
+/// the `tokio` feature.
+///
+/// A helper thread waits for receivers to make room and wakes the sending task through an
+/// eventfd registered with tokio. With the `futures` feature it is a `Sink`, which holds on
+/// to one value while the channel is full.
+pub struct AsyncSender<S: Sender> {
+    notifier: Notifier,
+    sender: S,
+    /// A value passed to `Sink::start_send` that didn't fit into the channel yet.
+    #[cfg(feature = "futures")]
+    pending: Option<S::Item>,
+}
+
+impl<S: Sender> AsyncSender<S> {
+    /// Wraps `sender`. Must be called within a tokio runtime.
+    pub fn new(sender: S) -> Result<Self> {
+        let bridge = unsafe { sender.not_full().0.bridge(None)? };
+        Ok(Self {
+            notifier: Notifier::new(bridge)?,
+            sender,
+            #[cfg(feature = "futures")]
+            pending: None,
+        })
+    }
+
+    /// Sends `value`, waiting while the channel is full.
+    pub async fn send(&mut self, value: S::Item) -> Result<()> {
+        let Self {
+            notifier, sender, ..
+        } = self;
+        let mut value = Some(value);
+        notifier
+            .until(|| {
+                let pending = value.take().expect("value is only taken once per attempt");
+                Ok(sender
+                    .try_send(pending)
+                    .map_err(|pending| value = Some(pending))
+                    .ok())
+            })
+            .await
+    }
+
+    /// Sends `value` without waiting, handing it back if the channel is full.
+    pub fn try_send(&mut self, value: S::Item) -> Result<(), S::Item> {
+        self.sender.try_send(value)
+    }
+
+    /// The wrapped sender.
+    pub fn get_ref(&self) -> &S {
+        &self.sender
+    }
+
+    /// Stops the helper thread and returns the wrapped sender. A value the `Sink` still
+    /// holds is dropped.
+    pub fn into_inner(self) -> S {
+        let Self {
+            notifier, sender, ..
+        } = self;
+        drop(notifier);
+        sender
+    }
+
+    /// Sends the value held by the `Sink`, if there is one.
+    #[cfg(feature = "futures")]
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let Self {
+            notifier,
+            sender,
+            pending,
+        } = self;
+        if pending.is_none() {
+            return Poll::Ready(Ok(()));
+        }
+        notifier.poll_until(cx, || {
+            let value = pending
+                .take()
+                .expect("value is only taken once per attempt");
+            sender
+                .try_send(value)
+                .map_err(|value| *pending = Some(value))
+                .ok()
+        })
+    }
+}
+
+/// Accepts a value whenever the previous one made it into the channel. Flushing waits until
+/// the last value is in the channel, not until it is received.
+#[cfg(feature = "futures")]
+impl<S: Sender + Unpin> Sink<S::Item> for AsyncSender<S>
+where
+    S::Item: Unpin,
+{
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().poll_pending(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: S::Item) -> Result<()> {
+        let this = self.get_mut();
+        if this.pending.is_some() {
+            return Err(Error::InvalidArgument(
+                "start_send called without poll_ready".to_owned(),
+            ));
+        }
+        this.pending = Some(item);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().poll_pending(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().poll_pending(cx)
+    }
+}
+
+/// The receiving end of a shared memory channel, a `spsc::Consumer` or an `mpmc::Queue`,
+/// that waits without blocking the thread while the channel is empty. Only available on
+/// Linux with the `tokio` feature.
+///
+/// A helper thread waits for senders to add values and wakes the receiving task through an
+/// eventfd registered with tokio. Unlike `ready_fd`, this works without permission to ptrace
+/// the receiving process.
+pub struct AsyncReceiver<R: Receiver> {
+    notifier: Notifier,
+    receiver: R,
+}
+
+impl<R: Receiver> AsyncReceiver<R> {
+    /// Wraps `receiver`. Must be called within a tokio runtime.
+    pub fn new(receiver: R) -> Result<Self> {
+        let bridge = unsafe { receiver.not_empty().0.bridge(None)? };
+        Ok(Self {
+            notifier: Notifier::new(bridge)?,
+            receiver,
+        })
+    }
+
+    /// Receives a value, waiting while the channel is empty.
+    pub async fn recv(&mut self) -> Result<R::Item> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    /// Polls for a value, registering the task to be woken when one may have arrived.
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Result<R::Item>> {
+        let Self { notifier, receiver } = self;
+        notifier.poll_until(cx, || receiver.try_recv())
+    }
+
+    /// Receives a value without waiting, returning `None` if the channel is empty.
+    pub fn try_recv(&mut self) -> Option<R::Item> {
+        self.receiver.try_recv()
+    }
+
+    /// The wrapped receiver.
+    pub fn get_ref(&self) -> &R {
+        &self.receiver
+    }
+
+    /// Stops the helper thread and returns the wrapped receiver.
+    pub fn into_inner(self) -> R {
+        let Self { notifier, receiver } = self;
+        drop(notifier);
+        receiver
+    }
+}
+
+/// Yields the values sent to the channel. Shared memory channels have no notion of being
+/// closed, so the stream never ends.
+#[cfg(feature = "futures")]
+impl<R: Receiver + Unpin> Stream for AsyncReceiver<R> {
+    type Item = Result<R::Item>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_recv(cx).map(Some)
+    }
+}
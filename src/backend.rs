@@ -0,0 +1,62 @@
+use std::{fmt, os::fd::OwnedFd};
+
+use nix::{
+    fcntl::{OFlag, open},
+    sys::{
+        mman::{shm_open, shm_unlink},
+        stat::Mode,
+    },
+    unistd::unlink,
+};
+
+use crate::error::{Error, Result};
+
+/// Where the backing file of a named object lives, which decides how it is opened and removed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ObjectPath {
+    /// A POSIX shared memory object, opened with `shm_open` by a name starting with a slash.
+    /// The system decides where such objects live, e.g. /dev/shm on Linux.
+    Shm(String),
+    /// A regular file opened with `open`, e.g. on a tmpfs mount chosen by the user.
+    File(String),
+}
+
+impl ObjectPath {
+    /// Opens the file with close-on-exec set, which `shm_open` always does.
+    pub(crate) fn open(&self, flags: OFlag, mode: Mode) -> nix::Result<OwnedFd> {
+        match self {
+            Self::Shm(name) => shm_open(name.as_str(), flags, mode),
+            Self::File(path) => open(path.as_str(), flags | OFlag::O_CLOEXEC, mode),
+        }
+    }
+
+    pub(crate) fn unlink(&self) -> Result<()> {
+        let result = match self {
+            Self::Shm(name) => shm_unlink(name.as_str()),
+            Self::File(path) => unlink(path.as_str()),
+        };
+        result.map_err(Error::unlink(&self.to_string()))
+    }
+
+    /// Path the lock files of the object start with, see `cleanup::LockFiles`. Those of shared
+    /// memory objects live in the temporary directory, which on macOS is private to the user.
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn lock_base(&self) -> String {
+        match self {
+            Self::Shm(name) => {
+                let dir = std::env::temp_dir();
+                format!("{}/nix-ipc.{}", dir.display(), &name[1..])
+            }
+            Self::File(path) => path.clone(),
+        }
+    }
+}
+
+impl fmt::Display for ObjectPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Shm(name) => write!(f, "shm {name}"),
+            Self::File(path) => f.write_str(path),
+        }
+    }
+}
@@ -0,0 +1,508 @@
+//! Broadcast ring in shared memory: every subscriber receives every message published.
+
+use std::{
+    cell::UnsafeCell,
+    marker::PhantomData,
+    mem::{MaybeUninit, align_of, size_of},
+    num::NonZeroUsize,
+    process, ptr,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering, fence},
+    time::{Duration, Instant},
+};
+
+use nix::fcntl::OFlag;
+
+use crate::{
+    builder::Builder,
+    cache_padded::CachePadded,
+    cleanup::CleanupPolicy,
+    error::{Error, Result},
+    futex::EventCount,
+    header::Header,
+    liveness::{LIVENESS_POLL, is_alive},
+    map::Mapping,
+    namespace::Namespace,
+    permissions::Permissions,
+    shm_safe::ShmSafe,
+};
+
+/// The most subscribers a bus can have at the same time.
+pub const MAX_SUBSCRIBERS: usize = 64;
+
+/// What happens when a subscriber falls a whole ring behind the publishers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LagPolicy {
+    /// Publishers wait until the slowest subscriber received the oldest message. Subscribers
+    /// of processes that died stop holding them up within 10 milliseconds.
+    Block,
+    /// Publishers overwrite the oldest message, and subscribers that didn't receive it in
+    /// time skip ahead to the oldest one left, counting what they missed.
+    DropOldest,
+    /// Like `DropOldest`, but a subscriber that fell behind gets `Error::Lagged` once before
+    /// continuing with the oldest message left.
+    Error,
+}
+
+impl LagPolicy {
+    fn to_raw(self) -> u32 {
+        match self {
+            Self::Block => 0,
+            Self::DropOldest => 1,
+            Self::Error => 2,
+        }
+    }
+}
+
+/// A registered subscriber, or a free entry if `pid` is 0.
+#[repr(C)]
+struct Subscription {
+    pid: AtomicU32,
+    /// Position of the next message the subscriber receives.
+    cursor: AtomicU64,
+}
+
+#[repr(C)]
+struct BusHeader {
+    header: Header,
+    capacity: u64,
+    policy: u32,
+    /// Total number of messages claimed by publishers.
+    tail: CachePadded<AtomicU64>,
+    /// Notified whenever a message is published.
+    not_empty: EventCount,
+    /// Notified whenever a subscriber receives a message or leaves.
+    not_full: EventCount,
+    /// Only registered and freed while holding the init flock.
+    subscriptions: [CachePadded<Subscription>; MAX_SUBSCRIBERS],
+}
+
+/// A slot holds the message at position `pos` once `seq` is `2 * pos + 2`, and is being
+/// written while `seq` is odd.
+#[repr(C)]
+struct Slot<T> {
+    seq: AtomicU64,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// Unlinks (deletes) the bus from /dev/shm.
+pub fn unlink(name: &str) -> Result<()> {
+    unlink_in(&Namespace::default(), name)
+}
+
+/// Unlinks the bus called `name` in `namespace`.
+pub fn unlink_in(namespace: &Namespace, name: &str) -> Result<()> {
+    namespace.path(name, ".bus").unlink()
+}
+
+/// Mapping of the bus shared by publishers and subscribers.
+struct Bus<T> {
+    map: Mapping,
+    capacity: u64,
+    policy: LagPolicy,
+    _marker: PhantomData<T>,
+}
+
+impl<T: ShmSafe + Copy> Bus<T> {
+    fn open(
+        namespace: &Namespace,
+        name: &str,
+        capacity: usize,
+        policy: LagPolicy,
+        permissions: &Permissions,
+    ) -> Result<Self> {
+        if size_of::<T>() == 0 {
+            return Err(Error::InvalidArgument(
+                "Cannot use zero-sized type in shared memory".to_owned(),
+            ));
+        }
+        if capacity == 0 {
+            return Err(Error::InvalidArgument(
+                "Bus capacity must be nonzero".to_owned(),
+            ));
+        }
+        let data_len = capacity.checked_mul(size_of::<Slot<T>>()).ok_or_else(|| {
+            Error::InvalidArgument(format!("Bus of {capacity} messages is too large"))
+        })?;
+        let map_len =
+            NonZeroUsize::new(Self::data_offset() + data_len).expect("BusHeader has nonzero size");
+
+        let path = namespace.path(name, ".bus");
+        let map = Mapping::open_init(
+            &path,
+            OFlag::O_CREAT,
+            permissions,
+            map_len,
+            CleanupPolicy::LastCloseRefCounted,
+            |raw| unsafe {
+                let bus = raw as *mut BusHeader;
+                (*bus).header.init::<Slot<T>>(data_len, 0);
+                (*bus).capacity = capacity as u64;
+                (*bus).policy = policy.to_raw();
+                Ok(())
+            },
+        )?;
+
+        let bus = map.ptr() as *const BusHeader;
+        {
+            let _init_lock = map.init_lock()?;
+            unsafe {
+                (*bus).header.validate::<Slot<T>>(data_len, 0)?;
+                if (*bus).policy != policy.to_raw() {
+                    return Err(Error::Validation(format!(
+                        "Bus was created with a different lag policy than {policy:?}"
+                    )));
+                }
+                (*bus).header.attach(&map)?;
+            }
+        }
+
+        Ok(Self {
+            map,
+            capacity: capacity as u64,
+            policy,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Offset of the first slot, after the header and aligned for it.
+    fn data_offset() -> usize {
+        size_of::<BusHeader>().next_multiple_of(align_of::<Slot<T>>())
+    }
+
+    fn header(&self) -> &BusHeader {
+        unsafe { &*(self.map.ptr() as *const BusHeader) }
+    }
+
+    fn slot(&self, pos: u64) -> &Slot<T> {
+        let index = (pos % self.capacity) as usize;
+        unsafe {
+            let slots = (self.map.ptr() as *const u8).add(Self::data_offset()) as *const Slot<T>;
+            &*slots.add(index)
+        }
+    }
+
+    /// Position of the slowest live subscriber, or `tail` if there is none. Subscribers of
+    /// dead processes are ignored.
+    fn slowest(&self, tail: u64) -> u64 {
+        self.header()
+            .subscriptions
+            .iter()
+            .filter(|subscription| {
+                let pid = subscription.pid.load(Ordering::Acquire);
+                pid != 0 && is_alive(pid)
+            })
+            .map(|subscription| subscription.cursor.load(Ordering::Acquire))
+            .fold(tail, u64::min)
+    }
+
+    fn try_send(&self, cached_slowest: &mut u64, value: T) -> Result<(), T> {
+        let header = self.header();
+        let mut tail = header.tail.load(Ordering::Acquire);
+        loop {
+            if self.policy == LagPolicy::Block && tail - *cached_slowest >= self.capacity {
+                *cached_slowest = self.slowest(tail);
+                if tail - *cached_slowest >= self.capacity {
+                    return Err(value);
+                }
+            }
+            match header.tail.compare_exchange_weak(
+                tail,
+                tail + 1,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => break,
+                Err(actual) => tail = actual,
+            }
+        }
+
+        // The publisher of the message one lap ago may still be writing it.
+        let slot = self.slot(tail);
+        let previous = (tail + 1).saturating_sub(self.capacity) * 2;
+        while slot.seq.load(Ordering::Acquire) < previous {
+            std::hint::spin_loop();
+        }
+
+        slot.seq.store(2 * tail + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        unsafe { ptr::write_volatile(slot.value.get(), MaybeUninit::new(value)) };
+        slot.seq.store(2 * tail + 2, Ordering::Release);
+        header.not_empty.notify_all();
+        Ok(())
+    }
+
+    fn subscribers(&self) -> usize {
+        self.header()
+            .subscriptions
+            .iter()
+            .filter(|subscription| subscription.pid.load(Ordering::Acquire) != 0)
+            .count()
+    }
+}
+
+impl<T> Drop for Bus<T> {
+    fn drop(&mut self) {
+        let bus = self.map.ptr() as *const BusHeader;
+        unsafe { (*bus).header.detach(&self.map) };
+    }
+}
+
+/// Publishing end of a broadcast bus. Any number of publishers can share a bus.
+pub struct Publisher<T: 'static> {
+    bus: Bus<T>,
+    /// Last seen position of the slowest subscriber, to avoid scanning them on every send.
+    cached_slowest: u64,
+}
+
+impl<T: ShmSafe + Copy> Publisher<T> {
+    /// Opens the bus in /dev/shm for publishing, creating it with room for `capacity`
+    /// messages and the lag policy `policy` if it doesn't exist. Attaching to a bus with a
+    /// different capacity or lag policy fails.
+    pub fn new(name: &str, capacity: usize, policy: LagPolicy) -> Result<Self> {
+        Self::builder(name).build(capacity, policy)
+    }
+
+    /// Returns a builder for setting the namespace and the permissions the bus is created with.
+    pub fn builder(name: &str) -> Builder<Self> {
+        Builder::new(name)
+    }
+
+    /// Publishes `value` without blocking, handing it back if a subscriber is a whole ring
+    /// behind under `LagPolicy::Block`. Never fails under the other policies.
+    pub fn try_send(&mut self, value: T) -> Result<(), T> {
+        self.bus.try_send(&mut self.cached_slowest, value)
+    }
+
+    /// Publishes `value`, blocking while a subscriber is a whole ring behind under
+    /// `LagPolicy::Block`.
+    pub fn send(&mut self, value: T) -> Result<()> {
+        self.send_until(value, None).map(|_| ())
+    }
+
+    /// Publishes `value`, blocking for at most `timeout` while a subscriber is a whole ring
+    /// behind. Returns the value back if it could not be published in time.
+    pub fn send_timeout(&mut self, value: T, timeout: Duration) -> Result<Option<T>> {
+        self.send_until(value, Some(Instant::now() + timeout))
+    }
+
+    fn send_until(&mut self, value: T, deadline: Option<Instant>) -> Result<Option<T>> {
+        let Self {
+            bus,
+            cached_slowest,
+        } = self;
+        let mut value = Some(value);
+        loop {
+            // Wake up now and then to stop waiting for subscribers that died.
+            let poll = match deadline {
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return Ok(value);
+                    }
+                    left.min(LIVENESS_POLL)
+                }
+                None => LIVENESS_POLL,
+            };
+            let sent = bus.header().not_full.wait_for(Some(poll), || {
+                let pending = value.take().expect("value is only taken once per attempt");
+                bus.try_send(cached_slowest, pending)
+                    .map_err(|pending| value = Some(pending))
+                    .ok()
+            })?;
+            if sent.is_some() {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Number of subscribers currently registered, including those of processes that died
+    /// without being noticed yet.
+    pub fn subscribers(&self) -> usize {
+        self.bus.subscribers()
+    }
+
+    /// Number of messages the ring holds.
+    pub fn capacity(&self) -> usize {
+        self.bus.capacity as usize
+    }
+}
+
+impl<T: ShmSafe + Copy> Builder<Publisher<T>> {
+    /// Opens the bus like `Publisher::new`.
+    pub fn build(self, capacity: usize, policy: LagPolicy) -> Result<Publisher<T>> {
+        let bus = Bus::open(
+            &self.namespace,
+            &self.name,
+            capacity,
+            policy,
+            &self.permissions,
+        )?;
+        let cached_slowest = bus.slowest(bus.header().tail.load(Ordering::Acquire));
+        Ok(Publisher {
+            bus,
+            cached_slowest,
+        })
+    }
+}
+
+/// Receiving end of a broadcast bus, with its own position in the stream of messages.
+pub struct Subscriber<T: 'static> {
+    bus: Bus<T>,
+    index: usize,
+    cursor: u64,
+    missed: u64,
+}
+
+impl<T: ShmSafe + Copy> Subscriber<T> {
+    /// Opens the bus in /dev/shm and subscribes to it, creating it with room for `capacity`
+    /// messages and the lag policy `policy` if it doesn't exist. Attaching to a bus with a
+    /// different capacity or lag policy fails.
+    ///
+    /// The subscriber receives the messages published from now on. Fails if the bus already
+    /// has `MAX_SUBSCRIBERS` subscribers.
+    pub fn new(name: &str, capacity: usize, policy: LagPolicy) -> Result<Self> {
+        Self::builder(name).build(capacity, policy)
+    }
+
+    /// Returns a builder for setting the namespace and the permissions the bus is created with.
+    pub fn builder(name: &str) -> Builder<Self> {
+        Builder::new(name)
+    }
+
+    /// Receives the next message without blocking, returning `None` if there is none.
+    /// Fails with `Error::Lagged` if messages were overwritten before they were received
+    /// under `LagPolicy::Error`.
+    pub fn try_recv(&mut self) -> Result<Option<T>> {
+        loop {
+            let pos = self.cursor;
+            let slot = self.bus.slot(pos);
+            let before = slot.seq.load(Ordering::Acquire);
+            if before < 2 * pos + 2 {
+                return Ok(None);
+            }
+            if before == 2 * pos + 2 {
+                // The copy may be torn, so it stays uninitialized until the counter proves
+                // it isn't.
+                let value = unsafe { ptr::read_volatile(slot.value.get()) };
+                fence(Ordering::Acquire);
+                if slot.seq.load(Ordering::Relaxed) == before {
+                    self.advance(pos + 1);
+                    return Ok(Some(unsafe { value.assume_init() }));
+                }
+            }
+
+            // Overwritten: skip to the oldest message left.
+            let tail = self.bus.header().tail.load(Ordering::Acquire);
+            let oldest = tail.saturating_sub(self.bus.capacity).max(pos + 1);
+            self.advance(oldest);
+            self.missed += oldest - pos;
+            if self.bus.policy == LagPolicy::Error {
+                return Err(Error::Lagged(oldest - pos));
+            }
+        }
+    }
+
+    /// Receives the next message, blocking while there is none.
+    pub fn recv(&mut self) -> Result<T> {
+        let value = self.recv_until(None)?;
+        Ok(value.expect("waiting without a timeout always yields a value"))
+    }
+
+    /// Receives the next message, blocking for at most `timeout` while there is none.
+    /// Returns `None` if nothing arrived in time.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<T>> {
+        self.recv_until(Some(timeout))
+    }
+
+    fn recv_until(&mut self, timeout: Option<Duration>) -> Result<Option<T>> {
+        let event = &self.bus.header().not_empty as *const EventCount;
+        // The event count lives in the mapping, which outlives this call.
+        let received = unsafe { &*event }.wait_for(timeout, || self.try_recv().transpose())?;
+        received.transpose()
+    }
+
+    /// Number of messages published but not yet received by this subscriber, capped at the
+    /// capacity.
+    pub fn len(&self) -> usize {
+        let tail = self.bus.header().tail.load(Ordering::Acquire);
+        tail.saturating_sub(self.cursor).min(self.bus.capacity) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Total number of messages this subscriber skipped because they were overwritten.
+    pub fn missed(&self) -> u64 {
+        self.missed
+    }
+
+    /// Number of messages the ring holds.
+    pub fn capacity(&self) -> usize {
+        self.bus.capacity as usize
+    }
+
+    fn advance(&mut self, cursor: u64) {
+        self.cursor = cursor;
+        let header = self.bus.header();
+        header.subscriptions[self.index]
+            .cursor
+            .store(cursor, Ordering::Release);
+        if self.bus.policy == LagPolicy::Block {
+            header.not_full.notify_all();
+        }
+    }
+}
+
+impl<T: ShmSafe + Copy> Builder<Subscriber<T>> {
+    /// Opens the bus like `Subscriber::new`.
+    pub fn build(self, capacity: usize, policy: LagPolicy) -> Result<Subscriber<T>> {
+        let bus = Bus::open(
+            &self.namespace,
+            &self.name,
+            capacity,
+            policy,
+            &self.permissions,
+        )?;
+        let header = bus.header();
+        let me = process::id();
+
+        let (index, cursor) = {
+            let _lock = bus.map.init_lock()?;
+            let index = header
+                .subscriptions
+                .iter()
+                .position(|subscription| {
+                    let pid = subscription.pid.load(Ordering::Relaxed);
+                    pid == 0 || !is_alive(pid)
+                })
+                .ok_or_else(|| {
+                    Error::InvalidArgument(format!("Bus already has {MAX_SUBSCRIBERS} subscribers"))
+                })?;
+            let subscription = &header.subscriptions[index];
+            let cursor = header.tail.load(Ordering::Acquire);
+            subscription.cursor.store(cursor, Ordering::Release);
+            subscription.pid.store(me, Ordering::Release);
+            (index, cursor)
+        };
+
+        Ok(Subscriber {
+            bus,
+            index,
+            cursor,
+            missed: 0,
+        })
+    }
+}
+
+impl<T: 'static> Drop for Subscriber<T> {
+    fn drop(&mut self) {
+        let header = unsafe { &*(self.bus.map.ptr() as *const BusHeader) };
+        if let Ok(_lock) = self.bus.map.init_lock() {
+            header.subscriptions[self.index]
+                .pid
+                .store(0, Ordering::Release);
+        }
+        header.not_full.notify_all();
+    }
+}
@@ -0,0 +1,40 @@
+use std::marker::PhantomData;
+
+use crate::{namespace::Namespace, permissions::Permissions};
+
+/// Builder for named objects that take no options besides the namespace they live in and
+/// the permissions they are created with, returned by their `builder` function, e.g.
+/// `Condvar::builder`.
+///
+/// It's finished with the `build` method of the object, which takes the same arguments as
+/// its `new` function.
+pub struct Builder<P> {
+    pub(crate) name: String,
+    pub(crate) permissions: Permissions,
+    pub(crate) namespace: Namespace,
+    _marker: PhantomData<fn() -> P>,
+}
+
+impl<P> Builder<P> {
+    pub(crate) fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            permissions: Permissions::default(),
+            namespace: Namespace::default(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Sets the permissions the backing file is created with, see `Permissions`. Only
+    /// applies if this handle creates the object.
+    pub fn permissions(mut self, permissions: Permissions) -> Self {
+        self.permissions = permissions;
+        self
+    }
+
+    /// Opens the object in `namespace` instead of the default one.
+    pub fn namespace(mut self, namespace: &Namespace) -> Self {
+        self.namespace = namespace.clone();
+        self
+    }
+}
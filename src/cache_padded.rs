@@ -0,0 +1,163 @@
+use std::{
+    mem::size_of,
+    ops::{Deref, DerefMut},
+};
+
+use crate::shm_safe::{ShmAtomic, ShmSafe, fnv1a_u64};
+
+/// Size of the cache lines `CachePadded` pads to.
+pub const CACHE_LINE: usize = 64;
+
+/// Size of the pages `PageAligned` aligns to. Systems with larger pages, such as some
+/// aarch64 configurations with 16 KiB pages, only get this alignment within a page.
+pub const PAGE_SIZE: usize = 4096;
+
+/// Aligns and pads a value to a cache line, so values written by different processes
+/// don't share one.
+///
+/// Put hot fields written by different processes in a `CachePadded` each, and check the
+/// layout with `assert_cache_isolated!`.
+#[repr(C, align(64))]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CachePadded<T>(pub(crate) T);
+
+impl<T> CachePadded<T> {
+    pub const fn new(value: T) -> Self {
+        Self(value)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for CachePadded<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+unsafe impl<T: ShmSafe> ShmSafe for CachePadded<T> {
+    const FINGERPRINT: u64 = fnv1a_u64(T::FINGERPRINT, size_of::<Self>() as u64);
+}
+
+unsafe impl<T: ShmAtomic> ShmAtomic for CachePadded<T> {}
+
+/// Aligns and pads a value to a page, so a sub-structure of shared data starts on its own
+/// page and can be locked, advised or protected separately from the rest.
+#[repr(C, align(4096))]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PageAligned<T>(pub(crate) T);
+
+impl<T> PageAligned<T> {
+    pub const fn new(value: T) -> Self {
+        Self(value)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for PageAligned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for PageAligned<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+unsafe impl<T: ShmSafe> ShmSafe for PageAligned<T> {
+    const FINGERPRINT: u64 = fnv1a_u64(T::FINGERPRINT, size_of::<Self>() as u64);
+}
+
+unsafe impl<T: ShmAtomic> ShmAtomic for PageAligned<T> {}
+
+/// Fails to compile unless each of the listed fields of a struct lies on cache lines none of
+/// the others touch, e.g. `assert_cache_isolated!(Stats, produced, consumed);`. The struct
+/// itself must be aligned to a cache line, which a `CachePadded` field takes care of.
+#[macro_export]
+macro_rules! assert_cache_isolated {
+    ($type:ty, $($field:ident),+ $(,)?) => {
+        const _: () = {
+            let value = ::core::mem::MaybeUninit::<$type>::uninit();
+            let ptr = value.as_ptr();
+            let fields = [$((
+                ::core::mem::offset_of!($type, $field),
+                $crate::__private::pointee_size(unsafe { &raw const (*ptr).$field }),
+            )),+];
+            assert!(
+                ::core::mem::align_of::<$type>() >= $crate::CACHE_LINE,
+                concat!("`", stringify!($type), "` must be aligned to a cache line"),
+            );
+            assert!(
+                $crate::__private::cache_isolated(&fields),
+                concat!(
+                    "Fields ",
+                    $(stringify!($field), " ",)+
+                    "of `",
+                    stringify!($type),
+                    "` share a cache line",
+                ),
+            );
+        };
+    };
+}
+
+/// Size of the value `ptr` points to, used by `assert_cache_isolated!` to size fields.
+pub const fn pointee_size<F>(_ptr: *const F) -> usize {
+    size_of::<F>()
+}
+
+/// Returns true if a struct of `size` and `align` bytes with the `(offset, size, align)`
+/// fields in declaration order has no padding, except for padding before fields or at the
+/// end that comes from aligning to a cache line or more.
+pub const fn padding_free(fields: &[(usize, usize, usize)], size: usize, align: usize) -> bool {
+    let mut end = 0;
+    let mut i = 0;
+    while i < fields.len() {
+        let (offset, field_size, field_align) = fields[i];
+        if offset != end && field_align < CACHE_LINE {
+            return false;
+        }
+        end = offset + field_size;
+        i += 1;
+    }
+    end == size || align >= CACHE_LINE
+}
+
+/// Returns true if no two of the `(offset, size)` ranges touch the same cache line.
+pub const fn cache_isolated(fields: &[(usize, usize)]) -> bool {
+    let mut i = 0;
+    while i < fields.len() {
+        let mut j = i + 1;
+        while j < fields.len() {
+            let (a_offset, a_size) = fields[i];
+            let (b_offset, b_size) = fields[j];
+            if a_size > 0 && b_size > 0 {
+                let a_last = (a_offset + a_size - 1) / CACHE_LINE;
+                let b_last = (b_offset + b_size - 1) / CACHE_LINE;
+                if a_last >= b_offset / CACHE_LINE && b_last >= a_offset / CACHE_LINE {
+                    return false;
+                }
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    true
+}
@@ -0,0 +1,33 @@
+/// Lookup table of CRC-32C (Castagnoli), one entry per byte value.
+const TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0x82f6_3b78,
+                _ => crc >> 1,
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC-32C of `len` bytes at `ptr`, which may include padding of the value stored there.
+///
+/// # Safety
+///
+/// `ptr` must be valid for reading `len` bytes.
+pub(crate) unsafe fn crc32c(ptr: *const u8, len: usize) -> u32 {
+    let mut crc = !0u32;
+    for i in 0..len {
+        let byte = unsafe { ptr.add(i).read() };
+        crc = TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
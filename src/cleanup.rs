@@ -0,0 +1,242 @@
+use std::os::fd::{AsRawFd, OwnedFd};
+
+use nix::errno::Errno;
+#[cfg(target_os = "linux")]
+use nix::{
+    fcntl::{FcntlArg, fcntl},
+    libc::{F_RDLCK, F_WRLCK, SEEK_SET, c_short, flock},
+};
+#[cfg(not(target_os = "linux"))]
+use nix::{
+    fcntl::{OFlag, open},
+    libc::{LOCK_EX, LOCK_NB, LOCK_SH},
+    sys::stat::Mode,
+};
+
+use crate::{
+    backend::ObjectPath,
+    error::{Error, Result},
+    fork::Reopen,
+    permissions::Permissions,
+};
+
+/// What happens to the backing file of a named object when a handle to it is dropped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CleanupPolicy {
+    /// Leave the file in place so the object persists.
+    #[default]
+    Never,
+    /// Unlink the file when this handle is dropped, even if other processes still use it.
+    UnlinkOnDrop,
+    /// Unlink the file when the last handle across all processes is dropped, counting handles
+    /// with any policy. Handles of crashed processes are released by the kernel and don't keep the file alive.
+    /// Handles inherited through `fork` count separately, so the parent and the child can
+    /// drop theirs in either order.
+    LastCloseRefCounted,
+}
+
+// Attached handles hold a shared lock over the whole file. A detaching handle that
+// manages to upgrade its lock to an exclusive one is the last one and may unlink the file.
+// Handles attach whatever their cleanup policy, so `Namespace::gc` can take the exclusive
+// lock the same way to find objects no process uses anymore.
+//
+// On Linux this is an OFD lock on the object itself, which doesn't interfere with the flock
+// serializing initialization. Elsewhere shared memory objects can't be locked at all, so both
+// locks are flocks on separate lock files next to the object, see `LockFiles`.
+
+/// The files the init lock and the attach lock of a named object are taken on.
+/// On Linux both are taken on the object itself, so this holds nothing.
+#[derive(Default)]
+pub(crate) struct LockFiles {
+    #[cfg(not(target_os = "linux"))]
+    init: Option<OwnedFd>,
+    #[cfg(not(target_os = "linux"))]
+    refs: Option<OwnedFd>,
+    /// Path the lock files start with.
+    #[cfg(not(target_os = "linux"))]
+    base: String,
+}
+
+impl LockFiles {
+    #[cfg(target_os = "linux")]
+    pub(crate) fn open(
+        _object: &ObjectPath,
+        _attached: bool,
+        _permissions: &Permissions,
+    ) -> Result<Self> {
+        Ok(Self::default())
+    }
+
+    /// Opens the lock files of `object`, attaching to it if `attached` is set, and creates them
+    /// with `permissions` so every process that may open the object can lock them too.
+    /// The lock files are never removed, as another process may be about to lock them.
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn open(
+        object: &ObjectPath,
+        attached: bool,
+        permissions: &Permissions,
+    ) -> Result<Self> {
+        let base = object.lock_base();
+        let init = open_lock_file(&format!("{base}.lock"), permissions)?;
+        let refs = match attached {
+            true => {
+                let refs = open_lock_file(&format!("{base}.refs"), permissions)?;
+                attach(&refs)?;
+                Some(refs)
+            }
+            false => None,
+        };
+        Ok(Self {
+            init: Some(init),
+            refs,
+            base,
+        })
+    }
+
+    /// Takes the attach lock of `object`, opened as `fd`, exclusively if no handle is attached
+    /// to it, keeping others from attaching until the returned lock files are dropped and
+    /// `fd` is closed.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn detached(_object: &ObjectPath, fd: &OwnedFd) -> Result<Option<Self>> {
+        Ok(is_last(fd)?.then(Self::default))
+    }
+
+    /// Takes the attach lock of `object`, opened as `fd`, exclusively if no handle is attached
+    /// to it, keeping others from attaching until the returned lock files are dropped.
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn detached(object: &ObjectPath, _fd: &OwnedFd) -> Result<Option<Self>> {
+        let base = object.lock_base();
+        let path = format!("{base}.refs");
+        let refs = match open(
+            path.as_str(),
+            OFlag::O_RDWR | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        ) {
+            Ok(refs) => refs,
+            // Nothing ever attached to the object.
+            Err(Errno::ENOENT) => {
+                return Ok(Some(Self {
+                    init: None,
+                    refs: None,
+                    base,
+                }));
+            }
+            Err(source) => return Err(Error::Open { path, source }),
+        };
+        Ok(is_last(&refs)?.then_some(Self {
+            init: None,
+            refs: Some(refs),
+            base,
+        }))
+    }
+
+    /// Returns the file to take the init lock on for the object opened as `fd`.
+    pub(crate) fn init<'a>(&'a self, fd: &'a OwnedFd) -> &'a OwnedFd {
+        #[cfg(not(target_os = "linux"))]
+        if let Some(init) = &self.init {
+            return init;
+        }
+        fd
+    }
+
+    /// Returns the file to take the attach lock on for the object opened as `fd`.
+    pub(crate) fn refs<'a>(&'a self, fd: &'a OwnedFd) -> &'a OwnedFd {
+        #[cfg(not(target_os = "linux"))]
+        if let Some(refs) = &self.refs {
+            return refs;
+        }
+        fd
+    }
+
+    /// The descriptors locking the object opened as `fd`, to open again in forked children.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn reopen(&self, fd: &OwnedFd, attached: bool) -> Vec<Reopen> {
+        vec![Reopen {
+            fd: fd.as_raw_fd(),
+            path: format!("/proc/self/fd/{}", fd.as_raw_fd()),
+            attach: attached,
+        }]
+    }
+
+    /// The descriptors locking the object opened as `fd`, to open again in forked children.
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn reopen(&self, _fd: &OwnedFd, _attached: bool) -> Vec<Reopen> {
+        let init = self.init.as_ref().map(|init| Reopen {
+            fd: init.as_raw_fd(),
+            path: format!("{}.lock", self.base),
+            attach: false,
+        });
+        let refs = self.refs.as_ref().map(|refs| Reopen {
+            fd: refs.as_raw_fd(),
+            path: format!("{}.refs", self.base),
+            attach: true,
+        });
+        init.into_iter().chain(refs).collect()
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_lock_file(path: &str, permissions: &Permissions) -> Result<OwnedFd> {
+    let flags = OFlag::O_RDWR | OFlag::O_CLOEXEC;
+    let create_error = |source| Error::Create {
+        path: path.to_owned(),
+        source,
+    };
+    match open(
+        path,
+        flags | OFlag::O_CREAT | OFlag::O_EXCL,
+        permissions.mode(),
+    ) {
+        Ok(fd) => {
+            permissions.apply(&fd)?;
+            Ok(fd)
+        }
+        Err(Errno::EEXIST) => open(path, flags, Mode::empty()).map_err(create_error),
+        Err(source) => Err(create_error(source)),
+    }
+}
+
+/// Marks `fd` as attached. Waits while a last closer is unlinking the file.
+#[cfg(target_os = "linux")]
+pub(crate) fn attach(fd: &OwnedFd) -> Result<()> {
+    fcntl(fd, FcntlArg::F_OFD_SETLKW(&whole_file(F_RDLCK)))
+        .map_err(Error::lock("fcntl(F_OFD_SETLKW)"))?;
+    Ok(())
+}
+
+/// Tries to upgrade the attach lock of `fd`, succeeding only if no other handle is attached.
+#[cfg(target_os = "linux")]
+pub(crate) fn is_last(fd: &OwnedFd) -> Result<bool> {
+    match fcntl(fd, FcntlArg::F_OFD_SETLK(&whole_file(F_WRLCK))) {
+        Ok(_) => Ok(true),
+        Err(Errno::EAGAIN | Errno::EACCES) => Ok(false),
+        Err(e) => Err(Error::lock("fcntl(F_OFD_SETLK)")(e)),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn whole_file(kind: i32) -> flock {
+    let mut lock: flock = unsafe { std::mem::zeroed() };
+    lock.l_type = kind as c_short;
+    lock.l_whence = SEEK_SET as c_short;
+    lock
+}
+
+/// Marks `fd` as attached. Waits while a last closer is unlinking the file.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn attach(fd: &OwnedFd) -> Result<()> {
+    Errno::result(unsafe { nix::libc::flock(fd.as_raw_fd(), LOCK_SH) })
+        .map_err(Error::lock("flock(LOCK_SH)"))?;
+    Ok(())
+}
+
+/// Tries to upgrade the attach lock of `fd`, succeeding only if no other handle is attached.
+/// A failed non-blocking upgrade keeps the shared lock.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn is_last(fd: &OwnedFd) -> Result<bool> {
+    match Errno::result(unsafe { nix::libc::flock(fd.as_raw_fd(), LOCK_EX | LOCK_NB) }) {
+        Ok(_) => Ok(true),
+        Err(Errno::EWOULDBLOCK) => Ok(false),
+        Err(e) => Err(Error::lock("flock(LOCK_EX)")(e)),
+    }
+}
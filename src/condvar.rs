@@ -0,0 +1,118 @@
+use std::{mem::size_of, num::NonZeroUsize, time::Duration};
+
+use nix::{fcntl::OFlag, libc::ETIMEDOUT};
+
+use crate::{
+    builder::Builder,
+    cleanup::CleanupPolicy,
+    error::{Error, Result},
+    map::Mapping,
+    namespace::Namespace,
+    r_mtx::{LockResult, RMtxGuard, TimedLockResult},
+    raw_lock::{self, RawCondvar},
+};
+
+/// An interprocess condition variable implemented using `pthread_cond_t` and shared memory.
+/// It is meant to be used together with an `RMtx` guarding the shared state.
+pub struct Condvar {
+    map: Mapping,
+}
+
+impl Condvar {
+    pub fn new(name: &str) -> Result<Self> {
+        Self::builder(name).build()
+    }
+
+    /// Returns a builder for setting the namespace and the permissions the condition variable is
+    /// created with.
+    pub fn builder(name: &str) -> Builder<Self> {
+        Builder::new(name)
+    }
+
+    /// Unlinks (deletes) the condition variable file from /dev/shm.
+    pub fn unlink(name: &str) -> Result<()> {
+        Self::unlink_in(&Namespace::default(), name)
+    }
+
+    /// Unlinks the condition variable called `name` in `namespace`.
+    pub fn unlink_in(namespace: &Namespace, name: &str) -> Result<()> {
+        namespace.path(name, ".cnd").unlink()
+    }
+
+    /// Blocks until notified, atomically releasing the mutex held by `guard` while waiting.
+    /// Spurious wakeups are possible, so the shared state should be re-checked in a loop.
+    /// If the mutex owner died in the meantime, `OwnerDiedRecovered` is returned and the
+    /// guarded state must be treated as possibly inconsistent.
+    pub fn wait(&self, guard: &mut RMtxGuard<'_>) -> Result<LockResult> {
+        let mtx = guard.mtx();
+        #[cfg(feature = "deadlock-detection")]
+        mtx.track_held(false);
+        let err = unsafe { raw_lock::wait(self.ptr(), mtx.raw())? };
+        let result = mtx.acquired(err, "pthread_cond_wait")?;
+        guard.set_result(result.clone());
+        Ok(result)
+    }
+
+    /// Like `wait`, but gives up after `timeout` has elapsed on the monotonic clock.
+    /// The mutex is held again on return, including when `TimedOut` is reported.
+    pub fn wait_timeout(
+        &self,
+        guard: &mut RMtxGuard<'_>,
+        timeout: Duration,
+    ) -> Result<TimedLockResult> {
+        let mtx = guard.mtx();
+        #[cfg(feature = "deadlock-detection")]
+        mtx.track_held(false);
+        let err = unsafe { raw_lock::wait_timeout(self.ptr(), mtx.raw(), timeout)? };
+        if err == ETIMEDOUT {
+            #[cfg(feature = "deadlock-detection")]
+            mtx.track_held(true);
+            return Ok(TimedLockResult::TimedOut);
+        }
+
+        let result = mtx.acquired(err, "pthread_cond_timedwait")?;
+        guard.set_result(result.clone());
+        Ok(match result {
+            LockResult::Acquired => TimedLockResult::Acquired,
+            LockResult::OwnerDiedRecovered => TimedLockResult::OwnerDiedRecovered,
+        })
+    }
+
+    /// Wakes up one waiting process or thread.
+    pub fn notify_one(&self) -> Result<()> {
+        raw_lock::check(unsafe { raw_lock::notify_one(self.ptr()) })
+            .map(|_| ())
+            .map_err(Error::lock("pthread_cond_signal"))
+    }
+
+    /// Wakes up all waiting processes and threads.
+    pub fn notify_all(&self) -> Result<()> {
+        raw_lock::check(unsafe { raw_lock::notify_all(self.ptr()) })
+            .map(|_| ())
+            .map_err(Error::lock("pthread_cond_broadcast"))
+    }
+
+    fn ptr(&self) -> *mut RawCondvar {
+        self.map.ptr() as *mut RawCondvar
+    }
+}
+
+impl Builder<Condvar> {
+    /// Opens the condition variable like `Condvar::new`.
+    pub fn build(self) -> Result<Condvar> {
+        let path = self.namespace.path(&self.name, ".cnd");
+        let len = NonZeroUsize::new(size_of::<RawCondvar>()).expect("RawCondvar has nonzero size");
+
+        let map = Mapping::open_init(
+            &path,
+            OFlag::O_CREAT,
+            &self.permissions,
+            len,
+            CleanupPolicy::Never,
+            |ptr| unsafe { raw_lock::init_condvar(ptr as *mut RawCondvar) },
+        )?;
+        map.inherit_in_forks(None);
+
+        Ok(Condvar { map })
+    }
+}
@@ -0,0 +1,171 @@
+use std::{marker::PhantomData, mem::size_of, num::NonZeroUsize, time::Duration};
+
+use nix::fcntl::OFlag;
+
+use crate::{
+    builder::Builder,
+    cleanup::CleanupPolicy,
+    double_buffer::Buffers,
+    error::{Error, Result},
+    futex::EventCount,
+    header::Header,
+    map::Mapping,
+    namespace::Namespace,
+    shm_safe::ShmSafe,
+};
+
+#[repr(C)]
+struct ConfigState<T> {
+    header: Header,
+    buffers: Buffers<T>,
+    /// Notified whenever a new version is published.
+    changed: EventCount,
+}
+
+/// Configuration in shared memory that one process publishes and any number of processes
+/// follow, replacing schemes like rereading a file on SIGHUP.
+///
+/// Versions are kept in two copies like in `DoubleBuffer`, so readers always get a complete
+/// one without locking. Each published version bumps a counter, which readers can poll with
+/// `version`, or wait on with `wait_changed` and `wait_changed_async` to pick up changes as
+/// they happen.
+pub struct ConfigCell<T: 'static> {
+    map: Mapping,
+    _marker: PhantomData<T>,
+}
+
+impl<T: ShmSafe + Copy> ConfigCell<T> {
+    /// Opens the configuration in /dev/shm, creating it holding `initial` if it doesn't
+    /// exist. Opening an existing one keeps its current version.
+    pub fn new(name: &str, initial: T) -> Result<Self> {
+        Self::builder(name).build(initial)
+    }
+
+    /// Returns a builder for setting the namespace and the permissions the configuration is created
+    /// with.
+    pub fn builder(name: &str) -> Builder<Self> {
+        Builder::new(name)
+    }
+
+    /// Unlinks (deletes) the configuration from /dev/shm.
+    pub fn unlink(name: &str) -> Result<()> {
+        Self::unlink_in(&Namespace::default(), name)
+    }
+
+    /// Unlinks the configuration called `name` in `namespace`.
+    pub fn unlink_in(namespace: &Namespace, name: &str) -> Result<()> {
+        namespace.path(name, ".cfg").unlink()
+    }
+
+    /// Returns a copy of the current configuration.
+    pub fn read(&self) -> T {
+        self.state().buffers.read().0
+    }
+
+    /// Returns a copy of the current configuration with its version.
+    pub fn read_versioned(&self) -> (T, u64) {
+        self.state().buffers.read()
+    }
+
+    /// Publishes a new version of the configuration and wakes the readers waiting for one.
+    pub fn publish(&self, config: T) {
+        self.state().buffers.write_with(|_| config);
+        self.state().changed.notify_all();
+    }
+
+    /// Publishes a new version computed by `f` from the current one.
+    pub fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&mut T),
+    {
+        self.state().buffers.update(f);
+        self.state().changed.notify_all();
+    }
+
+    /// The number of versions published since the configuration was created.
+    pub fn version(&self) -> u64 {
+        self.state().buffers.version()
+    }
+
+    /// Blocks until the version differs from `seen`, and returns the new one.
+    pub fn wait_changed(&self, seen: u64) -> Result<u64> {
+        let version = self.state().changed.wait_for(None, || self.changed(seen))?;
+        Ok(version.expect("waiting without a timeout always yields a version"))
+    }
+
+    /// Blocks until the version differs from `seen` and returns the new one, or `None` if
+    /// `timeout` elapsed first.
+    pub fn wait_changed_timeout(&self, seen: u64, timeout: Duration) -> Result<Option<u64>> {
+        self.state()
+            .changed
+            .wait_for(Some(timeout), || self.changed(seen))
+    }
+
+    /// Waits until the version differs from `seen` and returns the new one, without blocking
+    /// the thread. Only available on Linux with the `tokio` feature, and must be called
+    /// within a tokio runtime.
+    #[cfg(all(target_os = "linux", feature = "tokio"))]
+    pub async fn wait_changed_async(&self, seen: u64) -> Result<u64> {
+        let bridge = unsafe { self.state().changed.bridge(None)? };
+        let notifier = crate::async_io::Notifier::new(bridge)?;
+        notifier.until(|| Ok(self.changed(seen))).await
+    }
+
+    fn changed(&self, seen: u64) -> Option<u64> {
+        let version = self.version();
+        (version != seen).then_some(version)
+    }
+
+    fn state(&self) -> &ConfigState<T> {
+        unsafe { &*(self.map.ptr() as *const ConfigState<T>) }
+    }
+}
+
+impl<T: ShmSafe + Copy> Builder<ConfigCell<T>> {
+    /// Opens the configuration like `ConfigCell::new`.
+    pub fn build(self, initial: T) -> Result<ConfigCell<T>> {
+        if size_of::<T>() == 0 {
+            return Err(Error::InvalidArgument(
+                "Cannot use zero-sized type in shared memory".to_owned(),
+            ));
+        }
+        let len =
+            NonZeroUsize::new(size_of::<ConfigState<T>>()).expect("ConfigState has nonzero size");
+
+        let path = self.namespace.path(&self.name, ".cfg");
+        let map = Mapping::open_init(
+            &path,
+            OFlag::O_CREAT,
+            &self.permissions,
+            len,
+            CleanupPolicy::LastCloseRefCounted,
+            |raw| unsafe {
+                let state = raw as *mut ConfigState<T>;
+                (*state).header.init::<T>(size_of::<T>(), 0);
+                (*state).buffers.init(initial);
+                Ok(())
+            },
+        )?;
+
+        let state = map.ptr() as *const ConfigState<T>;
+        {
+            let _init_lock = map.init_lock()?;
+            unsafe {
+                (*state).header.validate::<T>(size_of::<T>(), 0)?;
+                (*state).header.attach(&map)?;
+            }
+        }
+
+        Ok(ConfigCell {
+            map,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<T: 'static> Drop for ConfigCell<T> {
+    fn drop(&mut self) {
+        let state = self.map.ptr() as *const ConfigState<T>;
+        unsafe { (*state).header.detach(&self.map) };
+    }
+}
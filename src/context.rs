@@ -0,0 +1,367 @@
+use std::{
+    any::Any,
+    cell::{Ref, RefCell},
+    collections::HashMap,
+    rc::{Rc, Weak},
+    time::Duration,
+};
+
+use nix::errno::Errno;
+
+#[cfg(target_os = "linux")]
+use crate::futex_mutex::{FutexMutex, FutexMutexBuilder};
+use crate::{
+    broadcast::{self, Publisher, Subscriber},
+    builder::Builder,
+    error::{Error, Result},
+    mpmc,
+    msg_queue::MsgQueue,
+    namespace::Namespace,
+    r_mtx::{RMtx, RMtxBuilder},
+    sem::Sem,
+    shm::{Shm, ShmBuilder},
+    shm_mutex::{ShmMutex, ShmMutexBuilder},
+    shm_safe::ShmSafe,
+    spsc::{self, Consumer, Producer},
+    watchdog::Watchdog,
+};
+
+/// A `Namespace` keeping track of the named objects opened through it, so an application
+/// can remove all of them with `shutdown` instead of unlinking each by name.
+///
+/// Opening a name again while a handle returned for it is still alive returns that handle
+/// instead of opening the object a second time. Like the handles it returns, a context can't
+/// be sent to other threads.
+///
+/// A context can also run a watchdog thread, started with `start_watchdog`, that recovers
+/// mutexes whose owner died without waiting for the next process to lock them.
+pub struct IpcContext {
+    namespace: Namespace,
+    objects: RefCell<HashMap<(Kind, String), Weak<dyn Any>>>,
+    watchdog: RefCell<Option<Watchdog>>,
+}
+
+/// What a name in the context refers to, since objects of different kinds may share a name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Kind {
+    Mtx,
+    #[cfg(target_os = "linux")]
+    FutexMutex,
+    Shm,
+    ShmMutex,
+    Sem,
+    Producer,
+    Consumer,
+    Mpmc,
+    MsgQueue,
+    Publisher,
+    Subscriber,
+}
+
+impl IpcContext {
+    /// Creates a context opening objects in `namespace`.
+    pub fn new(namespace: Namespace) -> Self {
+        Self {
+            namespace,
+            objects: RefCell::new(HashMap::new()),
+            watchdog: RefCell::new(None),
+        }
+    }
+
+    /// The namespace the objects are opened in.
+    pub fn namespace(&self) -> &Namespace {
+        &self.namespace
+    }
+
+    /// Returns the `RMtx` called `name`, opening it with `open` on the builder from
+    /// `Namespace::mtx` unless it is open already.
+    pub fn mtx(
+        &self,
+        name: &str,
+        open: impl FnOnce(RMtxBuilder) -> Result<RMtx>,
+    ) -> Result<Rc<RMtx>> {
+        self.get_or_open(Kind::Mtx, name, || open(self.namespace.mtx(name)))
+    }
+
+    /// Returns the `FutexMutex` called `name`, opening it with `open` on the builder from
+    /// `Namespace::futex_mutex` unless it is open already.
+    #[cfg(target_os = "linux")]
+    pub fn futex_mutex(
+        &self,
+        name: &str,
+        open: impl FnOnce(FutexMutexBuilder) -> Result<FutexMutex>,
+    ) -> Result<Rc<FutexMutex>> {
+        self.get_or_open(Kind::FutexMutex, name, || {
+            open(self.namespace.futex_mutex(name))
+        })
+    }
+
+    /// Returns the `Shm` called `name`, opening it with `open` on the builder from
+    /// `Namespace::shm` unless it is open already. The handle is shared, so it comes in a
+    /// `RefCell` for `Shm::access`.
+    pub fn shm<T: 'static>(
+        &self,
+        name: &str,
+        open: impl FnOnce(ShmBuilder<T>) -> Result<Shm<T>>,
+    ) -> Result<Rc<RefCell<Shm<T>>>> {
+        self.get_or_open(Kind::Shm, name, || {
+            open(self.namespace.shm(name)).map(RefCell::new)
+        })
+    }
+
+    /// Returns the `ShmMutex` called `name`, opening it with `open` on the builder from
+    /// `Namespace::shm_mutex` unless it is open already.
+    pub fn shm_mutex<T: 'static>(
+        &self,
+        name: &str,
+        open: impl FnOnce(ShmMutexBuilder<T>) -> Result<ShmMutex<T>>,
+    ) -> Result<Rc<ShmMutex<T>>> {
+        self.get_or_open(Kind::ShmMutex, name, || {
+            open(self.namespace.shm_mutex(name))
+        })
+    }
+
+    /// Returns the semaphore called `name`, opening it like `Namespace::sem` unless it is
+    /// open already.
+    pub fn sem(&self, name: &str, initial: u32) -> Result<Rc<Sem>> {
+        self.get_or_open(Kind::Sem, name, || self.namespace.sem(name, initial))
+    }
+
+    /// Returns the sending end of the `spsc` ring buffer called `name`, opening it with
+    /// `open` on a builder in the namespace unless it is open already. The handle is shared,
+    /// so it comes in a `RefCell` for sending.
+    pub fn producer<T: ShmSafe>(
+        &self,
+        name: &str,
+        open: impl FnOnce(Builder<Producer<T>>) -> Result<Producer<T>>,
+    ) -> Result<Rc<RefCell<Producer<T>>>> {
+        self.get_or_open(Kind::Producer, name, || {
+            open(Producer::builder(name).namespace(&self.namespace)).map(RefCell::new)
+        })
+    }
+
+    /// Like `producer`, for the receiving end of the ring buffer.
+    pub fn consumer<T: ShmSafe>(
+        &self,
+        name: &str,
+        open: impl FnOnce(Builder<Consumer<T>>) -> Result<Consumer<T>>,
+    ) -> Result<Rc<RefCell<Consumer<T>>>> {
+        self.get_or_open(Kind::Consumer, name, || {
+            open(Consumer::builder(name).namespace(&self.namespace)).map(RefCell::new)
+        })
+    }
+
+    /// Returns the `mpmc` queue called `name`, opening it with `open` on a builder in the
+    /// namespace unless it is open already.
+    pub fn mpmc<T: ShmSafe>(
+        &self,
+        name: &str,
+        open: impl FnOnce(Builder<mpmc::Queue<T>>) -> Result<mpmc::Queue<T>>,
+    ) -> Result<Rc<mpmc::Queue<T>>> {
+        self.get_or_open(Kind::Mpmc, name, || {
+            open(mpmc::Queue::builder(name).namespace(&self.namespace))
+        })
+    }
+
+    /// Returns the `MsgQueue` called `name`, opening it with `open` on a builder in the
+    /// namespace unless it is open already. The handle is shared, so it comes in a `RefCell`
+    /// for sending and receiving.
+    pub fn msg_queue(
+        &self,
+        name: &str,
+        open: impl FnOnce(Builder<MsgQueue>) -> Result<MsgQueue>,
+    ) -> Result<Rc<RefCell<MsgQueue>>> {
+        self.get_or_open(Kind::MsgQueue, name, || {
+            open(MsgQueue::builder(name).namespace(&self.namespace)).map(RefCell::new)
+        })
+    }
+
+    /// Returns the publisher of the `broadcast` bus called `name`, opening it with `open` on
+    /// a builder in the namespace unless it is open already. The handle is shared, so it
+    /// comes in a `RefCell` for sending.
+    pub fn publisher<T: ShmSafe + Copy>(
+        &self,
+        name: &str,
+        open: impl FnOnce(Builder<Publisher<T>>) -> Result<Publisher<T>>,
+    ) -> Result<Rc<RefCell<Publisher<T>>>> {
+        self.get_or_open(Kind::Publisher, name, || {
+            open(Publisher::builder(name).namespace(&self.namespace)).map(RefCell::new)
+        })
+    }
+
+    /// Like `publisher`, for a subscriber of the bus. The context subscribes once per name,
+    /// so callers asking for the same name share a position in the stream of messages.
+    pub fn subscriber<T: ShmSafe + Copy>(
+        &self,
+        name: &str,
+        open: impl FnOnce(Builder<Subscriber<T>>) -> Result<Subscriber<T>>,
+    ) -> Result<Rc<RefCell<Subscriber<T>>>> {
+        self.get_or_open(Kind::Subscriber, name, || {
+            open(Subscriber::builder(name).namespace(&self.namespace)).map(RefCell::new)
+        })
+    }
+
+    /// Starts a watchdog thread that tries to lock the mutexes registered with `watch_mtx`
+    /// and `watch_shm_mutex` every `interval`, which recovers those whose owner died and
+    /// runs their repair hooks, until the context is dropped. Fails if it runs already.
+    ///
+    /// Mutexes held by live processes are skipped until the next round. Errors, e.g. from
+    /// a mutex that was unlinked, are ignored.
+    pub fn start_watchdog(&self, interval: Duration) -> Result<()> {
+        let mut watchdog = self.watchdog.borrow_mut();
+        if watchdog.is_some() {
+            return Err(Error::InvalidArgument(
+                "Watchdog is already running".to_owned(),
+            ));
+        }
+        *watchdog = Some(Watchdog::start(self.namespace.clone(), interval)?);
+        Ok(())
+    }
+
+    /// Makes the watchdog watch the `RMtx` called `name`, opened with `open` on the
+    /// builder from `Namespace::mtx`, and run `repair` on its thread whenever it recovers
+    /// the mutex. The watchdog opens a handle of its own, as handles can't be sent to
+    /// other threads. Fails if the watchdog isn't running or opening fails.
+    pub fn watch_mtx(
+        &self,
+        name: &str,
+        open: impl FnOnce(RMtxBuilder) -> Result<RMtx> + Send + 'static,
+        repair: impl FnMut() + Send + 'static,
+    ) -> Result<()> {
+        self.watchdog()?.watch_mtx(name, open, repair)?;
+        self.remember(Kind::Mtx, name);
+        Ok(())
+    }
+
+    /// Like `watch_mtx`, for the `ShmMutex` called `name`. `repair` runs on the data when
+    /// the owner died, or when it is poisoned or doesn't match its checksum.
+    pub fn watch_shm_mutex<T: 'static>(
+        &self,
+        name: &str,
+        open: impl FnOnce(ShmMutexBuilder<T>) -> Result<ShmMutex<T>> + Send + 'static,
+        repair: impl FnMut(&mut T) + Send + 'static,
+    ) -> Result<()> {
+        self.watchdog()?.watch_shm_mutex(name, open, repair)?;
+        self.remember(Kind::ShmMutex, name);
+        Ok(())
+    }
+
+    /// Unlinks every object opened or watched through the context, including those whose
+    /// handles were dropped since. Objects that are gone already, e.g. because their last reference-counted
+    /// handle removed them, are skipped. Unlinks as many as possible before returning the
+    /// first error.
+    pub fn shutdown(self) -> Result<()> {
+        let mut result = Ok(());
+        for (kind, name) in self.objects.into_inner().into_keys() {
+            match kind.unlink(&self.namespace, &name) {
+                Err(e) if e.errno() != Some(Errno::ENOENT) && result.is_ok() => result = Err(e),
+                _ => {}
+            }
+        }
+        result
+    }
+
+    fn watchdog(&self) -> Result<Ref<'_, Watchdog>> {
+        Ref::filter_map(self.watchdog.borrow(), Option::as_ref)
+            .map_err(|_| Error::InvalidArgument("Watchdog isn't running".to_owned()))
+    }
+
+    /// Records an object opened outside the context, so `shutdown` unlinks it too.
+    fn remember(&self, kind: Kind, name: &str) {
+        let mut objects = self.objects.borrow_mut();
+        objects
+            .entry((kind, name.to_owned()))
+            .or_insert_with(|| Weak::<()>::new());
+    }
+
+    fn get_or_open<H: 'static, F>(&self, kind: Kind, name: &str, open: F) -> Result<Rc<H>>
+    where
+        F: FnOnce() -> Result<H>,
+    {
+        let key = (kind, name.to_owned());
+        if let Some(handle) = self.objects.borrow().get(&key).and_then(Weak::upgrade) {
+            // The kind of an object and the type of its handle only differ in its data type.
+            return handle.downcast().map_err(|_| {
+                Error::Validation(format!(
+                    "{kind:?} {name} is already open with another data type"
+                ))
+            });
+        }
+
+        // Opening may run an initializer that uses the context too.
+        let handle = Rc::new(open()?);
+        let weak = Rc::downgrade(&handle) as Weak<dyn Any>;
+        self.objects.borrow_mut().insert(key, weak);
+        Ok(handle)
+    }
+}
+
+impl Kind {
+    fn unlink(self, namespace: &Namespace, name: &str) -> Result<()> {
+        match self {
+            Self::Mtx => namespace.unlink_mtx(name),
+            #[cfg(target_os = "linux")]
+            Self::FutexMutex => namespace.unlink_futex_mutex(name),
+            Self::Shm => namespace.unlink_shm(name),
+            Self::ShmMutex => namespace.unlink_shm_mutex(name),
+            Self::Sem => namespace.unlink_sem(name),
+            // Both ends share the object, and whichever is unlinked second is skipped.
+            Self::Producer | Self::Consumer => spsc::unlink_in(namespace, name),
+            // The path doesn't depend on the element type.
+            Self::Mpmc => mpmc::Queue::<u8>::unlink_in(namespace, name),
+            Self::MsgQueue => MsgQueue::unlink_in(namespace, name),
+            Self::Publisher | Self::Subscriber => broadcast::unlink_in(namespace, name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::*;
+    use crate::broadcast::LagPolicy;
+
+    #[test]
+    fn channels_are_shared_and_unlinked_on_shutdown() {
+        let dir = env::temp_dir().join(format!("nix-ipc-test-context-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let context = IpcContext::new(Namespace::new(dir.display().to_string()));
+
+        let producer = context
+            .producer::<u64>("ring", |builder| builder.build(4))
+            .unwrap();
+        let again = context
+            .producer::<u64>("ring", |_| panic!("opened twice"))
+            .unwrap();
+        assert!(Rc::ptr_eq(&producer, &again));
+        let consumer = context
+            .consumer::<u64>("ring", |builder| builder.build(4))
+            .unwrap();
+        producer.borrow_mut().send(7).unwrap();
+        assert_eq!(consumer.borrow_mut().recv().unwrap(), 7);
+
+        let queue = context
+            .mpmc::<u64>("queue", |builder| builder.build(4))
+            .unwrap();
+        let messages = context
+            .msg_queue("messages", |builder| builder.build(4096))
+            .unwrap();
+        let publisher = context
+            .publisher::<u64>("bus", |builder| builder.build(4, LagPolicy::DropOldest))
+            .unwrap();
+        let subscriber = context
+            .subscriber::<u64>("bus", |builder| builder.build(4, LagPolicy::DropOldest))
+            .unwrap();
+
+        let files = ["ring.spsc", "queue.mpmc", "messages.msgq", "bus.bus"];
+        assert!(files.iter().all(|file| dir.join(file).exists()));
+        queue.send(1).unwrap();
+        messages.borrow_mut().send(b"message").unwrap();
+        publisher.borrow_mut().send(2).unwrap();
+        assert_eq!(subscriber.borrow_mut().recv().unwrap(), 2);
+        context.shutdown().unwrap();
+        assert!(!files.iter().any(|file| dir.join(file).exists()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,184 @@
+use std::os::fd::AsFd;
+#[cfg(target_os = "linux")]
+use std::{
+    io::{IoSlice, IoSliceMut},
+    os::fd::AsRawFd,
+};
+
+use nix::libc::{gid_t, pid_t, uid_t};
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use nix::sys::socket::{getsockopt, sockopt};
+#[cfg(target_os = "linux")]
+use nix::{
+    cmsg_space,
+    sys::socket::{
+        ControlMessage, ControlMessageOwned, MsgFlags, UnixCredentials, recvmsg, sendmsg,
+        setsockopt,
+    },
+};
+
+use crate::error::{Error, Result};
+
+/// Process id, user id and group id of a process on the other end of a unix socket,
+/// as verified by the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCredentials {
+    pub pid: pid_t,
+    pub uid: uid_t,
+    pub gid: gid_t,
+}
+
+#[cfg(target_os = "linux")]
+impl From<UnixCredentials> for PeerCredentials {
+    fn from(creds: UnixCredentials) -> Self {
+        Self {
+            pid: creds.pid(),
+            uid: creds.uid(),
+            gid: creds.gid(),
+        }
+    }
+}
+
+/// Returns the credentials the peer of the connected unix socket `socket` had when it
+/// connected, using `SO_PEERCRED`.
+#[cfg(target_os = "linux")]
+pub fn peer_credentials(socket: impl AsFd) -> Result<PeerCredentials> {
+    let creds = getsockopt(&socket, sockopt::PeerCredentials)
+        .map_err(Error::sys("getsockopt(SO_PEERCRED)"))?;
+    Ok(creds.into())
+}
+
+/// Returns the credentials the peer of the connected unix socket `socket` had when it
+/// connected, using `LOCAL_PEERCRED` and `LOCAL_PEERPID`. The group id is the peer's
+/// effective group.
+#[cfg(target_os = "macos")]
+pub fn peer_credentials(socket: impl AsFd) -> Result<PeerCredentials> {
+    let creds = getsockopt(&socket, sockopt::LocalPeerCred)
+        .map_err(Error::sys("getsockopt(LOCAL_PEERCRED)"))?;
+    let pid = getsockopt(&socket, sockopt::LocalPeerPid)
+        .map_err(Error::sys("getsockopt(LOCAL_PEERPID)"))?;
+    Ok(PeerCredentials {
+        pid,
+        uid: creds.uid(),
+        gid: creds.groups()[0],
+    })
+}
+
+/// Returns the credentials the peer of the connected unix socket `socket` had when it
+/// connected, using `LOCAL_PEERCRED`. The group id is the peer's effective group.
+#[cfg(target_os = "freebsd")]
+pub fn peer_credentials(socket: impl AsFd) -> Result<PeerCredentials> {
+    use nix::libc::{LOCAL_PEERCRED, xucred};
+
+    let creds: xucred = bsd_getsockopt(socket, LOCAL_PEERCRED, "getsockopt(LOCAL_PEERCRED)")?;
+    Ok(PeerCredentials {
+        pid: unsafe { creds.cr_pid__c_anonymous_union.cr_pid },
+        uid: creds.cr_uid,
+        gid: creds.cr_groups[0],
+    })
+}
+
+/// Returns the credentials the peer of the connected unix socket `socket` had when it
+/// connected, using `LOCAL_PEEREID`.
+#[cfg(target_os = "netbsd")]
+pub fn peer_credentials(socket: impl AsFd) -> Result<PeerCredentials> {
+    use nix::libc::{LOCAL_PEEREID, unpcbid};
+
+    let id: unpcbid = bsd_getsockopt(socket, LOCAL_PEEREID, "getsockopt(LOCAL_PEEREID)")?;
+    Ok(PeerCredentials {
+        pid: id.unp_pid,
+        uid: id.unp_euid,
+        gid: id.unp_egid,
+    })
+}
+
+/// Returns the credentials the peer of the connected unix socket `socket` had when it
+/// connected, using `SO_PEERCRED`.
+#[cfg(target_os = "openbsd")]
+pub fn peer_credentials(socket: impl AsFd) -> Result<PeerCredentials> {
+    use nix::libc::{SO_PEERCRED, sockpeercred};
+
+    let creds: sockpeercred = bsd_getsockopt(socket, SO_PEERCRED, "getsockopt(SO_PEERCRED)")?;
+    Ok(PeerCredentials {
+        pid: creds.pid,
+        uid: creds.uid,
+        gid: creds.gid,
+    })
+}
+
+/// Reads a socket option nix has no wrapper for. Those of unix sockets live at level 0
+/// (`SOL_LOCAL`), except OpenBSD's `SO_PEERCRED`.
+#[cfg(any(target_os = "freebsd", target_os = "netbsd", target_os = "openbsd"))]
+fn bsd_getsockopt<T>(socket: impl AsFd, name: nix::libc::c_int, op: &'static str) -> Result<T> {
+    use std::{mem::MaybeUninit, os::fd::AsRawFd};
+
+    use nix::{errno::Errno, libc::socklen_t};
+
+    #[cfg(target_os = "openbsd")]
+    let level = nix::libc::SOL_SOCKET;
+    #[cfg(not(target_os = "openbsd"))]
+    let level = 0;
+
+    let mut value = MaybeUninit::<T>::zeroed();
+    let mut len = size_of::<T>() as socklen_t;
+    Errno::result(unsafe {
+        nix::libc::getsockopt(
+            socket.as_fd().as_raw_fd(),
+            level,
+            name,
+            value.as_mut_ptr().cast(),
+            &mut len,
+        )
+    })
+    .map_err(Error::sys(op))?;
+    Ok(unsafe { value.assume_init() })
+}
+
+/// Sends the current credentials of this process over `socket` using `SCM_CREDENTIALS`.
+/// Unlike `SO_PEERCRED`, this reflects the process at the time of sending, e.g. after it
+/// dropped privileges. Only available on Linux.
+#[cfg(target_os = "linux")]
+pub fn send_credentials(socket: impl AsFd) -> Result<()> {
+    let creds = UnixCredentials::new();
+    let cmsgs = [ControlMessage::ScmCredentials(&creds)];
+    // Ancillary data has to accompany at least one byte of regular data.
+    sendmsg::<()>(
+        socket.as_fd().as_raw_fd(),
+        &[IoSlice::new(&[0])],
+        &cmsgs,
+        MsgFlags::empty(),
+        None,
+    )
+    .map_err(Error::sys("sendmsg"))?;
+    Ok(())
+}
+
+/// Receives credentials sent with `send_credentials` from `socket`.
+#[cfg(target_os = "linux")]
+pub fn recv_credentials(socket: impl AsFd) -> Result<PeerCredentials> {
+    setsockopt(&socket, sockopt::PassCred, &true).map_err(Error::sys("setsockopt(SO_PASSCRED)"))?;
+
+    let mut byte = [0];
+    let mut iov = [IoSliceMut::new(&mut byte)];
+    let mut cmsg_buffer = cmsg_space!(UnixCredentials);
+    let msg = recvmsg::<()>(
+        socket.as_fd().as_raw_fd(),
+        &mut iov,
+        Some(&mut cmsg_buffer),
+        MsgFlags::empty(),
+    )
+    .map_err(Error::sys("recvmsg"))?;
+    if msg.bytes == 0 {
+        return Err(Error::closed());
+    }
+
+    let creds = msg
+        .cmsgs()
+        .map_err(Error::sys("recvmsg"))?
+        .find_map(|cmsg| match cmsg {
+            ControlMessageOwned::ScmCredentials(creds) => Some(creds),
+            _ => None,
+        })
+        .ok_or_else(|| Error::Validation("Message carried no credentials".to_owned()))?;
+    Ok(creds.into())
+}
@@ -0,0 +1,248 @@
+use std::{
+    cell::UnsafeCell,
+    marker::PhantomData,
+    mem::{MaybeUninit, size_of},
+    num::NonZeroUsize,
+    ptr,
+    sync::atomic::{AtomicU64, Ordering, fence},
+    thread,
+};
+
+use nix::fcntl::OFlag;
+
+use crate::{
+    builder::Builder,
+    cache_padded::CachePadded,
+    cleanup::CleanupPolicy,
+    error::{Error, Result},
+    header::Header,
+    map::Mapping,
+    namespace::Namespace,
+    shm_safe::ShmSafe,
+};
+
+/// Spins a reader does on a copy being rewritten before yielding its time slice.
+const SPINS_BEFORE_YIELD: u32 = 64;
+
+/// One of the two copies, with a counter that is odd while the copy is being written.
+#[repr(C)]
+struct Buffer<T> {
+    seq: CachePadded<AtomicU64>,
+    value: UnsafeCell<T>,
+}
+
+/// The two copies with the index selecting the current one, laid out in a segment.
+#[repr(C)]
+pub(crate) struct Buffers<T> {
+    /// Number of completed writes. The current value is in the copy at its parity.
+    writes: CachePadded<AtomicU64>,
+    copies: [Buffer<T>; 2],
+}
+
+#[repr(C)]
+struct DoubleBufferState<T> {
+    header: Header,
+    buffers: Buffers<T>,
+}
+
+/// A value in shared memory kept in two copies, so readers in any number of processes always
+/// see a complete value without locking while it is being replaced.
+///
+/// A write fills the copy readers aren't directed to and then flips the index to it. Readers
+/// only retry if the copy they are reading gets rewritten meanwhile, which takes two writes
+/// during a single read. Compared to `SeqLock` it needs twice the memory, but reads don't
+/// slow down with frequent writes, which suits configuration or state that one daemon
+/// broadcasts to many readers.
+///
+/// Concurrent writers are serialized by spinning. A writer dying in the middle of `write`
+/// blocks further writes, while readers keep seeing the last complete value.
+pub struct DoubleBuffer<T: 'static> {
+    map: Mapping,
+    _marker: PhantomData<T>,
+}
+
+impl<T: ShmSafe + Copy> DoubleBuffer<T> {
+    /// Opens the value in /dev/shm, creating it holding `value` if it doesn't exist.
+    /// Opening an existing one keeps its current value.
+    pub fn new(name: &str, value: T) -> Result<Self> {
+        Self::builder(name).build(value)
+    }
+
+    /// Returns a builder for setting the namespace and the permissions the buffer is created with.
+    pub fn builder(name: &str) -> Builder<Self> {
+        Builder::new(name)
+    }
+
+    /// Unlinks (deletes) the value from /dev/shm.
+    pub fn unlink(name: &str) -> Result<()> {
+        Self::unlink_in(&Namespace::default(), name)
+    }
+
+    /// Unlinks the value called `name` in `namespace`.
+    pub fn unlink_in(namespace: &Namespace, name: &str) -> Result<()> {
+        namespace.path(name, ".dbuf").unlink()
+    }
+
+    /// Returns a copy of the current value.
+    pub fn read(&self) -> T {
+        self.state().buffers.read().0
+    }
+
+    /// Replaces the value.
+    pub fn write(&self, value: T) {
+        self.state().buffers.write_with(|_| value);
+    }
+
+    /// Replaces the value with one computed by `f` from the current value.
+    pub fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&mut T),
+    {
+        self.state().buffers.update(f);
+    }
+
+    /// The number of writes made so far, which readers can compare to skip unchanged values.
+    pub fn version(&self) -> u64 {
+        self.state().buffers.version()
+    }
+
+    fn state(&self) -> &DoubleBufferState<T> {
+        unsafe { &*(self.map.ptr() as *const DoubleBufferState<T>) }
+    }
+}
+
+impl<T: ShmSafe + Copy> Builder<DoubleBuffer<T>> {
+    /// Opens the buffer like `DoubleBuffer::new`.
+    pub fn build(self, value: T) -> Result<DoubleBuffer<T>> {
+        if size_of::<T>() == 0 {
+            return Err(Error::InvalidArgument(
+                "Cannot use zero-sized type in shared memory".to_owned(),
+            ));
+        }
+        let len = NonZeroUsize::new(size_of::<DoubleBufferState<T>>())
+            .expect("DoubleBufferState has nonzero size");
+
+        let path = self.namespace.path(&self.name, ".dbuf");
+        let map = Mapping::open_init(
+            &path,
+            OFlag::O_CREAT,
+            &self.permissions,
+            len,
+            CleanupPolicy::LastCloseRefCounted,
+            |raw| unsafe {
+                let state = raw as *mut DoubleBufferState<T>;
+                (*state).header.init::<T>(size_of::<T>(), 0);
+                (*state).buffers.init(value);
+                Ok(())
+            },
+        )?;
+
+        let state = map.ptr() as *const DoubleBufferState<T>;
+        {
+            let _init_lock = map.init_lock()?;
+            unsafe {
+                (*state).header.validate::<T>(size_of::<T>(), 0)?;
+                (*state).header.attach(&map)?;
+            }
+        }
+
+        Ok(DoubleBuffer {
+            map,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<T: Copy> Buffers<T> {
+    /// Fills in the first copy with `value`. Only for a new, zeroed segment.
+    pub(crate) fn init(&mut self, value: T) {
+        self.copies[0].value = UnsafeCell::new(value);
+    }
+
+    /// Returns a copy of the current value, with the number of writes that produced it.
+    pub(crate) fn read(&self) -> (T, u64) {
+        let mut spins = 0;
+        loop {
+            let writes = self.writes.load(Ordering::Acquire);
+            let copy = &self.copies[(writes % 2) as usize];
+            let before = copy.seq.load(Ordering::Acquire);
+            if before.is_multiple_of(2) {
+                // The copy may be torn, so it stays uninitialized until its counter proves
+                // it isn't.
+                let value =
+                    unsafe { ptr::read_volatile(copy.value.get() as *const MaybeUninit<T>) };
+                fence(Ordering::Acquire);
+                if copy.seq.load(Ordering::Relaxed) == before {
+                    return (unsafe { value.assume_init() }, writes);
+                }
+            }
+
+            spins += 1;
+            if spins < SPINS_BEFORE_YIELD {
+                std::hint::spin_loop();
+            } else {
+                thread::yield_now();
+            }
+        }
+    }
+
+    /// Replaces the value with one computed by `f` from the current value.
+    pub(crate) fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&mut T),
+    {
+        self.write_with(|current| {
+            let mut value = *current;
+            f(&mut value);
+            value
+        });
+    }
+
+    /// The number of writes made so far, which readers can compare to skip unchanged values.
+    pub(crate) fn version(&self) -> u64 {
+        self.writes.load(Ordering::Acquire)
+    }
+
+    /// Claims the copy readers aren't directed to, fills it with the value `f` computes from
+    /// the current one and flips readers to it.
+    pub(crate) fn write_with<F>(&self, f: F)
+    where
+        F: FnOnce(&T) -> T,
+    {
+        let (writes, seq) = loop {
+            let writes = self.writes.load(Ordering::Acquire);
+            let copy = &self.copies[((writes + 1) % 2) as usize];
+            let seq = copy.seq.load(Ordering::Relaxed);
+            if seq.is_multiple_of(2)
+                && copy
+                    .seq
+                    .compare_exchange(seq, seq + 1, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            {
+                // Another writer may have flipped the index before the copy was claimed.
+                if self.writes.load(Ordering::Acquire) == writes {
+                    break (writes, seq);
+                }
+                copy.seq.store(seq, Ordering::Release);
+            }
+            std::hint::spin_loop();
+        };
+        fence(Ordering::Release);
+
+        let current = &self.copies[(writes % 2) as usize];
+        let inactive = &self.copies[((writes + 1) % 2) as usize];
+        // Only writers change the current copy, and they are all waiting for the claimed one.
+        let value = f(unsafe { &*current.value.get() });
+        unsafe { ptr::write_volatile(inactive.value.get(), value) };
+
+        inactive.seq.store(seq + 2, Ordering::Release);
+        self.writes.store(writes + 1, Ordering::Release);
+    }
+}
+
+impl<T: 'static> Drop for DoubleBuffer<T> {
+    fn drop(&mut self) {
+        let state = self.map.ptr() as *const DoubleBufferState<T>;
+        unsafe { (*state).header.detach(&self.map) };
+    }
+}
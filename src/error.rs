@@ -0,0 +1,139 @@
+use std::{ffi::NulError, io};
+
+use nix::errno::Errno;
+
+/// Errors returned by this crate.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// Creating a new object failed, e.g. because it already exists or access was denied.
+    #[error("Failed to create {path}: {source}")]
+    Create { path: String, source: Errno },
+    /// Opening an existing object failed, e.g. because it doesn't exist or access was denied.
+    #[error("Failed to open {path}: {source}")]
+    Open { path: String, source: Errno },
+    /// Unlinking (deleting) a named object failed, e.g. because it doesn't exist.
+    #[error("Failed to unlink {path}: {source}")]
+    Unlink { path: String, source: Errno },
+    /// Sizing, mapping or remapping the memory of an object failed.
+    #[error("Failed to map {path}: {source}")]
+    Map { path: String, source: Errno },
+    /// Locking, unlocking, waiting on or signaling a synchronization primitive failed.
+    #[error("{op} failed: {source}")]
+    Lock { op: &'static str, source: Errno },
+    /// Marking a mutex consistent again after its owner died failed.
+    #[error("pthread_mutex_consistent failed: {source}")]
+    Recovery { source: Errno },
+    /// A poisoning mutex was left behind by an owner that died while holding it, and its
+    /// data hasn't been repaired since.
+    #[error("Mutex is poisoned: a previous owner died without the data being repaired")]
+    Poisoned,
+    /// The checksum of a checksummed `ShmMutex` doesn't match its data, e.g. because an
+    /// owner died halfway through an update or the data was written without the lock.
+    #[error("Checksum mismatch: the data changed without its checksum being updated")]
+    ChecksumMismatch,
+    /// An existing object doesn't match what the opener expects, e.g. its size, type or
+    /// schema version.
+    #[error("{0}")]
+    Validation(String),
+    /// An argument is out of range, e.g. a zero capacity or an oversized message.
+    #[error("{0}")]
+    InvalidArgument(String),
+    /// A broadcast subscriber fell a whole ring behind, and this many messages were
+    /// overwritten before it received them.
+    #[error("Subscriber fell behind and missed {0} messages")]
+    Lagged(u64),
+    /// The producer of a oneshot went away without setting its value.
+    #[error("Oneshot was cancelled: its sender went away without setting a value")]
+    Cancelled,
+    /// A lease expired and another process reclaimed it before its owner renewed it.
+    #[error("Lease was lost: it expired and was reclaimed by another process")]
+    LeaseLost,
+    /// The writer of a `SeqLock` died in the middle of a write, leaving the value torn until
+    /// it is replaced with `SeqLock::write`.
+    #[error("Writer died in the middle of a write, leaving the value torn")]
+    WriterDied,
+    /// Blocking on a mutex would never return, as the threads in the message wait on each
+    /// other in a cycle. Only detected with the `deadlock-detection` feature.
+    #[cfg(feature = "deadlock-detection")]
+    #[error("{0}")]
+    Deadlock(String),
+    /// Any other system call failed.
+    #[error("{op} failed: {source}")]
+    Sys { op: &'static str, source: Errno },
+    /// Reading from or writing to a socket or pipe failed.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// Encoding a value failed.
+    #[cfg(feature = "serde")]
+    #[error(transparent)]
+    Encode(#[from] bincode::error::EncodeError),
+    /// Decoding a value failed.
+    #[cfg(feature = "serde")]
+    #[error(transparent)]
+    Decode(#[from] bincode::error::DecodeError),
+    /// The server of a remote call failed to handle it, e.g. because it has no handler for
+    /// the request type.
+    #[cfg(feature = "serde")]
+    #[error("Remote call failed: {0}")]
+    Rpc(String),
+    /// An error from code using `anyhow`.
+    #[cfg(feature = "anyhow")]
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+/// Result type of this crate.
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    /// Returns a closure wrapping an `Errno` of unlinking `path`, for use with `map_err`.
+    pub(crate) fn unlink(path: &str) -> impl FnOnce(Errno) -> Self {
+        let path = path.to_owned();
+        move |source| Self::Unlink { path, source }
+    }
+
+    /// Returns a closure wrapping an `Errno` of the lock operation `op`, for use with `map_err`.
+    pub(crate) fn lock(op: &'static str) -> impl FnOnce(Errno) -> Self {
+        move |source| Self::Lock { op, source }
+    }
+
+    /// Returns a closure wrapping an `Errno` of the system call `op`, for use with `map_err`.
+    pub(crate) fn sys(op: &'static str) -> impl FnOnce(Errno) -> Self {
+        move |source| Self::Sys { op, source }
+    }
+
+    /// Returns the error of reading from a socket or pipe that the peer closed.
+    pub(crate) fn closed() -> Self {
+        Self::Io(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Connection closed by peer",
+        ))
+    }
+
+    /// Returns the `Errno` of an `io::Error` coming from a failed system call.
+    pub(crate) fn os_errno(e: &io::Error) -> Errno {
+        Errno::from_raw(e.raw_os_error().unwrap_or_default())
+    }
+
+    /// Returns the `Errno` behind the error, if it was caused by a failing system call.
+    pub fn errno(&self) -> Option<Errno> {
+        match self {
+            Self::Create { source, .. }
+            | Self::Open { source, .. }
+            | Self::Unlink { source, .. }
+            | Self::Map { source, .. }
+            | Self::Lock { source, .. }
+            | Self::Recovery { source }
+            | Self::Sys { source, .. } => Some(*source),
+            Self::Io(e) => e.raw_os_error().map(Errno::from_raw),
+            _ => None,
+        }
+    }
+}
+
+impl From<NulError> for Error {
+    fn from(e: NulError) -> Self {
+        Self::InvalidArgument(format!("Name contains a nul byte: {e}"))
+    }
+}
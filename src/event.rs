@@ -0,0 +1,108 @@
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+
+use nix::{
+    errno::Errno,
+    libc::{EFD_CLOEXEC, EFD_NONBLOCK, EFD_SEMAPHORE, POLLIN, c_int, eventfd, poll, pollfd},
+    unistd::{read, write},
+};
+
+use crate::error::{Error, Result};
+
+/// A notification counter backed by an eventfd.
+///
+/// `signal` adds to the counter and `wait` takes from it, blocking while it is zero. In
+/// counter mode, created with `new`, a wait takes the whole count; in semaphore mode, created
+/// with `semaphore`, it takes one. The eventfd can be registered with poll or epoll, and
+/// shared with other processes through inheritance or `send_fds`, then wrapped again with
+/// `Event::from`.
+///
+/// Only available on Linux.
+pub struct Event {
+    fd: OwnedFd,
+}
+
+impl Event {
+    /// Creates an event in counter mode, starting at `initial`.
+    pub fn new(initial: u32) -> Result<Self> {
+        Self::create(initial, 0)
+    }
+
+    /// Creates an event in semaphore mode, starting at `initial`.
+    pub fn semaphore(initial: u32) -> Result<Self> {
+        Self::create(initial, EFD_SEMAPHORE)
+    }
+
+    fn create(initial: u32, flags: c_int) -> Result<Self> {
+        let raw_fd = Errno::result(unsafe { eventfd(initial, flags | EFD_NONBLOCK | EFD_CLOEXEC) })
+            .map_err(Error::sys("eventfd"))?;
+        Ok(Self {
+            fd: unsafe { OwnedFd::from_raw_fd(raw_fd) },
+        })
+    }
+
+    /// Adds 1 to the counter, waking a waiter.
+    pub fn signal(&self) -> Result<()> {
+        self.add(1)
+    }
+
+    /// Adds `n` to the counter. Fails with `EAGAIN` if the counter would overflow.
+    pub fn add(&self, n: u64) -> Result<()> {
+        write(&self.fd, &n.to_ne_bytes()).map_err(Error::sys("write(eventfd)"))?;
+        Ok(())
+    }
+
+    /// Blocks until the counter is nonzero, then takes from it, returning how much was taken.
+    pub fn wait(&self) -> Result<u64> {
+        loop {
+            if let Some(n) = self.try_wait()? {
+                return Ok(n);
+            }
+            wait_readable(&self.fd)?;
+        }
+    }
+
+    /// Waits without blocking the thread until the counter is nonzero, then takes from it,
+    /// returning how much was taken. Must be called within a tokio runtime.
+    #[cfg(feature = "tokio")]
+    pub async fn wait_async(&self) -> Result<u64> {
+        crate::async_io::readable(&self.fd, || self.try_wait()).await
+    }
+
+    /// Takes from the counter if it is nonzero, without blocking.
+    pub fn try_wait(&self) -> Result<Option<u64>> {
+        let mut buf = [0; 8];
+        match read(&self.fd, &mut buf) {
+            Ok(_) => Ok(Some(u64::from_ne_bytes(buf))),
+            Err(Errno::EAGAIN) => Ok(None),
+            Err(e) => Err(Error::sys("read(eventfd)")(e)),
+        }
+    }
+}
+
+/// Wraps an eventfd, e.g. one received with `recv_fds`. Waiting needs it to be nonblocking,
+/// which it is if it was created by `Event`.
+impl From<OwnedFd> for Event {
+    fn from(fd: OwnedFd) -> Self {
+        Self { fd }
+    }
+}
+
+impl AsFd for Event {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+/// Blocks until `fd` is readable. Another reader may empty it before the caller reads, so
+/// callers read without blocking and wait again if there was nothing.
+pub(crate) fn wait_readable(fd: &OwnedFd) -> Result<()> {
+    let mut fds = [pollfd {
+        fd: fd.as_raw_fd(),
+        events: POLLIN,
+        revents: 0,
+    }];
+    match Errno::result(unsafe { poll(fds.as_mut_ptr(), 1, -1) }) {
+        Ok(_) | Err(Errno::EINTR) => Ok(()),
+        Err(e) => Err(Error::sys("poll")(e)),
+    }
+}
@@ -0,0 +1,109 @@
+use std::{
+    io::{self, IoSlice, IoSliceMut},
+    os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+};
+
+use nix::{
+    cmsg_space,
+    fcntl::{FcntlArg, FdFlag, fcntl},
+    sys::socket::{ControlMessage, ControlMessageOwned, MsgFlags, recvmsg, sendmsg},
+};
+
+use crate::error::{Error, Result};
+
+/// Most file descriptors the kernel accepts in one `SCM_RIGHTS` message.
+const MAX_FDS: usize = 253;
+
+/// Sends `fds` over the connected unix socket `socket` using `SCM_RIGHTS`.
+/// The receiving process gets its own descriptors referring to the same open files.
+pub fn send_fds(socket: impl AsFd, fds: &[BorrowedFd<'_>]) -> Result<()> {
+    // Ancillary data has to accompany at least one byte of regular data.
+    let sent = send_with_fds(socket.as_fd(), &[IoSlice::new(&[0])], fds)?;
+    if sent != 1 {
+        return Err(io::Error::from(io::ErrorKind::WriteZero).into());
+    }
+    Ok(())
+}
+
+/// Receives file descriptors sent with `send_fds` from the unix socket `socket`.
+/// The descriptors are created with `O_CLOEXEC` set.
+pub fn recv_fds(socket: impl AsFd) -> Result<Vec<OwnedFd>> {
+    let mut byte = [0];
+    let (received, fds) = recv_with_fds(socket.as_fd(), &mut byte)?;
+    if received == 0 {
+        return Err(Error::closed());
+    }
+    Ok(fds)
+}
+
+/// Sets or clears the close-on-exec flag of `fd`. All descriptors this crate creates have
+/// it set, so they don't leak into spawned subcommands; clearing it lets a program started
+/// with `exec` inherit the descriptor, e.g. a re-executed worker taking over an anonymous
+/// `Shm` or an `Event` whose number it finds in an argument or environment variable.
+pub fn set_cloexec(fd: impl AsFd, cloexec: bool) -> Result<()> {
+    let fd = fd.as_fd();
+    let flags = fcntl(fd, FcntlArg::F_GETFD).map_err(Error::sys("fcntl(F_GETFD)"))?;
+    let mut flags = FdFlag::from_bits_retain(flags);
+    flags.set(FdFlag::FD_CLOEXEC, cloexec);
+    fcntl(fd, FcntlArg::F_SETFD(flags)).map_err(Error::sys("fcntl(F_SETFD)"))?;
+    Ok(())
+}
+
+/// Sends the data in `iov` with `fds` attached, returning how many bytes were sent.
+pub(crate) fn send_with_fds(
+    socket: BorrowedFd<'_>,
+    iov: &[IoSlice<'_>],
+    fds: &[BorrowedFd<'_>],
+) -> Result<usize> {
+    if fds.len() > MAX_FDS {
+        return Err(Error::InvalidArgument(format!(
+            "Cannot send more than {MAX_FDS} file descriptors at once"
+        )));
+    }
+    let raw_fds: Vec<RawFd> = fds.iter().map(|fd| fd.as_raw_fd()).collect();
+    let cmsgs = [ControlMessage::ScmRights(&raw_fds)];
+
+    sendmsg::<()>(socket.as_raw_fd(), iov, &cmsgs, MsgFlags::empty(), None)
+        .map_err(Error::sys("sendmsg"))
+}
+
+/// Receives data into `buf` along with any file descriptors attached to it.
+pub(crate) fn recv_with_fds(
+    socket: BorrowedFd<'_>,
+    buf: &mut [u8],
+) -> Result<(usize, Vec<OwnedFd>)> {
+    let mut iov = [IoSliceMut::new(buf)];
+    let mut cmsg_buffer = cmsg_space!([RawFd; MAX_FDS]);
+
+    let msg = recvmsg::<()>(
+        socket.as_raw_fd(),
+        &mut iov,
+        Some(&mut cmsg_buffer),
+        recv_flags(),
+    )
+    .map_err(Error::sys("recvmsg"))?;
+
+    let mut fds = Vec::new();
+    for cmsg in msg.cmsgs().map_err(Error::sys("recvmsg"))? {
+        if let ControlMessageOwned::ScmRights(raw_fds) = cmsg {
+            fds.extend(
+                raw_fds
+                    .into_iter()
+                    .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+            );
+        }
+    }
+    // Without MSG_CMSG_CLOEXEC the received fds are briefly inheritable by a concurrent fork.
+    #[cfg(not(target_os = "linux"))]
+    for fd in &fds {
+        fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).map_err(Error::sys("fcntl"))?;
+    }
+    Ok((msg.bytes, fds))
+}
+
+fn recv_flags() -> MsgFlags {
+    #[cfg(target_os = "linux")]
+    return MsgFlags::MSG_CMSG_CLOEXEC;
+    #[cfg(not(target_os = "linux"))]
+    MsgFlags::empty()
+}
@@ -0,0 +1,204 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    os::{
+        fd::{AsFd, BorrowedFd, OwnedFd},
+        unix::fs::OpenOptionsExt,
+    },
+    path::{Path, PathBuf},
+};
+
+use nix::{
+    errno::Errno,
+    fcntl::{FcntlArg, OFlag, fcntl},
+    libc::O_NONBLOCK,
+    unistd::mkfifo,
+};
+
+#[cfg(feature = "serde")]
+use crate::framing::{decode, encode};
+use crate::framing::{read_frame, write_frame};
+use crate::{
+    error::{Error, Result},
+    permissions::Permissions,
+};
+
+/// One end of a named pipe, created with `mkfifo` if it doesn't exist yet.
+///
+/// Besides plain `Read`/`Write`, messages can be sent as frames prefixed with their length,
+/// or as values encoded with bincode when the `serde` feature is enabled. Frames of up to
+/// `PIPE_BUF` bytes including the 4 byte prefix are written atomically, so several writers
+/// can share a FIFO as long as their messages stay that small.
+pub struct Fifo {
+    file: File,
+}
+
+impl Fifo {
+    /// Opens the reading end. Unlike a plain `open`, this doesn't wait for a writer; reads
+    /// return end-of-file while no writer has the FIFO open.
+    pub fn reader(path: impl AsRef<Path>) -> Result<Self> {
+        Self::builder(path).reader()
+    }
+
+    /// Opens the writing end, blocking until a reader has the FIFO open.
+    pub fn writer(path: impl AsRef<Path>) -> Result<Self> {
+        Self::builder(path).writer()
+    }
+
+    /// Opens the writing end without blocking, returning `None` if there is no reader.
+    pub fn try_writer(path: impl AsRef<Path>) -> Result<Option<Self>> {
+        Self::builder(path).try_writer()
+    }
+
+    /// Returns a builder for setting the permissions the FIFO is created with.
+    pub fn builder(path: impl AsRef<Path>) -> FifoBuilder {
+        FifoBuilder {
+            path: path.as_ref().to_owned(),
+            permissions: Permissions::default(),
+        }
+    }
+
+    /// Removes the FIFO from the filesystem; open ends keep working.
+    pub fn unlink(path: impl AsRef<Path>) -> Result<()> {
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    /// In non-blocking mode, reads and writes that would wait fail with
+    /// `ErrorKind::WouldBlock` instead.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        let flags = fcntl(&self.file, FcntlArg::F_GETFL).map_err(Error::sys("fcntl(F_GETFL)"))?;
+        let mut flags = OFlag::from_bits_truncate(flags);
+        flags.set(OFlag::O_NONBLOCK, nonblocking);
+        fcntl(&self.file, FcntlArg::F_SETFL(flags)).map_err(Error::sys("fcntl(F_SETFL)"))?;
+        Ok(())
+    }
+
+    /// Sends `msg` as one frame.
+    pub fn send_bytes(&mut self, msg: &[u8]) -> Result<()> {
+        write_frame(&self.file, msg)
+    }
+
+    /// Receives the next frame, failing if all writers closed the FIFO.
+    pub fn recv_bytes(&mut self) -> Result<Vec<u8>> {
+        read_frame(&self.file)
+    }
+
+    /// Encodes `value` with bincode and sends it as one frame.
+    #[cfg(feature = "serde")]
+    pub fn send<T: serde::Serialize>(&mut self, value: &T) -> Result<()> {
+        self.send_bytes(&encode(value)?)
+    }
+
+    /// Receives the next frame and decodes it with bincode.
+    #[cfg(feature = "serde")]
+    pub fn recv<T: serde::de::DeserializeOwned>(&mut self) -> Result<T> {
+        decode(&self.recv_bytes()?)
+    }
+}
+
+/// Builder for `Fifo`, created with `Fifo::builder`.
+pub struct FifoBuilder {
+    path: PathBuf,
+    permissions: Permissions,
+}
+
+impl FifoBuilder {
+    /// Sets the permissions the FIFO is created with, see `Permissions`. Only applies if
+    /// this handle creates the FIFO.
+    pub fn permissions(mut self, permissions: Permissions) -> Self {
+        self.permissions = permissions;
+        self
+    }
+
+    /// Opens the reading end like `Fifo::reader`.
+    pub fn reader(self) -> Result<Fifo> {
+        let path = self.path.as_path();
+        self.create()?;
+        let file = OpenOptions::new()
+            .read(true)
+            .custom_flags(O_NONBLOCK)
+            .open(path)
+            .map_err(|e| open_error(path, e))?;
+
+        let fifo = Fifo { file };
+        fifo.set_nonblocking(false)?;
+        Ok(fifo)
+    }
+
+    /// Opens the writing end like `Fifo::writer`.
+    pub fn writer(self) -> Result<Fifo> {
+        let path = self.path.as_path();
+        self.create()?;
+        let file = OpenOptions::new()
+            .write(true)
+            .open(path)
+            .map_err(|e| open_error(path, e))?;
+        Ok(Fifo { file })
+    }
+
+    /// Opens the writing end like `Fifo::try_writer`.
+    pub fn try_writer(self) -> Result<Option<Fifo>> {
+        let path = self.path.as_path();
+        self.create()?;
+        let file = match OpenOptions::new()
+            .write(true)
+            .custom_flags(O_NONBLOCK)
+            .open(path)
+        {
+            Ok(file) => file,
+            Err(e) if e.raw_os_error() == Some(Errno::ENXIO as i32) => return Ok(None),
+            Err(e) => return Err(open_error(path, e)),
+        };
+
+        let fifo = Fifo { file };
+        fifo.set_nonblocking(false)?;
+        Ok(Some(fifo))
+    }
+
+    fn create(&self) -> Result<()> {
+        match mkfifo(&self.path, self.permissions.mode()) {
+            Ok(()) => self.permissions.apply_path(&self.path),
+            Err(Errno::EEXIST) => Ok(()),
+            Err(source) => Err(Error::Create {
+                path: self.path.display().to_string(),
+                source,
+            }),
+        }
+    }
+}
+
+fn open_error(path: &Path, e: io::Error) -> Error {
+    Error::Open {
+        path: path.display().to_string(),
+        source: Error::os_errno(&e),
+    }
+}
+
+impl Read for Fifo {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for Fifo {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl From<Fifo> for OwnedFd {
+    fn from(fifo: Fifo) -> Self {
+        fifo.file.into()
+    }
+}
+
+impl AsFd for Fifo {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+}
@@ -0,0 +1,223 @@
+// What happens to handles when a process forks. The child inherits every mapping, so handles
+// keep working there without reopening anything, with three exceptions:
+//
+// - Handles lock their object through open file descriptions, which the child shares with
+//   the parent. Left alone, the two wouldn't exclude each other from initializing or
+//   detaching, and whichever detached first would take the other's attachment for its own,
+//   find itself last and unlink the object under the other's feet. `pthread_atfork` handlers
+//   therefore open and attach new file descriptions before forking, which the child puts in
+//   place of the shared ones before counting its handles in the headers, see `register`.
+//   Objects without a count of handles, like `RMtx`, `ShmMutex`, `Condvar` and `RwLk`, get
+//   new descriptions too, but have nothing to update.
+// - Locks held while forking stay held by the thread of the parent. The child must not
+//   release them, so it has to `mem::forget` guards it inherits.
+// - Helper threads, like those behind `AsyncMtx` and `WaitSet::add_futex`, only exist in the
+//   parent. Handles relying on one must be opened again in the child.
+//
+// Mappings made with `ShmBuilder::dont_fork` aren't inherited at all.
+
+use std::{
+    cell::UnsafeCell,
+    ffi::{CString, c_void},
+    os::fd::{AsRawFd, BorrowedFd, OwnedFd, RawFd},
+    ptr::NonNull,
+    sync::{
+        Once,
+        atomic::{AtomicBool, AtomicU32, Ordering},
+    },
+    thread,
+};
+
+use nix::{
+    fcntl::{FcntlArg, FdFlag, OFlag, fcntl, open},
+    libc::{LOCK_EX, LOCK_UN, PROT_READ, PROT_WRITE, dup2, flock, mprotect, pthread_atfork},
+    sys::stat::Mode,
+};
+
+use crate::cleanup;
+
+/// A descriptor to replace in a forked child with one referring to the same file through a
+/// new file description.
+pub(crate) struct Reopen {
+    pub(crate) fd: RawFd,
+    /// Opens the file of `fd` anew.
+    pub(crate) path: String,
+    /// Whether `fd` holds an attach lock, which the new description takes too.
+    pub(crate) attach: bool,
+}
+
+/// A `Reopen` ready to be used between the `pthread_atfork` handlers.
+struct Reopened {
+    fd: RawFd,
+    path: CString,
+    attach: bool,
+    /// The new description, opened and attached before forking.
+    prepared: Option<OwnedFd>,
+}
+
+/// A handle to set up again in forked children.
+struct Inherited {
+    /// The descriptor of the mapped object, identifying the handle.
+    fd: RawFd,
+    reopen: Vec<Reopened>,
+    /// The descriptor to take the init lock on while counting the handle.
+    init: RawFd,
+    /// The count of attached handles in the header of the segment, if it has one.
+    attached: Option<*const AtomicU32>,
+    /// The start of the mapping, if it is currently mapped read-only.
+    read_only: Option<*mut c_void>,
+}
+
+struct Registry {
+    locked: AtomicBool,
+    handles: UnsafeCell<Vec<Inherited>>,
+}
+
+// `handles` is only accessed while holding `locked`.
+unsafe impl Sync for Registry {}
+
+static REGISTRY: Registry = Registry {
+    locked: AtomicBool::new(false),
+    handles: UnsafeCell::new(Vec::new()),
+};
+
+static INSTALL: Once = Once::new();
+
+/// Makes forked children set up the handle mapping the object `fd` on their own, reopening
+/// the descriptors in `reopen` and counting the handle in `attached`, if given, under the
+/// init lock held through `init`. `fd` must be passed to `unregister` before it is closed,
+/// and `attached` must stay mapped until then.
+pub(crate) fn register(
+    fd: &OwnedFd,
+    reopen: Vec<Reopen>,
+    init: &OwnedFd,
+    attached: Option<&AtomicU32>,
+) {
+    INSTALL.call_once(|| unsafe {
+        pthread_atfork(Some(prepare), Some(in_parent), Some(in_child));
+    });
+
+    // Paths of objects can't contain NUL bytes, since they were opened already.
+    let reopen = reopen
+        .into_iter()
+        .filter_map(|r| {
+            Some(Reopened {
+                fd: r.fd,
+                path: CString::new(r.path).ok()?,
+                attach: r.attach,
+                prepared: None,
+            })
+        })
+        .collect();
+    let handle = Inherited {
+        fd: fd.as_raw_fd(),
+        reopen,
+        init: init.as_raw_fd(),
+        attached: attached.map(|attached| attached as *const AtomicU32),
+        read_only: None,
+    };
+
+    lock();
+    unsafe { (*REGISTRY.handles.get()).push(handle) };
+    unlock();
+}
+
+/// Forgets the handle mapping the object `fd`, which is about to be closed.
+pub(crate) fn unregister(fd: &OwnedFd) {
+    lock();
+    unsafe { (*REGISTRY.handles.get()).retain(|handle| handle.fd != fd.as_raw_fd()) };
+    unlock();
+}
+
+/// Records whether the mapping of the object `fd`, starting at `ptr`, is mapped read-only,
+/// so the count of attached handles can still be updated.
+pub(crate) fn set_read_only(fd: &OwnedFd, ptr: NonNull<c_void>, read_only: bool) {
+    lock();
+    for handle in unsafe { &mut *REGISTRY.handles.get() } {
+        if handle.fd == fd.as_raw_fd() {
+            handle.read_only = read_only.then_some(ptr.as_ptr());
+        }
+    }
+    unlock();
+}
+
+fn lock() {
+    while REGISTRY.locked.swap(true, Ordering::Acquire) {
+        thread::yield_now();
+    }
+}
+
+fn unlock() {
+    REGISTRY.locked.store(false, Ordering::Release);
+}
+
+/// Runs before forking. Holds the registry locked until the fork is done, so the child never
+/// sees it mid-update, and opens the descriptions the child will use. Handles are attached
+/// through them before the parent returns from `fork`, so it can't find itself last if it
+/// detaches right away. A description that can't be opened leaves the handle shared.
+extern "C" fn prepare() {
+    lock();
+    for handle in unsafe { &mut *REGISTRY.handles.get() } {
+        for reopen in &mut handle.reopen {
+            let flags = OFlag::O_RDWR | OFlag::O_CLOEXEC;
+            reopen.prepared = open(reopen.path.as_c_str(), flags, Mode::empty())
+                .ok()
+                .filter(|fd| !reopen.attach || cleanup::attach(fd).is_ok());
+        }
+    }
+}
+
+/// Runs in the parent after forking, dropping its references to the child's descriptions.
+extern "C" fn in_parent() {
+    for handle in unsafe { &mut *REGISTRY.handles.get() } {
+        for reopen in &mut handle.reopen {
+            reopen.prepared = None;
+        }
+    }
+    unlock();
+}
+
+/// Runs in the child right after forking, where only async-signal-safe calls are allowed.
+/// Puts the prepared descriptions in place, keeping the numbers and flags of the
+/// descriptors, and counts the handles.
+extern "C" fn in_child() {
+    for handle in unsafe { &mut *REGISTRY.handles.get() } {
+        for reopen in &mut handle.reopen {
+            if let Some(prepared) = reopen.prepared.take() {
+                replace(reopen.fd, prepared);
+            }
+        }
+        let Some(attached) = handle.attached else {
+            continue;
+        };
+        unsafe {
+            flock(handle.init, LOCK_EX);
+            let len = attached as usize + size_of::<AtomicU32>();
+            if let Some(start) = handle.read_only {
+                mprotect(start, len - start as usize, PROT_READ | PROT_WRITE);
+            }
+            (*attached).fetch_add(1, Ordering::AcqRel);
+            if let Some(start) = handle.read_only {
+                mprotect(start, len - start as usize, PROT_READ);
+            }
+            flock(handle.init, LOCK_UN);
+        }
+    }
+    unlock();
+}
+
+/// Makes `fd` refer to the description of `new`, keeping its flags.
+fn replace(fd: RawFd, new: OwnedFd) {
+    let Ok(flags) = fcntl(unsafe { BorrowedFd::borrow_raw(fd) }, FcntlArg::F_GETFD) else {
+        return;
+    };
+    if unsafe { dup2(new.as_raw_fd(), fd) } != -1 {
+        // dup2 clears close-on-exec.
+        let flags = FdFlag::from_bits_retain(flags);
+        fcntl(
+            unsafe { BorrowedFd::borrow_raw(fd) },
+            FcntlArg::F_SETFD(flags),
+        )
+        .ok();
+    }
+}
@@ -0,0 +1,59 @@
+use std::io::{ErrorKind, Read, Write};
+
+use crate::error::{Error, Result};
+
+/// Prefixes `msg` with its length as a little-endian `u32`.
+pub(crate) fn frame(msg: &[u8]) -> Result<Vec<u8>> {
+    let len = prefix(msg)?;
+    Ok([&len[..], msg].concat())
+}
+
+/// The length prefix of the frame holding `msg`.
+pub(crate) fn prefix(msg: &[u8]) -> Result<[u8; 4]> {
+    let len = u32::try_from(msg.len()).map_err(|_| {
+        Error::InvalidArgument(format!(
+            "Message of {} bytes is too large to frame",
+            msg.len()
+        ))
+    })?;
+    Ok(len.to_le_bytes())
+}
+
+/// Writes `msg` as one frame with a single `write_all`.
+pub(crate) fn write_frame(mut writer: impl Write, msg: &[u8]) -> Result<()> {
+    writer.write_all(&frame(msg)?)?;
+    Ok(())
+}
+
+/// Reads the next frame, failing if the stream ends before it starts.
+pub(crate) fn read_frame(mut reader: impl Read) -> Result<Vec<u8>> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len).map_err(|e| match e.kind() {
+        ErrorKind::UnexpectedEof => Error::closed(),
+        _ => e.into(),
+    })?;
+    read_frame_body(reader, len)
+}
+
+/// Reads the message of a frame whose length prefix has already been read.
+pub(crate) fn read_frame_body(mut reader: impl Read, len: [u8; 4]) -> Result<Vec<u8>> {
+    let mut msg = vec![0; u32::from_le_bytes(len) as usize];
+    reader.read_exact(&mut msg)?;
+    Ok(msg)
+}
+
+/// Encodes `value` with bincode.
+#[cfg(feature = "serde")]
+pub(crate) fn encode<T: serde::Serialize>(value: &T) -> Result<Vec<u8>> {
+    Ok(bincode::serde::encode_to_vec(
+        value,
+        bincode::config::standard(),
+    )?)
+}
+
+/// Decodes a value encoded with `encode`.
+#[cfg(feature = "serde")]
+pub(crate) fn decode<T: serde::de::DeserializeOwned>(msg: &[u8]) -> Result<T> {
+    let (value, _) = bincode::serde::decode_from_slice(msg, bincode::config::standard())?;
+    Ok(value)
+}
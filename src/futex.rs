@@ -0,0 +1,355 @@
+//! Blocking on a 32-bit word of shared memory until another process changes it, the
+//! building block of the crate's own locks and channels.
+//!
+//! Linux uses futexes, macOS `__ulock_wait` and FreeBSD `_umtx_op`. Elsewhere waiters poll
+//! the word every millisecond and waking does nothing, see `Capabilities::wait_on_address`.
+
+use std::{
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+use nix::libc::{CLOCK_MONOTONIC, timespec};
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd"))]
+use nix::{
+    errno::Errno,
+    libc::{EINTR, ETIMEDOUT, c_int},
+};
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd"))]
+use crate::error::Error;
+use crate::{error::Result, time::deadline};
+
+/// Blocks while `word` holds `expected`, until another thread or process calls `wake` on it
+/// or `timeout` elapses. Returns false if the timeout elapsed.
+///
+/// Returns immediately if `word` doesn't hold `expected`, and can also return spuriously, so
+/// callers check the word again in a loop. To work across processes, `word` must live in
+/// memory they share, such as a `Shm<AtomicU32>`.
+pub fn wait(word: &AtomicU32, expected: u32, timeout: Option<Duration>) -> Result<bool> {
+    let deadline = timeout
+        .map(|timeout| deadline(CLOCK_MONOTONIC, timeout))
+        .transpose()?;
+    wait_until(word, expected, deadline.as_ref())
+}
+
+/// Blocks while `word` holds `expected`, until woken or the absolute monotonic `deadline`.
+/// Returns false if the deadline passed. Spurious wakeups are possible.
+///
+/// The futex is not private, so it works across processes mapping the same memory.
+#[cfg(target_os = "linux")]
+pub(crate) fn wait_until(
+    word: &AtomicU32,
+    expected: u32,
+    deadline: Option<&timespec>,
+) -> Result<bool> {
+    use nix::libc::{EAGAIN, FUTEX_BITSET_MATCH_ANY, FUTEX_WAIT_BITSET, SYS_futex, syscall};
+
+    let ret = unsafe {
+        syscall(
+            SYS_futex,
+            word.as_ptr(),
+            FUTEX_WAIT_BITSET,
+            expected,
+            deadline.map_or(std::ptr::null(), |t| t as *const timespec),
+            std::ptr::null::<u32>(),
+            FUTEX_BITSET_MATCH_ANY,
+        )
+    };
+    if ret == 0 {
+        return Ok(true);
+    }
+    match Errno::last_raw() {
+        EAGAIN | EINTR => Ok(true),
+        ETIMEDOUT => Ok(false),
+        _ => Err(Error::lock("futex(FUTEX_WAIT_BITSET)")(Errno::last())),
+    }
+}
+
+/// Wakes up to `count` waiters blocked on `word`, or all of them if it is `u32::MAX`.
+#[cfg(target_os = "linux")]
+pub fn wake(word: &AtomicU32, count: u32) -> Result<()> {
+    use nix::libc::{FUTEX_WAKE, SYS_futex, syscall};
+
+    let ret = unsafe { syscall(SYS_futex, word.as_ptr(), FUTEX_WAKE, clamp(count)) };
+    Errno::result(ret)
+        .map(drop)
+        .map_err(Error::lock("futex(FUTEX_WAKE)"))
+}
+
+/// Wakes up to `count` waiters blocked on `from` and moves up to `requeue` of the remaining
+/// ones over to wait on `to`, as long as `from` still holds `expected`. Returns false
+/// without doing anything if it doesn't. Moving waiters instead of waking them avoids a
+/// thundering herd on a lock they would contend for next.
+///
+/// Only available on Linux.
+#[cfg(target_os = "linux")]
+pub fn requeue(
+    from: &AtomicU32,
+    expected: u32,
+    to: &AtomicU32,
+    count: u32,
+    requeue: u32,
+) -> Result<bool> {
+    use nix::libc::{EAGAIN, FUTEX_CMP_REQUEUE, SYS_futex, syscall};
+
+    // The number of waiters to requeue is passed in place of the timeout.
+    let ret = unsafe {
+        syscall(
+            SYS_futex,
+            from.as_ptr(),
+            FUTEX_CMP_REQUEUE,
+            clamp(count),
+            clamp(requeue) as usize,
+            to.as_ptr(),
+            expected,
+        )
+    };
+    if ret >= 0 {
+        return Ok(true);
+    }
+    match Errno::last_raw() {
+        EAGAIN => Ok(false),
+        _ => Err(Error::lock("futex(FUTEX_CMP_REQUEUE)")(Errno::last())),
+    }
+}
+
+/// Limits a number of waiters to what the kernel takes as a signed int.
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+fn clamp(count: u32) -> c_int {
+    count.min(c_int::MAX as u32) as c_int
+}
+
+// macOS has no futex, but the `__ulock` calls behind libc++'s atomic waits do the same, and
+// work across processes with `UL_COMPARE_AND_WAIT_SHARED`. They take relative timeouts.
+#[cfg(target_os = "macos")]
+const UL_COMPARE_AND_WAIT_SHARED: u32 = 3;
+#[cfg(target_os = "macos")]
+const ULF_WAKE_ALL: u32 = 0x100;
+
+#[cfg(target_os = "macos")]
+unsafe extern "C" {
+    fn __ulock_wait(
+        operation: u32,
+        addr: *mut nix::libc::c_void,
+        value: u64,
+        timeout_us: u32,
+    ) -> c_int;
+    fn __ulock_wake(operation: u32, addr: *mut nix::libc::c_void, wake_value: u64) -> c_int;
+}
+
+#[cfg(target_os = "macos")]
+pub(crate) fn wait_until(
+    word: &AtomicU32,
+    expected: u32,
+    deadline: Option<&timespec>,
+) -> Result<bool> {
+    // A timeout of zero waits forever.
+    let timeout_us = match deadline {
+        None => 0,
+        Some(deadline) => match crate::time::remaining(CLOCK_MONOTONIC, deadline)? {
+            Some(left) => left.as_micros().clamp(1, u32::MAX as u128) as u32,
+            None => return Ok(false),
+        },
+    };
+    let ret = unsafe {
+        __ulock_wait(
+            UL_COMPARE_AND_WAIT_SHARED,
+            word.as_ptr().cast(),
+            expected as u64,
+            timeout_us,
+        )
+    };
+    if ret >= 0 {
+        return Ok(true);
+    }
+    match Errno::last_raw() {
+        EINTR => Ok(true),
+        ETIMEDOUT => Ok(false),
+        _ => Err(Error::lock("__ulock_wait")(Errno::last())),
+    }
+}
+
+/// Wakes up to `count` waiters blocked on `word`. On macOS only one or all can be woken.
+#[cfg(target_os = "macos")]
+pub fn wake(word: &AtomicU32, count: u32) -> Result<()> {
+    let operation = match count {
+        1 => UL_COMPARE_AND_WAIT_SHARED,
+        _ => UL_COMPARE_AND_WAIT_SHARED | ULF_WAKE_ALL,
+    };
+    let ret = unsafe { __ulock_wake(operation, word.as_ptr().cast(), 0) };
+    match Errno::result(ret) {
+        Ok(_) | Err(Errno::ENOENT) => Ok(()),
+        Err(e) => Err(Error::lock("__ulock_wake")(e)),
+    }
+}
+
+#[cfg(target_os = "freebsd")]
+pub(crate) fn wait_until(
+    word: &AtomicU32,
+    expected: u32,
+    deadline: Option<&timespec>,
+) -> Result<bool> {
+    use nix::libc::{_umtx_op, _umtx_time, UMTX_ABSTIME, UMTX_OP_WAIT_UINT, c_ulong, c_void};
+
+    // Without the _PRIVATE suffix the wait works across processes. A timeout is passed as
+    // the size of `_umtx_time` in `uaddr` and a pointer to it in `uaddr2`.
+    let mut timeout = deadline.map(|deadline| _umtx_time {
+        _timeout: *deadline,
+        _flags: UMTX_ABSTIME,
+        _clockid: CLOCK_MONOTONIC as u32,
+    });
+    let (size, timeout) = match timeout.as_mut() {
+        Some(timeout) => (
+            size_of::<_umtx_time>(),
+            timeout as *mut _umtx_time as *mut c_void,
+        ),
+        None => (0, std::ptr::null_mut()),
+    };
+    let ret = unsafe {
+        _umtx_op(
+            word.as_ptr().cast(),
+            UMTX_OP_WAIT_UINT,
+            expected as c_ulong,
+            size as *mut c_void,
+            timeout,
+        )
+    };
+    if ret == 0 {
+        return Ok(true);
+    }
+    match Errno::last_raw() {
+        EINTR => Ok(true),
+        ETIMEDOUT => Ok(false),
+        _ => Err(Error::lock("_umtx_op(UMTX_OP_WAIT_UINT)")(Errno::last())),
+    }
+}
+
+/// Wakes up to `count` waiters blocked on `word`, or all of them if it is `u32::MAX`.
+#[cfg(target_os = "freebsd")]
+pub fn wake(word: &AtomicU32, count: u32) -> Result<()> {
+    use nix::libc::{_umtx_op, UMTX_OP_WAKE, c_ulong};
+
+    let ret = unsafe {
+        _umtx_op(
+            word.as_ptr().cast(),
+            UMTX_OP_WAKE,
+            clamp(count) as c_ulong,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    Errno::result(ret)
+        .map(drop)
+        .map_err(Error::lock("_umtx_op(UMTX_OP_WAKE)"))
+}
+
+// Where there is no wait-on-address call usable across processes, such as on NetBSD and
+// OpenBSD, waiters poll the word instead and waking is a no-op.
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "freebsd")))]
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "freebsd")))]
+pub(crate) fn wait_until(
+    word: &AtomicU32,
+    expected: u32,
+    deadline: Option<&timespec>,
+) -> Result<bool> {
+    let sleep = match deadline {
+        None => POLL_INTERVAL,
+        Some(deadline) => match crate::time::remaining(CLOCK_MONOTONIC, deadline)? {
+            Some(left) => left.min(POLL_INTERVAL),
+            None => return Ok(false),
+        },
+    };
+    if word.load(Ordering::SeqCst) == expected {
+        std::thread::sleep(sleep);
+    }
+    Ok(true)
+}
+
+/// Does nothing, as waiters poll the word on this platform.
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "freebsd")))]
+pub fn wake(_word: &AtomicU32, _count: u32) -> Result<()> {
+    Ok(())
+}
+
+/// Lets processes block until a condition on shared memory may have changed,
+/// without a lock protecting the condition.
+///
+/// Waiters re-check their condition after announcing themselves, and notifiers bump a
+/// sequence number before looking for waiters, so no wakeup is lost in between.
+#[repr(C)]
+pub(crate) struct EventCount {
+    seq: AtomicU32,
+    waiters: AtomicU32,
+}
+
+impl EventCount {
+    /// Polls until `poll` returns `Some`, blocking between attempts until notified.
+    /// Returns `None` if `timeout` elapsed first.
+    pub(crate) fn wait_for<R>(
+        &self,
+        timeout: Option<Duration>,
+        mut poll: impl FnMut() -> Option<R>,
+    ) -> Result<Option<R>> {
+        if let Some(result) = poll() {
+            return Ok(Some(result));
+        }
+
+        let deadline = timeout
+            .map(|timeout| deadline(CLOCK_MONOTONIC, timeout))
+            .transpose()?;
+        loop {
+            self.waiters.fetch_add(1, Ordering::SeqCst);
+            let seq = self.seq.load(Ordering::SeqCst);
+            let result = poll();
+            let woken = match result {
+                Some(_) => Ok(true),
+                None => wait_until(&self.seq, seq, deadline.as_ref()),
+            };
+            self.waiters.fetch_sub(1, Ordering::SeqCst);
+
+            if result.is_some() {
+                return Ok(result);
+            }
+            if !woken? {
+                return Ok(poll());
+            }
+        }
+    }
+
+    /// Starts a helper thread signaling an eventfd whenever the event count is notified,
+    /// counting itself as a waiter so notifiers wake it. With `poll`, it also signals that
+    /// often, for conditions nobody notifies about, such as a process dying.
+    ///
+    /// # Safety
+    ///
+    /// The event count must stay mapped until the bridge is dropped.
+    #[cfg(all(target_os = "linux", feature = "tokio"))]
+    pub(crate) unsafe fn bridge(
+        &self,
+        poll: Option<Duration>,
+    ) -> Result<crate::futex_bridge::FutexBridge> {
+        let seq = self.seq.load(Ordering::SeqCst);
+        unsafe { crate::futex_bridge::FutexBridge::new(&self.seq, seq, Some(&self.waiters), poll) }
+    }
+
+    /// Wakes all waiters, if there are any.
+    pub(crate) fn notify_all(&self) {
+        self.notify(u32::MAX);
+    }
+
+    /// Wakes one waiter, if there is any.
+    pub(crate) fn notify_one(&self) {
+        self.notify(1);
+    }
+
+    fn notify(&self, count: u32) {
+        self.seq.fetch_add(1, Ordering::SeqCst);
+        if self.waiters.load(Ordering::SeqCst) > 0 {
+            // Waking can only fail for an invalid address, which a reference never is.
+            wake(&self.seq, count).ok();
+        }
+    }
+}
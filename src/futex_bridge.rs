@@ -0,0 +1,118 @@
+use std::{
+    os::fd::AsFd,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU32, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::{
+    error::{Error, Result},
+    event::Event,
+    futex,
+};
+
+/// How long a bridge sleeps at most before checking whether it should stop.
+const BRIDGE_POLL: Duration = Duration::from_millis(100);
+
+/// A helper thread turning changes of a futex word into signals of an eventfd, so waiting
+/// for the word can be done with epoll or an async runtime.
+pub(crate) struct FutexBridge {
+    word: *const AtomicU32,
+    event: Event,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl FutexBridge {
+    /// Starts watching `word`, signaling the eventfd whenever its value changes from the one
+    /// it had when last signaled, starting with `expected`.
+    ///
+    /// While waiting, the thread counts itself in `waiters`, for words whose notifiers only
+    /// wake when someone waits. With a `tick`, it also signals whenever that much time passed
+    /// without a change, for waiters that have to check on something else now and then.
+    ///
+    /// # Safety
+    ///
+    /// `word` and `waiters` must stay mapped until the bridge is dropped, and the bridge must
+    /// not be leaked, as the thread keeps using them until then.
+    pub(crate) unsafe fn new(
+        word: &AtomicU32,
+        expected: u32,
+        waiters: Option<&AtomicU32>,
+        tick: Option<Duration>,
+    ) -> Result<Self> {
+        let event = Event::new(0)?;
+        let signal = Event::from(
+            event
+                .as_fd()
+                .try_clone_to_owned()
+                .map_err(|e| Error::sys("dup")(Error::os_errno(&e)))?,
+        );
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let address = word as *const AtomicU32 as usize;
+        let waiters_address = waiters.map(|waiters| waiters as *const AtomicU32 as usize);
+        let thread = thread::Builder::new()
+            .name("nix-ipc-futex".to_owned())
+            .spawn(move || {
+                let word = unsafe { &*(address as *const AtomicU32) };
+                let waiters =
+                    waiters_address.map(|address| unsafe { &*(address as *const AtomicU32) });
+                let mut seen = expected;
+                while !thread_stop.load(Ordering::Acquire) {
+                    if let Some(waiters) = waiters {
+                        waiters.fetch_add(1, Ordering::SeqCst);
+                    }
+                    let value = word.load(Ordering::SeqCst);
+                    let woken = if value == seen {
+                        futex::wait(word, seen, Some(tick.unwrap_or(BRIDGE_POLL)))
+                    } else {
+                        Ok(true)
+                    };
+                    if let Some(waiters) = waiters {
+                        waiters.fetch_sub(1, Ordering::SeqCst);
+                    }
+
+                    if value != seen {
+                        seen = value;
+                        signal.signal().ok();
+                    } else if matches!(woken, Ok(false)) && tick.is_some() {
+                        signal.signal().ok();
+                    }
+                }
+            })
+            .map_err(Error::Io)?;
+
+        Ok(Self {
+            word,
+            event,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// The eventfd signaled when the word changes.
+    pub(crate) fn event(&self) -> &Event {
+        &self.event
+    }
+
+    /// Returns true if the bridge watches `word`.
+    pub(crate) fn watches(&self, word: &AtomicU32) -> bool {
+        std::ptr::eq(self.word, word)
+    }
+}
+
+impl Drop for FutexBridge {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        // Other waiters on the word may wake spuriously, which they have to tolerate anyway.
+        futex::wake(unsafe { &*self.word }, u32::MAX).ok();
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
@@ -0,0 +1,315 @@
+use std::{
+    mem::size_of,
+    num::NonZeroUsize,
+    ptr,
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+use nix::{
+    errno::Errno,
+    fcntl::OFlag,
+    libc::{
+        CLOCK_REALTIME, EAGAIN, EINTR, ESRCH, ETIMEDOUT, FUTEX_LOCK_PI, FUTEX_OWNER_DIED,
+        FUTEX_TID_MASK, FUTEX_TRYLOCK_PI, FUTEX_UNLOCK_PI, SYS_futex, SYS_gettid, syscall,
+        timespec,
+    },
+};
+
+use crate::{
+    cleanup::CleanupPolicy,
+    error::{Error, Result},
+    map::Mapping,
+    namespace::Namespace,
+    permissions::Permissions,
+    r_mtx::{LockResult, TimedLockResult, TryLockResult},
+    time::deadline,
+};
+
+/// Version of the layout of `FutexMutex` files, stored in their second word.
+const LAYOUT_VERSION: u32 = 1;
+
+/// The contents of a `FutexMutex` file.
+#[repr(C)]
+struct Word {
+    /// The kernel's priority-inheritance futex word: the thread id of the owner, or 0 when
+    /// unlocked, plus the `FUTEX_WAITERS` and `FUTEX_OWNER_DIED` bits.
+    lock: AtomicU32,
+    /// `LAYOUT_VERSION`, written when the file is created.
+    version: u32,
+}
+
+/// An interprocess mutex with a layout defined by this crate instead of the C library, so
+/// processes built against glibc, musl or in other languages can share it. Linux only.
+///
+/// The file holds 8 bytes in native byte order: the futex word used with `FUTEX_LOCK_PI` and
+/// `FUTEX_UNLOCK_PI`, holding the thread id of the owner or 0, followed by a `u32` layout
+/// version, currently 1. Another implementation following the kernel's priority-inheritance
+/// futex protocol on the first word interoperates with this one.
+///
+/// The owner is a thread, and the mutex is recovered when the kernel reports that thread to be
+/// gone, even if the rest of its process lives on. A thread id reused by a new thread in the
+/// meantime keeps the mutex locked until that thread exits. Timeouts are measured on the
+/// realtime clock, which `FUTEX_LOCK_PI` requires.
+pub struct FutexMutex {
+    map: Mapping,
+}
+
+impl FutexMutex {
+    pub fn new(name: &str) -> Result<Self> {
+        Self::builder(name).build()
+    }
+
+    /// Returns a builder for configuring how the mutex is opened and cleaned up.
+    pub fn builder(name: &str) -> FutexMutexBuilder {
+        FutexMutexBuilder {
+            name: name.to_owned(),
+            cleanup: CleanupPolicy::default(),
+            namespace: Namespace::default(),
+            permissions: Permissions::default(),
+        }
+    }
+
+    /// Unlinks (deletes) the mutex file from /dev/shm.
+    pub fn unlink(name: &str) -> Result<()> {
+        Namespace::default().unlink_futex_mutex(name)
+    }
+
+    /// Locks the mutex and returns a guard that unlocks it when dropped.
+    pub fn lock(&self) -> Result<FutexMutexGuard<'_>> {
+        let result = self.lock_raw()?;
+        Ok(FutexMutexGuard { mtx: self, result })
+    }
+
+    /// Locks the mutex without a guard; the caller is responsible for calling `unlock`.
+    pub fn lock_raw(&self) -> Result<LockResult> {
+        Ok(self
+            .lock_until(None)?
+            .expect("Locking without a deadline never times out"))
+    }
+
+    /// Attempts to lock the mutex without blocking, returning `None` if it is held elsewhere.
+    pub fn try_lock(&self) -> Result<Option<FutexMutexGuard<'_>>> {
+        let result = match self.try_lock_raw()? {
+            TryLockResult::Acquired => LockResult::Acquired,
+            TryLockResult::OwnerDiedRecovered => LockResult::OwnerDiedRecovered,
+            TryLockResult::WouldBlock => return Ok(None),
+        };
+        Ok(Some(FutexMutexGuard { mtx: self, result }))
+    }
+
+    /// Attempts to lock the mutex without blocking and without a guard.
+    pub fn try_lock_raw(&self) -> Result<TryLockResult> {
+        let tid = gettid();
+        loop {
+            if self
+                .word()
+                .compare_exchange(0, tid, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                return Ok(TryLockResult::Acquired);
+            }
+            let ret = unsafe { syscall(SYS_futex, self.word().as_ptr(), FUTEX_TRYLOCK_PI) };
+            if ret == 0 {
+                return Ok(match self.acquired() {
+                    LockResult::Acquired => TryLockResult::Acquired,
+                    LockResult::OwnerDiedRecovered => TryLockResult::OwnerDiedRecovered,
+                });
+            }
+            match Errno::last_raw() {
+                EINTR => continue,
+                EAGAIN => return Ok(TryLockResult::WouldBlock),
+                ESRCH => {
+                    if self.take_over(tid) {
+                        return Ok(TryLockResult::OwnerDiedRecovered);
+                    }
+                }
+                _ => return Err(Error::lock("futex(FUTEX_TRYLOCK_PI)")(Errno::last())),
+            }
+        }
+    }
+
+    /// Locks the mutex, giving up after `timeout` has elapsed on the realtime clock.
+    pub fn lock_timeout(&self, timeout: Duration) -> Result<Option<FutexMutexGuard<'_>>> {
+        let result = match self.lock_timeout_raw(timeout)? {
+            TimedLockResult::Acquired => LockResult::Acquired,
+            TimedLockResult::OwnerDiedRecovered => LockResult::OwnerDiedRecovered,
+            TimedLockResult::TimedOut => return Ok(None),
+        };
+        Ok(Some(FutexMutexGuard { mtx: self, result }))
+    }
+
+    /// Locks the mutex with a timeout and without a guard.
+    pub fn lock_timeout_raw(&self, timeout: Duration) -> Result<TimedLockResult> {
+        let abstime = deadline(CLOCK_REALTIME, timeout)?;
+        Ok(match self.lock_until(Some(&abstime))? {
+            Some(LockResult::Acquired) => TimedLockResult::Acquired,
+            Some(LockResult::OwnerDiedRecovered) => TimedLockResult::OwnerDiedRecovered,
+            None => TimedLockResult::TimedOut,
+        })
+    }
+
+    /// Unlocks the mutex, which must be held by the calling thread.
+    pub fn unlock(&self) -> Result<()> {
+        let tid = gettid();
+        if self.word().load(Ordering::Relaxed) & FUTEX_TID_MASK != tid {
+            return Err(Error::lock("futex(FUTEX_UNLOCK_PI)")(Errno::EPERM));
+        }
+        if self
+            .word()
+            .compare_exchange(tid, 0, Ordering::Release, Ordering::Relaxed)
+            .is_ok()
+        {
+            return Ok(());
+        }
+        // There are waiters, so the kernel has to hand the lock over.
+        Errno::result(unsafe { syscall(SYS_futex, self.word().as_ptr(), FUTEX_UNLOCK_PI) })
+            .map(drop)
+            .map_err(Error::lock("futex(FUTEX_UNLOCK_PI)"))
+    }
+
+    /// Blocks until the mutex is acquired, or returns `None` once the absolute realtime
+    /// `deadline` has passed.
+    fn lock_until(&self, deadline: Option<&timespec>) -> Result<Option<LockResult>> {
+        let tid = gettid();
+        if self
+            .word()
+            .compare_exchange(0, tid, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            return Ok(Some(LockResult::Acquired));
+        }
+
+        loop {
+            let timeout = deadline.map_or(ptr::null(), |t| t as *const timespec);
+            let ret =
+                unsafe { syscall(SYS_futex, self.word().as_ptr(), FUTEX_LOCK_PI, 0, timeout) };
+            if ret == 0 {
+                return Ok(Some(self.acquired()));
+            }
+            match Errno::last_raw() {
+                // EAGAIN means the owner is exiting right now.
+                EINTR | EAGAIN => continue,
+                ETIMEDOUT => return Ok(None),
+                ESRCH => {
+                    if self.take_over(tid) {
+                        return Ok(Some(LockResult::OwnerDiedRecovered));
+                    }
+                }
+                _ => return Err(Error::lock("futex(FUTEX_LOCK_PI)")(Errno::last())),
+            }
+        }
+    }
+
+    /// Finishes acquiring the mutex through the kernel, which marks it `FUTEX_OWNER_DIED` if
+    /// the previous owner died with the word on its robust list.
+    fn acquired(&self) -> LockResult {
+        let word = self.word().load(Ordering::Relaxed);
+        if word & FUTEX_OWNER_DIED != 0 {
+            self.word().fetch_and(!FUTEX_OWNER_DIED, Ordering::Relaxed);
+            return LockResult::OwnerDiedRecovered;
+        }
+        LockResult::Acquired
+    }
+
+    /// Takes the mutex over from an owner the kernel reported as gone, returning false if
+    /// another thread did so first.
+    fn take_over(&self, tid: u32) -> bool {
+        let word = self.word().load(Ordering::Relaxed);
+        word & FUTEX_TID_MASK != 0
+            && self
+                .word()
+                .compare_exchange(word, tid, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+    }
+
+    fn word(&self) -> &AtomicU32 {
+        unsafe { &(*(self.map.ptr() as *const Word)).lock }
+    }
+}
+
+fn gettid() -> u32 {
+    unsafe { syscall(SYS_gettid) as u32 }
+}
+
+/// Builder for `FutexMutex`, created with `FutexMutex::builder`.
+pub struct FutexMutexBuilder {
+    name: String,
+    cleanup: CleanupPolicy,
+    namespace: Namespace,
+    permissions: Permissions,
+}
+
+impl FutexMutexBuilder {
+    /// Sets what happens to the mutex file when the handle is dropped.
+    pub fn cleanup(mut self, cleanup: CleanupPolicy) -> Self {
+        self.cleanup = cleanup;
+        self
+    }
+
+    /// Opens the mutex in `namespace` instead of the default one.
+    pub fn namespace(mut self, namespace: &Namespace) -> Self {
+        self.namespace = namespace.clone();
+        self
+    }
+
+    /// Sets the permissions the backing file is created with, see `Permissions`. Only
+    /// applies if this handle creates the mutex.
+    pub fn permissions(mut self, permissions: Permissions) -> Self {
+        self.permissions = permissions;
+        self
+    }
+
+    /// Opens the mutex, creating it if it doesn't exist yet.
+    /// Fails if the file was created with a different layout version.
+    pub fn build(self) -> Result<FutexMutex> {
+        let path = self.namespace.path(&self.name, ".fmx");
+        let len = NonZeroUsize::new(size_of::<Word>()).expect("Word has nonzero size");
+
+        let mut map = Mapping::open_init(
+            &path,
+            OFlag::O_CREAT,
+            &self.permissions,
+            len,
+            self.cleanup,
+            |ptr| {
+                unsafe { (*(ptr as *mut Word)).version = LAYOUT_VERSION };
+                Ok(())
+            },
+        )?;
+
+        let version = unsafe { (*(map.ptr() as *const Word)).version };
+        if version != LAYOUT_VERSION {
+            return Err(Error::Validation(format!(
+                "{path} has futex mutex layout version {version}, expected {LAYOUT_VERSION}"
+            )));
+        }
+        map.record_in(&self.namespace, &self.name, ".fmx")?;
+        map.inherit_in_forks(None);
+        Ok(FutexMutex { map })
+    }
+}
+
+/// RAII guard returned by `FutexMutex::lock`, unlocking the mutex on drop.
+pub struct FutexMutexGuard<'a> {
+    mtx: &'a FutexMutex,
+    result: LockResult,
+}
+
+impl FutexMutexGuard<'_> {
+    /// How the mutex was acquired.
+    pub fn lock_result(&self) -> &LockResult {
+        &self.result
+    }
+
+    /// Returns true if the previous owner died while holding the mutex.
+    pub fn owner_died_recovered(&self) -> bool {
+        matches!(self.result, LockResult::OwnerDiedRecovered)
+    }
+}
+
+impl Drop for FutexMutexGuard<'_> {
+    fn drop(&mut self) {
+        self.mtx.unlock().ok();
+    }
+}
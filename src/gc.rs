@@ -0,0 +1,255 @@
+use std::{fs, os::fd::OwnedFd};
+
+use nix::{
+    errno::Errno,
+    fcntl::OFlag,
+    sys::{stat::Mode, uio::pread},
+};
+
+use crate::{
+    backend::ObjectPath,
+    cleanup::LockFiles,
+    error::{Error, Result},
+    header::{self, MAGIC_LEN},
+    namespace::Namespace,
+};
+
+/// Suffixes of the objects that start without a header, such as the mutexes, which can only
+/// be told apart from files of other programs by their name.
+const HEADERLESS: &[&str] = &[
+    ".amx", ".are", ".cnd", ".fmx", ".ltc", ".mre", ".mtx", ".once", ".rwl", ".sch", ".wgr",
+];
+
+impl Namespace {
+    /// Unlinks the objects in this namespace that no process uses anymore, e.g. those left
+    /// behind by crashed processes, and returns their paths.
+    ///
+    /// An object is unused once no handle is attached to it: every handle is, whatever its
+    /// cleanup policy, until it is dropped or its process dies. Only objects starting with
+    /// the prefix of the namespace are looked at, and of those only the ones recognized by
+    /// their nix-ipc header are removed. Objects without a header, such as `RMtx` and
+    /// `Condvar`, are only recognized by the suffix of their name if the namespace has a
+    /// prefix setting them apart from the files of other programs, or if its registry (see
+    /// `with_registry`) still records a handle of them, as it does for those of crashed
+    /// processes until the registry is pruned. Processes opening an object while it is
+    /// removed wait and create it anew. Objects this process can't open, such as those of
+    /// other users, are skipped.
+    ///
+    /// Handles opened with `Shm::open_readonly` or `Shm::open_private` don't count.
+    /// Semaphores and the lock files used outside Linux are never removed.
+    pub fn gc(&self) -> Result<Vec<String>> {
+        self.collect(true)
+    }
+
+    /// Returns the paths of the objects `gc` would unlink, without unlinking them.
+    pub fn orphans(&self) -> Result<Vec<String>> {
+        self.collect(false)
+    }
+
+    fn collect(&self, unlink: bool) -> Result<Vec<String>> {
+        let registry = match self.has_registry() {
+            true => Some(self.registry()?),
+            false => None,
+        };
+        let mut collected = Vec::new();
+        for (name, object) in self.objects()? {
+            let named = !self.prefix().is_empty()
+                || registry
+                    .as_ref()
+                    .is_some_and(|registry| registry.records(&name));
+            let Some(locked) = open_unused(&object, named)? else {
+                continue;
+            };
+            if unlink {
+                match object.unlink() {
+                    // Removed by its last handle meanwhile.
+                    Err(e) if e.errno() == Some(Errno::ENOENT) => continue,
+                    result => result?,
+                }
+            }
+            drop(locked);
+            collected.push(object.to_string());
+        }
+        Ok(collected)
+    }
+
+    /// Lists the objects in the namespace with their names in it, which may include files of
+    /// other programs.
+    fn objects(&self) -> Result<Vec<(String, ObjectPath)>> {
+        #[cfg(target_os = "linux")]
+        let dir = self.dir().unwrap_or("/dev/shm").to_owned();
+        // Shared memory objects can't be listed, but their lock files can.
+        #[cfg(not(target_os = "linux"))]
+        let (dir, lock_files) = match self.dir() {
+            Some(dir) => (dir.to_owned(), false),
+            None => (std::env::temp_dir().display().to_string(), true),
+        };
+
+        let entries = fs::read_dir(&dir).map_err(|e| Error::Open {
+            path: dir.clone(),
+            source: Error::os_errno(&e),
+        })?;
+        let mut objects = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            let file_name = entry.file_name();
+            let Some(file_name) = file_name.to_str() else {
+                continue;
+            };
+
+            #[cfg(not(target_os = "linux"))]
+            let file_name = match lock_files {
+                true => match file_name
+                    .strip_prefix("nix-ipc.")
+                    .and_then(|name| name.strip_suffix(".refs"))
+                {
+                    Some(name) => name,
+                    None => continue,
+                },
+                false if file_name.ends_with(".lock") || file_name.ends_with(".refs") => continue,
+                false => file_name,
+            };
+            // Named semaphores live in /dev/shm too.
+            #[cfg(target_os = "linux")]
+            if self.dir().is_none() && file_name.starts_with("sem.") {
+                continue;
+            }
+
+            // The registry and lock graph of the namespace stay even while unused.
+            if let Some(name) = file_name.strip_prefix(self.prefix())
+                && !matches!(name, "nix-ipc.reg" | "nix-ipc.locks")
+            {
+                objects.push((name.to_owned(), self.path(name, "")));
+            }
+        }
+        Ok(objects)
+    }
+}
+
+/// Opens `object` if it is one of this crate that no handle is attached to, keeping others
+/// from attaching until the returned descriptor and lock files are dropped. Objects without
+/// a header only count as ours if `named` says their name identifies them.
+fn open_unused(object: &ObjectPath, named: bool) -> Result<Option<(OwnedFd, LockFiles)>> {
+    let fd = match object.open(OFlag::O_RDWR, Mode::empty()) {
+        Ok(fd) => fd,
+        Err(Errno::ENOENT | Errno::EACCES | Errno::EPERM) => return Ok(None),
+        Err(source) => {
+            let path = object.to_string();
+            return Err(Error::Open { path, source });
+        }
+    };
+    if !is_ours(object, &fd, named)? {
+        return Ok(None);
+    }
+    Ok(LockFiles::detached(object, &fd)?.map(|locks| (fd, locks)))
+}
+
+/// Returns true if `object`, opened as `fd`, was created by this crate, which for objects
+/// without a header takes `named` to say so.
+fn is_ours(object: &ObjectPath, fd: &OwnedFd, named: bool) -> Result<bool> {
+    let path = object.to_string();
+    if HEADERLESS.iter().any(|suffix| path.ends_with(suffix)) {
+        return Ok(named);
+    }
+    let mut magic = [0; MAGIC_LEN];
+    match pread(fd, &mut magic, 0) {
+        Ok(MAGIC_LEN) => Ok(header::is_magic(&magic)),
+        // Empty files are still being created, or aren't ours.
+        Ok(_) => Ok(false),
+        Err(e) => Err(Error::sys("pread")(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, path::PathBuf, process};
+
+    use nix::libc;
+
+    use super::*;
+
+    /// A namespace in a directory unique to this test and process, removed again on drop.
+    struct TestDir {
+        dir: PathBuf,
+    }
+
+    impl TestDir {
+        fn new(test: &str) -> Self {
+            let dir = env::temp_dir().join(format!("nix-ipc-test-{test}-{}", process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            Self { dir }
+        }
+
+        fn namespace(&self) -> Namespace {
+            Namespace::new(self.dir.display().to_string())
+        }
+
+        fn file(&self, name: &str) -> PathBuf {
+            self.dir.join(name)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.dir).ok();
+        }
+    }
+
+    /// Opens the `RMtx` called `name` in a forked child, which exits without dropping it.
+    fn leave_mutex_behind(namespace: &Namespace, name: &str) {
+        match unsafe { libc::fork() } {
+            -1 => panic!("fork failed"),
+            0 => {
+                let mtx = namespace.mtx(name).build();
+                std::mem::forget(mtx);
+                unsafe { libc::_exit(0) }
+            }
+            child => {
+                let mut status = 0;
+                assert_eq!(unsafe { libc::waitpid(child, &mut status, 0) }, child);
+            }
+        }
+    }
+
+    #[test]
+    fn files_of_other_programs_are_left_alone() {
+        let test = TestDir::new("gc-foreign");
+        fs::write(test.file("foreign.mtx"), [0; 64]).unwrap();
+        fs::write(test.file("foreign"), [0; 64]).unwrap();
+
+        for namespace in [test.namespace(), test.namespace().with_registry()] {
+            assert!(namespace.orphans().unwrap().is_empty());
+            assert!(namespace.gc().unwrap().is_empty());
+        }
+        assert!(test.file("foreign.mtx").exists());
+        assert!(test.file("foreign").exists());
+    }
+
+    #[test]
+    fn headerless_objects_recorded_in_the_registry_are_collected() {
+        let test = TestDir::new("gc-registry");
+        let namespace = test.namespace().with_registry();
+        leave_mutex_behind(&namespace, "left");
+        fs::write(test.file("foreign.mtx"), [0; 64]).unwrap();
+
+        let path = test.file("left.mtx").display().to_string();
+        assert_eq!(namespace.gc().unwrap(), [path]);
+        assert!(!test.file("left.mtx").exists());
+        assert!(test.file("foreign.mtx").exists());
+    }
+
+    #[test]
+    fn headerless_objects_are_collected_under_a_prefix() {
+        let test = TestDir::new("gc-prefix");
+        let namespace = test.namespace().with_prefix("app-");
+        leave_mutex_behind(&namespace, "left");
+        fs::write(test.file("foreign.mtx"), [0; 64]).unwrap();
+
+        let path = test.file("app-left.mtx").display().to_string();
+        assert_eq!(namespace.gc().unwrap(), [path]);
+        assert!(test.file("foreign.mtx").exists());
+    }
+}
@@ -0,0 +1,187 @@
+use std::{
+    any::type_name,
+    mem::{align_of, size_of},
+    process,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    error::{Error, Result},
+    map::Mapping,
+    shm_safe::{FNV_OFFSET, fnv1a, fnv1a_u64},
+};
+
+/// Bookkeeping stored in front of the data in every segment.
+#[repr(C)]
+pub(crate) struct Header {
+    /// Identifies the segment as created by this crate, including the header format version.
+    magic: [u8; 8],
+    /// Reads as `ENDIAN_TAG` only on machines with the creator's byte order.
+    endian: u32,
+    /// User-defined version of the data schema, checked when attaching.
+    schema_version: u32,
+    /// Major, minor and patch version of the crate that created the segment.
+    crate_version: [u16; 4],
+    /// Size of the data in bytes as seen by the creator.
+    size: u64,
+    /// Hash of the type name and layout of the data, checked when attaching.
+    layout: u64,
+    /// Number of attached handles, only modified under the init flock.
+    attached: AtomicU32,
+    /// Tells apart segments created under the same name, e.g. after one was unlinked and
+    /// created again.
+    generation: u64,
+    /// `ShmSafe::FINGERPRINT` of the data, covering its field names, offsets and types, or 0
+    /// if the creator didn't record one.
+    fingerprint: u64,
+}
+
+const MAGIC: [u8; 8] = *b"NIXIPC\0\x03";
+const ENDIAN_TAG: u32 = 0x0102_0304;
+
+/// Size of the magic number that starts every header.
+pub(crate) const MAGIC_LEN: usize = MAGIC.len();
+
+/// Returns true if `magic` starts a header written by any version of this crate.
+pub(crate) fn is_magic(magic: &[u8; MAGIC_LEN]) -> bool {
+    // The last byte is the version of the header format.
+    magic[..MAGIC_LEN - 1] == MAGIC[..MAGIC_LEN - 1]
+}
+
+impl Header {
+    /// Fills in the header of a freshly created segment holding `size` bytes of `T`.
+    pub(crate) fn init<T>(&mut self, size: usize, schema_version: u32) {
+        self.magic = MAGIC;
+        self.endian = ENDIAN_TAG;
+        self.schema_version = schema_version;
+        self.crate_version = crate_version();
+        self.size = size as u64;
+        self.layout = layout::<T>();
+        self.generation = new_generation();
+    }
+
+    /// Checks that the header of an existing segment matches what the opener expects.
+    pub(crate) fn validate<T>(&self, size: usize, schema_version: u32) -> Result<()> {
+        if self.magic != MAGIC {
+            return Err(Error::Validation(
+                "Shared memory object has no compatible nix-ipc header".to_owned(),
+            ));
+        }
+        if self.endian != ENDIAN_TAG {
+            return Err(Error::Validation(
+                "Shared memory object was created with a different byte order".to_owned(),
+            ));
+        }
+        if self.schema_version != schema_version {
+            let [major, minor, patch, _] = self.crate_version;
+            return Err(Error::Validation(format!(
+                "Shared memory object has schema version {}, expected {schema_version} \
+                 (created by nix-ipc {major}.{minor}.{patch})",
+                self.schema_version
+            )));
+        }
+        if self.size != size as u64 {
+            return Err(Error::Validation(format!(
+                "Shared memory object holds {} bytes of data, expected {size}",
+                self.size
+            )));
+        }
+        if self.layout != layout::<T>() {
+            return Err(Error::Validation(format!(
+                "Shared memory object was created for a different type than {}",
+                type_name::<T>()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Records the `ShmSafe::FINGERPRINT` of the data in a freshly created segment.
+    pub(crate) fn set_fingerprint(&mut self, fingerprint: u64) {
+        self.fingerprint = fingerprint;
+    }
+
+    /// Checks that the data of an existing segment has the field layout described by
+    /// `fingerprint`, which catches binaries built with different definitions of a type
+    /// that still agree on its name and size. Passes if either side has no fingerprint.
+    pub(crate) fn validate_fingerprint<T>(&self, fingerprint: u64) -> Result<()> {
+        if self.fingerprint == 0 || fingerprint == 0 || self.fingerprint == fingerprint {
+            return Ok(());
+        }
+        let [major, minor, patch, _] = self.crate_version;
+        Err(Error::Validation(format!(
+            "Shared memory object holds a {} with different fields, field offsets or field \
+             types than this build (fingerprint {:016x}, expected {fingerprint:016x}; created \
+             by nix-ipc {major}.{minor}.{patch})",
+            type_name::<T>(),
+            self.fingerprint
+        )))
+    }
+
+    /// Counts a new handle, resetting the count if no other reference-counted handle exists,
+    /// and counts it again in every child forked while it is attached. Must be called while
+    /// holding the init lock of `map`.
+    pub(crate) fn attach(&self, map: &Mapping) -> Result<()> {
+        if map.is_only_attached()? {
+            self.attached.store(1, Ordering::Release);
+        } else {
+            self.attached.fetch_add(1, Ordering::AcqRel);
+        }
+        map.inherit_in_forks(Some(&self.attached));
+        Ok(())
+    }
+
+    /// Stops counting a handle of `map`.
+    pub(crate) fn detach(&self, map: &Mapping) {
+        if let Ok(_init_lock) = map.init_lock() {
+            let count = self.attached.load(Ordering::Acquire);
+            self.attached
+                .store(count.saturating_sub(1), Ordering::Release);
+        }
+    }
+
+    /// Updates the data size after the segment was resized.
+    pub(crate) fn set_size(&mut self, size: usize) {
+        self.size = size as u64;
+    }
+
+    pub(crate) fn attached(&self) -> u32 {
+        self.attached.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation
+    }
+}
+
+/// Returns a generation no other segment is likely to have, from the time, the process and
+/// the number of segments it created before.
+fn new_generation() -> u64 {
+    static CREATED: AtomicU64 = AtomicU64::new(0);
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos() as u64);
+    let hash = fnv1a_u64(FNV_OFFSET, nanos);
+    let hash = fnv1a_u64(hash, process::id() as u64);
+    fnv1a_u64(hash, CREATED.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Hash of the type name, size and alignment of T.
+fn layout<T>() -> u64 {
+    let hash = fnv1a(FNV_OFFSET, type_name::<T>().as_bytes());
+    let hash = fnv1a_u64(hash, size_of::<T>() as u64);
+    fnv1a_u64(hash, align_of::<T>() as u64)
+}
+
+/// Returns the version of this crate as stored in segment headers.
+fn crate_version() -> [u16; 4] {
+    let mut version = [0; 4];
+    for (part, number) in version
+        .iter_mut()
+        .zip(env!("CARGO_PKG_VERSION").split(['.', '-']))
+    {
+        *part = number.parse().unwrap_or(0);
+    }
+    version
+}
@@ -0,0 +1,277 @@
+use std::{
+    mem::size_of,
+    num::NonZeroUsize,
+    process,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError, Sender},
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use nix::fcntl::OFlag;
+
+use crate::{
+    builder::Builder,
+    cache_padded::CachePadded,
+    cleanup::CleanupPolicy,
+    error::{Error, Result},
+    header::Header,
+    liveness,
+    map::Mapping,
+    namespace::Namespace,
+    time,
+};
+
+#[repr(C)]
+struct HeartbeatHeader {
+    header: Header,
+    slots: u64,
+}
+
+#[repr(C)]
+struct Slot {
+    /// The process beating in the slot, or 0 if it is free.
+    pid: AtomicU32,
+    /// Nanoseconds on the monotonic clock at the last beat.
+    beat: AtomicU64,
+}
+
+/// How a peer seen by `Heartbeat::peers` is doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerState {
+    /// It beat within the staleness limit.
+    Alive,
+    /// It still runs but hasn't beaten within the staleness limit, e.g. because it is
+    /// deadlocked or stopped.
+    Stale,
+    /// It exited without dropping its heartbeat.
+    Dead,
+}
+
+/// A process taking part in a heartbeat, as seen by `Heartbeat::peers`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Peer {
+    pub pid: u32,
+    /// Time since its last beat.
+    pub since_beat: Duration,
+    pub state: PeerState,
+}
+
+/// A liveness protocol between processes: each handle takes a slot in shared memory and
+/// stamps it with the time whenever it beats, and `peers` tells which processes beat
+/// recently, which stopped beating while still running, and which died.
+///
+/// Unlike robust mutexes and `PeerWatcher`, this also catches peers that are wedged rather
+/// than dead, e.g. for electing a new master or reclaiming their resources. Beats are taken
+/// by calling `beat` from the work loop, so a stuck loop shows, or by a helper thread
+/// started with `beat_every`, which only shows a stuck process. Slots of dead processes are
+/// reused by new handles, and a handle frees its slot when dropped. Children forked with a
+/// handle share its slot, and should open one of their own.
+pub struct Heartbeat {
+    map: Mapping,
+    slots: usize,
+    slot: usize,
+    beater: Option<(Sender<()>, JoinHandle<()>)>,
+}
+
+impl Heartbeat {
+    /// Opens the heartbeat in /dev/shm, creating it with room for `slots` processes if it
+    /// doesn't exist, and takes a slot for this handle. Fails if all slots are taken by
+    /// running processes, or if the heartbeat exists with a different number of slots.
+    pub fn new(name: &str, slots: usize) -> Result<Self> {
+        Self::builder(name).build(slots)
+    }
+
+    /// Returns a builder for setting the namespace and the permissions the heartbeat table is
+    /// created with.
+    pub fn builder(name: &str) -> Builder<Self> {
+        Builder::new(name)
+    }
+
+    /// Unlinks (deletes) the heartbeat from /dev/shm.
+    pub fn unlink(name: &str) -> Result<()> {
+        Self::unlink_in(&Namespace::default(), name)
+    }
+
+    /// Unlinks the heartbeat called `name` in `namespace`.
+    pub fn unlink_in(namespace: &Namespace, name: &str) -> Result<()> {
+        namespace.path(name, ".hbt").unlink()
+    }
+
+    /// Stamps the slot of this handle with the current time.
+    pub fn beat(&self) -> Result<()> {
+        beat(slot_at(&self.map, self.slot))
+    }
+
+    /// Starts a helper thread beating every `interval` until the handle is dropped,
+    /// replacing one started before.
+    pub fn beat_every(&mut self, interval: Duration) -> Result<()> {
+        self.stop_beating();
+        self.beat()?;
+
+        let (stop, stopped) = mpsc::channel();
+        let address = slot_at(&self.map, self.slot) as *const Slot as usize;
+        let thread = thread::Builder::new()
+            .name("nix-ipc-heartbeat".to_owned())
+            .spawn(move || {
+                // The handle joins the thread before unmapping the slot.
+                let slot = unsafe { &*(address as *const Slot) };
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    beat(slot).ok();
+                }
+            })
+            .map_err(Error::Io)?;
+        self.beater = Some((stop, thread));
+        Ok(())
+    }
+
+    /// Returns the processes taking part, including this one, with their state. Peers that
+    /// haven't beaten for `stale_after` are considered stale.
+    pub fn peers(&self, stale_after: Duration) -> Result<Vec<Peer>> {
+        let now = time::monotonic_nanos()?;
+        let mut peers = Vec::new();
+        for index in 0..self.slots {
+            let slot = slot_at(&self.map, index);
+            let pid = slot.pid.load(Ordering::Acquire);
+            if pid == 0 {
+                continue;
+            }
+            let since_beat =
+                Duration::from_nanos(now.saturating_sub(slot.beat.load(Ordering::Acquire)));
+            let state = if !liveness::is_alive(pid) {
+                PeerState::Dead
+            } else if since_beat > stale_after {
+                PeerState::Stale
+            } else {
+                PeerState::Alive
+            };
+            peers.push(Peer {
+                pid,
+                since_beat,
+                state,
+            });
+        }
+        Ok(peers)
+    }
+
+    /// The number of processes the heartbeat has room for.
+    pub fn slots(&self) -> usize {
+        self.slots
+    }
+
+    fn stop_beating(&mut self) {
+        if let Some((stop, thread)) = self.beater.take() {
+            drop(stop);
+            thread.join().ok();
+        }
+    }
+
+    /// Offset of the first slot, after the header and aligned to a cache line.
+    fn data_offset() -> usize {
+        size_of::<HeartbeatHeader>().next_multiple_of(align_of::<CachePadded<Slot>>())
+    }
+}
+
+impl Builder<Heartbeat> {
+    /// Opens the heartbeat table like `Heartbeat::new`.
+    pub fn build(self, slots: usize) -> Result<Heartbeat> {
+        if slots == 0 {
+            return Err(Error::InvalidArgument(
+                "Heartbeat must have at least one slot".to_owned(),
+            ));
+        }
+        let data_len = slots
+            .checked_mul(size_of::<CachePadded<Slot>>())
+            .ok_or_else(|| {
+                Error::InvalidArgument(format!("Heartbeat of {slots} slots is too large"))
+            })?;
+        let map_len = NonZeroUsize::new(Heartbeat::data_offset() + data_len)
+            .expect("HeartbeatHeader has nonzero size");
+
+        let path = self.namespace.path(&self.name, ".hbt");
+        let map = Mapping::open_init(
+            &path,
+            OFlag::O_CREAT,
+            &self.permissions,
+            map_len,
+            CleanupPolicy::LastCloseRefCounted,
+            |raw| unsafe {
+                let heartbeat = raw as *mut HeartbeatHeader;
+                (*heartbeat).header.init::<CachePadded<Slot>>(data_len, 0);
+                (*heartbeat).slots = slots as u64;
+                Ok(())
+            },
+        )?;
+
+        let heartbeat = map.ptr() as *const HeartbeatHeader;
+        let slot = {
+            let _init_lock = map.init_lock()?;
+            unsafe {
+                (*heartbeat)
+                    .header
+                    .validate::<CachePadded<Slot>>(data_len, 0)?;
+            }
+            // Claimed before attaching, so failing to claim one leaves nothing to detach.
+            let slot = claim(&map, slots)?;
+            unsafe { (*heartbeat).header.attach(&map)? };
+            slot
+        };
+
+        Ok(Heartbeat {
+            map,
+            slots,
+            slot,
+            beater: None,
+        })
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        self.stop_beating();
+        // A child forked with the handle shares the slot of its parent, and must not free it.
+        let pid = process::id();
+        slot_at(&self.map, self.slot)
+            .pid
+            .compare_exchange(pid, 0, Ordering::AcqRel, Ordering::Relaxed)
+            .ok();
+        let heartbeat = self.map.ptr() as *const HeartbeatHeader;
+        unsafe { (*heartbeat).header.detach(&self.map) };
+    }
+}
+
+/// Takes a free slot or one of a dead process and beats in it.
+fn claim(map: &Mapping, slots: usize) -> Result<usize> {
+    let pid = process::id();
+    for index in 0..slots {
+        let slot = slot_at(map, index);
+        let current = slot.pid.load(Ordering::Acquire);
+        if (current == 0 || !liveness::is_alive(current))
+            && slot
+                .pid
+                .compare_exchange(current, pid, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+        {
+            beat(slot)?;
+            return Ok(index);
+        }
+    }
+    Err(Error::InvalidArgument(format!(
+        "Heartbeat is full: all {slots} slots are taken"
+    )))
+}
+
+fn slot_at(map: &Mapping, index: usize) -> &Slot {
+    unsafe {
+        let slots =
+            (map.ptr() as *const u8).add(Heartbeat::data_offset()) as *const CachePadded<Slot>;
+        &(*slots.add(index)).0
+    }
+}
+
+fn beat(slot: &Slot) -> Result<()> {
+    slot.beat.store(time::monotonic_nanos()?, Ordering::Release);
+    Ok(())
+}
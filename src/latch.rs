@@ -0,0 +1,114 @@
+use std::{
+    mem::size_of,
+    num::NonZeroUsize,
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+use nix::fcntl::OFlag;
+
+use crate::{
+    builder::Builder, cleanup::CleanupPolicy, error::Result, futex::EventCount, map::Mapping,
+    namespace::Namespace,
+};
+
+#[repr(C)]
+struct LatchState {
+    count: AtomicU32,
+    event: EventCount,
+}
+
+/// A countdown latch shared between processes: `wait` blocks until `count_down` has been
+/// called as many times as the latch was created with.
+///
+/// A latch can't be reset. Unlink it and create a new one to count down again.
+pub struct Latch {
+    map: Mapping,
+}
+
+impl Latch {
+    /// Opens the latch in /dev/shm, creating it with `count` if it doesn't exist.
+    /// Opening an existing latch keeps its current count.
+    pub fn new(name: &str, count: u32) -> Result<Self> {
+        Self::builder(name).build(count)
+    }
+
+    /// Returns a builder for setting the namespace and the permissions the latch is created with.
+    pub fn builder(name: &str) -> Builder<Self> {
+        Builder::new(name)
+    }
+
+    /// Unlinks (deletes) the latch file from /dev/shm.
+    pub fn unlink(name: &str) -> Result<()> {
+        Self::unlink_in(&Namespace::default(), name)
+    }
+
+    /// Unlinks the latch called `name` in `namespace`.
+    pub fn unlink_in(namespace: &Namespace, name: &str) -> Result<()> {
+        namespace.path(name, ".ltc").unlink()
+    }
+
+    /// Decrements the count, waking all waiters when it reaches zero.
+    /// Counting down a latch that is already open does nothing.
+    pub fn count_down(&self) {
+        let state = self.state();
+        let previous = state
+            .count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                count.checked_sub(1)
+            });
+        if previous == Ok(1) {
+            state.event.notify_all();
+        }
+    }
+
+    /// Blocks until the count reaches zero.
+    pub fn wait(&self) -> Result<()> {
+        self.wait_for(None).map(drop)
+    }
+
+    /// Blocks until the count reaches zero, returning false if `timeout` elapsed first.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<bool> {
+        self.wait_for(Some(timeout))
+    }
+
+    /// The number of `count_down` calls still needed to open the latch.
+    pub fn count(&self) -> u32 {
+        self.state().count.load(Ordering::Acquire)
+    }
+
+    fn wait_for(&self, timeout: Option<Duration>) -> Result<bool> {
+        let state = self.state();
+        let opened = state
+            .event
+            .wait_for(timeout, || (self.count() == 0).then_some(()))?;
+        Ok(opened.is_some())
+    }
+
+    fn state(&self) -> &LatchState {
+        unsafe { &*(self.map.ptr() as *const LatchState) }
+    }
+}
+
+impl Builder<Latch> {
+    /// Opens the latch like `Latch::new`.
+    pub fn build(self, count: u32) -> Result<Latch> {
+        let path = self.namespace.path(&self.name, ".ltc");
+        let len = NonZeroUsize::new(size_of::<LatchState>()).expect("LatchState has nonzero size");
+
+        let map = Mapping::open_init(
+            &path,
+            OFlag::O_CREAT,
+            &self.permissions,
+            len,
+            CleanupPolicy::Never,
+            |ptr| {
+                unsafe { (*(ptr as *mut LatchState)).count = AtomicU32::new(count) };
+                Ok(())
+            },
+        )?;
+        map.inherit_in_forks(None);
+
+        Ok(Latch { map })
+    }
+}
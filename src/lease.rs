@@ -0,0 +1,259 @@
+use std::{cell::UnsafeCell, mem::size_of, num::NonZeroUsize, process, time::Duration};
+
+use nix::fcntl::OFlag;
+
+use crate::{
+    builder::Builder,
+    cleanup::CleanupPolicy,
+    error::{Error, Result},
+    futex::EventCount,
+    header::Header,
+    liveness::{self, LIVENESS_POLL},
+    map::Mapping,
+    namespace::Namespace,
+    r_mtx::{MutexKind, MutexProtocol, acquired},
+    raw_lock::{self, RawMutex, Unlock},
+    time,
+};
+
+#[repr(C)]
+struct LeaseFile {
+    header: Header,
+    /// Guards `state`, which is only changed briefly while holding it.
+    mtx: RawMutex,
+    state: UnsafeCell<State>,
+    released: EventCount,
+}
+
+#[repr(C)]
+struct State {
+    /// The process holding the lease, or 0 if it is free.
+    owner: u32,
+    /// The process the lease was last reclaimed from, or 0 once it has been recovered.
+    reclaimed_from: u32,
+    /// Incremented whenever the lease is acquired.
+    token: u64,
+    /// When the lease expires, in nanoseconds on the monotonic clock.
+    expires: u64,
+}
+
+/// An interprocess lock held for a limited time, for owners that may hang rather than die.
+///
+/// Acquiring it grants a lease lasting the TTL of the handle, which the owner keeps alive
+/// by calling `Lease::renew` before it runs out. A robust mutex only frees the lock of an
+/// owner that died; a lease also frees that of an owner that is stuck, as contenders may
+/// reclaim it once it expired, or right away if its owner is gone. The stuck owner finds
+/// out with `Error::LeaseLost` on its next renewal, and should stop touching the protected
+/// resource before that, e.g. by checking the fencing token from `Lease::token`.
+///
+/// A contender reclaiming a lease can repair what the previous owner left behind with
+/// `acquire_with_recovery`. Until a repair finishes, later owners are asked to repair again.
+pub struct LeaseLock {
+    map: Mapping,
+    ttl: Duration,
+}
+
+impl LeaseLock {
+    /// Opens the lock in /dev/shm, creating it if it doesn't exist yet. Leases acquired
+    /// through this handle last `ttl` from acquiring or renewing them.
+    pub fn new(name: &str, ttl: Duration) -> Result<Self> {
+        Self::builder(name).build(ttl)
+    }
+
+    /// Returns a builder for setting the namespace and the permissions the lock is created with.
+    pub fn builder(name: &str) -> Builder<Self> {
+        Builder::new(name)
+    }
+
+    /// Unlinks (deletes) the lock from /dev/shm.
+    pub fn unlink(name: &str) -> Result<()> {
+        Self::unlink_in(&Namespace::default(), name)
+    }
+
+    /// Unlinks the lock called `name` in `namespace`.
+    pub fn unlink_in(namespace: &Namespace, name: &str) -> Result<()> {
+        namespace.path(name, ".lse").unlink()
+    }
+
+    /// Blocks until the lease is acquired, reclaiming it once it expires or its owner dies.
+    /// Whether it was reclaimed is told by `Lease::reclaimed_from`.
+    pub fn acquire(&self) -> Result<Lease<'_>> {
+        self.acquire_with_recovery(|_| {})
+    }
+
+    /// Like `acquire`, but runs `recover` with the pid of the previous owner before handing
+    /// out the lease if it was reclaimed, or if an earlier recovery didn't finish. If this
+    /// process dies or `recover` panics, the next owner is asked to recover again.
+    pub fn acquire_with_recovery<F>(&self, recover: F) -> Result<Lease<'_>>
+    where
+        F: FnOnce(u32),
+    {
+        let lease = loop {
+            let claimed = self
+                .file()
+                .released
+                .wait_for(Some(LIVENESS_POLL), || self.try_claim().transpose())?;
+            if let Some(lease) = claimed {
+                break lease?;
+            }
+        };
+        if let Some(pid) = lease.reclaimed_from {
+            recover(pid);
+            self.recovered(lease.token)?;
+        }
+        Ok(lease)
+    }
+
+    /// Acquires the lease if it is free, expired or held by a process that died, without
+    /// blocking, and returns `None` otherwise. A reclaimed lease is handed out without
+    /// recovery, see `Lease::reclaimed_from`.
+    pub fn try_acquire(&self) -> Result<Option<Lease<'_>>> {
+        let lease = self.try_claim()?;
+        if let Some(lease) = &lease
+            && lease.reclaimed_from.is_some()
+        {
+            self.recovered(lease.token)?;
+        }
+        Ok(lease)
+    }
+
+    /// How long leases acquired through this handle last.
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Takes the lease if it is free or can be reclaimed.
+    fn try_claim(&self) -> Result<Option<Lease<'_>>> {
+        let now = time::monotonic_nanos()?;
+        self.locked(|state| {
+            if state.owner != 0 {
+                if now < state.expires && liveness::is_alive(state.owner) {
+                    return None;
+                }
+                state.reclaimed_from = state.owner;
+            }
+            state.owner = process::id();
+            state.token += 1;
+            state.expires = now.saturating_add(self.ttl.as_nanos() as u64);
+            Some(Lease {
+                lock: self,
+                token: state.token,
+                reclaimed_from: (state.reclaimed_from != 0).then_some(state.reclaimed_from),
+            })
+        })
+    }
+
+    /// Marks the lease acquired with `token` as recovered, unless it was lost meanwhile.
+    fn recovered(&self, token: u64) -> Result<()> {
+        self.locked(|state| {
+            if state.token == token {
+                state.reclaimed_from = 0;
+            }
+        })
+    }
+
+    /// Runs `f` on the state while holding its mutex. Dying while holding it leaves
+    /// nothing to repair, as each field is valid on its own and reclaiming checks them all.
+    fn locked<R>(&self, f: impl FnOnce(&mut State) -> R) -> Result<R> {
+        let mtx = unsafe { &raw mut (*(self.map.ptr() as *mut LeaseFile)).mtx };
+        let err = unsafe { raw_lock::lock(mtx)? };
+        acquired(mtx, err, "pthread_mutex_lock")?;
+        let _unlock = Unlock(mtx);
+        Ok(f(unsafe { &mut *self.file().state.get() }))
+    }
+
+    fn file(&self) -> &LeaseFile {
+        unsafe { &*(self.map.ptr() as *const LeaseFile) }
+    }
+}
+
+impl Builder<LeaseLock> {
+    /// Opens the lock like `LeaseLock::new`.
+    pub fn build(self, ttl: Duration) -> Result<LeaseLock> {
+        if ttl.is_zero() {
+            return Err(Error::InvalidArgument(
+                "Lease TTL must be nonzero".to_owned(),
+            ));
+        }
+        let len = NonZeroUsize::new(size_of::<LeaseFile>()).expect("LeaseFile has nonzero size");
+        let path = self.namespace.path(&self.name, ".lse");
+        let map = Mapping::open_init(
+            &path,
+            OFlag::O_CREAT,
+            &self.permissions,
+            len,
+            CleanupPolicy::Never,
+            |raw| unsafe {
+                let file = raw as *mut LeaseFile;
+                (*file).header.init::<LeaseFile>(0, 0);
+                raw_lock::init_mutex(&raw mut (*file).mtx, MutexKind::Normal, MutexProtocol::None)
+            },
+        )?;
+
+        let file = map.ptr() as *const LeaseFile;
+        {
+            let _init_lock = map.init_lock()?;
+            unsafe {
+                (*file).header.validate::<LeaseFile>(0, 0)?;
+                (*file).header.attach(&map)?;
+            }
+        }
+
+        Ok(LeaseLock { map, ttl })
+    }
+}
+
+impl Drop for LeaseLock {
+    fn drop(&mut self) {
+        self.file().header.detach(&self.map);
+    }
+}
+
+/// A lease on a `LeaseLock`, released when dropped.
+pub struct Lease<'a> {
+    lock: &'a LeaseLock,
+    token: u64,
+    reclaimed_from: Option<u32>,
+}
+
+impl Lease<'_> {
+    /// Extends the lease to last the TTL from now. Fails with `Error::LeaseLost` if it
+    /// expired and another process reclaimed it.
+    pub fn renew(&self) -> Result<()> {
+        let now = time::monotonic_nanos()?;
+        let ttl = self.lock.ttl;
+        self.lock.locked(|state| {
+            if state.token != self.token || state.owner == 0 {
+                return Err(Error::LeaseLost);
+            }
+            state.expires = now.saturating_add(ttl.as_nanos() as u64);
+            Ok(())
+        })?
+    }
+
+    /// Increases with every acquisition of the lock, so resources can reject writes
+    /// carrying the token of an owner that lost its lease since.
+    pub fn token(&self) -> u64 {
+        self.token
+    }
+
+    /// The process the lease was reclaimed from, if its lease expired or it died, or if an
+    /// earlier recovery didn't finish.
+    pub fn reclaimed_from(&self) -> Option<u32> {
+        self.reclaimed_from
+    }
+}
+
+impl Drop for Lease<'_> {
+    fn drop(&mut self) {
+        let released = self.lock.locked(|state| {
+            // A lost lease belongs to its new owner.
+            if state.token == self.token {
+                state.owner = 0;
+            }
+        });
+        if released.is_ok() {
+            self.lock.file().released.notify_all();
+        }
+    }
+}
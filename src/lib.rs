@@ -1,5 +1,170 @@
-pub use r_mtx::{LockResult, RMtx};
-pub use shm::Shm;
+#[cfg(all(target_os = "linux", feature = "tokio"))]
+pub use async_io::{AsyncMtx, AsyncMtxGuard, AsyncReceiver, AsyncSender};
+pub use builder::Builder;
+pub use cache_padded::{CACHE_LINE, CachePadded, PAGE_SIZE, PageAligned};
+pub use cleanup::CleanupPolicy;
+pub use condvar::Condvar;
+pub use config_cell::ConfigCell;
+pub use context::IpcContext;
+pub use credentials::{PeerCredentials, peer_credentials};
+#[cfg(target_os = "linux")]
+pub use credentials::{recv_credentials, send_credentials};
+pub use double_buffer::DoubleBuffer;
+pub use error::{Error, Result};
+#[cfg(target_os = "linux")]
+pub use event::Event;
+pub use fd_passing::{recv_fds, send_fds, set_cloexec};
+pub use fifo::{Fifo, FifoBuilder};
+#[cfg(target_os = "linux")]
+pub use futex_mutex::{FutexMutex, FutexMutexBuilder, FutexMutexGuard};
+pub use heartbeat::{Heartbeat, Peer, PeerState};
+pub use latch::Latch;
+pub use lease::{Lease, LeaseLock};
+pub use map::{HugePages, MsyncMode};
+pub use metrics::{
+    Counter, Gauge, Histogram, Metric, MetricValue, MetricsBlock, MetricsBlockBuilder,
+};
+#[cfg(target_os = "linux")]
+pub use mq_queue::MqQueue;
+pub use msg_queue::{MsgGuard, MsgQueue};
+pub use namespace::Namespace;
+pub use oneshot::{Oneshot, OneshotSender};
+#[cfg(target_os = "linux")]
+pub use peer_watcher::PeerWatcher;
+pub use permissions::Permissions;
+pub use platform::Capabilities;
+pub use r_mtx::{
+    LockResult, MutexKind, MutexProtocol, RMtx, RMtxBuilder, RMtxGuard, TimedLockResult,
+    TryLockResult,
+};
+pub use rate_limiter::RateLimiter;
+pub use raw_shm::RawShm;
+#[cfg(target_os = "linux")]
+pub use ready::ReadyFd;
+pub use registry::ProcessRegistry;
+pub use reset_event::{AutoResetEvent, ManualResetEvent};
+pub use rw_lk::{RwLk, RwLkReadGuard, RwLkWriteGuard};
+pub use sem::Sem;
+pub use seq_lock::SeqLock;
+pub use sharded_counter::ShardedCounter;
+pub use shm::{Shm, ShmBuilder, ShmPrivate, ShmReader};
+pub use shm_arena::{ShmArena, ShmBox};
+pub use shm_array::{ShmArray, ShmArrayGuard};
+pub use shm_cache::ShmCache;
+pub use shm_hash_map::ShmHashMap;
+pub use shm_log::{LogRecords, ShmLog, ShmLogBuilder};
+pub use shm_mutex::{Recover, ShmMutex, ShmMutexBuilder, ShmMutexGuard};
+pub use shm_once::ShmOnce;
+pub use shm_safe::{ShmAtomic, ShmSafe};
+pub use shm_stream::ShmStream;
+#[cfg(target_os = "linux")]
+pub use signals::{SignalInfo, Signals};
+pub use spawn::{IPC_FD_ENV, spawn_with_ipc};
+#[cfg(target_os = "linux")]
+pub use timer::{SharedSchedule, Timer};
+pub use topic::{MAX_TOPIC_LEN, Topic, TopicFilter, TopicPublisher, TopicSubscriber};
+pub use unix_channel::{UnixChannel, UnixChannelListener};
+#[cfg(all(target_os = "linux", feature = "uring"))]
+pub use uring::{UringChannel, UringChannelBuilder};
+pub use wait_group::WaitGroup;
+#[cfg(target_os = "linux")]
+pub use wait_set::WaitSet;
 
+#[cfg(feature = "derive")]
+pub use nix_ipc_derive::{ShmAtomic, ShmSafe};
+
+#[cfg(all(target_os = "linux", feature = "tokio"))]
+mod async_io;
+mod backend;
+pub mod broadcast;
+mod builder;
+mod cache_padded;
+mod checksum;
+mod cleanup;
+mod condvar;
+mod config_cell;
+mod context;
+mod credentials;
+mod double_buffer;
+mod error;
+#[cfg(target_os = "linux")]
+mod event;
+mod fd_passing;
+mod fifo;
+mod fork;
+mod framing;
+pub mod futex;
+#[cfg(target_os = "linux")]
+mod futex_bridge;
+#[cfg(target_os = "linux")]
+mod futex_mutex;
+mod gc;
+mod header;
+mod heartbeat;
+mod latch;
+mod lease;
+mod liveness;
+#[cfg(feature = "deadlock-detection")]
+mod lock_graph;
+mod map;
+mod metrics;
+#[cfg(feature = "mio")]
+mod mio_source;
+pub mod mpmc;
+#[cfg(target_os = "linux")]
+mod mq_queue;
+mod msg_queue;
+mod namespace;
+mod oneshot;
+#[cfg(target_os = "linux")]
+mod peer_watcher;
+mod permissions;
+#[cfg(any(not(robust_mutex), all(target_os = "linux", feature = "tokio")))]
+mod pid_mutex;
+mod platform;
 mod r_mtx;
+mod rate_limiter;
+mod raw_lock;
+mod raw_shm;
+mod ready;
+mod registry;
+mod reset_event;
+#[cfg(feature = "serde")]
+pub mod rpc;
+mod rw_lk;
+mod sem;
+mod seq_lock;
+mod sharded_counter;
 mod shm;
+mod shm_arena;
+mod shm_array;
+mod shm_cache;
+mod shm_hash_map;
+mod shm_log;
+mod shm_mutex;
+mod shm_once;
+mod shm_safe;
+mod shm_stream;
+#[cfg(target_os = "linux")]
+mod signals;
+mod spawn;
+pub mod spsc;
+mod time;
+#[cfg(target_os = "linux")]
+mod timer;
+mod topic;
+mod unix_channel;
+#[cfg(all(target_os = "linux", feature = "uring"))]
+mod uring;
+mod wait_group;
+#[cfg(target_os = "linux")]
+mod wait_set;
+mod watchdog;
+
+#[doc(hidden)]
+pub mod __private {
+    pub use crate::{
+        cache_padded::{cache_isolated, padding_free, pointee_size},
+        shm_safe::{FNV_OFFSET, fnv1a, fnv1a_u64},
+    };
+}
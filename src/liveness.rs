@@ -0,0 +1,15 @@
+use std::time::Duration;
+
+use nix::{
+    errno::Errno,
+    libc::{kill, pid_t},
+};
+
+/// How often a waiter checks whether the processes it waits for are still alive, as a dead
+/// process never wakes it.
+pub(crate) const LIVENESS_POLL: Duration = Duration::from_millis(10);
+
+/// Returns true if the process `pid` exists, even if it belongs to another user.
+pub(crate) fn is_alive(pid: u32) -> bool {
+    unsafe { kill(pid as pid_t, 0) == 0 || Errno::last() == Errno::EPERM }
+}
@@ -0,0 +1,371 @@
+use std::{
+    backtrace::Backtrace,
+    cell::UnsafeCell,
+    fmt::Write,
+    fs,
+    mem::size_of,
+    num::NonZeroUsize,
+    process, ptr,
+    sync::{
+        Mutex,
+        atomic::{AtomicU32, AtomicU64, Ordering},
+    },
+};
+
+use nix::fcntl::OFlag;
+
+use crate::{
+    backend::ObjectPath,
+    cleanup::CleanupPolicy,
+    error::{Error, Result},
+    header::Header,
+    liveness,
+    map::Mapping,
+    namespace::Namespace,
+    permissions::Permissions,
+    shm_safe::{FNV_OFFSET, fnv1a},
+};
+
+/// Number of locks held or waited for that can be recorded at once, across all processes.
+const ENTRIES: usize = 256;
+
+/// Longest lock name recorded, in bytes.
+const NAME_LEN: usize = 64;
+
+/// Longest backtrace recorded for a waiting thread, in bytes.
+const TRACE_LEN: usize = 2048;
+
+/// Marks an entry while its process fills it in.
+const CLAIMING: u32 = u32::MAX;
+
+const HOLDING: u32 = 1;
+const WAITING: u32 = 2;
+
+#[repr(C)]
+struct Entry {
+    /// The process the entry belongs to, or 0 if it is free.
+    pid: AtomicU32,
+    state: AtomicU32,
+    thread: AtomicU64,
+    lock: AtomicU64,
+    name_len: AtomicU32,
+    trace_len: AtomicU32,
+    name: UnsafeCell<[u8; NAME_LEN]>,
+    /// Where a waiting thread blocked.
+    trace: UnsafeCell<[u8; TRACE_LEN]>,
+}
+
+#[repr(C)]
+struct GraphFile {
+    header: Header,
+    entries: [Entry; ENTRIES],
+}
+
+/// The lock graph of a namespace, shared by all processes using it, recording which thread
+/// holds which `RMtx` and which one it waits for. Only built with the `deadlock-detection`
+/// feature, as every lock and unlock updates it. Cycles through mutexes of different
+/// namespaces aren't detected.
+struct Graph {
+    map: Mapping,
+}
+
+// Entries are claimed and released through their atomic pids, and the rest is only written
+// to entries claimed by the writer.
+unsafe impl Send for Graph {}
+unsafe impl Sync for Graph {}
+
+/// The graphs opened so far by the path of their object, or `None` for those that couldn't
+/// be opened, which disables the detection in their namespace. They stay open until the
+/// process exits.
+static GRAPHS: Mutex<Vec<(String, Option<&'static Graph>)>> = Mutex::new(Vec::new());
+
+/// Identifies a mutex in the lock graph of its namespace by the name of its object.
+pub(crate) struct LockId {
+    id: u64,
+    name: String,
+    graph: Option<&'static Graph>,
+}
+
+/// What `wait` recorded, removed when dropped.
+pub(crate) struct Waiting {
+    graph: &'static Graph,
+    index: usize,
+}
+
+/// A copy of an entry of a live process.
+#[derive(Clone)]
+struct Snapshot {
+    pid: u32,
+    thread: u64,
+    state: u32,
+    lock: u64,
+    name: String,
+    trace: String,
+}
+
+impl LockId {
+    pub(crate) fn new(namespace: &Namespace, name: String) -> Self {
+        Self {
+            id: fnv1a(FNV_OFFSET, name.as_bytes()),
+            name,
+            graph: graph(namespace),
+        }
+    }
+}
+
+/// Records whether the calling thread holds `lock`.
+pub(crate) fn set_held(lock: &LockId, held: bool) {
+    let Some(graph) = lock.graph else {
+        return;
+    };
+    let found = graph.find(HOLDING, lock.id);
+    match (held, found) {
+        (true, None) => {
+            graph.claim(HOLDING, lock, "");
+        }
+        (false, Some(index)) => graph.release(index),
+        _ => {}
+    }
+}
+
+/// Records that the calling thread is about to block on `lock`, which it found held. Fails
+/// with `Error::Deadlock` instead if that would close a cycle of threads waiting on each
+/// other.
+pub(crate) fn wait(lock: &LockId) -> Result<Option<Waiting>> {
+    let Some(graph) = lock.graph else {
+        return Ok(None);
+    };
+    let trace = Backtrace::force_capture().to_string();
+    let Some(index) = graph.claim(WAITING, lock, &trace) else {
+        return Ok(None);
+    };
+    let waiting = Waiting { graph, index };
+    match graph.cycle(lock.id) {
+        Some(cycle) => Err(Error::Deadlock(report(&cycle))),
+        None => Ok(Some(waiting)),
+    }
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        self.graph.release(self.index);
+    }
+}
+
+/// Returns the graph of `namespace`, opening it the first time.
+fn graph(namespace: &Namespace) -> Option<&'static Graph> {
+    let path = namespace.path("nix-ipc", ".locks");
+    let mut graphs = GRAPHS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((_, graph)) = graphs.iter().find(|(p, _)| *p == path.to_string()) {
+        return *graph;
+    }
+    let graph = Graph::open(&path)
+        .ok()
+        .map(|graph| &*Box::leak(Box::new(graph)));
+    graphs.push((path.to_string(), graph));
+    graph
+}
+
+impl Graph {
+    fn open(path: &ObjectPath) -> Result<Self> {
+        let data_len = size_of::<[Entry; ENTRIES]>();
+        let len = NonZeroUsize::new(size_of::<GraphFile>()).expect("GraphFile has nonzero size");
+        let map = Mapping::open_init(
+            path,
+            OFlag::O_CREAT,
+            &Permissions::default(),
+            len,
+            CleanupPolicy::Never,
+            |raw| unsafe {
+                (*(raw as *mut GraphFile)).header.init::<Entry>(data_len, 0);
+                Ok(())
+            },
+        )?;
+        let file = map.ptr() as *const GraphFile;
+        {
+            let _init_lock = map.init_lock()?;
+            unsafe {
+                (*file).header.validate::<Entry>(data_len, 0)?;
+                (*file).header.attach(&map)?;
+            }
+        }
+        Ok(Self { map })
+    }
+
+    fn entry(&self, index: usize) -> &Entry {
+        unsafe { &(*(self.map.ptr() as *const GraphFile)).entries[index] }
+    }
+
+    /// Finds the entry of the calling thread in `state` for `lock`.
+    fn find(&self, state: u32, lock: u64) -> Option<usize> {
+        let (pid, thread) = (process::id(), thread_id());
+        (0..ENTRIES).find(|&index| {
+            let entry = self.entry(index);
+            entry.pid.load(Ordering::Acquire) == pid
+                && entry.thread.load(Ordering::Relaxed) == thread
+                && entry.state.load(Ordering::Relaxed) == state
+                && entry.lock.load(Ordering::Relaxed) == lock
+        })
+    }
+
+    /// Fills in a free entry, or one of a dead process, for the calling thread. Returns
+    /// `None` if all are taken, leaving the lock untracked.
+    fn claim(&self, state: u32, lock: &LockId, trace: &str) -> Option<usize> {
+        (0..ENTRIES).find(|&index| {
+            let entry = self.entry(index);
+            let current = entry.pid.load(Ordering::Acquire);
+            if current == CLAIMING || (current != 0 && liveness::is_alive(current)) {
+                return false;
+            }
+            if entry
+                .pid
+                .compare_exchange(current, CLAIMING, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+            {
+                return false;
+            }
+            entry.state.store(state, Ordering::Relaxed);
+            entry.thread.store(thread_id(), Ordering::Relaxed);
+            entry.lock.store(lock.id, Ordering::Relaxed);
+            let name = truncate(&lock.name, NAME_LEN);
+            let trace = truncate(trace, TRACE_LEN);
+            entry.name_len.store(name.len() as u32, Ordering::Relaxed);
+            entry.trace_len.store(trace.len() as u32, Ordering::Relaxed);
+            unsafe {
+                ptr::copy_nonoverlapping(name.as_ptr(), entry.name.get() as *mut u8, name.len());
+                ptr::copy_nonoverlapping(trace.as_ptr(), entry.trace.get() as *mut u8, trace.len());
+            }
+            // SeqCst, so of two threads closing a cycle at once, at least one sees the other.
+            entry.pid.store(process::id(), Ordering::SeqCst);
+            true
+        })
+    }
+
+    fn release(&self, index: usize) {
+        let pid = process::id();
+        self.entry(index)
+            .pid
+            .compare_exchange(pid, 0, Ordering::Release, Ordering::Relaxed)
+            .ok();
+    }
+
+    /// Copies the entries of live processes. Entries changing meanwhile are skipped.
+    fn snapshot(&self) -> Vec<Snapshot> {
+        (0..ENTRIES)
+            .filter_map(|index| {
+                let entry = self.entry(index);
+                let pid = entry.pid.load(Ordering::SeqCst);
+                if pid == 0 || pid == CLAIMING || !liveness::is_alive(pid) {
+                    return None;
+                }
+                let name_len = (entry.name_len.load(Ordering::Relaxed) as usize).min(NAME_LEN);
+                let trace_len = (entry.trace_len.load(Ordering::Relaxed) as usize).min(TRACE_LEN);
+                let mut name = [0; NAME_LEN];
+                let mut trace = vec![0; TRACE_LEN];
+                unsafe {
+                    ptr::copy_nonoverlapping(
+                        entry.name.get() as *const u8,
+                        name.as_mut_ptr(),
+                        NAME_LEN,
+                    );
+                    ptr::copy_nonoverlapping(
+                        entry.trace.get() as *const u8,
+                        trace.as_mut_ptr(),
+                        TRACE_LEN,
+                    );
+                }
+                let snapshot = Snapshot {
+                    pid,
+                    thread: entry.thread.load(Ordering::Relaxed),
+                    state: entry.state.load(Ordering::Relaxed),
+                    lock: entry.lock.load(Ordering::Relaxed),
+                    name: String::from_utf8_lossy(&name[..name_len]).into_owned(),
+                    trace: String::from_utf8_lossy(&trace[..trace_len]).into_owned(),
+                };
+                (entry.pid.load(Ordering::SeqCst) == pid).then_some(snapshot)
+            })
+            .collect()
+    }
+
+    /// Follows the locks from `lock`, which the calling thread waits for, to their holders
+    /// and the locks those wait for. Returns the waiting entries along the way if that leads
+    /// back to the calling thread.
+    fn cycle(&self, lock: u64) -> Option<Vec<(Snapshot, Snapshot)>> {
+        let entries = self.snapshot();
+        let me = (process::id(), thread_id());
+        let mut waiter = entries
+            .iter()
+            .position(|e| e.state == WAITING && (e.pid, e.thread) == me && e.lock == lock)?;
+        let mut steps = Vec::new();
+        for _ in 0..ENTRIES {
+            let waited = entries[waiter].lock;
+            let holder = entries
+                .iter()
+                .position(|e| e.state == HOLDING && e.lock == waited)?;
+            let node = (entries[holder].pid, entries[holder].thread);
+            steps.push((waiter, holder));
+            if node == me {
+                let cycle = steps
+                    .into_iter()
+                    .map(|(waiter, holder)| (entries[waiter].clone(), entries[holder].clone()))
+                    .collect();
+                return Some(cycle);
+            }
+            waiter = entries
+                .iter()
+                .position(|e| e.state == WAITING && (e.pid, e.thread) == node)?;
+        }
+        None
+    }
+}
+
+/// Describes the threads of a cycle, given as pairs of a waiting thread and the holder of
+/// the lock it waits for.
+fn report(cycle: &[(Snapshot, Snapshot)]) -> String {
+    let mut report = "Deadlock detected:".to_owned();
+    for (waiter, holder) in cycle {
+        write!(
+            report,
+            "\n  {} waits for {}, held by {}",
+            describe(waiter),
+            waiter.name,
+            describe(holder),
+        )
+        .ok();
+    }
+    for (waiter, _) in cycle {
+        write!(
+            report,
+            "\n\n{} blocked at:\n{}",
+            describe(waiter),
+            waiter.trace.trim_end()
+        )
+        .ok();
+    }
+    report
+}
+
+fn describe(entry: &Snapshot) -> String {
+    let name = fs::read_to_string(format!("/proc/{}/comm", entry.pid));
+    let name = name.as_deref().map_or("?", str::trim);
+    format!("process {} ({name}) thread {}", entry.pid, entry.thread)
+}
+
+/// Cuts `s` to at most `len` bytes at a character boundary.
+fn truncate(s: &str, len: usize) -> &str {
+    let mut end = s.len().min(len);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+#[cfg(target_os = "linux")]
+fn thread_id() -> u64 {
+    unsafe { nix::libc::syscall(nix::libc::SYS_gettid) as u64 }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn thread_id() -> u64 {
+    unsafe { nix::libc::pthread_self() as u64 }
+}
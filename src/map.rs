@@ -0,0 +1,871 @@
+use std::{
+    ffi::c_void,
+    num::NonZeroUsize,
+    os::{
+        fd::{AsFd, BorrowedFd, FromRawFd, OwnedFd},
+        unix::io::AsRawFd,
+    },
+    ptr::NonNull,
+    sync::atomic::AtomicU32,
+};
+
+use nix::{
+    errno::Errno,
+    fcntl::{Flock, FlockArg, OFlag},
+    libc::{dup, off_t},
+    sys::{
+        mman::{
+            MapFlags, MmapAdvise, MsFlags, ProtFlags, madvise, mlock, mmap, mmap_anonymous,
+            mprotect, msync, munmap,
+        },
+        stat::{Mode, fstat},
+    },
+    unistd::ftruncate,
+};
+#[cfg(target_os = "linux")]
+use nix::{
+    fcntl::{FcntlArg, SealFlag, fcntl},
+    libc::{MPOL_PREFERRED, SYS_mbind, c_ulong, fstatfs, statfs, syscall},
+    sys::{
+        memfd::{MFdFlags, memfd_create},
+        mman::{MRemapFlags, mremap},
+    },
+};
+
+use crate::{
+    backend::ObjectPath,
+    cleanup::{self, CleanupPolicy, LockFiles},
+    error::{Error, Result},
+    fd_passing::set_cloexec,
+    fork,
+    namespace::Namespace,
+    permissions::Permissions,
+    registry::Registration,
+};
+
+/// A shared mapping of a named file, unmapped when dropped.
+pub(crate) struct Mapping {
+    fd: OwnedFd,
+    ptr: NonNull<c_void>,
+    len: NonZeroUsize,
+    /// Describes the file in error messages.
+    path: String,
+    /// The name of the file, unless it is anonymous.
+    object: Option<ObjectPath>,
+    cleanup: CleanupPolicy,
+    locks: LockFiles,
+    /// Whether the mapping lies between two inaccessible guard pages.
+    guarded: bool,
+    /// The entry of the handle in the registry of its namespace.
+    registration: Option<Registration>,
+}
+
+/// Whether a mapping is backed by huge pages, which cuts TLB misses on segments of hundreds
+/// of megabytes or more. Huge pages are only available on Linux, and ignored elsewhere.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum HugePages {
+    /// Regular pages.
+    #[default]
+    Never,
+    /// Asks the kernel to back the mapping with transparent huge pages. Objects in /dev/shm
+    /// only get them if `/sys/kernel/mm/transparent_hugepage/shmem_enabled` is `advise` or
+    /// `always`, and regular pages otherwise.
+    Transparent,
+    /// Maps the object with `MAP_HUGETLB`, using huge pages reserved by the administrator.
+    /// This requires the object to live in a namespace on a hugetlbfs mount, such as
+    /// `Namespace::new("/dev/hugepages")`. Where it can't, e.g. in /dev/shm, it falls back
+    /// to `Transparent`.
+    Explicit,
+}
+
+/// How `msync` writes the changes to a file-backed mapping out to its file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsyncMode {
+    /// Waits until the changes reached the storage device.
+    Sync,
+    /// Schedules the changes to be written and returns right away.
+    Async,
+}
+
+/// How `Mapping::open_init_with` maps an object, beyond its size and protection.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct MapOptions {
+    pub(crate) huge_pages: HugePages,
+    /// Faults all pages in while mapping, instead of on first touch.
+    pub(crate) prefault: bool,
+    /// Locks all pages in memory, so they are never swapped out.
+    pub(crate) lock: bool,
+    /// Leaves the mapping out of core dumps.
+    pub(crate) dont_dump: bool,
+    /// Starts reading the pages in ahead of their first use.
+    pub(crate) will_need: bool,
+    /// Leaves the mapping out of children created with `fork`.
+    pub(crate) dont_fork: bool,
+    /// NUMA node to allocate the pages from.
+    pub(crate) numa_node: Option<u32>,
+    /// Surrounds the mapping with inaccessible guard pages.
+    pub(crate) guard_pages: bool,
+    /// Clears close-on-exec on the descriptor, so programs started with `exec` inherit it.
+    pub(crate) inheritable: bool,
+}
+
+impl Mapping {
+    /// Opens the object at `path` with the given extra `flags` and maps it shared.
+    /// A freshly created file is sized to `len` and `init` runs on its mapping, while holding
+    /// an exclusive flock so concurrent openers wait for it to finish. An existing file is
+    /// never resized and must already have a size of `len`.
+    pub(crate) fn open_init<F>(
+        object: &ObjectPath,
+        flags: OFlag,
+        permissions: &Permissions,
+        len: NonZeroUsize,
+        cleanup: CleanupPolicy,
+        init: F,
+    ) -> Result<Self>
+    where
+        F: FnOnce(*mut c_void) -> Result<()>,
+    {
+        Self::open_init_with(
+            object,
+            flags,
+            permissions,
+            len,
+            cleanup,
+            MapOptions::default(),
+            init,
+        )
+    }
+
+    /// Like `open_init`, mapping the object with `options`. Files on hugetlbfs are sized up
+    /// to whole huge pages, whatever the options are.
+    pub(crate) fn open_init_with<F>(
+        object: &ObjectPath,
+        flags: OFlag,
+        permissions: &Permissions,
+        len: NonZeroUsize,
+        cleanup: CleanupPolicy,
+        options: MapOptions,
+        init: F,
+    ) -> Result<Self>
+    where
+        F: FnOnce(*mut c_void) -> Result<()>,
+    {
+        let (fd, locks) = open_attached(object, flags | OFlag::O_RDWR, permissions, true)?;
+        if options.inheritable {
+            set_cloexec(&fd, false)?;
+        }
+        let path = &object.to_string();
+        let len = backing_len(&fd, len)?;
+
+        let init_lock = exclusive_flock(locks.init(&fd))?;
+
+        let size = file_size(&fd)?;
+        let created = size == 0;
+        if created {
+            // Left empty on failure, so the next opener creates it again.
+            permissions.apply(&fd)?;
+            ftruncate(&fd, len.get() as off_t).map_err(map_error(path))?;
+        } else if !size_matches(object, size, len) {
+            return Err(size_mismatch(path, len, size));
+        }
+
+        let prot = ProtFlags::PROT_READ | ProtFlags::PROT_WRITE;
+        let ptr = map_guarded(path, &fd, len, prot, options).inspect_err(|_| {
+            // Leave the file empty so the next opener runs the initializer again.
+            if created {
+                ftruncate(&fd, 0).ok();
+            }
+        })?;
+
+        let ready = advise(ptr, len, options).and_then(|()| match options.lock {
+            true => lock_pages(ptr, len),
+            false => Ok(()),
+        });
+        if let Err(e) = ready.and_then(|()| match created {
+            true => init(ptr.as_ptr()),
+            false => Ok(()),
+        }) {
+            // Leave the file empty so the next opener runs the initializer again.
+            if created {
+                ftruncate(&fd, 0).ok();
+            }
+            unsafe { unmap(ptr, len, options.guard_pages) };
+            return Err(e);
+        }
+
+        init_lock
+            .unlock()
+            .map_err(|(_, e)| Error::lock("flock(LOCK_UN)")(e))?;
+
+        Ok(Self {
+            fd,
+            ptr,
+            len,
+            path: path.to_owned(),
+            object: Some(object.clone()),
+            cleanup,
+            locks,
+            guarded: options.guard_pages,
+            registration: None,
+        })
+    }
+
+    /// Opens the existing object at `path` read-only and maps it shared with `PROT_READ`.
+    /// The handle doesn't keep a reference-counted object alive; its mapping stays valid
+    /// after the object is unlinked.
+    pub(crate) fn open_readonly(object: &ObjectPath, len: NonZeroUsize) -> Result<Self> {
+        Self::open_unattached(object, len, ProtFlags::PROT_READ, MapFlags::MAP_SHARED)
+    }
+
+    /// Opens the existing object at `path` read-only and maps a private copy of it, which
+    /// can be written without other processes seeing the changes. Every page is copied
+    /// right away, so changes other processes make later aren't seen either. Like
+    /// `open_readonly`, the handle doesn't keep the object alive.
+    pub(crate) fn open_private(object: &ObjectPath, len: NonZeroUsize) -> Result<Self> {
+        let prot = ProtFlags::PROT_READ | ProtFlags::PROT_WRITE;
+        let map = Self::open_unattached(object, len, prot, MapFlags::MAP_PRIVATE)?;
+        let page = page_size();
+        for offset in (0..map.len.get()).step_by(page) {
+            // Writing to a page gives this process its own copy of it.
+            let byte = unsafe { map.ptr.cast::<u8>().add(offset) };
+            unsafe { byte.write_volatile(byte.read_volatile()) };
+        }
+        Ok(map)
+    }
+
+    fn open_unattached(
+        object: &ObjectPath,
+        len: NonZeroUsize,
+        prot: ProtFlags,
+        flags: MapFlags,
+    ) -> Result<Self> {
+        let (fd, locks) = open_attached(object, OFlag::O_RDONLY, &Permissions::default(), false)?;
+        let path = &object.to_string();
+        let len = backing_len(&fd, len)?;
+
+        let size = file_size(&fd)?;
+        if !size_matches(object, size, len) {
+            return Err(size_mismatch(path, len, size));
+        }
+
+        let ptr = unsafe { mmap(None, len, prot, flags, &fd, 0) }.map_err(map_error(path))?;
+        Ok(Self {
+            fd,
+            ptr,
+            len,
+            path: path.to_owned(),
+            object: Some(object.clone()),
+            cleanup: CleanupPolicy::Never,
+            locks,
+            guarded: false,
+            registration: None,
+        })
+    }
+
+    /// Creates an anonymous memfd of `len` bytes, maps it shared and runs `init` on it.
+    /// With `seal`, the size of the file is sealed so no process can grow or shrink it.
+    pub(crate) fn anonymous<F>(len: NonZeroUsize, seal: bool, init: F) -> Result<Self>
+    where
+        F: FnOnce(*mut c_void) -> Result<()>,
+    {
+        let (fd, path) = anonymous_file(seal)?;
+        ftruncate(&fd, len.get() as off_t).map_err(map_error(&path))?;
+        #[cfg(target_os = "linux")]
+        if seal {
+            fcntl(
+                &fd,
+                FcntlArg::F_ADD_SEALS(SealFlag::F_SEAL_GROW | SealFlag::F_SEAL_SHRINK),
+            )
+            .map_err(map_error(&path))?;
+        }
+
+        let ptr = map_shared(
+            &path,
+            &fd,
+            len,
+            ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+        )?;
+        let map = Self {
+            path,
+            object: None,
+            fd,
+            ptr,
+            len,
+            cleanup: CleanupPolicy::Never,
+            locks: LockFiles::default(),
+            guarded: false,
+            registration: None,
+        };
+        init(map.ptr())?;
+        Ok(map)
+    }
+
+    /// Maps an already open file shared, e.g. one received from another process.
+    /// The file must already have a size of `len`, and it is never unlinked.
+    pub(crate) fn from_fd(fd: OwnedFd, len: NonZeroUsize) -> Result<Self> {
+        let path = format!("fd {}", fd.as_raw_fd());
+
+        let size = file_size(&fd)?;
+        if size != len.get() as off_t {
+            return Err(size_mismatch(&path, len, size));
+        }
+
+        let ptr = map_shared(
+            &path,
+            &fd,
+            len,
+            ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+        )?;
+        Ok(Self {
+            fd,
+            ptr,
+            len,
+            path,
+            object: None,
+            cleanup: CleanupPolicy::Never,
+            locks: LockFiles::default(),
+            guarded: false,
+            registration: None,
+        })
+    }
+
+    /// Opens the existing object at `path` and maps it shared with whatever size it currently has.
+    pub(crate) fn open_existing(object: &ObjectPath, cleanup: CleanupPolicy) -> Result<Self> {
+        let (fd, locks) = open_attached(object, OFlag::O_RDWR, &Permissions::default(), true)?;
+        let path = &object.to_string();
+
+        let size = {
+            let _init_lock = exclusive_flock(locks.init(&fd))?;
+            file_size(&fd)?
+        };
+        let len = NonZeroUsize::new(size as usize)
+            .ok_or_else(|| Error::Validation(format!("{path} has not been initialized")))?;
+
+        let ptr = map_shared(path, &fd, len, ProtFlags::PROT_READ | ProtFlags::PROT_WRITE)?;
+        Ok(Self {
+            fd,
+            ptr,
+            len,
+            path: path.to_owned(),
+            object: Some(object.clone()),
+            cleanup,
+            locks,
+            guarded: false,
+            registration: None,
+        })
+    }
+
+    /// Grows the file to at least `len` bytes if needed and remaps it to `len` bytes,
+    /// possibly at a different address.
+    pub(crate) fn resize(&mut self, len: NonZeroUsize) -> Result<()> {
+        if file_size(&self.fd)? < len.get() as off_t {
+            ftruncate(&self.fd, len.get() as off_t).map_err(map_error(&self.path))?;
+        }
+
+        #[cfg(target_os = "linux")]
+        let remapped = match self.guarded {
+            // Moving the mapping would leave its guard pages behind.
+            true => Err(Errno::ENOSYS),
+            false => unsafe {
+                mremap(
+                    self.ptr,
+                    self.len.get(),
+                    len.get(),
+                    MRemapFlags::MREMAP_MAYMOVE,
+                    None,
+                )
+            },
+        };
+        // Elsewhere there is no mremap, so the file is always mapped again.
+        #[cfg(not(target_os = "linux"))]
+        let remapped = Err::<NonNull<c_void>, _>(Errno::ENOSYS);
+        self.ptr = match remapped {
+            Ok(ptr) => ptr,
+            Err(_) => {
+                let prot = ProtFlags::PROT_READ | ProtFlags::PROT_WRITE;
+                let options = MapOptions {
+                    guard_pages: self.guarded,
+                    ..MapOptions::default()
+                };
+                let ptr = map_guarded(&self.path, &self.fd, len, prot, options)?;
+                unsafe { unmap(self.ptr, self.len, self.guarded) };
+                ptr
+            }
+        };
+        self.len = len;
+        Ok(())
+    }
+
+    /// Maps the first `len` bytes of the object now at `object` read-only, without attaching
+    /// to it, e.g. to peek at its header. Returns `None` if there is no such object or it is
+    /// smaller than `len`, e.g. because it is still being created.
+    pub(crate) fn peek(object: &ObjectPath, len: NonZeroUsize) -> Result<Option<Self>> {
+        let path = object.to_string();
+        let fd = match object.open(OFlag::O_RDONLY, Mode::empty()) {
+            Ok(fd) => fd,
+            Err(Errno::ENOENT) => return Ok(None),
+            Err(source) => return Err(Error::Open { path, source }),
+        };
+        if file_size(&fd)? < len.get() as off_t {
+            return Ok(None);
+        }
+        let ptr = map_shared(&path, &fd, len, ProtFlags::PROT_READ)?;
+        Ok(Some(Self {
+            fd,
+            ptr,
+            len,
+            path,
+            object: None,
+            cleanup: CleanupPolicy::Never,
+            locks: LockFiles::default(),
+            guarded: false,
+            registration: None,
+        }))
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len.get()
+    }
+
+    /// The name of the mapped file, unless it is anonymous.
+    pub(crate) fn object(&self) -> Option<&ObjectPath> {
+        self.object.as_ref()
+    }
+
+    pub(crate) fn cleanup(&self) -> CleanupPolicy {
+        self.cleanup
+    }
+
+    pub(crate) fn ptr(&self) -> *mut c_void {
+        self.ptr.as_ptr()
+    }
+
+    /// Changes the protection of the whole mapping, for this process only.
+    pub(crate) fn protect(&self, prot: ProtFlags) -> Result<()> {
+        unsafe { mprotect(self.ptr, self.len.get(), prot) }.map_err(Error::sys("mprotect"))?;
+        fork::set_read_only(&self.fd, self.ptr, !prot.contains(ProtFlags::PROT_WRITE));
+        Ok(())
+    }
+
+    /// Writes changes to the mapping out to the backing file, which only matters for files
+    /// on persistent storage.
+    pub(crate) fn msync(&self, mode: MsyncMode) -> Result<()> {
+        let flags = match mode {
+            MsyncMode::Sync => MsFlags::MS_SYNC,
+            MsyncMode::Async => MsFlags::MS_ASYNC,
+        };
+        unsafe { msync(self.ptr, self.len.get(), flags) }.map_err(Error::sys("msync"))
+    }
+
+    pub(crate) fn fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+
+    /// Takes the exclusive flock also used to serialize initialization.
+    pub(crate) fn init_lock(&self) -> Result<Flock<OwnedFd>> {
+        exclusive_flock(self.locks.init(&self.fd))
+    }
+
+    /// Records the handle in the registry of `namespace` under `name` and `suffix`, if it
+    /// keeps one, until the mapping is dropped.
+    pub(crate) fn record_in(
+        &mut self,
+        namespace: &Namespace,
+        name: &str,
+        suffix: &str,
+    ) -> Result<()> {
+        self.registration = namespace.register(name, suffix)?;
+        Ok(())
+    }
+
+    /// Makes forked children lock the object through descriptions of their own and count
+    /// their handle in `attached`, if given, see `fork`. `attached` must lie within the
+    /// mapping.
+    pub(crate) fn inherit_in_forks(&self, attached: Option<&AtomicU32>) {
+        // Only named objects are attached to.
+        let reopen = self.locks.reopen(&self.fd, self.object.is_some());
+        fork::register(&self.fd, reopen, self.locks.init(&self.fd), attached);
+    }
+
+    /// Returns true if no other handle using reference counting is attached.
+    pub(crate) fn is_only_attached(&self) -> Result<bool> {
+        let refs = self.locks.refs(&self.fd);
+        if self.cleanup != CleanupPolicy::LastCloseRefCounted || !cleanup::is_last(refs)? {
+            return Ok(false);
+        }
+        // Go back to a shared lock so other processes can attach again.
+        cleanup::attach(refs)?;
+        Ok(true)
+    }
+}
+
+/// Takes an exclusive flock on a duplicate of `fd`, released when the returned lock is dropped.
+fn exclusive_flock(fd: &OwnedFd) -> Result<Flock<OwnedFd>> {
+    let dup_raw_fd = Errno::result(unsafe { dup(fd.as_raw_fd()) }).map_err(Error::sys("dup"))?;
+    let dup_fd = unsafe { OwnedFd::from_raw_fd(dup_raw_fd) };
+
+    Flock::lock(dup_fd, FlockArg::LockExclusive).map_err(|(_, e)| Error::lock("flock(LOCK_EX)")(e))
+}
+
+/// Opens the object at `path`, registering the handle as attached if `attach` is set. If the
+/// last handle of another process, or `Namespace::gc`, unlinked the file meanwhile, it is
+/// opened again.
+fn open_attached(
+    path: &ObjectPath,
+    flags: OFlag,
+    permissions: &Permissions,
+    attach: bool,
+) -> Result<(OwnedFd, LockFiles)> {
+    let locks = LockFiles::open(path, attach, permissions)?;
+    loop {
+        let fd = path.open(flags, permissions.mode()).map_err(|source| {
+            let path = path.to_string();
+            match flags.contains(OFlag::O_CREAT) {
+                true => Error::Create { path, source },
+                false => Error::Open { path, source },
+            }
+        })?;
+
+        // Without lock files on the object itself, the handle was attached by opening them
+        // and waited there for a last closer to finish.
+        if !attach || cfg!(not(target_os = "linux")) {
+            return Ok((fd, locks));
+        }
+
+        cleanup::attach(&fd)?;
+        if fstat(&fd).map_err(Error::sys("fstat"))?.st_nlink > 0 {
+            return Ok((fd, locks));
+        }
+    }
+}
+
+fn map_shared(
+    path: &str,
+    fd: &OwnedFd,
+    len: NonZeroUsize,
+    prot: ProtFlags,
+) -> Result<NonNull<c_void>> {
+    let ptr = unsafe { mmap(None, len, prot, MapFlags::MAP_SHARED, fd, 0) };
+    ptr.map_err(map_error(path))
+}
+
+/// Maps `fd` shared like `map_shared`, backed by huge pages as far as available and with
+/// its pages faulted in if asked to.
+#[cfg(target_os = "linux")]
+fn map_with(
+    path: &str,
+    fd: &OwnedFd,
+    len: NonZeroUsize,
+    prot: ProtFlags,
+    options: MapOptions,
+    at: Option<NonNull<c_void>>,
+) -> Result<NonNull<c_void>> {
+    let (addr, mut flags) = placement(at);
+    // With a NUMA node, pages are only faulted in once the policy is in place.
+    if options.prefault && options.numa_node.is_none() {
+        flags |= MapFlags::MAP_POPULATE;
+    }
+
+    // Huge pages need an aligned address, which the guard pages don't leave.
+    if options.huge_pages == HugePages::Explicit && at.is_none() {
+        match unsafe { mmap(None, len, prot, flags | MapFlags::MAP_HUGETLB, fd, 0) } {
+            Ok(ptr) => return place(ptr, len, options).map(|()| ptr),
+            // Not on hugetlbfs, or out of reserved huge pages.
+            Err(Errno::EINVAL | Errno::ENOMEM) => {}
+            Err(e) => return Err(map_error(path)(e)),
+        }
+    }
+
+    let ptr = unsafe { mmap(addr, len, prot, flags, fd, 0) }.map_err(map_error(path))?;
+    if options.huge_pages != HugePages::Never {
+        // Fails where transparent huge pages are compiled out, leaving regular pages.
+        unsafe { madvise(ptr, len.get(), MmapAdvise::MADV_HUGEPAGE).ok() };
+    }
+    place(ptr, len, options)?;
+    Ok(ptr)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn map_with(
+    path: &str,
+    fd: &OwnedFd,
+    len: NonZeroUsize,
+    prot: ProtFlags,
+    options: MapOptions,
+    at: Option<NonNull<c_void>>,
+) -> Result<NonNull<c_void>> {
+    let (addr, flags) = placement(at);
+    let ptr = unsafe { mmap(addr, len, prot, flags, fd, 0) }.map_err(map_error(path))?;
+    if options.prefault {
+        touch_pages(ptr, len);
+    }
+    Ok(ptr)
+}
+
+/// Maps `fd` like `map_with`, between two inaccessible guard pages if `options` asks for
+/// them. The guard pages only cover whole pages, so accesses past the end of a mapping that
+/// doesn't end on a page boundary fault only once they reach the next page.
+fn map_guarded(
+    path: &str,
+    fd: &OwnedFd,
+    len: NonZeroUsize,
+    prot: ProtFlags,
+    options: MapOptions,
+) -> Result<NonNull<c_void>> {
+    if !options.guard_pages {
+        return map_with(path, fd, len, prot, options, None);
+    }
+    let page = page_size();
+    let reserved = guarded_len(len);
+    let flags = MapFlags::MAP_PRIVATE;
+    let base = unsafe { mmap_anonymous(None, reserved, ProtFlags::PROT_NONE, flags) }
+        .map_err(Error::sys("mmap"))?;
+    let at = unsafe { base.byte_add(page) };
+    map_with(path, fd, len, prot, options, Some(at)).inspect_err(|_| {
+        unsafe { munmap(base, reserved.get()).ok() };
+    })
+}
+
+/// Unmaps a mapping made by `map_guarded`, together with its guard pages.
+unsafe fn unmap(ptr: NonNull<c_void>, len: NonZeroUsize, guarded: bool) {
+    let result = match guarded {
+        true => unsafe { munmap(ptr.byte_sub(page_size()), guarded_len(len).get()) },
+        false => unsafe { munmap(ptr, len.get()) },
+    };
+    result.ok();
+}
+
+/// Length of a mapping of `len` bytes plus a guard page on either side.
+fn guarded_len(len: NonZeroUsize) -> NonZeroUsize {
+    let page = page_size();
+    NonZeroUsize::new(len.get().next_multiple_of(page) + 2 * page).expect("length is nonzero")
+}
+
+/// Address and flags for mapping a file shared at `at`, replacing what is mapped there, or
+/// wherever the kernel picks.
+fn placement(at: Option<NonNull<c_void>>) -> (Option<NonZeroUsize>, MapFlags) {
+    match at {
+        Some(at) => (
+            NonZeroUsize::new(at.as_ptr() as usize),
+            MapFlags::MAP_SHARED | MapFlags::MAP_FIXED,
+        ),
+        None => (None, MapFlags::MAP_SHARED),
+    }
+}
+
+fn page_size() -> usize {
+    unsafe { nix::libc::sysconf(nix::libc::_SC_PAGESIZE) as usize }
+}
+
+/// Binds a fresh mapping to the NUMA node of `options`, then faults its pages in if
+/// `options` asks to prefault.
+///
+/// On tmpfs the policy belongs to the object, so pages any process faults in later come
+/// from that node too. Pages already in memory move only if this process is the only one
+/// mapping them.
+#[cfg(target_os = "linux")]
+fn place(ptr: NonNull<c_void>, len: NonZeroUsize, options: MapOptions) -> Result<()> {
+    const MPOL_MF_MOVE: u32 = 1 << 1;
+    const BITS: usize = c_ulong::BITS as usize;
+
+    let Some(node) = options.numa_node else {
+        return Ok(());
+    };
+    let node = node as usize;
+    let mut mask = vec![0 as c_ulong; node / BITS + 1];
+    mask[node / BITS] |= 1 << (node % BITS);
+    // The kernel counts one node less than it's told.
+    let max_node = mask.len() * BITS + 1;
+    let ret = unsafe {
+        syscall(
+            SYS_mbind,
+            ptr.as_ptr(),
+            len.get(),
+            MPOL_PREFERRED,
+            mask.as_ptr(),
+            max_node,
+            MPOL_MF_MOVE,
+        )
+    };
+    if let Err(e) = Errno::result(ret) {
+        unsafe { munmap(ptr, len.get()).ok() };
+        return Err(Error::sys("mbind")(e));
+    }
+    if options.prefault {
+        touch_pages(ptr, len);
+    }
+    Ok(())
+}
+
+/// Faults in every page of a mapping by reading from it.
+fn touch_pages(ptr: NonNull<c_void>, len: NonZeroUsize) {
+    let page = page_size();
+    for offset in (0..len.get()).step_by(page) {
+        unsafe { ptr.cast::<u8>().add(offset).read_volatile() };
+    }
+}
+
+/// Applies the advice of `options` to a mapping. Only the read-ahead hint is available
+/// outside of Linux.
+fn advise(ptr: NonNull<c_void>, len: NonZeroUsize, options: MapOptions) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    for (wanted, advice) in [
+        (options.dont_dump, MmapAdvise::MADV_DONTDUMP),
+        (options.dont_fork, MmapAdvise::MADV_DONTFORK),
+    ] {
+        if wanted {
+            unsafe { madvise(ptr, len.get(), advice) }.map_err(Error::sys("madvise"))?;
+        }
+    }
+    if options.will_need {
+        // Only a hint, so failing to act on it is no error.
+        unsafe { madvise(ptr, len.get(), MmapAdvise::MADV_WILLNEED).ok() };
+    }
+    Ok(())
+}
+
+/// Locks the pages of a mapping in memory, faulting them in. Fails if that exceeds
+/// `RLIMIT_MEMLOCK` for unprivileged processes.
+fn lock_pages(ptr: NonNull<c_void>, len: NonZeroUsize) -> Result<()> {
+    unsafe { mlock(ptr, len.get()) }.map_err(Error::sys("mlock"))
+}
+
+/// Size of the file backing an object of `len` bytes, rounded up to whole huge pages on
+/// hugetlbfs, which can't size or map files otherwise.
+#[cfg(target_os = "linux")]
+fn backing_len(fd: &OwnedFd, len: NonZeroUsize) -> Result<NonZeroUsize> {
+    const HUGETLBFS_MAGIC: u32 = 0x958458f6;
+
+    let mut stat = unsafe { std::mem::zeroed::<statfs>() };
+    Errno::result(unsafe { fstatfs(fd.as_raw_fd(), &mut stat) }).map_err(Error::sys("fstatfs"))?;
+    if stat.f_type as u32 != HUGETLBFS_MAGIC {
+        return Ok(len);
+    }
+    let huge_page = stat.f_bsize as usize;
+    len.get()
+        .checked_next_multiple_of(huge_page)
+        .and_then(NonZeroUsize::new)
+        .ok_or_else(|| Error::InvalidArgument(format!("Object of {len} bytes is too large")))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn backing_len(_fd: &OwnedFd, len: NonZeroUsize) -> Result<NonZeroUsize> {
+    Ok(len)
+}
+
+/// Creates an unnamed file for `Mapping::anonymous`, described by the returned string.
+#[cfg(target_os = "linux")]
+fn anonymous_file(_seal: bool) -> Result<(OwnedFd, String)> {
+    let fd = memfd_create(
+        "nix-ipc",
+        MFdFlags::MFD_CLOEXEC | MFdFlags::MFD_ALLOW_SEALING,
+    )
+    .map_err(|source| Error::Create {
+        path: "memfd".to_owned(),
+        source,
+    })?;
+    let path = format!("memfd {}", fd.as_raw_fd());
+    Ok((fd, path))
+}
+
+/// Creates an unnamed file for `Mapping::anonymous`, described by the returned string.
+/// Without memfd this is a temporary file that is unlinked right away.
+#[cfg(not(target_os = "linux"))]
+fn anonymous_file(seal: bool) -> Result<(OwnedFd, String)> {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    static COUNTER: AtomicU32 = AtomicU32::new(0);
+
+    if seal {
+        return Err(Error::InvalidArgument(
+            "Sealing is only supported on Linux".to_owned(),
+        ));
+    }
+    let path = format!(
+        "{}/nix-ipc.anon.{}.{}",
+        std::env::temp_dir().display(),
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    let fd = nix::fcntl::open(
+        path.as_str(),
+        OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_RDWR | OFlag::O_CLOEXEC,
+        Mode::from_bits_truncate(0o600),
+    )
+    .map_err(|source| Error::Create {
+        path: path.clone(),
+        source,
+    })?;
+    nix::unistd::unlink(path.as_str()).map_err(Error::unlink(&path))?;
+    let path = format!("anonymous file {}", fd.as_raw_fd());
+    Ok((fd, path))
+}
+
+/// Returns true if `object` still names the file open as `fd`, or if that can't be told.
+fn leads_to(object: &ObjectPath, fd: &OwnedFd) -> bool {
+    let Ok(current) = object.open(OFlag::O_RDONLY, Mode::empty()) else {
+        return false;
+    };
+    match (fstat(fd), fstat(&current)) {
+        (Ok(mapped), Ok(current)) => {
+            (mapped.st_dev, mapped.st_ino) == (current.st_dev, current.st_ino)
+        }
+        _ => true,
+    }
+}
+
+/// Returns true if a file of `size` bytes holds an object of `len` bytes. macOS rounds the size
+/// of shared memory objects up to whole pages.
+fn size_matches(object: &ObjectPath, size: off_t, len: NonZeroUsize) -> bool {
+    if size == len.get() as off_t {
+        return true;
+    }
+    #[cfg(target_os = "macos")]
+    if let ObjectPath::Shm(_) = object {
+        let page = page_size();
+        return size == len.get().next_multiple_of(page) as off_t;
+    }
+    let _ = object;
+    false
+}
+
+fn file_size(fd: &OwnedFd) -> Result<off_t> {
+    Ok(fstat(fd).map_err(Error::sys("fstat"))?.st_size)
+}
+
+/// Returns a closure turning an `Errno` of sizing or mapping `path` into an error.
+fn map_error(path: &str) -> impl FnOnce(Errno) -> Error {
+    let path = path.to_owned();
+    move |source| Error::Map { path, source }
+}
+
+fn size_mismatch(path: &str, expected: NonZeroUsize, found: off_t) -> Error {
+    Error::Validation(format!(
+        "Size mismatch for {path}: expected {} bytes, found {found}",
+        expected.get()
+    ))
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        fork::unregister(&self.fd);
+        unsafe {
+            unmap(self.ptr, self.len, self.guarded);
+        }
+
+        let unlink_now = match self.cleanup {
+            CleanupPolicy::Never => false,
+            CleanupPolicy::UnlinkOnDrop => true,
+            CleanupPolicy::LastCloseRefCounted => {
+                cleanup::is_last(self.locks.refs(&self.fd)).unwrap_or(false)
+            }
+        };
+        // A stale handle must not unlink the object created under its name since.
+        if unlink_now
+            && let Some(object) = &self.object
+            && leads_to(object, &self.fd)
+        {
+            object.unlink().ok();
+        }
+    }
+}
@@ -0,0 +1,499 @@
+use std::{
+    collections::HashSet,
+    mem::size_of,
+    num::NonZeroUsize,
+    slice,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use nix::fcntl::OFlag;
+
+use crate::{
+    cleanup::CleanupPolicy,
+    error::{Error, Result},
+    header::Header,
+    map::Mapping,
+    namespace::Namespace,
+    permissions::Permissions,
+};
+
+/// Longest metric name, in bytes.
+const NAME_LEN: usize = 64;
+
+const COUNTER: u32 = 1;
+const GAUGE: u32 = 2;
+const HISTOGRAM: u32 = 3;
+
+#[repr(C)]
+struct MetricsHeader {
+    header: Header,
+    metrics: u64,
+    values: u64,
+}
+
+/// Describes a metric in the schema, which comes after the header.
+#[repr(C)]
+#[derive(Clone, PartialEq, Eq)]
+struct Descriptor {
+    kind: u32,
+    /// Number of bucket bounds of a histogram, 0 for other metrics.
+    buckets: u32,
+    /// Index of the first value of the metric. A histogram has its bucket bounds, the
+    /// counts of its buckets and of the values above the last bound, and the count and sum
+    /// of all values.
+    first: u64,
+    name_len: u64,
+    name: [u8; NAME_LEN],
+}
+
+/// A set of named counters, gauges and histograms in shared memory, which worker processes
+/// update without locking and an exporter process reads.
+///
+/// Workers declare the metrics with `MetricsBlock::builder` and get handles to update them
+/// by name. The block starts with a schema listing the metrics, so an exporter can open it
+/// with `open` and list them with `metrics` without knowing them at compile time. Each
+/// value is updated atomically, but a listing taken while workers update them isn't a
+/// snapshot of all values at one instant. Values sharing a cache line slow down workers
+/// updating them from different CPUs, for which a `ShardedCounter` is better suited.
+pub struct MetricsBlock {
+    map: Mapping,
+    metrics: usize,
+    values: usize,
+}
+
+/// Builder for `MetricsBlock`, created with `MetricsBlock::builder`.
+pub struct MetricsBlockBuilder {
+    name: String,
+    permissions: Permissions,
+    namespace: Namespace,
+    metrics: Vec<(String, u32, Vec<u64>)>,
+}
+
+/// A metric read from a `MetricsBlock`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metric {
+    pub name: String,
+    pub value: MetricValue,
+}
+
+/// The value of a metric read from a `MetricsBlock`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetricValue {
+    Counter(u64),
+    Gauge(i64),
+    Histogram {
+        /// Upper bounds of the buckets, inclusive.
+        bounds: Vec<u64>,
+        /// Number of values in each bucket, followed by the number above the last bound.
+        counts: Vec<u64>,
+        count: u64,
+        sum: u64,
+    },
+}
+
+/// A counter of a `MetricsBlock`, which only goes up.
+pub struct Counter<'a> {
+    value: &'a AtomicU64,
+}
+
+/// A gauge of a `MetricsBlock`, which is set to the current value of something.
+pub struct Gauge<'a> {
+    value: &'a AtomicU64,
+}
+
+/// A histogram of a `MetricsBlock`, counting values in buckets.
+pub struct Histogram<'a> {
+    bounds: &'a [AtomicU64],
+    counts: &'a [AtomicU64],
+    count: &'a AtomicU64,
+    sum: &'a AtomicU64,
+}
+
+impl MetricsBlock {
+    /// Returns a builder declaring the metrics of the block.
+    pub fn builder(name: &str) -> MetricsBlockBuilder {
+        MetricsBlockBuilder {
+            name: name.to_owned(),
+            permissions: Permissions::default(),
+            namespace: Namespace::default(),
+            metrics: Vec::new(),
+        }
+    }
+
+    /// Opens an existing block in /dev/shm with whatever metrics it was declared with.
+    pub fn open(name: &str) -> Result<Self> {
+        Self::builder(name).open()
+    }
+
+    /// Unlinks (deletes) the block from /dev/shm.
+    pub fn unlink(name: &str) -> Result<()> {
+        Self::unlink_in(&Namespace::default(), name)
+    }
+
+    /// Unlinks the block called `name` in `namespace`.
+    pub fn unlink_in(namespace: &Namespace, name: &str) -> Result<()> {
+        namespace.path(name, ".mtr").unlink()
+    }
+
+    /// Returns the counter named `name`.
+    pub fn counter(&self, name: &str) -> Result<Counter<'_>> {
+        Ok(self.counter_at(self.find(name, COUNTER, "counter")?))
+    }
+
+    /// Returns the gauge named `name`.
+    pub fn gauge(&self, name: &str) -> Result<Gauge<'_>> {
+        Ok(self.gauge_at(self.find(name, GAUGE, "gauge")?))
+    }
+
+    /// Returns the histogram named `name`.
+    pub fn histogram(&self, name: &str) -> Result<Histogram<'_>> {
+        Ok(self.histogram_at(self.find(name, HISTOGRAM, "histogram")?))
+    }
+
+    /// Reads all metrics, in the order they were declared.
+    pub fn metrics(&self) -> Vec<Metric> {
+        self.descriptors()
+            .iter()
+            .map(|descriptor| {
+                let value = match descriptor.kind {
+                    COUNTER => MetricValue::Counter(self.counter_at(descriptor).get()),
+                    GAUGE => MetricValue::Gauge(self.gauge_at(descriptor).get()),
+                    _ => {
+                        let histogram = self.histogram_at(descriptor);
+                        MetricValue::Histogram {
+                            bounds: load(histogram.bounds),
+                            counts: load(histogram.counts),
+                            count: histogram.count.load(Ordering::Relaxed),
+                            sum: histogram.sum.load(Ordering::Relaxed),
+                        }
+                    }
+                };
+                Metric {
+                    name: name_of(descriptor).to_owned(),
+                    value,
+                }
+            })
+            .collect()
+    }
+
+    /// Validates the header and schema of the mapped block, against the declared one if
+    /// given, and registers the new handle in it.
+    fn attach(map: Mapping, expected: Option<&[Descriptor]>) -> Result<Self> {
+        let block = map.ptr() as *const MetricsHeader;
+        let init_lock = map.init_lock()?;
+
+        let (metrics, values) = unsafe { ((*block).metrics, (*block).values) };
+        let data_len = usize::try_from(metrics)
+            .ok()
+            .zip(usize::try_from(values).ok())
+            .and_then(|(metrics, values)| data_len(metrics, values))
+            .filter(|&data_len| size_of::<MetricsHeader>() + data_len == map.len())
+            .ok_or_else(|| {
+                Error::Validation(format!(
+                    "Metrics block with {metrics} metrics and {values} values doesn't fit \
+                     its {} bytes",
+                    map.len()
+                ))
+            })?;
+        unsafe { (*block).header.validate::<Descriptor>(data_len, 0)? };
+
+        // Checked before building the handle, which would detach when dropped.
+        let schema = unsafe { descriptors(&map, metrics as usize) };
+        for descriptor in schema {
+            let slots = match descriptor.kind {
+                COUNTER | GAUGE => 1,
+                HISTOGRAM => 2 * descriptor.buckets as u64 + 3,
+                _ => u64::MAX,
+            };
+            if descriptor.name_len > NAME_LEN as u64
+                || descriptor
+                    .first
+                    .checked_add(slots)
+                    .is_none_or(|end| end > values)
+            {
+                return Err(Error::Validation(
+                    "Metrics block has a corrupted schema".to_owned(),
+                ));
+            }
+        }
+        if let Some(expected) = expected
+            && schema != expected
+        {
+            return Err(Error::Validation(
+                "Metrics block was declared with different metrics".to_owned(),
+            ));
+        }
+        unsafe { (*block).header.attach(&map)? };
+        drop(init_lock);
+        Ok(Self {
+            map,
+            metrics: metrics as usize,
+            values: values as usize,
+        })
+    }
+
+    fn find(&self, name: &str, kind: u32, kind_name: &str) -> Result<&Descriptor> {
+        self.descriptors()
+            .iter()
+            .find(|descriptor| descriptor.kind == kind && name_of(descriptor) == name)
+            .ok_or_else(|| {
+                Error::InvalidArgument(format!("Metrics block has no {kind_name} named {name}"))
+            })
+    }
+
+    fn counter_at(&self, descriptor: &Descriptor) -> Counter<'_> {
+        Counter {
+            value: &self.values(descriptor.first, 1)[0],
+        }
+    }
+
+    fn gauge_at(&self, descriptor: &Descriptor) -> Gauge<'_> {
+        Gauge {
+            value: &self.values(descriptor.first, 1)[0],
+        }
+    }
+
+    fn histogram_at(&self, descriptor: &Descriptor) -> Histogram<'_> {
+        let buckets = descriptor.buckets as usize;
+        let values = self.values(descriptor.first, 2 * buckets + 3);
+        let (bounds, rest) = values.split_at(buckets);
+        let (counts, totals) = rest.split_at(buckets + 1);
+        Histogram {
+            bounds,
+            counts,
+            count: &totals[0],
+            sum: &totals[1],
+        }
+    }
+
+    fn descriptors(&self) -> &[Descriptor] {
+        unsafe { descriptors(&self.map, self.metrics) }
+    }
+
+    /// The `len` values starting at index `first`, which `attach` checked are in the block.
+    fn values(&self, first: u64, len: usize) -> &[AtomicU64] {
+        unsafe {
+            let offset = size_of::<MetricsHeader>() + self.metrics * size_of::<Descriptor>();
+            let values = (self.map.ptr() as *const u8).add(offset) as *const AtomicU64;
+            debug_assert!(first as usize + len <= self.values);
+            slice::from_raw_parts(values.add(first as usize), len)
+        }
+    }
+}
+
+impl Drop for MetricsBlock {
+    fn drop(&mut self) {
+        let block = self.map.ptr() as *const MetricsHeader;
+        unsafe { (*block).header.detach(&self.map) };
+    }
+}
+
+impl MetricsBlockBuilder {
+    /// Sets the permissions the backing file is created with, see `Permissions`. Only
+    /// applies if this handle creates the block.
+    pub fn permissions(mut self, permissions: Permissions) -> Self {
+        self.permissions = permissions;
+        self
+    }
+
+    /// Opens the block in `namespace` instead of the default one.
+    pub fn namespace(mut self, namespace: &Namespace) -> Self {
+        self.namespace = namespace.clone();
+        self
+    }
+
+    /// Declares a counter.
+    pub fn counter(mut self, name: &str) -> Self {
+        self.metrics.push((name.to_owned(), COUNTER, Vec::new()));
+        self
+    }
+
+    /// Declares a gauge.
+    pub fn gauge(mut self, name: &str) -> Self {
+        self.metrics.push((name.to_owned(), GAUGE, Vec::new()));
+        self
+    }
+
+    /// Declares a histogram with buckets up to each of `bounds`, which must be increasing,
+    /// and one for values above the last.
+    pub fn histogram(mut self, name: &str, bounds: &[u64]) -> Self {
+        self.metrics
+            .push((name.to_owned(), HISTOGRAM, bounds.to_vec()));
+        self
+    }
+
+    /// Creates the block with the declared metrics, or opens it if it exists. Opening a
+    /// block declared with different metrics fails.
+    pub fn build(self) -> Result<MetricsBlock> {
+        let (descriptors, values) = self.schema()?;
+        let len = data_len(descriptors.len(), values)
+            .and_then(|data_len| NonZeroUsize::new(size_of::<MetricsHeader>() + data_len))
+            .ok_or_else(|| Error::InvalidArgument("Metrics block is too large".to_owned()))?;
+        let data_len = len.get() - size_of::<MetricsHeader>();
+
+        let path = self.namespace.path(&self.name, ".mtr");
+        let map = Mapping::open_init(
+            &path,
+            OFlag::O_CREAT,
+            &self.permissions,
+            len,
+            CleanupPolicy::LastCloseRefCounted,
+            |raw| unsafe {
+                let block = raw as *mut MetricsHeader;
+                (*block).header.init::<Descriptor>(data_len, 0);
+                (*block).metrics = descriptors.len() as u64;
+                (*block).values = values as u64;
+                let first = (raw as *mut u8).add(size_of::<MetricsHeader>()) as *mut Descriptor;
+                let slots = first.add(descriptors.len()) as *mut u64;
+                for (index, (descriptor, (_, _, bounds))) in
+                    descriptors.iter().zip(&self.metrics).enumerate()
+                {
+                    first.add(index).write(descriptor.clone());
+                    let bounds_at = slots.add(descriptor.first as usize);
+                    bounds_at.copy_from_nonoverlapping(bounds.as_ptr(), bounds.len());
+                }
+                Ok(())
+            },
+        )?;
+
+        let block = MetricsBlock::attach(map, Some(&descriptors))?;
+        for (descriptor, (name, _, bounds)) in descriptors.iter().zip(&self.metrics) {
+            if descriptor.kind == HISTOGRAM
+                && load(block.histogram_at(descriptor).bounds) != *bounds
+            {
+                return Err(Error::Validation(format!(
+                    "Histogram {name} was declared with different bounds"
+                )));
+            }
+        }
+        Ok(block)
+    }
+
+    /// Opens the existing block like `MetricsBlock::open`, with whatever metrics it was
+    /// declared with rather than those declared on the builder.
+    pub fn open(self) -> Result<MetricsBlock> {
+        let path = self.namespace.path(&self.name, ".mtr");
+        let map = Mapping::open_existing(&path, CleanupPolicy::LastCloseRefCounted)?;
+        if map.len() < size_of::<MetricsHeader>() {
+            return Err(Error::Validation(format!(
+                "{path} is too small to hold a metrics block"
+            )));
+        }
+        MetricsBlock::attach(map, None)
+    }
+
+    /// Lays out the declared metrics, returning their descriptors and the number of values.
+    fn schema(&self) -> Result<(Vec<Descriptor>, usize)> {
+        let mut names = HashSet::new();
+        let mut descriptors = Vec::with_capacity(self.metrics.len());
+        let mut values = 0;
+        for (name, kind, bounds) in &self.metrics {
+            if name.len() > NAME_LEN {
+                return Err(Error::InvalidArgument(format!(
+                    "Metric name {name} is longer than {NAME_LEN} bytes"
+                )));
+            }
+            if !names.insert(name.as_str()) {
+                return Err(Error::InvalidArgument(format!(
+                    "Metric {name} is declared twice"
+                )));
+            }
+            if !bounds.is_sorted_by(|a, b| a < b) || bounds.len() > u32::MAX as usize / 2 {
+                return Err(Error::InvalidArgument(format!(
+                    "Bounds of histogram {name} must be increasing"
+                )));
+            }
+            let mut descriptor = Descriptor {
+                kind: *kind,
+                buckets: bounds.len() as u32,
+                first: values as u64,
+                name_len: name.len() as u64,
+                name: [0; NAME_LEN],
+            };
+            descriptor.name[..name.len()].copy_from_slice(name.as_bytes());
+            values += match *kind {
+                HISTOGRAM => 2 * bounds.len() + 3,
+                _ => 1,
+            };
+            descriptors.push(descriptor);
+        }
+        Ok((descriptors, values))
+    }
+}
+
+impl Counter<'_> {
+    /// Adds `n` to the counter.
+    pub fn add(&self, n: u64) {
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Adds one to the counter.
+    pub fn increment(&self) {
+        self.add(1);
+    }
+
+    /// The current count.
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+impl Gauge<'_> {
+    /// Replaces the value of the gauge.
+    pub fn set(&self, value: i64) {
+        self.value.store(value as u64, Ordering::Relaxed);
+    }
+
+    /// Adds `n`, which may be negative, to the gauge.
+    pub fn add(&self, n: i64) {
+        self.value.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// The current value.
+    pub fn get(&self) -> i64 {
+        self.value.load(Ordering::Relaxed) as i64
+    }
+}
+
+impl Histogram<'_> {
+    /// Counts `value` in the first bucket whose bound it doesn't exceed.
+    pub fn record(&self, value: u64) {
+        let bucket = self
+            .bounds
+            .partition_point(|bound| bound.load(Ordering::Relaxed) < value);
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+}
+
+/// Size of the schema and values of a block, if it fits in memory.
+fn data_len(metrics: usize, values: usize) -> Option<usize> {
+    metrics
+        .checked_mul(size_of::<Descriptor>())?
+        .checked_add(values.checked_mul(size_of::<AtomicU64>())?)
+}
+
+/// The schema of the block mapped by `map`.
+///
+/// # Safety
+///
+/// The mapping must hold a block with at least `metrics` metrics.
+unsafe fn descriptors(map: &Mapping, metrics: usize) -> &[Descriptor] {
+    unsafe {
+        let first = (map.ptr() as *const u8).add(size_of::<MetricsHeader>());
+        slice::from_raw_parts(first as *const Descriptor, metrics)
+    }
+}
+
+fn name_of(descriptor: &Descriptor) -> &str {
+    let name = &descriptor.name[..descriptor.name_len as usize];
+    std::str::from_utf8(name).unwrap_or("")
+}
+
+fn load(values: &[AtomicU64]) -> Vec<u64> {
+    values
+        .iter()
+        .map(|value| value.load(Ordering::Relaxed))
+        .collect()
+}
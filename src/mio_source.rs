@@ -0,0 +1,52 @@
+use std::{
+    io,
+    os::fd::{AsFd, AsRawFd},
+};
+
+use mio::{Interest, Registry, Token, event::Source, unix::SourceFd};
+
+#[cfg(target_os = "linux")]
+use crate::{
+    event::Event, mq_queue::MqQueue, peer_watcher::PeerWatcher, ready::ReadyFd, signals::Signals,
+    timer::Timer,
+};
+use crate::{
+    fifo::Fifo,
+    unix_channel::{UnixChannel, UnixChannelListener},
+};
+
+macro_rules! impl_source {
+    ($($t:ty),* $(,)?) => {
+        $(
+        /// Registers the descriptor with mio. mio reports readiness edge-triggered, so after
+        /// an event the source has to be drained, e.g. with `try_wait` or `try_recv`.
+        impl Source for $t {
+            fn register(
+                &mut self,
+                registry: &Registry,
+                token: Token,
+                interests: Interest,
+            ) -> io::Result<()> {
+                SourceFd(&self.as_fd().as_raw_fd()).register(registry, token, interests)
+            }
+
+            fn reregister(
+                &mut self,
+                registry: &Registry,
+                token: Token,
+                interests: Interest,
+            ) -> io::Result<()> {
+                SourceFd(&self.as_fd().as_raw_fd()).reregister(registry, token, interests)
+            }
+
+            fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+                SourceFd(&self.as_fd().as_raw_fd()).deregister(registry)
+            }
+        })*
+    };
+}
+
+impl_source!(Fifo, UnixChannel, UnixChannelListener);
+
+#[cfg(target_os = "linux")]
+impl_source!(Event, MqQueue, PeerWatcher, ReadyFd, Signals, Timer);
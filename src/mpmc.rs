@@ -0,0 +1,314 @@
+//! Multi-producer, multi-consumer bounded queue in shared memory.
+
+use std::{
+    cell::UnsafeCell,
+    marker::PhantomData,
+    mem::{MaybeUninit, size_of},
+    num::NonZeroUsize,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use nix::fcntl::OFlag;
+
+#[cfg(target_os = "linux")]
+use crate::ready::ReadyFd;
+use crate::{
+    builder::Builder,
+    cache_padded::CachePadded,
+    cleanup::CleanupPolicy,
+    error::{Error, Result},
+    futex::EventCount,
+    header::Header,
+    map::Mapping,
+    namespace::Namespace,
+    ready::{ReadySlot, Signaler},
+    shm_safe::ShmSafe,
+};
+
+#[repr(C)]
+struct QueueHeader {
+    header: Header,
+    capacity: u64,
+    /// Position of the next value to send.
+    enqueue_pos: CachePadded<AtomicU64>,
+    /// Position of the next value to receive.
+    dequeue_pos: CachePadded<AtomicU64>,
+    /// Notified whenever a value is sent.
+    not_empty: EventCount,
+    /// Notified whenever a value is received.
+    not_full: EventCount,
+    ready: ReadySlot,
+}
+
+/// A slot holds the value sent at position `seq` once `seq` is one past it, and is free for
+/// the value at position `seq` when the two are equal.
+#[repr(C)]
+struct Slot<T> {
+    seq: AtomicU64,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// Bounded queue in shared memory that any number of processes can send to and receive from.
+///
+/// Based on Dmitry Vyukov's bounded MPMC queue: every slot carries a sequence number, so
+/// senders and receivers only contend on a single atomic position each and never take a lock.
+/// A process dying between claiming a slot and filling or emptying it stalls the queue at
+/// that slot.
+pub struct Queue<T: 'static> {
+    map: Mapping,
+    capacity: u64,
+    signaler: Signaler,
+    _marker: PhantomData<T>,
+}
+
+impl<T: ShmSafe> Queue<T> {
+    /// Opens the queue in /dev/shm, creating it with room for `capacity` values if it doesn't
+    /// exist. Attaching to a queue of a different capacity fails.
+    pub fn new(name: &str, capacity: usize) -> Result<Self> {
+        Self::builder(name).build(capacity)
+    }
+
+    /// Returns a builder for setting the namespace and the permissions the queue is created with.
+    pub fn builder(name: &str) -> Builder<Self> {
+        Builder::new(name)
+    }
+
+    /// Unlinks (deletes) the queue from /dev/shm.
+    pub fn unlink(name: &str) -> Result<()> {
+        Self::unlink_in(&Namespace::default(), name)
+    }
+
+    /// Unlinks the queue called `name` in `namespace`.
+    pub fn unlink_in(namespace: &Namespace, name: &str) -> Result<()> {
+        namespace.path(name, ".mpmc").unlink()
+    }
+
+    /// Sends `value` without blocking, handing it back if the queue is full.
+    pub fn try_send(&self, value: T) -> Result<(), T> {
+        let header = self.header();
+        let mut pos = header.enqueue_pos.load(Ordering::Relaxed);
+        loop {
+            let slot = self.slot(pos);
+            let seq = slot.seq.load(Ordering::Acquire);
+            if seq == pos {
+                match header.enqueue_pos.compare_exchange_weak(
+                    pos,
+                    pos + 1,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        unsafe { (*slot.value.get()).write(value) };
+                        slot.seq.store(pos + 1, Ordering::Release);
+                        header.not_empty.notify_all();
+                        self.signaler.signal(&header.ready);
+                        return Ok(());
+                    }
+                    Err(actual) => pos = actual,
+                }
+            } else if seq < pos {
+                // The slot still holds the value from one lap ago.
+                return Err(value);
+            } else {
+                pos = header.enqueue_pos.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Receives a value without blocking, returning `None` if the queue is empty.
+    pub fn try_recv(&self) -> Option<T> {
+        let header = self.header();
+        let mut pos = header.dequeue_pos.load(Ordering::Relaxed);
+        loop {
+            let slot = self.slot(pos);
+            let seq = slot.seq.load(Ordering::Acquire);
+            if seq == pos + 1 {
+                match header.dequeue_pos.compare_exchange_weak(
+                    pos,
+                    pos + 1,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        let value = unsafe { (*slot.value.get()).assume_init_read() };
+                        slot.seq.store(pos + self.capacity, Ordering::Release);
+                        header.not_full.notify_all();
+                        return Some(value);
+                    }
+                    Err(actual) => pos = actual,
+                }
+            } else if seq < pos + 1 {
+                // Nothing has been sent to this slot yet.
+                return None;
+            } else {
+                pos = header.dequeue_pos.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Sends `value`, blocking while the queue is full.
+    pub fn send(&self, value: T) -> Result<()> {
+        self.send_until(value, None).map(|_| ())
+    }
+
+    /// Sends `value`, blocking for at most `timeout` while the queue is full.
+    /// Returns the value back if it could not be sent in time.
+    pub fn send_timeout(&self, value: T, timeout: Duration) -> Result<Option<T>> {
+        self.send_until(value, Some(timeout))
+    }
+
+    /// Receives a value, blocking while the queue is empty.
+    pub fn recv(&self) -> Result<T> {
+        let value = self.header().not_empty.wait_for(None, || self.try_recv())?;
+        Ok(value.expect("waiting without a timeout always yields a value"))
+    }
+
+    /// Receives a value, blocking for at most `timeout` while the queue is empty.
+    /// Returns `None` if nothing arrived in time.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Option<T>> {
+        self.header()
+            .not_empty
+            .wait_for(Some(timeout), || self.try_recv())
+    }
+
+    /// Returns a handle that becomes readable when values are sent, for use with epoll.
+    /// All handles of the queue share the same eventfd.
+    #[cfg(target_os = "linux")]
+    pub fn ready_fd(&self) -> Result<ReadyFd> {
+        self.header().ready.ready_fd()
+    }
+
+    fn send_until(&self, value: T, timeout: Option<Duration>) -> Result<Option<T>> {
+        let mut value = Some(value);
+        let sent = self.header().not_full.wait_for(timeout, || {
+            let pending = value.take().expect("value is only taken once per attempt");
+            self.try_send(pending)
+                .map_err(|pending| value = Some(pending))
+                .ok()
+        })?;
+        Ok(if sent.is_some() { None } else { value })
+    }
+
+    /// Approximate number of values waiting to be received.
+    pub fn len(&self) -> usize {
+        let enqueue = self.header().enqueue_pos.load(Ordering::Acquire);
+        let dequeue = self.header().dequeue_pos.load(Ordering::Acquire);
+        enqueue.saturating_sub(dequeue) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Maximum number of values the queue holds.
+    pub fn capacity(&self) -> usize {
+        self.capacity as usize
+    }
+
+    /// Offset of the first slot, after the header and aligned for it.
+    fn data_offset() -> usize {
+        size_of::<QueueHeader>().next_multiple_of(align_of::<Slot<T>>())
+    }
+
+    fn header(&self) -> &QueueHeader {
+        unsafe { &*(self.map.ptr() as *const QueueHeader) }
+    }
+
+    fn slot(&self, pos: u64) -> &Slot<T> {
+        let index = (pos % self.capacity) as usize;
+        unsafe {
+            let slots = (self.map.ptr() as *const u8).add(Self::data_offset()) as *const Slot<T>;
+            &*slots.add(index)
+        }
+    }
+}
+
+impl<T: ShmSafe> Builder<Queue<T>> {
+    /// Opens the queue like `Queue::new`.
+    pub fn build(self, capacity: usize) -> Result<Queue<T>> {
+        if size_of::<T>() == 0 {
+            return Err(Error::InvalidArgument(
+                "Cannot use zero-sized type in shared memory".to_owned(),
+            ));
+        }
+        if capacity == 0 {
+            return Err(Error::InvalidArgument(
+                "Queue capacity must be nonzero".to_owned(),
+            ));
+        }
+        let data_len = capacity.checked_mul(size_of::<Slot<T>>()).ok_or_else(|| {
+            Error::InvalidArgument(format!("Queue of {capacity} values is too large"))
+        })?;
+        let map_len = NonZeroUsize::new(Queue::<T>::data_offset() + data_len)
+            .expect("QueueHeader has nonzero size");
+
+        let path = self.namespace.path(&self.name, ".mpmc");
+        let map = Mapping::open_init(
+            &path,
+            OFlag::O_CREAT,
+            &self.permissions,
+            map_len,
+            CleanupPolicy::LastCloseRefCounted,
+            |raw| unsafe {
+                let queue = raw as *mut QueueHeader;
+                (*queue).header.init::<T>(data_len, 0);
+                (*queue).capacity = capacity as u64;
+                let slots = (raw as *mut u8).add(Queue::<T>::data_offset()) as *mut Slot<T>;
+                for index in 0..capacity {
+                    (*slots.add(index)).seq = AtomicU64::new(index as u64);
+                }
+                Ok(())
+            },
+        )?;
+
+        let queue = map.ptr() as *const QueueHeader;
+        {
+            let _init_lock = map.init_lock()?;
+            unsafe {
+                (*queue).header.validate::<T>(data_len, 0)?;
+                (*queue).header.attach(&map)?;
+            }
+        }
+
+        Ok(Queue {
+            map,
+            capacity: capacity as u64,
+            signaler: Signaler::default(),
+            _marker: PhantomData,
+        })
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "tokio"))]
+impl<T: ShmSafe> crate::async_io::sealed::Sender for Queue<T> {
+    type Item = T;
+
+    fn try_send(&mut self, value: T) -> Result<(), T> {
+        Queue::try_send(self, value)
+    }
+
+    fn not_full(&self) -> crate::async_io::sealed::Notified<'_> {
+        crate::async_io::sealed::Notified(&self.header().not_full)
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "tokio"))]
+impl<T: ShmSafe> crate::async_io::sealed::Receiver for Queue<T> {
+    type Item = T;
+
+    fn try_recv(&mut self) -> Option<T> {
+        Queue::try_recv(self)
+    }
+
+    fn not_empty(&self) -> crate::async_io::sealed::Notified<'_> {
+        crate::async_io::sealed::Notified(&self.header().not_empty)
+    }
+}
+
+impl<T: 'static> Drop for Queue<T> {
+    fn drop(&mut self) {
+        let queue = self.map.ptr() as *const QueueHeader;
+        unsafe { (*queue).header.detach(&self.map) };
+    }
+}
@@ -0,0 +1,342 @@
+use std::{
+    ffi::CString,
+    mem::zeroed,
+    os::fd::{AsFd, BorrowedFd},
+    ptr,
+    time::Duration,
+};
+
+#[cfg(not(target_env = "musl"))]
+use nix::libc::mq_notify;
+use nix::{
+    errno::Errno,
+    libc::{
+        CLOCK_REALTIME, EEXIST, EINTR, ENOENT, ETIMEDOUT, O_CREAT, O_EXCL, O_RDWR, SIGEV_NONE,
+        SIGEV_SIGNAL, c_char, c_int, c_long, c_uint, mq_attr, mq_close, mq_getattr, mq_open,
+        mq_timedreceive, mq_timedsend, mq_unlink, mqd_t, sigevent, timespec,
+    },
+};
+
+use crate::{
+    builder::Builder,
+    error::{Error, Result},
+    namespace::Namespace,
+    time::deadline,
+};
+
+#[cfg(feature = "serde")]
+use crate::framing::{decode, encode};
+
+// musl has mq_notify, but the libc crate doesn't declare it there.
+#[cfg(target_env = "musl")]
+unsafe extern "C" {
+    fn mq_notify(mqdes: mqd_t, sevp: *const sigevent) -> c_int;
+}
+
+/// An interprocess message queue implemented using POSIX message queues.
+///
+/// Messages carry a priority, and higher priority messages are received first.
+/// Timeouts are measured on the realtime clock, since `mq_timedreceive` has no clock choice.
+/// Only available on Linux.
+pub struct MqQueue {
+    mqd: mqd_t,
+    msg_size: usize,
+}
+
+impl MqQueue {
+    /// Opens the queue, creating it with room for `max_msgs` messages of up to `msg_size`
+    /// bytes if it doesn't exist yet. An existing queue keeps its original limits.
+    pub fn new(name: &str, max_msgs: usize, msg_size: usize) -> Result<Self> {
+        Self::builder(name).build(max_msgs, msg_size)
+    }
+
+    /// Returns a builder for setting the namespace and the permissions the queue is created with.
+    pub fn builder(name: &str) -> Builder<Self> {
+        Builder::new(name)
+    }
+
+    /// Opens an existing queue, failing if it doesn't exist.
+    pub fn open(name: &str) -> Result<Self> {
+        Self::builder(name).open()
+    }
+
+    fn opened(mqd: mqd_t) -> Result<Self> {
+        if mqd == -1 {
+            return Err(Error::sys("mq_open")(Errno::last()));
+        }
+        let mut queue = Self { mqd, msg_size: 0 };
+        queue.msg_size = queue.attr()?.mq_msgsize as usize;
+        Ok(queue)
+    }
+
+    /// Removes the queue name; processes that have it open keep using it.
+    pub fn unlink(name: &str) -> Result<()> {
+        let c_name = CString::new(format!("/{}", name))?;
+        Errno::result(unsafe { mq_unlink(c_name.as_ptr()) })
+            .map(|_| ())
+            .map_err(Error::sys("mq_unlink"))
+    }
+
+    /// Removes the name of the queue called `name` in `namespace`.
+    pub fn unlink_in(namespace: &Namespace, name: &str) -> Result<()> {
+        Self::unlink(&namespace.object_name(name))
+    }
+
+    /// Sends `msg` with the given priority, blocking while the queue is full.
+    pub fn send(&self, msg: &[u8], priority: u32) -> Result<()> {
+        loop {
+            let ret = unsafe {
+                nix::libc::mq_send(
+                    self.mqd,
+                    msg.as_ptr() as *const c_char,
+                    msg.len(),
+                    priority as c_uint,
+                )
+            };
+            if ret == 0 {
+                return Ok(());
+            }
+            match Errno::last_raw() {
+                EINTR => continue,
+                _ => return Err(Error::sys("mq_send")(Errno::last())),
+            }
+        }
+    }
+
+    /// Sends `msg` without blocking, returning false if the queue is full.
+    pub fn try_send(&self, msg: &[u8], priority: u32) -> Result<bool> {
+        self.send_until(msg, priority, &expired())
+    }
+
+    /// Sends `msg`, giving up after `timeout` has elapsed, and returns whether it was sent.
+    pub fn send_timeout(&self, msg: &[u8], priority: u32, timeout: Duration) -> Result<bool> {
+        self.send_until(msg, priority, &deadline(CLOCK_REALTIME, timeout)?)
+    }
+
+    /// Receives the oldest message of the highest priority into `buf`, blocking while the
+    /// queue is empty. Returns the message length and priority.
+    /// `buf` must be at least `msg_size` bytes long.
+    pub fn recv(&self, buf: &mut [u8]) -> Result<(usize, u32)> {
+        loop {
+            let mut priority = 0;
+            let ret = unsafe {
+                nix::libc::mq_receive(
+                    self.mqd,
+                    buf.as_mut_ptr() as *mut c_char,
+                    buf.len(),
+                    &mut priority,
+                )
+            };
+            if ret >= 0 {
+                return Ok((ret as usize, priority));
+            }
+            match Errno::last_raw() {
+                EINTR => continue,
+                _ => return Err(Error::sys("mq_receive")(Errno::last())),
+            }
+        }
+    }
+
+    /// Receives a message into `buf` without blocking, returning `None` if the queue is empty.
+    pub fn try_recv(&self, buf: &mut [u8]) -> Result<Option<(usize, u32)>> {
+        self.recv_until(buf, &expired())
+    }
+
+    /// Receives a message into `buf`, giving up after `timeout` has elapsed.
+    pub fn recv_timeout(&self, buf: &mut [u8], timeout: Duration) -> Result<Option<(usize, u32)>> {
+        self.recv_until(buf, &deadline(CLOCK_REALTIME, timeout)?)
+    }
+
+    /// Receives a message into a newly allocated buffer, blocking while the queue is empty.
+    pub fn recv_vec(&self) -> Result<(Vec<u8>, u32)> {
+        let mut buf = vec![0; self.msg_size];
+        let (len, priority) = self.recv(&mut buf)?;
+        buf.truncate(len);
+        Ok((buf, priority))
+    }
+
+    /// Encodes `value` with bincode and sends it with the given priority, blocking while the
+    /// queue is full. Fails if the encoding is larger than `msg_size`.
+    #[cfg(feature = "serde")]
+    pub fn send_serialized<T: serde::Serialize>(&self, value: &T, priority: u32) -> Result<()> {
+        self.send(&encode(value)?, priority)
+    }
+
+    /// Receives a message like `recv_vec` and decodes it with bincode. Returns the value and
+    /// its priority.
+    #[cfg(feature = "serde")]
+    pub fn recv_deserialized<T: serde::de::DeserializeOwned>(&self) -> Result<(T, u32)> {
+        let (msg, priority) = self.recv_vec()?;
+        Ok((decode(&msg)?, priority))
+    }
+
+    /// Maximum size of a message in bytes.
+    pub fn msg_size(&self) -> usize {
+        self.msg_size
+    }
+
+    /// Maximum number of messages in the queue.
+    pub fn max_msgs(&self) -> Result<usize> {
+        Ok(self.attr()?.mq_maxmsg as usize)
+    }
+
+    /// Number of messages currently in the queue.
+    pub fn len(&self) -> Result<usize> {
+        Ok(self.attr()?.mq_curmsgs as usize)
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Asks for `signal` to be delivered to this process when a message arrives in the
+    /// empty queue. The registration is removed once it fires, and only one process can be
+    /// registered at a time.
+    pub fn notify_signal(&self, signal: c_int) -> Result<()> {
+        let mut event: sigevent = unsafe { zeroed() };
+        event.sigev_notify = SIGEV_SIGNAL;
+        event.sigev_signo = signal;
+        self.notify(&event)
+    }
+
+    /// Registers for notification without any signal being sent, only reserving the queue
+    /// against other processes' registrations.
+    pub fn notify_none(&self) -> Result<()> {
+        let mut event: sigevent = unsafe { zeroed() };
+        event.sigev_notify = SIGEV_NONE;
+        self.notify(&event)
+    }
+
+    /// Removes this process's notification registration.
+    pub fn cancel_notify(&self) -> Result<()> {
+        Errno::result(unsafe { mq_notify(self.mqd, ptr::null()) })
+            .map(|_| ())
+            .map_err(Error::sys("mq_notify"))
+    }
+
+    fn notify(&self, event: &sigevent) -> Result<()> {
+        Errno::result(unsafe { mq_notify(self.mqd, event) })
+            .map(|_| ())
+            .map_err(Error::sys("mq_notify"))
+    }
+
+    fn send_until(&self, msg: &[u8], priority: u32, abstime: &timespec) -> Result<bool> {
+        loop {
+            let ret = unsafe {
+                mq_timedsend(
+                    self.mqd,
+                    msg.as_ptr() as *const c_char,
+                    msg.len(),
+                    priority as c_uint,
+                    abstime,
+                )
+            };
+            if ret == 0 {
+                return Ok(true);
+            }
+            match Errno::last_raw() {
+                EINTR => continue,
+                ETIMEDOUT => return Ok(false),
+                _ => return Err(Error::sys("mq_timedsend")(Errno::last())),
+            }
+        }
+    }
+
+    fn recv_until(&self, buf: &mut [u8], abstime: &timespec) -> Result<Option<(usize, u32)>> {
+        loop {
+            let mut priority = 0;
+            let ret = unsafe {
+                mq_timedreceive(
+                    self.mqd,
+                    buf.as_mut_ptr() as *mut c_char,
+                    buf.len(),
+                    &mut priority,
+                    abstime,
+                )
+            };
+            if ret >= 0 {
+                return Ok(Some((ret as usize, priority)));
+            }
+            match Errno::last_raw() {
+                EINTR => continue,
+                ETIMEDOUT => return Ok(None),
+                _ => return Err(Error::sys("mq_timedreceive")(Errno::last())),
+            }
+        }
+    }
+
+    fn attr(&self) -> Result<mq_attr> {
+        let mut attr: mq_attr = unsafe { zeroed() };
+        Errno::result(unsafe { mq_getattr(self.mqd, &mut attr) })
+            .map_err(Error::sys("mq_getattr"))?;
+        Ok(attr)
+    }
+}
+
+impl Builder<MqQueue> {
+    /// Opens the queue like `MqQueue::new`. Only the prefix of the namespace applies, as
+    /// queues are placed by the system.
+    pub fn build(self, max_msgs: usize, msg_size: usize) -> Result<MqQueue> {
+        let c_name = CString::new(format!("/{}", self.namespace.object_name(&self.name)))?;
+        let mut attr: mq_attr = unsafe { zeroed() };
+        attr.mq_maxmsg = max_msgs as c_long;
+        attr.mq_msgsize = msg_size as c_long;
+
+        loop {
+            // Creating exclusively tells whether this handle created the queue.
+            let mqd = unsafe {
+                mq_open(
+                    c_name.as_ptr(),
+                    O_CREAT | O_EXCL | O_RDWR,
+                    self.permissions.mode().bits(),
+                    &mut attr as *mut mq_attr,
+                )
+            };
+            if mqd != -1 {
+                let queue = MqQueue::opened(mqd)?;
+                self.permissions.apply(queue.as_fd())?;
+                return Ok(queue);
+            }
+            if Errno::last_raw() != EEXIST {
+                return Err(Error::sys("mq_open")(Errno::last()));
+            }
+
+            let mqd = unsafe { mq_open(c_name.as_ptr(), O_RDWR) };
+            // Not finding it means it was unlinked in the meantime, so it's created again.
+            if mqd != -1 || Errno::last_raw() != ENOENT {
+                return MqQueue::opened(mqd);
+            }
+        }
+    }
+
+    /// Opens the existing queue like `MqQueue::open`.
+    pub fn open(self) -> Result<MqQueue> {
+        let c_name = CString::new(format!("/{}", self.namespace.object_name(&self.name)))?;
+        let mqd = unsafe { mq_open(c_name.as_ptr(), O_RDWR) };
+        MqQueue::opened(mqd)
+    }
+}
+
+/// A deadline in the past, making timed calls return immediately when they would block.
+fn expired() -> timespec {
+    timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    }
+}
+
+/// On Linux a message queue descriptor is a file descriptor, readable while the queue holds
+/// a message and writable while it has room.
+impl AsFd for MqQueue {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        unsafe { BorrowedFd::borrow_raw(self.mqd) }
+    }
+}
+
+impl Drop for MqQueue {
+    fn drop(&mut self) {
+        unsafe {
+            Errno::result(mq_close(self.mqd)).ok();
+        }
+    }
+}
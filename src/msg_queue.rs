@@ -0,0 +1,864 @@
+#[cfg(feature = "rkyv")]
+use std::{cell::Cell, mem::MaybeUninit};
+use std::{
+    num::NonZeroUsize,
+    ops::Deref,
+    ptr, slice,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use nix::fcntl::OFlag;
+#[cfg(feature = "rkyv")]
+use rkyv::{
+    api::high::{HighSerializer, HighValidator},
+    bytecheck::CheckBytes,
+    rancor,
+    ser::{Positional, Writer, allocator::ArenaHandle, writer::Buffer},
+};
+
+use crate::{
+    builder::Builder,
+    cache_padded::CachePadded,
+    cleanup::CleanupPolicy,
+    error::{Error, Result},
+    futex::EventCount,
+    header::Header,
+    map::Mapping,
+    namespace::Namespace,
+    shm_safe::ShmSafe,
+};
+
+#[cfg(feature = "serde")]
+use crate::framing::{decode, encode};
+
+#[repr(C)]
+struct QueueHeader {
+    header: Header,
+    capacity: u64,
+    /// Byte position of the next message to receive, only written by the receiver.
+    head: CachePadded<AtomicU64>,
+    /// Byte position after the last message sent, only written by the sender.
+    tail: CachePadded<AtomicU64>,
+    /// Notified whenever a message is sent.
+    not_empty: EventCount,
+    /// Notified whenever a message is released by the receiver.
+    not_full: EventCount,
+}
+
+/// Every message is preceded by its length, padded so records stay 8-byte aligned.
+const RECORD_HEADER: usize = 8;
+/// Length stored in place of a record that doesn't fit before the end of the buffer,
+/// telling the receiver to continue at the start.
+const WRAP: u32 = u32::MAX;
+/// Messages of typed values start with the fingerprint of the type, keeping the values 8-byte
+/// aligned.
+const FINGERPRINT_LEN: usize = 8;
+
+/// The rkyv serializer archiving values straight into the ring.
+#[cfg(feature = "rkyv")]
+type RingSerializer<'a, 'b> = HighSerializer<RingWriter<'b>, ArenaHandle<'a>, rancor::Error>;
+
+/// Queue of variable-length byte messages in a shared ring buffer.
+///
+/// One process sends and one process receives. Messages are copied into the ring once and
+/// received without copying: `recv` returns a guard borrowing the bytes in shared memory,
+/// and the space is only handed back to the sender when the guard is dropped.
+///
+/// Structured messages can be viewed in place the same way, without decoding them:
+///
+/// - Values of `ShmSafe` types sent with `send_value` or `send_slice` are copied into the
+///   ring as they are, behind a fingerprint of their type that `MsgGuard::slice` checks.
+/// - With the `rkyv` feature, values of any type rkyv can archive, such as ones holding
+///   strings or vectors, are serialized straight into the ring with `send_archived`, and
+///   `MsgGuard::archived` validates the archive before handing out a view of it.
+pub struct MsgQueue {
+    map: Mapping,
+    capacity: u64,
+}
+
+impl MsgQueue {
+    /// Opens the queue in /dev/shm, creating it with a ring of `capacity` bytes (rounded up
+    /// to a multiple of 8) if it doesn't exist. Attaching with a different capacity fails.
+    pub fn new(name: &str, capacity: usize) -> Result<Self> {
+        Self::builder(name).build(capacity)
+    }
+
+    /// Returns a builder for setting the namespace and the permissions the queue is created with.
+    pub fn builder(name: &str) -> Builder<Self> {
+        Builder::new(name)
+    }
+
+    /// Unlinks (deletes) the queue from /dev/shm.
+    pub fn unlink(name: &str) -> Result<()> {
+        Self::unlink_in(&Namespace::default(), name)
+    }
+
+    /// Unlinks the queue called `name` in `namespace`.
+    pub fn unlink_in(namespace: &Namespace, name: &str) -> Result<()> {
+        namespace.path(name, ".msgq").unlink()
+    }
+
+    /// Largest message that can be sent, half of the ring minus the length prefix.
+    /// Keeping messages this small guarantees one always fits into an empty ring.
+    pub fn max_message_len(&self) -> usize {
+        self.capacity as usize / 2 - RECORD_HEADER
+    }
+
+    /// Sends `msg` without blocking, returning false if there is not enough free space.
+    pub fn try_send(&mut self, msg: &[u8]) -> Result<bool> {
+        self.check_len(msg.len())?;
+        Ok(self.write(msg))
+    }
+
+    /// Sends `msg`, blocking until there is enough free space.
+    pub fn send(&mut self, msg: &[u8]) -> Result<()> {
+        self.check_len(msg.len())?;
+        self.header()
+            .not_full
+            .wait_for(None, || self.write(msg).then_some(()))?;
+        Ok(())
+    }
+
+    /// Sends `msg`, blocking for at most `timeout` until there is enough free space.
+    /// Returns false if it could not be sent in time.
+    pub fn send_timeout(&mut self, msg: &[u8], timeout: Duration) -> Result<bool> {
+        self.check_len(msg.len())?;
+        let sent = self
+            .header()
+            .not_full
+            .wait_for(Some(timeout), || self.write(msg).then_some(()))?;
+        Ok(sent.is_some())
+    }
+
+    /// Receives the next message without blocking, returning `None` if there is none.
+    pub fn try_recv(&mut self) -> Option<MsgGuard<'_>> {
+        let (pos, len) = self.peek()?;
+        Some(MsgGuard {
+            queue: self,
+            pos,
+            len,
+        })
+    }
+
+    /// Receives the next message, blocking until there is one.
+    pub fn recv(&mut self) -> Result<MsgGuard<'_>> {
+        let next = self.header().not_empty.wait_for(None, || self.peek())?;
+        let (pos, len) = next.expect("waiting without a timeout always yields a value");
+        Ok(MsgGuard {
+            queue: self,
+            pos,
+            len,
+        })
+    }
+
+    /// Receives the next message, blocking for at most `timeout` until there is one.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<MsgGuard<'_>>> {
+        let next = self
+            .header()
+            .not_empty
+            .wait_for(Some(timeout), || self.peek())?;
+        Ok(next.map(|(pos, len)| MsgGuard {
+            queue: self,
+            pos,
+            len,
+        }))
+    }
+
+    /// Encodes `value` with bincode and sends it, blocking until there is enough free space.
+    /// Fails if the encoding is larger than `max_message_len`.
+    #[cfg(feature = "serde")]
+    pub fn send_serialized<T: serde::Serialize>(&mut self, value: &T) -> Result<()> {
+        self.send(&encode(value)?)
+    }
+
+    /// Receives the next message, blocking until there is one, and decodes it with bincode.
+    /// The message is released whether decoding succeeds or not.
+    #[cfg(feature = "serde")]
+    pub fn recv_deserialized<T: serde::de::DeserializeOwned>(&mut self) -> Result<T> {
+        let msg = self.recv()?;
+        decode(&msg)
+    }
+
+    /// Sends a copy of `value` without blocking, returning false if there is not enough free
+    /// space. The receiver views it in place with `MsgGuard::value`.
+    pub fn try_send_value<T: ShmSafe>(&mut self, value: &T) -> Result<bool> {
+        self.try_send_slice(slice::from_ref(value))
+    }
+
+    /// Sends a copy of `value`, blocking until there is enough free space. The receiver views
+    /// it in place with `MsgGuard::value`.
+    pub fn send_value<T: ShmSafe>(&mut self, value: &T) -> Result<()> {
+        self.send_slice(slice::from_ref(value))
+    }
+
+    /// Sends a copy of `value`, blocking for at most `timeout` until there is enough free
+    /// space. Returns false if it could not be sent in time.
+    pub fn send_value_timeout<T: ShmSafe>(&mut self, value: &T, timeout: Duration) -> Result<bool> {
+        self.send_slice_timeout(slice::from_ref(value), timeout)
+    }
+
+    /// Sends a copy of `values` without blocking, returning false if there is not enough free
+    /// space. The receiver views them in place with `MsgGuard::slice`.
+    pub fn try_send_slice<T: ShmSafe>(&mut self, values: &[T]) -> Result<bool> {
+        self.check_values(values)?;
+        Ok(self.write_values(values))
+    }
+
+    /// Sends a copy of `values`, blocking until there is enough free space. The receiver
+    /// views them in place with `MsgGuard::slice`.
+    ///
+    /// The values are copied straight into the ring, behind an 8-byte fingerprint of the
+    /// layout of `T` that the receiver checks, so structured data crosses over with a
+    /// single copy and no encoding. Types aligned to more than 8 bytes are rejected.
+    pub fn send_slice<T: ShmSafe>(&mut self, values: &[T]) -> Result<()> {
+        self.check_values(values)?;
+        self.header()
+            .not_full
+            .wait_for(None, || self.write_values(values).then_some(()))?;
+        Ok(())
+    }
+
+    /// Sends a copy of `values`, blocking for at most `timeout` until there is enough free
+    /// space. Returns false if they could not be sent in time.
+    pub fn send_slice_timeout<T: ShmSafe>(
+        &mut self,
+        values: &[T],
+        timeout: Duration,
+    ) -> Result<bool> {
+        self.check_values(values)?;
+        let sent = self
+            .header()
+            .not_full
+            .wait_for(Some(timeout), || self.write_values(values).then_some(()))?;
+        Ok(sent.is_some())
+    }
+
+    /// Archives `value` with rkyv straight into the ring without blocking, returning false if
+    /// there is not enough free space. The receiver views it in place with
+    /// `MsgGuard::archived`. Only available with the `rkyv` feature.
+    #[cfg(feature = "rkyv")]
+    pub fn try_send_archived<T>(&mut self, value: &T) -> Result<bool>
+    where
+        T: for<'a, 'b> rkyv::Serialize<RingSerializer<'a, 'b>>,
+    {
+        self.write_archived(value)
+    }
+
+    /// Archives `value` with rkyv straight into the ring, blocking until there is enough free
+    /// space. The receiver views it in place with `MsgGuard::archived`. Only available with
+    /// the `rkyv` feature.
+    ///
+    /// The size of the archive isn't known up front, so it is written into whatever space is
+    /// free, and archived again once the receiver released messages if it didn't fit. Fails
+    /// if the archive is larger than `max_message_len`.
+    #[cfg(feature = "rkyv")]
+    pub fn send_archived<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: for<'a, 'b> rkyv::Serialize<RingSerializer<'a, 'b>>,
+    {
+        let sent = self.header().not_full.wait_for(None, || {
+            self.write_archived(value)
+                .map(|sent| sent.then_some(()))
+                .transpose()
+        })?;
+        sent.expect("waiting without a timeout always yields a value")
+    }
+
+    /// Archives `value` with rkyv straight into the ring, blocking for at most `timeout`
+    /// until there is enough free space. Returns false if it could not be sent in time. Only
+    /// available with the `rkyv` feature.
+    #[cfg(feature = "rkyv")]
+    pub fn send_archived_timeout<T>(&mut self, value: &T, timeout: Duration) -> Result<bool>
+    where
+        T: for<'a, 'b> rkyv::Serialize<RingSerializer<'a, 'b>>,
+    {
+        let sent = self.header().not_full.wait_for(Some(timeout), || {
+            self.write_archived(value)
+                .map(|sent| sent.then_some(()))
+                .transpose()
+        })?;
+        sent.transpose().map(|sent| sent.is_some())
+    }
+
+    /// Number of bytes in the ring taken up by unreleased messages.
+    pub fn len(&self) -> usize {
+        let tail = self.header().tail.load(Ordering::Acquire);
+        let head = self.header().head.load(Ordering::Acquire);
+        tail.saturating_sub(head) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Size of the ring in bytes.
+    pub fn capacity(&self) -> usize {
+        self.capacity as usize
+    }
+
+    fn check_len(&self, len: usize) -> Result<()> {
+        if len > self.max_message_len() {
+            return Err(Error::InvalidArgument(format!(
+                "Message of {len} bytes exceeds the maximum of {} bytes",
+                self.max_message_len()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Checks that `values` can be sent and viewed in place.
+    fn check_values<T: ShmSafe>(&self, values: &[T]) -> Result<()> {
+        if size_of::<T>() == 0 {
+            return Err(Error::InvalidArgument(
+                "Cannot use zero-sized type in shared memory".to_owned(),
+            ));
+        }
+        if align_of::<T>() > RECORD_HEADER {
+            return Err(Error::InvalidArgument(format!(
+                "Values aligned to {} bytes can't be viewed in place, as messages are only \
+                 aligned to {RECORD_HEADER}",
+                align_of::<T>()
+            )));
+        }
+        self.check_len(FINGERPRINT_LEN + size_of_val(values))
+    }
+
+    /// Copies `values` into the ring behind the fingerprint of their type, if there is room.
+    fn write_values<T: ShmSafe>(&self, values: &[T]) -> bool {
+        let fingerprint = T::FINGERPRINT.to_le_bytes();
+        self.write_parts(&[
+            (fingerprint.as_ptr(), FINGERPRINT_LEN),
+            (values.as_ptr() as *const u8, size_of_val(values)),
+        ])
+    }
+
+    /// Copies `msg` into the ring if there is room, wrapping to the start if it doesn't fit
+    /// before the end.
+    fn write(&self, msg: &[u8]) -> bool {
+        self.write_parts(&[(msg.as_ptr(), msg.len())])
+    }
+
+    /// Like `write`, for a message made of several `(pointer, length)` parts copied one after
+    /// the other.
+    fn write_parts(&self, parts: &[(*const u8, usize)]) -> bool {
+        let header = self.header();
+        let tail = header.tail.load(Ordering::Relaxed);
+        let head = header.head.load(Ordering::Acquire);
+
+        let len: usize = parts.iter().map(|&(_, len)| len).sum();
+        let record = (RECORD_HEADER + len).next_multiple_of(RECORD_HEADER) as u64;
+        let until_end = self.capacity - tail % self.capacity;
+        let padding = if record > until_end { until_end } else { 0 };
+        if tail + padding + record - head > self.capacity {
+            return false;
+        }
+
+        unsafe {
+            let mut data = self.data(tail + padding);
+            for &(part, part_len) in parts {
+                ptr::copy_nonoverlapping(part, data, part_len);
+                data = data.add(part_len);
+            }
+        }
+        self.publish(tail, padding, len);
+        true
+    }
+
+    /// Archives `value` into the largest stretch of free space, at the tail or after a wrap
+    /// marker at the start, returning false if it didn't fit there.
+    #[cfg(feature = "rkyv")]
+    fn write_archived<T>(&self, value: &T) -> Result<bool>
+    where
+        T: for<'a, 'b> rkyv::Serialize<RingSerializer<'a, 'b>>,
+    {
+        let header = self.header();
+        let tail = header.tail.load(Ordering::Relaxed);
+        let head = header.head.load(Ordering::Acquire);
+
+        let free = self.capacity - (tail - head);
+        let until_end = self.capacity - tail % self.capacity;
+        let (padding, room) = match free.checked_sub(until_end) {
+            Some(at_start) if at_start > until_end => (until_end, at_start),
+            _ => (0, free.min(until_end)),
+        };
+        let room = (room as usize)
+            .saturating_sub(RECORD_HEADER)
+            .min(self.max_message_len());
+
+        let data = unsafe {
+            slice::from_raw_parts_mut(self.data(tail + padding) as *mut MaybeUninit<u8>, room)
+        };
+        let Some(len) = archive(value, data)? else {
+            let max = self.max_message_len();
+            // Only worth waiting for the receiver to release messages if it fits at all.
+            if room < max && archive(value, &mut vec![MaybeUninit::uninit(); max])?.is_some() {
+                return Ok(false);
+            }
+            return Err(Error::InvalidArgument(format!(
+                "Archived message exceeds the maximum of {max} bytes"
+            )));
+        };
+        self.publish(tail, padding, len);
+        Ok(true)
+    }
+
+    /// Hands the message of `len` bytes written at `tail`, after `padding` bytes skipped with
+    /// a wrap marker, over to the receiver.
+    fn publish(&self, mut tail: u64, padding: u64, len: usize) {
+        unsafe {
+            if padding > 0 {
+                self.record(tail).write(WRAP);
+                tail += padding;
+            }
+            self.record(tail).write(len as u32);
+        }
+        let record = (RECORD_HEADER + len).next_multiple_of(RECORD_HEADER) as u64;
+        let header = self.header();
+        header.tail.store(tail + record, Ordering::Release);
+        header.not_empty.notify_all();
+    }
+
+    /// Returns the position and length of the next message, skipping a wrap marker.
+    fn peek(&self) -> Option<(u64, usize)> {
+        let header = self.header();
+        let mut head = header.head.load(Ordering::Relaxed);
+        if head == header.tail.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let mut len = unsafe { self.record(head).read() };
+        if len == WRAP {
+            head += self.capacity - head % self.capacity;
+            header.head.store(head, Ordering::Release);
+            len = unsafe { self.record(head).read() };
+        }
+        Some((head, len as usize))
+    }
+
+    /// Releases the message at `pos`, handing its space back to the sender.
+    fn release(&self, pos: u64, len: usize) {
+        let record = (RECORD_HEADER + len).next_multiple_of(RECORD_HEADER) as u64;
+        let header = self.header();
+        header.head.store(pos + record, Ordering::Release);
+        header.not_full.notify_all();
+    }
+
+    fn header(&self) -> &QueueHeader {
+        unsafe { &*(self.map.ptr() as *const QueueHeader) }
+    }
+
+    /// Pointer to the length prefix of the record at byte position `pos`.
+    fn record(&self, pos: u64) -> *mut u32 {
+        let offset = size_of::<QueueHeader>() + (pos % self.capacity) as usize;
+        unsafe { (self.map.ptr() as *mut u8).add(offset) as *mut u32 }
+    }
+
+    /// Pointer to the message of the record at byte position `pos`.
+    fn data(&self, pos: u64) -> *mut u8 {
+        unsafe { (self.record(pos) as *mut u8).add(RECORD_HEADER) }
+    }
+}
+
+impl Builder<MsgQueue> {
+    /// Opens the queue like `MsgQueue::new`.
+    pub fn build(self, capacity: usize) -> Result<MsgQueue> {
+        let capacity = capacity.next_multiple_of(RECORD_HEADER);
+        if capacity < 4 * RECORD_HEADER {
+            return Err(Error::InvalidArgument(format!(
+                "Message queue capacity must be at least {} bytes",
+                4 * RECORD_HEADER
+            )));
+        }
+        let map_len = size_of::<QueueHeader>()
+            .checked_add(capacity)
+            .and_then(NonZeroUsize::new)
+            .ok_or_else(|| {
+                Error::InvalidArgument(format!("Message queue of {capacity} bytes is too large"))
+            })?;
+
+        let path = self.namespace.path(&self.name, ".msgq");
+        let map = Mapping::open_init(
+            &path,
+            OFlag::O_CREAT,
+            &self.permissions,
+            map_len,
+            CleanupPolicy::LastCloseRefCounted,
+            |raw| unsafe {
+                let queue = raw as *mut QueueHeader;
+                (*queue).header.init::<u8>(capacity, 0);
+                (*queue).capacity = capacity as u64;
+                Ok(())
+            },
+        )?;
+
+        let queue = map.ptr() as *const QueueHeader;
+        {
+            let _init_lock = map.init_lock()?;
+            unsafe {
+                (*queue).header.validate::<u8>(capacity, 0)?;
+                (*queue).header.attach(&map)?;
+            }
+        }
+
+        Ok(MsgQueue {
+            map,
+            capacity: capacity as u64,
+        })
+    }
+}
+
+impl Drop for MsgQueue {
+    fn drop(&mut self) {
+        self.header().header.detach(&self.map);
+    }
+}
+
+/// A received message borrowed from shared memory, released back to the sender when dropped.
+pub struct MsgGuard<'a> {
+    queue: &'a MsgQueue,
+    pos: u64,
+    len: usize,
+}
+
+impl Deref for MsgGuard<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.queue.data(self.pos), self.len) }
+    }
+}
+
+impl MsgGuard<'_> {
+    /// Views a message sent with `send_value` in place. Fails if it was sent with another
+    /// type, or holds a slice of other than one value.
+    pub fn value<T: ShmSafe>(&self) -> Result<&T> {
+        match self.slice::<T>()? {
+            [value] => Ok(value),
+            values => Err(Error::Validation(format!(
+                "Message holds {} values, not one",
+                values.len()
+            ))),
+        }
+    }
+
+    /// Views a message sent with `send_slice` or `send_value` in place. Fails if it was sent
+    /// with another type.
+    pub fn slice<T: ShmSafe>(&self) -> Result<&[T]> {
+        let Some((fingerprint, data)) = self.split_first_chunk::<FINGERPRINT_LEN>() else {
+            return Err(Error::Validation(format!(
+                "Message of {} bytes is too short to hold typed values",
+                self.len
+            )));
+        };
+        if u64::from_le_bytes(*fingerprint) != T::FINGERPRINT {
+            return Err(Error::Validation(format!(
+                "Message doesn't hold values of type {}",
+                std::any::type_name::<T>()
+            )));
+        }
+        if size_of::<T>() == 0 || !data.len().is_multiple_of(size_of::<T>()) {
+            return Err(Error::Validation(format!(
+                "Message of {} bytes doesn't hold whole values of {} bytes",
+                data.len(),
+                size_of::<T>()
+            )));
+        }
+        if !(data.as_ptr() as usize).is_multiple_of(align_of::<T>()) {
+            return Err(Error::Validation(format!(
+                "Message isn't aligned to the {} bytes its values need",
+                align_of::<T>()
+            )));
+        }
+        let values = data.as_ptr() as *const T;
+        Ok(unsafe { slice::from_raw_parts(values, data.len() / size_of::<T>()) })
+    }
+
+    /// Views a message sent with `send_archived` in place as the archived form of `T`, after
+    /// validating the archive, so a malformed message fails instead of being misread. Only
+    /// available with the `rkyv` feature.
+    #[cfg(feature = "rkyv")]
+    pub fn archived<T>(&self) -> Result<&T::Archived>
+    where
+        T: rkyv::Archive,
+        T::Archived: for<'a> CheckBytes<HighValidator<'a, rancor::Error>>,
+    {
+        rkyv::access::<T::Archived, rancor::Error>(self).map_err(|e| {
+            Error::Validation(format!(
+                "Message doesn't hold an archived {}: {e}",
+                std::any::type_name::<T>()
+            ))
+        })
+    }
+}
+
+impl Drop for MsgGuard<'_> {
+    fn drop(&mut self) {
+        self.queue.release(self.pos, self.len);
+    }
+}
+
+/// Archives `value` into `data`, returning the length of the archive, or `None` if it
+/// doesn't fit.
+#[cfg(feature = "rkyv")]
+fn archive<T>(value: &T, data: &mut [MaybeUninit<u8>]) -> Result<Option<usize>>
+where
+    T: for<'a, 'b> rkyv::Serialize<RingSerializer<'a, 'b>>,
+{
+    let overflowed = Cell::new(false);
+    let writer = RingWriter {
+        buffer: Buffer::from(data),
+        overflowed: &overflowed,
+    };
+    match rkyv::api::high::to_bytes_in(value, writer) {
+        Ok(writer) => Ok(Some(writer.buffer.len())),
+        Err(_) if overflowed.get() => Ok(None),
+        Err(e) => Err(Error::InvalidArgument(format!(
+            "Failed to archive message: {e}"
+        ))),
+    }
+}
+
+/// Writes an archive into the ring like the `Buffer` it wraps, and notes in `overflowed`
+/// whether it ran out of room, which rkyv errors don't tell apart from other failures.
+#[cfg(feature = "rkyv")]
+pub struct RingWriter<'a> {
+    buffer: Buffer<'a>,
+    overflowed: &'a Cell<bool>,
+}
+
+#[cfg(feature = "rkyv")]
+impl Positional for RingWriter<'_> {
+    fn pos(&self) -> usize {
+        self.buffer.pos()
+    }
+}
+
+#[cfg(feature = "rkyv")]
+impl<E: rancor::Source> Writer<E> for RingWriter<'_> {
+    fn write(&mut self, bytes: &[u8]) -> std::result::Result<(), E> {
+        // Running out of room is the only way writing to a buffer fails.
+        Writer::<E>::write(&mut self.buffer, bytes).inspect_err(|_| self.overflowed.set(true))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+
+    /// Values aligned more strictly than messages are.
+    #[repr(C, align(16))]
+    #[derive(Clone, Copy)]
+    struct Wide([u64; 2]);
+
+    unsafe impl ShmSafe for Wide {}
+
+    /// Opens a queue under a name unique to this test and process, removed again on drop.
+    struct TestQueue {
+        name: String,
+        queue: MsgQueue,
+    }
+
+    impl TestQueue {
+        fn new(test: &str) -> Self {
+            let name = format!("test-{test}-{}", process::id());
+            MsgQueue::unlink(&name).ok();
+            let queue = MsgQueue::new(&name, 4096).unwrap();
+            Self { name, queue }
+        }
+    }
+
+    impl Drop for TestQueue {
+        fn drop(&mut self) {
+            MsgQueue::unlink(&self.name).ok();
+        }
+    }
+
+    /// A message with the fingerprint of `T` followed by `data`.
+    fn typed_message<T: ShmSafe>(data: &[u8]) -> Vec<u8> {
+        let mut msg = T::FINGERPRINT.to_le_bytes().to_vec();
+        msg.extend_from_slice(data);
+        msg
+    }
+
+    #[test]
+    fn slice_views_values_of_the_sent_type() {
+        let mut test = TestQueue::new("msq-slice");
+        test.queue.send_slice(&[1u32, 2, 3]).unwrap();
+        assert_eq!(
+            test.queue.recv().unwrap().slice::<u32>().unwrap(),
+            [1, 2, 3]
+        );
+        test.queue.send_value(&4u64).unwrap();
+        assert_eq!(*test.queue.recv().unwrap().value::<u64>().unwrap(), 4);
+    }
+
+    #[test]
+    fn slice_rejects_other_types() {
+        let mut test = TestQueue::new("msq-type");
+        test.queue.send_slice(&[1u32, 2]).unwrap();
+        let msg = test.queue.recv().unwrap();
+        assert!(matches!(msg.slice::<u64>(), Err(Error::Validation(_))));
+        assert!(matches!(msg.slice::<i32>(), Err(Error::Validation(_))));
+        assert!(matches!(msg.value::<u32>(), Err(Error::Validation(_))));
+        drop(msg);
+
+        test.queue.send(b"short").unwrap();
+        let msg = test.queue.recv().unwrap();
+        assert!(matches!(msg.slice::<u8>(), Err(Error::Validation(_))));
+    }
+
+    #[test]
+    fn slice_rejects_partial_values() {
+        let mut test = TestQueue::new("msq-partial");
+        test.queue.send(&typed_message::<u64>(&[0; 12])).unwrap();
+        let msg = test.queue.recv().unwrap();
+        assert!(matches!(msg.slice::<u64>(), Err(Error::Validation(_))));
+    }
+
+    #[test]
+    fn slice_rejects_misaligned_values() {
+        let mut test = TestQueue::new("msq-align");
+        assert!(matches!(
+            test.queue.send_value(&Wide([1, 2])),
+            Err(Error::InvalidArgument(_))
+        ));
+
+        // Records are aligned to 8 bytes, so an empty message moves the next one by 8.
+        test.queue.send(&[]).unwrap();
+        let aligned = test
+            .queue
+            .recv()
+            .unwrap()
+            .as_ptr()
+            .addr()
+            .is_multiple_of(align_of::<Wide>());
+        if aligned {
+            test.queue.send(&[]).unwrap();
+            drop(test.queue.recv().unwrap());
+        }
+        test.queue.send(&typed_message::<Wide>(&[0; 16])).unwrap();
+        let msg = test.queue.recv().unwrap();
+        assert_ne!(
+            msg[FINGERPRINT_LEN..].as_ptr().addr() % align_of::<Wide>(),
+            0
+        );
+        assert!(matches!(msg.slice::<Wide>(), Err(Error::Validation(_))));
+    }
+
+    #[cfg(feature = "rkyv")]
+    #[derive(rkyv::Archive, rkyv::Serialize)]
+    struct Record {
+        id: u32,
+        name: String,
+        samples: Vec<u64>,
+    }
+
+    #[cfg(feature = "rkyv")]
+    fn record(id: u32, name_len: usize) -> Record {
+        Record {
+            id,
+            name: "x".repeat(name_len),
+            samples: (0..u64::from(id % 5)).collect(),
+        }
+    }
+
+    /// A value whose serialization always fails, without running out of room.
+    #[cfg(feature = "rkyv")]
+    struct Unserializable;
+
+    #[cfg(feature = "rkyv")]
+    impl rkyv::Archive for Unserializable {
+        type Archived = ();
+        type Resolver = ();
+
+        fn resolve(&self, _: (), _: rkyv::Place<()>) {}
+    }
+
+    #[cfg(feature = "rkyv")]
+    impl<S: rancor::Fallible + ?Sized> rkyv::Serialize<S> for Unserializable
+    where
+        S::Error: rancor::Source,
+    {
+        fn serialize(&self, _: &mut S) -> std::result::Result<(), S::Error> {
+            Err(rancor::Source::new(std::io::Error::other("unserializable")))
+        }
+    }
+
+    #[cfg(feature = "rkyv")]
+    #[test]
+    fn archived_messages_round_trip() {
+        let mut test = TestQueue::new("msq-archived");
+        // Enough rounds to wrap around the ring several times.
+        for id in 0..200 {
+            test.queue
+                .send_archived(&record(id, id as usize % 50))
+                .unwrap();
+            let msg = test.queue.recv().unwrap();
+            let archived = msg.archived::<Record>().unwrap();
+            assert_eq!(archived.id, id);
+            assert_eq!(archived.name.len(), id as usize % 50);
+            assert_eq!(archived.samples.len(), id as usize % 5);
+        }
+    }
+
+    #[cfg(feature = "rkyv")]
+    #[test]
+    fn archived_rejects_corrupted_archives() {
+        let mut test = TestQueue::new("msq-corrupted");
+        let mut archive = rkyv::to_bytes::<rancor::Error>(&record(3, 20))
+            .unwrap()
+            .to_vec();
+        test.queue.send(&archive).unwrap();
+        assert!(test.queue.recv().unwrap().archived::<Record>().is_ok());
+
+        // The root ends the archive, so this points its fields out of bounds.
+        let len = archive.len();
+        archive[len - 8..].fill(0xff);
+        test.queue.send(&archive).unwrap();
+        let msg = test.queue.recv().unwrap();
+        assert!(matches!(
+            msg.archived::<Record>(),
+            Err(Error::Validation(_))
+        ));
+    }
+
+    #[cfg(feature = "rkyv")]
+    #[test]
+    fn send_archived_fails_on_errors_other_than_running_out_of_room() {
+        let mut test = TestQueue::new("msq-unserializable");
+        // Leaves less room than the maximum message length.
+        test.queue.send(&[0; 64]).unwrap();
+        assert!(matches!(
+            test.queue.send_archived(&Unserializable),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            test.queue.try_send_archived(&Unserializable),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[cfg(feature = "rkyv")]
+    #[test]
+    fn send_archived_waits_for_room_up_to_the_maximum() {
+        let mut test = TestQueue::new("msq-archived-full");
+        let large = record(1, test.queue.max_message_len() / 2);
+        test.queue.send(&[0; 64]).unwrap();
+        while test.queue.try_send_archived(&large).unwrap() {}
+        assert!(
+            !test
+                .queue
+                .send_archived_timeout(&large, Duration::from_millis(10))
+                .unwrap()
+        );
+
+        let too_large = record(1, test.queue.max_message_len());
+        assert!(matches!(
+            test.queue.try_send_archived(&too_large),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+}
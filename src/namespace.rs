@@ -0,0 +1,167 @@
+use std::env;
+
+#[cfg(target_os = "linux")]
+use crate::futex_mutex::{FutexMutex, FutexMutexBuilder};
+use crate::{
+    backend::ObjectPath,
+    error::{Error, Result},
+    r_mtx::{RMtx, RMtxBuilder},
+    registry::{ProcessRegistry, Registration},
+    sem::Sem,
+    shm::{Shm, ShmBuilder},
+    shm_mutex::{ShmMutex, ShmMutexBuilder},
+};
+
+/// Where named objects live: a base directory for their backing files and a prefix added to
+/// every name, so separate applications, containers or test runs don't collide.
+///
+/// The default namespace opens POSIX shared memory objects with `shm_open` (backed by
+/// /dev/shm on Linux) without a prefix, which is what the plain constructors such as
+/// `Shm::new` use.
+///
+/// Named semaphores and `MqQueue`s are always placed by the system, so only the prefix applies
+/// to them.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Namespace {
+    /// Directory of the backing files, or `None` to use `shm_open`.
+    dir: Option<String>,
+    prefix: String,
+    /// Whether handles are recorded in a `ProcessRegistry`.
+    registry: bool,
+}
+
+impl Namespace {
+    /// Places backing files in `dir`, which must already exist and should be on a tmpfs
+    /// mount so the objects never hit the disk.
+    pub fn new(dir: impl Into<String>) -> Self {
+        Self {
+            dir: Some(dir.into()),
+            prefix: String::new(),
+            registry: false,
+        }
+    }
+
+    /// Places backing files in `$XDG_RUNTIME_DIR`, the per-user tmpfs of systemd systems.
+    pub fn runtime_dir() -> Result<Self> {
+        let dir = env::var("XDG_RUNTIME_DIR")
+            .map_err(|_| Error::Validation("XDG_RUNTIME_DIR is not set".to_owned()))?;
+        Ok(Self::new(dir))
+    }
+
+    /// Adds `prefix` in front of the name of every object opened through the namespace.
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Records the handles opened through the namespace in its `ProcessRegistry`, so which
+    /// processes have which objects open can be looked up. Applies to the mutexes,
+    /// `Shm`, `ShmMutex` and `ShmLog`, but not to handles of other kinds.
+    pub fn with_registry(mut self) -> Self {
+        self.registry = true;
+        self
+    }
+
+    /// Opens the registry of the namespace, creating it if it doesn't exist yet. It can be
+    /// opened whether or not this namespace was created with `with_registry`, e.g. by a
+    /// tool inspecting the objects of an application.
+    pub fn registry(&self) -> Result<ProcessRegistry> {
+        ProcessRegistry::open(self)
+    }
+
+    /// The directory holding the backing files, or `None` if they are POSIX shared memory
+    /// objects.
+    pub fn dir(&self) -> Option<&str> {
+        self.dir.as_deref()
+    }
+
+    /// The prefix added to every name.
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Returns true if the namespace was created with `with_registry`.
+    pub(crate) fn has_registry(&self) -> bool {
+        self.registry
+    }
+
+    /// Returns a builder for the `RMtx` called `name` in this namespace.
+    pub fn mtx(&self, name: &str) -> RMtxBuilder {
+        RMtx::builder(name).namespace(self)
+    }
+
+    /// Returns a builder for the `FutexMutex` called `name` in this namespace.
+    #[cfg(target_os = "linux")]
+    pub fn futex_mutex(&self, name: &str) -> FutexMutexBuilder {
+        FutexMutex::builder(name).namespace(self)
+    }
+
+    /// Returns a builder for the `Shm` called `name` in this namespace.
+    pub fn shm<T: 'static>(&self, name: &str) -> ShmBuilder<T> {
+        Shm::builder(name).namespace(self)
+    }
+
+    /// Returns a builder for the `ShmMutex` called `name` in this namespace.
+    pub fn shm_mutex<T: 'static>(&self, name: &str) -> ShmMutexBuilder<T> {
+        ShmMutex::builder(name).namespace(self)
+    }
+
+    /// Opens the semaphore called `name` in this namespace, see `Sem::new`.
+    pub fn sem(&self, name: &str, initial: u32) -> Result<Sem> {
+        Sem::builder(name).namespace(self).build(initial)
+    }
+
+    /// Unlinks the `RMtx` called `name` in this namespace.
+    pub fn unlink_mtx(&self, name: &str) -> Result<()> {
+        self.unlink_file(name, ".mtx")
+    }
+
+    /// Unlinks the `FutexMutex` called `name` in this namespace.
+    #[cfg(target_os = "linux")]
+    pub fn unlink_futex_mutex(&self, name: &str) -> Result<()> {
+        self.unlink_file(name, ".fmx")
+    }
+
+    /// Unlinks the `Shm` called `name` in this namespace.
+    pub fn unlink_shm(&self, name: &str) -> Result<()> {
+        self.unlink_file(name, "")
+    }
+
+    /// Unlinks the `ShmMutex` called `name` in this namespace.
+    pub fn unlink_shm_mutex(&self, name: &str) -> Result<()> {
+        self.unlink_file(name, ".smx")
+    }
+
+    /// Unlinks the semaphore called `name` in this namespace.
+    pub fn unlink_sem(&self, name: &str) -> Result<()> {
+        Sem::unlink(&self.object_name(name))
+    }
+
+    /// Returns where the backing file of the object called `name` lives, whose kind is
+    /// told apart by `suffix`.
+    pub(crate) fn path(&self, name: &str, suffix: &str) -> ObjectPath {
+        match &self.dir {
+            Some(dir) => ObjectPath::File(format!("{dir}/{}{name}{suffix}", self.prefix)),
+            None => ObjectPath::Shm(format!("/{}{name}{suffix}", self.prefix)),
+        }
+    }
+
+    /// Records that this process opened the object called `name`, whose kind is told apart
+    /// by `suffix`, if the namespace keeps a registry.
+    pub(crate) fn register(&self, name: &str, suffix: &str) -> Result<Option<Registration>> {
+        if !self.registry {
+            return Ok(None);
+        }
+        Ok(self.registry()?.register(&format!("{name}{suffix}")))
+    }
+
+    /// The name of an object that lives outside the directory of the namespace, like a
+    /// semaphore, with the prefix added.
+    pub(crate) fn object_name(&self, name: &str) -> String {
+        format!("{}{name}", self.prefix)
+    }
+
+    fn unlink_file(&self, name: &str, suffix: &str) -> Result<()> {
+        self.path(name, suffix).unlink()
+    }
+}
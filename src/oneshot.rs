@@ -0,0 +1,284 @@
+use std::{
+    cell::UnsafeCell,
+    marker::PhantomData,
+    mem::{MaybeUninit, size_of},
+    num::NonZeroUsize,
+    process, ptr,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+
+use nix::fcntl::OFlag;
+
+use crate::{
+    builder::Builder,
+    cleanup::CleanupPolicy,
+    error::{Error, Result},
+    futex::EventCount,
+    header::Header,
+    liveness::{LIVENESS_POLL, is_alive},
+    map::Mapping,
+    namespace::Namespace,
+    shm_safe::ShmSafe,
+};
+
+const EMPTY: u32 = 0;
+const WRITING: u32 = 1;
+const SET: u32 = 2;
+const CANCELLED: u32 = 3;
+
+#[repr(C)]
+struct OneshotState<T> {
+    header: Header,
+    state: AtomicU32,
+    /// Process of the registered sender, or 0 before one registered.
+    sender: AtomicU32,
+    /// Notified when the value is set or the oneshot is cancelled.
+    event: EventCount,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// A value in shared memory that one process sets exactly once, and any number of processes
+/// wait for.
+///
+/// The producer registers with `Oneshot::sender` and sets the value through the returned
+/// `OneshotSender`. If it drops the sender without setting a value, or its process dies,
+/// waiters fail with `Error::Cancelled` instead of waiting forever. Waiters that start
+/// before a sender registered wait for one to show up.
+///
+/// A oneshot completes only once. Unlink it and create a new one to use the name again.
+pub struct Oneshot<T: 'static> {
+    map: Mapping,
+    _marker: PhantomData<T>,
+}
+
+impl<T: ShmSafe + Copy> Oneshot<T> {
+    /// Opens the oneshot in /dev/shm, creating it without a value if it doesn't exist.
+    pub fn new(name: &str) -> Result<Self> {
+        Self::builder(name).build()
+    }
+
+    /// Returns a builder for setting the namespace and the permissions the oneshot is created with.
+    pub fn builder(name: &str) -> Builder<Self> {
+        Builder::new(name)
+    }
+
+    /// Opens the oneshot like `new` and registers the calling process as the one setting it.
+    /// Fails if it already completed, or if another live process registered as its sender.
+    pub fn sender(name: &str) -> Result<OneshotSender<T>> {
+        Self::builder(name).sender()
+    }
+
+    /// Unlinks (deletes) the oneshot from /dev/shm.
+    pub fn unlink(name: &str) -> Result<()> {
+        Self::unlink_in(&Namespace::default(), name)
+    }
+
+    /// Unlinks the oneshot called `name` in `namespace`.
+    pub fn unlink_in(namespace: &Namespace, name: &str) -> Result<()> {
+        namespace.path(name, ".osh").unlink()
+    }
+
+    /// Blocks until the value is set and returns it. Fails with `Error::Cancelled` if the
+    /// sender went away without setting it.
+    pub fn wait(&self) -> Result<T> {
+        let value = self.wait_until(None)?;
+        Ok(value.expect("waiting without a timeout always yields a value"))
+    }
+
+    /// Blocks until the value is set and returns it, or `None` if `timeout` elapsed first.
+    /// Fails with `Error::Cancelled` if the sender went away without setting it.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<Option<T>> {
+        self.wait_until(Some(Instant::now() + timeout))
+    }
+
+    /// Waits until the value is set and returns it, without blocking the thread. Only
+    /// available on Linux with the `tokio` feature, and must be called within a tokio
+    /// runtime.
+    #[cfg(all(target_os = "linux", feature = "tokio"))]
+    pub async fn wait_async(&self) -> Result<T> {
+        // Waking up now and then notices a sender that died.
+        let bridge = unsafe { self.state().event.bridge(Some(LIVENESS_POLL))? };
+        let notifier = crate::async_io::Notifier::new(bridge)?;
+        notifier.until(|| self.try_get()).await
+    }
+
+    /// Returns the value if it is set, or `None` if it isn't yet. Fails with
+    /// `Error::Cancelled` if the sender went away without setting it.
+    pub fn try_get(&self) -> Result<Option<T>> {
+        let state = self.state();
+        match state.state.load(Ordering::Acquire) {
+            SET => {
+                let value = unsafe { ptr::read(state.value.get()).assume_init() };
+                Ok(Some(value))
+            }
+            CANCELLED => Err(Error::Cancelled),
+            current => {
+                let sender = state.sender.load(Ordering::Acquire);
+                if sender == 0 || is_alive(sender) {
+                    return Ok(None);
+                }
+                match state.state.compare_exchange(
+                    current,
+                    CANCELLED,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                ) {
+                    Ok(_) => {
+                        state.event.notify_all();
+                        Err(Error::Cancelled)
+                    }
+                    // Set or cancelled just now.
+                    Err(_) => self.try_get(),
+                }
+            }
+        }
+    }
+
+    /// Returns true if the value is set.
+    pub fn is_set(&self) -> bool {
+        self.state().state.load(Ordering::Acquire) == SET
+    }
+
+    fn wait_until(&self, deadline: Option<Instant>) -> Result<Option<T>> {
+        loop {
+            // Wake up now and then to notice a sender that died.
+            let poll = match deadline {
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return self.try_get();
+                    }
+                    left.min(LIVENESS_POLL)
+                }
+                None => LIVENESS_POLL,
+            };
+            let result = self
+                .state()
+                .event
+                .wait_for(Some(poll), || self.try_get().transpose())?;
+            if let Some(result) = result {
+                return result.map(Some);
+            }
+        }
+    }
+
+    fn state(&self) -> &OneshotState<T> {
+        unsafe { &*(self.map.ptr() as *const OneshotState<T>) }
+    }
+}
+
+impl<T: ShmSafe + Copy> Builder<Oneshot<T>> {
+    /// Opens the oneshot like `Oneshot::new`.
+    pub fn build(self) -> Result<Oneshot<T>> {
+        if size_of::<T>() == 0 {
+            return Err(Error::InvalidArgument(
+                "Cannot use zero-sized type in shared memory".to_owned(),
+            ));
+        }
+        let len =
+            NonZeroUsize::new(size_of::<OneshotState<T>>()).expect("OneshotState has nonzero size");
+
+        let path = self.namespace.path(&self.name, ".osh");
+        let map = Mapping::open_init(
+            &path,
+            OFlag::O_CREAT,
+            &self.permissions,
+            len,
+            CleanupPolicy::LastCloseRefCounted,
+            |raw| unsafe {
+                let state = raw as *mut OneshotState<T>;
+                (*state).header.init::<T>(size_of::<T>(), 0);
+                Ok(())
+            },
+        )?;
+
+        let state = map.ptr() as *const OneshotState<T>;
+        {
+            let _init_lock = map.init_lock()?;
+            unsafe {
+                (*state).header.validate::<T>(size_of::<T>(), 0)?;
+                (*state).header.attach(&map)?;
+            }
+        }
+
+        Ok(Oneshot {
+            map,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Opens the oneshot like `Oneshot::sender`.
+    pub fn sender(self) -> Result<OneshotSender<T>> {
+        let oneshot = self.build()?;
+        {
+            let state = oneshot.state();
+            let _init_lock = oneshot.map.init_lock()?;
+            if state.state.load(Ordering::Acquire) != EMPTY {
+                return Err(Error::InvalidArgument(
+                    "Oneshot was already completed".to_owned(),
+                ));
+            }
+            let sender = state.sender.load(Ordering::Acquire);
+            if sender != 0 && is_alive(sender) {
+                return Err(Error::InvalidArgument(format!(
+                    "Oneshot already has a sender in process {sender}"
+                )));
+            }
+            state.sender.store(process::id(), Ordering::Release);
+        }
+        Ok(OneshotSender {
+            oneshot,
+            done: false,
+        })
+    }
+}
+
+impl<T: 'static> Drop for Oneshot<T> {
+    fn drop(&mut self) {
+        let state = self.map.ptr() as *const OneshotState<T>;
+        unsafe { (*state).header.detach(&self.map) };
+    }
+}
+
+/// Setting end of a `Oneshot`, created with `Oneshot::sender`. Dropping it without calling
+/// `send` cancels the oneshot.
+pub struct OneshotSender<T: 'static> {
+    oneshot: Oneshot<T>,
+    done: bool,
+}
+
+impl<T: ShmSafe + Copy> OneshotSender<T> {
+    /// Sets the value and wakes all waiters.
+    pub fn send(mut self, value: T) -> Result<()> {
+        self.done = true;
+        let state = self.oneshot.state();
+        state
+            .state
+            .compare_exchange(EMPTY, WRITING, Ordering::AcqRel, Ordering::Acquire)
+            .map_err(|_| Error::InvalidArgument("Oneshot was already completed".to_owned()))?;
+        unsafe { (*state.value.get()).write(value) };
+        state.state.store(SET, Ordering::Release);
+        state.event.notify_all();
+        Ok(())
+    }
+
+    /// Cancels the oneshot, failing its waiters with `Error::Cancelled`.
+    pub fn cancel(self) {}
+}
+
+impl<T: 'static> Drop for OneshotSender<T> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        let state = unsafe { &*(self.oneshot.map.ptr() as *const OneshotState<T>) };
+        if state
+            .state
+            .compare_exchange(EMPTY, CANCELLED, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            state.event.notify_all();
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use std::{
+    os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use nix::{
+    errno::Errno,
+    libc::{POLLIN, SYS_pidfd_open, c_int, pid_t, poll, pollfd, syscall},
+};
+
+use crate::{
+    error::{Error, Result},
+    event::wait_readable,
+};
+
+/// Notices when another process exits, through a pidfd that becomes readable at that moment.
+///
+/// Robust mutexes only tell a process that a peer died once it takes a lock the peer held.
+/// A watcher reports the death right away, e.g. to fail over a service, by blocking in
+/// `wait`, through `on_exit`, or by registering it with poll, epoll or tokio. The pidfd
+/// refers to the process itself, so a new process reusing its pid isn't mistaken for it.
+///
+/// Only available on Linux 5.3 and later.
+pub struct PeerWatcher {
+    fd: OwnedFd,
+    pid: u32,
+}
+
+impl PeerWatcher {
+    /// Starts watching the process `pid`. Fails if there is no such process, including when
+    /// it has exited and been reaped already.
+    pub fn new(pid: u32) -> Result<Self> {
+        let raw_fd = Errno::result(unsafe { syscall(SYS_pidfd_open, pid as pid_t, 0) })
+            .map_err(Error::sys("pidfd_open"))?;
+        Ok(Self {
+            fd: unsafe { OwnedFd::from_raw_fd(raw_fd as c_int) },
+            pid,
+        })
+    }
+
+    /// The process being watched.
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Returns true if the process has exited, without blocking.
+    pub fn has_exited(&self) -> Result<bool> {
+        self.poll(0)
+    }
+
+    /// Blocks until the process exits.
+    pub fn wait(&self) -> Result<()> {
+        while !self.has_exited()? {
+            wait_readable(&self.fd)?;
+        }
+        Ok(())
+    }
+
+    /// Blocks until the process exits or `timeout` passes, returning true if it exited.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<bool> {
+        self.poll(timeout.as_millis().min(c_int::MAX as u128) as c_int)
+    }
+
+    /// Waits without blocking the thread until the process exits. Must be called within a
+    /// tokio runtime.
+    #[cfg(feature = "tokio")]
+    pub async fn wait_async(&self) -> Result<()> {
+        crate::async_io::readable(&self.fd, || Ok(self.has_exited()?.then_some(()))).await
+    }
+
+    /// Calls `callback` with the pid from a helper thread once the process exits, returning
+    /// the thread. The callback isn't called if waiting fails.
+    pub fn on_exit<F>(self, callback: F) -> Result<JoinHandle<()>>
+    where
+        F: FnOnce(u32) + Send + 'static,
+    {
+        thread::Builder::new()
+            .name("nix-ipc-peer".to_owned())
+            .spawn(move || {
+                if self.wait().is_ok() {
+                    callback(self.pid);
+                }
+            })
+            .map_err(Error::Io)
+    }
+
+    /// Polls the pidfd for up to `timeout_ms` milliseconds, returning true if it is readable.
+    fn poll(&self, timeout_ms: c_int) -> Result<bool> {
+        let mut fds = [pollfd {
+            fd: self.fd.as_raw_fd(),
+            events: POLLIN,
+            revents: 0,
+        }];
+        match Errno::result(unsafe { poll(fds.as_mut_ptr(), 1, timeout_ms) }) {
+            Ok(ready) => Ok(ready > 0),
+            Err(Errno::EINTR) => Ok(false),
+            Err(e) => Err(Error::sys("poll")(e)),
+        }
+    }
+}
+
+impl AsFd for PeerWatcher {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
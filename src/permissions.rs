@@ -0,0 +1,82 @@
+use std::{os::fd::AsFd, path::Path};
+
+use nix::{
+    fcntl::AT_FDCWD,
+    sys::stat::{FchmodatFlags, Mode, fchmod, fchmodat, fstat},
+    unistd::{Gid, Uid, chown, fchown},
+};
+
+use crate::error::{Error, Result};
+
+/// Access rights given to the backing file of a named object when a handle creates it, e.g.
+/// to share an object between a daemon and workers running as other users of one group.
+///
+/// The mode is applied with `fchmod` after creating the file, so the umask doesn't strip
+/// any of its bits. Opening an existing object never changes its permissions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions {
+    mode: Mode,
+    owner: Option<Uid>,
+    group: Option<Gid>,
+}
+
+impl Permissions {
+    /// Permission bits `mode`, e.g. `0o660` for read and write access by the group. Bits
+    /// other than the permission bits are ignored.
+    pub fn new(mode: u32) -> Self {
+        Self {
+            mode: Mode::from_bits_truncate((mode & 0o777) as _),
+            owner: None,
+            group: None,
+        }
+    }
+
+    /// Hands the file over to the user `owner`, which requires privileges such as
+    /// `CAP_CHOWN`.
+    pub fn owner(mut self, owner: u32) -> Self {
+        self.owner = Some(Uid::from_raw(owner));
+        self
+    }
+
+    /// Hands the file over to the group `group`, which the creating process must be a
+    /// member of unless it is privileged.
+    pub fn group(mut self, group: u32) -> Self {
+        self.group = Some(Gid::from_raw(group));
+        self
+    }
+
+    pub(crate) fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Applies the permissions to the freshly created file `fd`.
+    pub(crate) fn apply<Fd: AsFd>(&self, fd: Fd) -> Result<()> {
+        // Where the umask left the mode alone there is nothing to change.
+        let current = fstat(&fd).map_err(Error::sys("fstat"))?.st_mode;
+        if Mode::from_bits_truncate(current) != self.mode {
+            fchmod(&fd, self.mode).map_err(Error::sys("fchmod"))?;
+        }
+        if self.owner.is_some() || self.group.is_some() {
+            fchown(&fd, self.owner, self.group).map_err(Error::sys("fchown"))?;
+        }
+        Ok(())
+    }
+
+    /// Applies the permissions to the freshly created file at `path`, for objects that
+    /// aren't opened as a file descriptor.
+    pub(crate) fn apply_path(&self, path: &Path) -> Result<()> {
+        fchmodat(AT_FDCWD, path, self.mode, FchmodatFlags::FollowSymlink)
+            .map_err(Error::sys("fchmodat"))?;
+        if self.owner.is_some() || self.group.is_some() {
+            chown(path, self.owner, self.group).map_err(Error::sys("chown"))?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for Permissions {
+    /// Read and write access for the creating user only.
+    fn default() -> Self {
+        Self::new(0o600)
+    }
+}
@@ -0,0 +1,132 @@
+use std::{
+    process,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use nix::{
+    errno::Errno,
+    libc::{
+        CLOCK_MONOTONIC, EBUSY, ENOTRECOVERABLE, EOWNERDEAD, EPERM, ETIMEDOUT, c_int, timespec,
+    },
+};
+
+use crate::{
+    error::Result,
+    futex,
+    liveness::{LIVENESS_POLL, is_alive},
+    time,
+};
+
+const CONSISTENT: u32 = 0;
+const INCONSISTENT: u32 = 1;
+const NOT_RECOVERABLE: u32 = 2;
+
+/// A process-shared mutex for targets without robust pthread mutexes, reporting owner death
+/// with the same return codes as a robust `pthread_mutex_t`.
+///
+/// The lock word holds the pid of the owner, so waiters notice a dead owner by checking
+/// whether its process still exists, and take the lock over. Unlike a robust mutex this
+/// only detects the death of whole processes, and a recycled pid makes a dead owner look
+/// alive until the new process exits.
+#[repr(C)]
+pub(crate) struct PidMutex {
+    owner: AtomicU32,
+    waiters: AtomicU32,
+    state: AtomicU32,
+}
+
+impl PidMutex {
+    /// Blocks until the mutex is acquired or the absolute monotonic `deadline` passed.
+    /// Returns 0, `EOWNERDEAD`, `ETIMEDOUT` or `ENOTRECOVERABLE`.
+    pub(crate) fn lock(&self, deadline: Option<&timespec>) -> Result<c_int> {
+        loop {
+            let owner = match self.try_acquire() {
+                Ok(err) => return Ok(err),
+                Err(owner) => owner,
+            };
+
+            let poll = time::deadline(CLOCK_MONOTONIC, LIVENESS_POLL)?;
+            self.waiters.fetch_add(1, Ordering::SeqCst);
+            let woken = futex::wait_until(&self.owner, owner, Some(&poll));
+            self.waiters.fetch_sub(1, Ordering::SeqCst);
+            woken?;
+
+            if let Some(deadline) = deadline
+                && time::remaining(CLOCK_MONOTONIC, deadline)?.is_none()
+            {
+                return Ok(self.try_acquire().unwrap_or(ETIMEDOUT));
+            }
+        }
+    }
+
+    /// Acquires the mutex if that's possible without blocking.
+    /// Returns 0, `EOWNERDEAD`, `EBUSY` or `ENOTRECOVERABLE`.
+    pub(crate) fn try_lock(&self) -> c_int {
+        self.try_acquire().unwrap_or(EBUSY)
+    }
+
+    /// Releases the mutex. Unlocking it after owner death without marking it consistent
+    /// first makes it permanently unusable, like a robust mutex.
+    pub(crate) fn unlock(&self) -> c_int {
+        if self.owner.load(Ordering::Relaxed) != process::id() {
+            return EPERM;
+        }
+        if self.state.load(Ordering::Relaxed) == INCONSISTENT {
+            self.state.store(NOT_RECOVERABLE, Ordering::Relaxed);
+        }
+        self.owner.store(0, Ordering::SeqCst);
+        if self.waiters.load(Ordering::SeqCst) > 0 {
+            futex::wake(&self.owner, 1).ok();
+        }
+        0
+    }
+
+    /// Marks the state protected by the mutex consistent again after owner death.
+    pub(crate) fn consistent(&self) -> c_int {
+        match self.state.compare_exchange(
+            INCONSISTENT,
+            CONSISTENT,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => 0,
+            Err(_) => Errno::EINVAL as c_int,
+        }
+    }
+
+    /// The word holding the pid of the owner, or 0 while the mutex is unlocked.
+    #[cfg(all(target_os = "linux", feature = "tokio"))]
+    pub(crate) fn owner(&self) -> &AtomicU32 {
+        &self.owner
+    }
+
+    /// Takes the mutex if it is free or its owner died, otherwise returns the owner's pid.
+    fn try_acquire(&self) -> std::result::Result<c_int, u32> {
+        if self.state.load(Ordering::Acquire) == NOT_RECOVERABLE {
+            return Ok(ENOTRECOVERABLE);
+        }
+
+        let me = process::id();
+        let owner = match self
+            .owner
+            .compare_exchange(0, me, Ordering::Acquire, Ordering::Relaxed)
+        {
+            Ok(_) => return Ok(0),
+            Err(owner) => owner,
+        };
+        if is_alive(owner) {
+            return Err(owner);
+        }
+        match self
+            .owner
+            .compare_exchange(owner, me, Ordering::Acquire, Ordering::Relaxed)
+        {
+            Ok(_) => {
+                self.state.store(INCONSISTENT, Ordering::Relaxed);
+                Ok(EOWNERDEAD)
+            }
+            // Another waiter took over first.
+            Err(_) => Err(owner),
+        }
+    }
+}
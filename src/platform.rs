@@ -0,0 +1,66 @@
+/// What the target platform supports natively, and so which fallbacks the crate uses.
+///
+/// The values are fixed at compile time from the target OS and the `pid-mutex` feature:
+///
+/// | | Linux | FreeBSD | macOS | NetBSD, OpenBSD |
+/// |---|---|---|---|---|
+/// | `robust_mutex` | yes | yes | no | no |
+/// | `shared_rwlock` | yes | yes | yes | no |
+/// | `wait_on_address` | futex | `_umtx_op` | `__ulock_wait` | no |
+/// | `memfd` | yes | no | no | no |
+/// | `message_queue` | yes | no | no | no |
+/// | `ready_fd` | yes | no | no | no |
+/// | `credential_passing` | yes | no | no | no |
+///
+/// Named objects are created with `shm_open` everywhere. Outside Linux, the locks serializing
+/// their initialization and counting their handles are flocks on lock files in the temporary
+/// directory, as shared memory objects can't be locked there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Capabilities {
+    /// Process-shared robust pthread mutexes. Without them, `RMtx`, `ShmMutex` and `ShmArray`
+    /// use a mutex holding the pid of its owner, which notices the death of the owning
+    /// process by checking whether the pid still exists. It reports owner death the same way,
+    /// but only for whole processes, and can be fooled by a recycled pid.
+    ///
+    /// The `pid-mutex` feature selects that fallback everywhere, e.g. for a libc whose robust
+    /// mutexes are missing or broken, as on older Android. All processes sharing a mutex must be built the same way, as the
+    /// two don't share a layout.
+    pub robust_mutex: bool,
+    /// Process-shared pthread reader-writer locks. Without them, `RwLk` uses a lock built on
+    /// atomics and `wait_on_address`.
+    pub shared_rwlock: bool,
+    /// A call blocking on a word of shared memory until another process wakes it. Without it,
+    /// blocked channels and locks poll every millisecond instead.
+    pub wait_on_address: bool,
+    /// Anonymous memory files, which `Shm::anonymous` uses and `Shm::anonymous_sealed` needs.
+    /// Without them, anonymous segments are backed by unlinked temporary files.
+    pub memfd: bool,
+    /// POSIX message queues, needed for `MqQueue`.
+    pub message_queue: bool,
+    /// eventfd, timerfd, signalfd and pidfd, needed for `Event`, `Timer`, `Signals` and
+    /// `ready_fd` on channels.
+    pub ready_fd: bool,
+    /// Sending credentials over unix sockets with `send_credentials`.
+    pub credential_passing: bool,
+}
+
+impl Capabilities {
+    /// Returns the capabilities of the platform the crate was compiled for.
+    pub const fn current() -> Self {
+        let linux = cfg!(target_os = "linux");
+        Self {
+            robust_mutex: cfg!(robust_mutex),
+            shared_rwlock: cfg!(shared_rwlock),
+            wait_on_address: cfg!(any(
+                target_os = "linux",
+                target_os = "macos",
+                target_os = "freebsd"
+            )),
+            memfd: linux,
+            message_queue: linux,
+            ready_fd: linux,
+            credential_passing: linux,
+        }
+    }
+}
@@ -1,30 +1,25 @@
-use std::{
-    ffi::c_void,
-    mem::{size_of, zeroed},
-    num::NonZeroUsize,
-    os::{
-        fd::{FromRawFd, OwnedFd},
-        unix::io::AsRawFd,
-    },
-};
+use std::{iter, mem::size_of, num::NonZeroUsize, thread, time::Duration};
 
-use anyhow::{Result, anyhow};
 use nix::{
-    errno::Errno,
-    fcntl::{Flock, FlockArg, OFlag, open},
-    libc::{
-        EOWNERDEAD, PTHREAD_MUTEX_ROBUST, PTHREAD_PROCESS_SHARED, c_int, dup, munmap, off_t,
-        pthread_mutex_consistent, pthread_mutex_init, pthread_mutex_lock, pthread_mutex_t,
-        pthread_mutex_unlock, pthread_mutexattr_destroy, pthread_mutexattr_init,
-        pthread_mutexattr_setpshared, pthread_mutexattr_setrobust, pthread_mutexattr_t,
-    },
-    sys::{
-        mman::{MapFlags, ProtFlags, mmap},
-        stat::Mode,
-    },
-    unistd::ftruncate,
+    fcntl::OFlag,
+    libc::{EBUSY, EOWNERDEAD, ETIMEDOUT, c_int},
+    sys::stat::fstat,
+};
+
+#[cfg(feature = "deadlock-detection")]
+use crate::lock_graph::{self, LockId};
+use crate::{
+    cleanup::CleanupPolicy,
+    error::{Error, Result},
+    map::Mapping,
+    namespace::Namespace,
+    permissions::Permissions,
+    raw_lock::{self, RawMutex},
 };
 
+/// Longest pause of `RMtx::lock_all` between attempts.
+const MAX_BACKOFF: Duration = Duration::from_millis(1);
+
 /// The result of locking an interprocess mutex.
 #[derive(Debug, Clone)]
 pub enum LockResult {
@@ -34,103 +29,418 @@ pub enum LockResult {
     OwnerDiedRecovered,
 }
 
+/// The result of a non-blocking lock attempt on an interprocess mutex.
+#[derive(Debug, Clone)]
+pub enum TryLockResult {
+    /// Mutex acquired normally without prior owner death.
+    Acquired,
+    /// Mutex acquired after recovering from a previous owner's death.
+    OwnerDiedRecovered,
+    /// Mutex is currently held by someone else.
+    WouldBlock,
+}
+
+/// The result of a timed lock attempt on an interprocess mutex.
+#[derive(Debug, Clone)]
+pub enum TimedLockResult {
+    /// Mutex acquired normally without prior owner death.
+    Acquired,
+    /// Mutex acquired after recovering from a previous owner's death.
+    OwnerDiedRecovered,
+    /// The timeout elapsed before the mutex could be acquired.
+    TimedOut,
+}
+
+/// The scheduling priority protocol of an `RMtx`, chosen when the mutex is created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MutexProtocol {
+    /// Holding the mutex doesn't affect the owner's priority.
+    #[default]
+    None,
+    /// The owner runs at the priority of the highest-priority thread blocked on the mutex,
+    /// avoiding priority inversion (`PTHREAD_PRIO_INHERIT`).
+    Inherit,
+    /// The owner runs at least at the priority `ceiling` while holding the mutex
+    /// (`PTHREAD_PRIO_PROTECT`).
+    Protect { ceiling: i32 },
+}
+
+impl MutexProtocol {
+    /// Encodes the protocol for the header of the mutex file.
+    fn to_raw(self) -> (u32, i32) {
+        match self {
+            Self::None => (0, 0),
+            Self::Inherit => (1, 0),
+            Self::Protect { ceiling } => (2, ceiling),
+        }
+    }
+}
+
+/// How an `RMtx` behaves when the owning thread locks it again, chosen when the mutex is
+/// created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MutexKind {
+    /// Locking the mutex again deadlocks (`PTHREAD_MUTEX_NORMAL`).
+    #[default]
+    Normal,
+    /// Locking the mutex again fails with `EDEADLK`, and unlocking a mutex held by another
+    /// thread fails with `EPERM` (`PTHREAD_MUTEX_ERRORCHECK`).
+    ErrorCheck,
+    /// Locking the mutex again succeeds, and it is released once unlocked as many times
+    /// (`PTHREAD_MUTEX_RECURSIVE`).
+    Recursive,
+}
+
+impl MutexKind {
+    /// Encodes the kind for the header of the mutex file.
+    fn to_raw(self) -> u32 {
+        match self {
+            Self::Normal => 0,
+            Self::ErrorCheck => 1,
+            Self::Recursive => 2,
+        }
+    }
+}
+
+/// The contents of a mutex file: the settings the mutex was created with, then the mutex.
+#[repr(C)]
+struct MtxFile {
+    kind: u32,
+    protocol: u32,
+    ceiling: i32,
+    mtx: RawMutex,
+}
+
 /// An interprocess, robust mutex implemented using `pthread_mutex_t` and shared memory.
+///
+/// On targets without robust pthread mutexes, such as macOS, a mutex recording the pid of its
+/// owner is used instead, which detects owner death by checking whether that process exists.
+///
+/// With the `deadlock-detection` feature, every process records which mutexes its threads
+/// hold and wait for in a graph kept in the namespace of the mutexes, and `lock` fails with
+/// `Error::Deadlock` instead of blocking forever when waiting would close a cycle.
 pub struct RMtx {
-    _fd: OwnedFd,
-    ptr: *mut pthread_mutex_t,
+    map: Mapping,
+    ptr: *mut RawMutex,
+    #[cfg(feature = "deadlock-detection")]
+    id: LockId,
 }
 
 impl RMtx {
     pub fn new(name: &str) -> Result<Self> {
-        let path = format!("/dev/shm/{}.mtx", name);
-        let fd = open(
-            path.as_str(),
-            OFlag::O_CREAT | OFlag::O_RDWR,
-            Mode::from_bits_truncate(0o600),
-        )?;
+        Self::builder(name).build()
+    }
 
-        ftruncate(&fd, size_of::<pthread_mutex_t>() as off_t)?;
-
-        let dup_raw_fd = unsafe { Errno::result(dup(fd.as_raw_fd()))? };
-        let dup_fd = unsafe { OwnedFd::from_raw_fd(dup_raw_fd) };
-
-        let init_lock = Flock::lock(dup_fd, FlockArg::LockExclusive)
-            .map_err(|(_, e)| anyhow!("init-lock failed: {}", e))?;
-
-        let len = NonZeroUsize::new(size_of::<pthread_mutex_t>())
-            .expect("pthread_mutex_t has nonzero size");
-        let raw_ptr = unsafe {
-            mmap(
-                None,
-                len,
-                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
-                MapFlags::MAP_SHARED,
-                &fd,
-                0,
-            )?
-        };
+    /// Returns a builder for configuring how the mutex is opened and cleaned up.
+    pub fn builder(name: &str) -> RMtxBuilder {
+        RMtxBuilder {
+            name: name.to_owned(),
+            cleanup: CleanupPolicy::default(),
+            namespace: Namespace::default(),
+            permissions: Permissions::default(),
+            kind: MutexKind::default(),
+            protocol: MutexProtocol::default(),
+        }
+    }
 
-        let mtx_ptr = raw_ptr.as_ptr() as *mut pthread_mutex_t;
-
-        let first = unsafe { *(mtx_ptr as *const c_int) };
-        if first == 0 {
-            let mut attr: pthread_mutexattr_t = unsafe { zeroed() };
-            unsafe {
-                Errno::result(pthread_mutexattr_init(&mut attr))?;
-                Errno::result(pthread_mutexattr_setpshared(
-                    &mut attr,
-                    PTHREAD_PROCESS_SHARED,
-                ))?;
-                Errno::result(pthread_mutexattr_setrobust(&mut attr, PTHREAD_MUTEX_ROBUST))?;
-                Errno::result(pthread_mutex_init(mtx_ptr, &attr))?;
-                Errno::result(pthread_mutexattr_destroy(&mut attr))?;
+    /// Unlinks (deletes) the mutex file from /dev/shm.
+    /// Processes that have it open keep using it until they drop their handles.
+    pub fn unlink(name: &str) -> Result<()> {
+        Namespace::default().unlink_mtx(name)
+    }
+
+    /// Locks the mutex and returns a guard that unlocks it when dropped.
+    pub fn lock(&self) -> Result<RMtxGuard<'_>> {
+        let result = self.lock_raw()?;
+        Ok(RMtxGuard { mtx: self, result })
+    }
+
+    /// Locks all of `mutexes`, returning their guards in the same order.
+    ///
+    /// Taking several mutexes one by one deadlocks when another process takes the same ones
+    /// in a different order. This never waits for a mutex while holding others: it blocks on
+    /// one, then tries the rest, and if one is busy releases them all, backs off and starts
+    /// over with that one. Trying them in an order all processes agree on, that of the
+    /// inodes of their files, keeps processes locking overlapping sets from repeatedly
+    /// getting in each other's way. A guard reports its owner died if that was found on any
+    /// attempt. Fails if the same mutex is passed twice.
+    pub fn lock_all<'a>(mutexes: &[&'a RMtx]) -> Result<Vec<RMtxGuard<'a>>> {
+        let mut order = Vec::with_capacity(mutexes.len());
+        for (index, mtx) in mutexes.iter().enumerate() {
+            let stat = fstat(mtx.map.fd()).map_err(Error::sys("fstat"))?;
+            order.push(((stat.st_dev, stat.st_ino), index));
+        }
+        order.sort_unstable();
+        if order.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+            return Err(Error::InvalidArgument(
+                "The same mutex was passed to lock_all twice".to_owned(),
+            ));
+        }
+
+        if mutexes.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Mutexes recovered from a dead owner, which later attempts find merely unlocked.
+        let mut died = vec![false; mutexes.len()];
+        let mut first = 0;
+        let mut backoff = Duration::from_micros(50);
+        'retry: loop {
+            let mut guards: Vec<Option<RMtxGuard<'a>>> = mutexes.iter().map(|_| None).collect();
+            // The blocking lock comes first, as nothing may be held while waiting.
+            let positions = iter::once(first).chain((0..order.len()).filter(|&p| p != first));
+            for position in positions {
+                let index = order[position].1;
+                let guard = if position == first {
+                    Some(mutexes[index].lock()?)
+                } else {
+                    mutexes[index].try_lock()?
+                };
+                match guard {
+                    Some(mut guard) => {
+                        died[index] |= guard.owner_died_recovered();
+                        if died[index] {
+                            guard.result = LockResult::OwnerDiedRecovered;
+                        }
+                        guards[index] = Some(guard);
+                    }
+                    None => {
+                        drop(guards);
+                        thread::sleep(backoff);
+                        backoff = (backoff * 2).min(MAX_BACKOFF);
+                        first = position;
+                        continue 'retry;
+                    }
+                }
             }
+            return Ok(guards.into_iter().flatten().collect());
         }
+    }
 
-        init_lock
-            .unlock()
-            .map_err(|(_, e)| anyhow!("init-unlock failed: {}", e))?;
+    /// Locks the mutex without a guard; the caller is responsible for calling `unlock`.
+    pub fn lock_raw(&self) -> Result<LockResult> {
+        // Only blocking can deadlock, and recording the backtrace is costly.
+        #[cfg(feature = "deadlock-detection")]
+        let _waiting = match unsafe { raw_lock::try_lock(self.ptr) } {
+            EBUSY => lock_graph::wait(&self.id)?,
+            err => return self.acquired(err, "pthread_mutex_trylock"),
+        };
+        let err = unsafe { raw_lock::lock(self.ptr)? };
+        self.acquired(err, "pthread_mutex_lock")
+    }
 
-        Ok(Self {
-            _fd: fd,
-            ptr: mtx_ptr,
-        })
+    /// Attempts to lock the mutex without blocking, returning `None` if it is held elsewhere.
+    pub fn try_lock(&self) -> Result<Option<RMtxGuard<'_>>> {
+        let result = match self.try_lock_raw()? {
+            TryLockResult::Acquired => LockResult::Acquired,
+            TryLockResult::OwnerDiedRecovered => LockResult::OwnerDiedRecovered,
+            TryLockResult::WouldBlock => return Ok(None),
+        };
+        Ok(Some(RMtxGuard { mtx: self, result }))
     }
 
-    pub fn lock(&self) -> Result<LockResult> {
-        let err = unsafe { pthread_mutex_lock(self.ptr) };
-        if err == EOWNERDEAD {
-            unsafe {
-                Errno::result(pthread_mutex_consistent(self.ptr))
-                    .map_err(|e| anyhow!("pthread_mutex_consistent failed: {e}"))?;
-            }
-            Ok(LockResult::OwnerDiedRecovered)
-        } else {
-            Errno::result(err)
-                .map(|_| LockResult::Acquired)
-                .map_err(|e| anyhow!("pthread_mutex_lock failed: {e}"))
+    /// Attempts to lock the mutex without blocking and without a guard.
+    pub fn try_lock_raw(&self) -> Result<TryLockResult> {
+        let err = unsafe { raw_lock::try_lock(self.ptr) };
+        match err {
+            EBUSY => Ok(TryLockResult::WouldBlock),
+            _ => match self.acquired(err, "pthread_mutex_trylock")? {
+                LockResult::Acquired => Ok(TryLockResult::Acquired),
+                LockResult::OwnerDiedRecovered => Ok(TryLockResult::OwnerDiedRecovered),
+            },
+        }
+    }
+
+    /// Locks the mutex, giving up after `timeout` has elapsed on the monotonic clock.
+    pub fn lock_timeout(&self, timeout: Duration) -> Result<Option<RMtxGuard<'_>>> {
+        let result = match self.lock_timeout_raw(timeout)? {
+            TimedLockResult::Acquired => LockResult::Acquired,
+            TimedLockResult::OwnerDiedRecovered => LockResult::OwnerDiedRecovered,
+            TimedLockResult::TimedOut => return Ok(None),
+        };
+        Ok(Some(RMtxGuard { mtx: self, result }))
+    }
+
+    /// Locks the mutex with a timeout and without a guard.
+    pub fn lock_timeout_raw(&self, timeout: Duration) -> Result<TimedLockResult> {
+        let err = unsafe { raw_lock::lock_timeout(self.ptr, timeout)? };
+
+        match err {
+            ETIMEDOUT => Ok(TimedLockResult::TimedOut),
+            _ => match self.acquired(err, "pthread_mutex_clocklock")? {
+                LockResult::Acquired => Ok(TimedLockResult::Acquired),
+                LockResult::OwnerDiedRecovered => Ok(TimedLockResult::OwnerDiedRecovered),
+            },
         }
     }
 
     pub fn unlock(&self) -> Result<()> {
-        unsafe {
-            Errno::result(pthread_mutex_unlock(self.ptr))
-                .map(|_| ())
-                .map_err(|e| anyhow!("pthread_mutex_unlock failed: {e}"))
+        #[cfg(feature = "deadlock-detection")]
+        self.track_held(false);
+        raw_lock::check(unsafe { raw_lock::unlock(self.ptr) })
+            .map_err(Error::lock("pthread_mutex_unlock"))
+    }
+
+    pub(crate) fn raw(&self) -> *mut RawMutex {
+        self.ptr
+    }
+
+    /// Records in the lock graph whether the calling thread holds the mutex, e.g. while a
+    /// condition variable releases it.
+    #[cfg(feature = "deadlock-detection")]
+    pub(crate) fn track_held(&self, held: bool) {
+        lock_graph::set_held(&self.id, held);
+    }
+
+    /// Interprets the return code of a call that acquires the mutex,
+    /// marking it consistent again if the previous owner died.
+    pub(crate) fn acquired(&self, err: c_int, op: &'static str) -> Result<LockResult> {
+        let result = acquired(self.ptr, err, op)?;
+        #[cfg(feature = "deadlock-detection")]
+        self.track_held(true);
+        Ok(result)
+    }
+}
+
+/// Interprets the return code of a call that acquired the mutex at `ptr`.
+pub(crate) fn acquired(ptr: *mut RawMutex, err: c_int, op: &'static str) -> Result<LockResult> {
+    if err == EOWNERDEAD {
+        raw_lock::check(unsafe { raw_lock::consistent(ptr) })
+            .map_err(|source| Error::Recovery { source })?;
+        Ok(LockResult::OwnerDiedRecovered)
+    } else {
+        raw_lock::check(err)
+            .map(|()| LockResult::Acquired)
+            .map_err(Error::lock(op))
+    }
+}
+
+/// Builder for `RMtx`, created with `RMtx::builder`.
+pub struct RMtxBuilder {
+    name: String,
+    cleanup: CleanupPolicy,
+    namespace: Namespace,
+    permissions: Permissions,
+    kind: MutexKind,
+    protocol: MutexProtocol,
+}
+
+impl RMtxBuilder {
+    /// Sets what happens to the mutex file in /dev/shm when the handle is dropped.
+    pub fn cleanup(mut self, cleanup: CleanupPolicy) -> Self {
+        self.cleanup = cleanup;
+        self
+    }
+
+    /// Opens the mutex in `namespace` instead of the default one.
+    pub fn namespace(mut self, namespace: &Namespace) -> Self {
+        self.namespace = namespace.clone();
+        self
+    }
+
+    /// Sets the permissions the backing file is created with, see `Permissions`. Only
+    /// applies if this handle creates the mutex.
+    pub fn permissions(mut self, permissions: Permissions) -> Self {
+        self.permissions = permissions;
+        self
+    }
+
+    /// Sets the kind of mutex to create. Opening an existing mutex fails unless it was created
+    /// with the same kind.
+    ///
+    /// Kinds other than `Normal` need robust pthread mutexes, see `Capabilities`. A recursive
+    /// mutex must be held only once when waiting on a `Condvar` with it.
+    pub fn kind(mut self, kind: MutexKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    /// Sets the priority protocol the mutex is created with. Opening an existing mutex fails
+    /// unless it was created with the same protocol.
+    ///
+    /// Priority protocols need robust pthread mutexes, see `Capabilities`. With glibc, timed
+    /// locking of a mutex using `Inherit` needs glibc 2.35 and Linux 5.14.
+    pub fn protocol(mut self, protocol: MutexProtocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Opens the mutex, creating and initializing it if it doesn't exist yet.
+    pub fn build(self) -> Result<RMtx> {
+        let path = self.namespace.path(&self.name, ".mtx");
+        let len = NonZeroUsize::new(size_of::<MtxFile>()).expect("MtxFile has nonzero size");
+        let kind = self.kind.to_raw();
+        let (protocol, ceiling) = self.protocol.to_raw();
+
+        let mut map = Mapping::open_init(
+            &path,
+            OFlag::O_CREAT,
+            &self.permissions,
+            len,
+            self.cleanup,
+            |ptr| unsafe {
+                let file = ptr as *mut MtxFile;
+                (*file).kind = kind;
+                (*file).protocol = protocol;
+                (*file).ceiling = ceiling;
+                raw_lock::init_mutex(&raw mut (*file).mtx, self.kind, self.protocol)
+            },
+        )?;
+
+        let file = map.ptr() as *mut MtxFile;
+        if unsafe { (*file).kind } != kind {
+            return Err(Error::Validation(format!(
+                "{path} was created as a different kind of mutex than {:?}",
+                self.kind
+            )));
+        }
+        let found = unsafe { ((*file).protocol, (*file).ceiling) };
+        if found != (protocol, ceiling) {
+            return Err(Error::Validation(format!(
+                "{path} was created with a different priority protocol than {:?}",
+                self.protocol
+            )));
         }
+        map.record_in(&self.namespace, &self.name, ".mtx")?;
+        map.inherit_in_forks(None);
+        let ptr = unsafe { &raw mut (*file).mtx };
+        Ok(RMtx {
+            map,
+            ptr,
+            #[cfg(feature = "deadlock-detection")]
+            id: LockId::new(&self.namespace, path.to_string()),
+        })
     }
 }
 
-impl Drop for RMtx {
+/// RAII guard returned by `RMtx::lock`, unlocking the mutex on drop.
+pub struct RMtxGuard<'a> {
+    mtx: &'a RMtx,
+    result: LockResult,
+}
+
+impl<'a> RMtxGuard<'a> {
+    pub(crate) fn mtx(&self) -> &'a RMtx {
+        self.mtx
+    }
+
+    pub(crate) fn set_result(&mut self, result: LockResult) {
+        self.result = result;
+    }
+
+    /// How the mutex was acquired.
+    pub fn lock_result(&self) -> &LockResult {
+        &self.result
+    }
+
+    /// Returns true if the previous owner died while holding the mutex.
+    pub fn owner_died_recovered(&self) -> bool {
+        matches!(self.result, LockResult::OwnerDiedRecovered)
+    }
+}
+
+impl Drop for RMtxGuard<'_> {
     fn drop(&mut self) {
-        unsafe {
-            // Don't destroy the on-disk mutex so it remains valid for other processes
-            // Errno::result(nix::libc::pthread_mutex_destroy(self.ptr)).ok();
-            Errno::result(munmap(
-                self.ptr as *mut c_void,
-                size_of::<pthread_mutex_t>(),
-            ))
-            .ok();
-        }
+        self.mtx.unlock().ok();
     }
 }
@@ -0,0 +1,242 @@
+use std::{
+    mem::size_of,
+    num::NonZeroUsize,
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use nix::fcntl::OFlag;
+
+use crate::{
+    builder::Builder,
+    cleanup::CleanupPolicy,
+    error::{Error, Result},
+    header::Header,
+    map::Mapping,
+    namespace::Namespace,
+    time,
+};
+
+/// Bits of the bucket state holding the number of tokens, below the timestamp.
+const TOKEN_BITS: u32 = 20;
+const TOKEN_MASK: u64 = (1 << TOKEN_BITS) - 1;
+
+/// The timestamp in microseconds since the bucket was created, wrapping after about 200
+/// days.
+const STAMP_MASK: u64 = (1 << (64 - TOKEN_BITS)) - 1;
+
+const MICROS_PER_SEC: u128 = 1_000_000;
+
+#[repr(C)]
+struct Bucket {
+    header: Header,
+    /// Tokens added per second.
+    rate: u64,
+    /// Tokens the bucket holds at most.
+    burst: u64,
+    /// When the bucket was created, in nanoseconds on the monotonic clock.
+    epoch: u64,
+    /// The tokens in the bucket in the low bits, and in the high bits the time at which
+    /// that count was current, so both change in a single compare-and-swap.
+    state: AtomicU64,
+}
+
+/// A token bucket in shared memory, so any number of processes can share one budget of
+/// operations per second, e.g. toward an upstream API.
+///
+/// The bucket refills at a steady rate up to its burst size, and each operation takes
+/// tokens from it, waiting in `acquire` or failing in `try_acquire` when there aren't
+/// enough. Nothing is locked, so a process dying at any point leaves the bucket intact.
+pub struct RateLimiter {
+    map: Mapping,
+}
+
+impl RateLimiter {
+    /// Opens the limiter in /dev/shm, creating it full with `burst` tokens refilled at
+    /// `per_second` if it doesn't exist. Opening an existing one keeps its rate and burst
+    /// size. The burst size must be below 2^20.
+    pub fn new(name: &str, per_second: u64, burst: u32) -> Result<Self> {
+        Self::builder(name).build(per_second, burst)
+    }
+
+    /// Returns a builder for setting the namespace and the permissions the limiter is created with.
+    pub fn builder(name: &str) -> Builder<Self> {
+        Builder::new(name)
+    }
+
+    /// Unlinks (deletes) the limiter from /dev/shm.
+    pub fn unlink(name: &str) -> Result<()> {
+        Self::unlink_in(&Namespace::default(), name)
+    }
+
+    /// Unlinks the limiter called `name` in `namespace`.
+    pub fn unlink_in(namespace: &Namespace, name: &str) -> Result<()> {
+        namespace.path(name, ".rlm").unlink()
+    }
+
+    /// Takes `tokens` from the bucket, blocking until there are enough.
+    pub fn acquire(&self, tokens: u32) -> Result<()> {
+        while let Some(wait) = self.take(tokens)? {
+            thread::sleep(wait);
+        }
+        Ok(())
+    }
+
+    /// Takes `tokens` from the bucket, blocking until there are enough or `timeout` passes.
+    /// Returns false if it timed out, without taking any.
+    pub fn acquire_timeout(&self, tokens: u32, timeout: Duration) -> Result<bool> {
+        let deadline = Instant::now() + timeout;
+        while let Some(wait) = self.take(tokens)? {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(false);
+            }
+            thread::sleep(wait.min(remaining));
+        }
+        Ok(true)
+    }
+
+    /// Takes `tokens` from the bucket if there are enough, without blocking.
+    pub fn try_acquire(&self, tokens: u32) -> Result<bool> {
+        Ok(self.take(tokens)?.is_none())
+    }
+
+    /// The number of tokens in the bucket right now.
+    pub fn available(&self) -> Result<u32> {
+        let now = self.now()?;
+        let (_, tokens) = self.refill(self.bucket().state.load(Ordering::Acquire), now);
+        Ok(tokens as u32)
+    }
+
+    /// Tokens added to the bucket per second.
+    pub fn rate(&self) -> u64 {
+        self.bucket().rate
+    }
+
+    /// Tokens the bucket holds at most.
+    pub fn burst(&self) -> u32 {
+        self.bucket().burst as u32
+    }
+
+    /// Takes `tokens` if there are enough, and otherwise returns how long it takes until
+    /// there are, unless others take them first.
+    fn take(&self, tokens: u32) -> Result<Option<Duration>> {
+        let bucket = self.bucket();
+        if u64::from(tokens) > bucket.burst {
+            return Err(Error::InvalidArgument(format!(
+                "Cannot take {tokens} tokens from a bucket holding at most {}",
+                bucket.burst
+            )));
+        }
+        let now = self.now()?;
+        let mut current = bucket.state.load(Ordering::Acquire);
+        loop {
+            let (stamp, available) = self.refill(current, now);
+            if available < u64::from(tokens) {
+                let missing = u128::from(u64::from(tokens) - available);
+                let wait = (missing * MICROS_PER_SEC).div_ceil(u128::from(bucket.rate));
+                let credit = u128::from(elapsed(stamp, now));
+                let wait = wait.saturating_sub(credit).max(1);
+                return Ok(Some(Duration::from_micros(wait as u64)));
+            }
+            let new = pack(stamp, available - u64::from(tokens));
+            match bucket.state.compare_exchange_weak(
+                current,
+                new,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return Ok(None),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// Adds the tokens due since the timestamp of `state` to its count, returning the new
+    /// timestamp and count. The timestamp only advances by the time the added tokens took,
+    /// so partial tokens aren't lost, except once the bucket is full.
+    fn refill(&self, state: u64, now: u64) -> (u64, u64) {
+        let bucket = self.bucket();
+        let (stamp, tokens) = unpack(state);
+        let elapsed = elapsed(stamp, now);
+        let added = u128::from(elapsed) * u128::from(bucket.rate) / MICROS_PER_SEC;
+        if u128::from(tokens) + added >= u128::from(bucket.burst) {
+            return (now, bucket.burst);
+        }
+        let spent = added * MICROS_PER_SEC / u128::from(bucket.rate);
+        ((stamp + spent as u64) & STAMP_MASK, tokens + added as u64)
+    }
+
+    /// Microseconds since the bucket was created, as stored in its timestamp.
+    fn now(&self) -> Result<u64> {
+        let nanos = time::monotonic_nanos()?.saturating_sub(self.bucket().epoch);
+        Ok((nanos / 1000) & STAMP_MASK)
+    }
+
+    fn bucket(&self) -> &Bucket {
+        unsafe { &*(self.map.ptr() as *const Bucket) }
+    }
+}
+
+impl Builder<RateLimiter> {
+    /// Opens the limiter like `RateLimiter::new`.
+    pub fn build(self, per_second: u64, burst: u32) -> Result<RateLimiter> {
+        if per_second == 0 || burst == 0 || u64::from(burst) > TOKEN_MASK {
+            return Err(Error::InvalidArgument(format!(
+                "Invalid rate of {per_second}/s with burst of {burst}, both must be nonzero \
+                 and the burst below 2^{TOKEN_BITS}"
+            )));
+        }
+        let len = NonZeroUsize::new(size_of::<Bucket>()).expect("Bucket has nonzero size");
+        let path = self.namespace.path(&self.name, ".rlm");
+        let map = Mapping::open_init(
+            &path,
+            OFlag::O_CREAT,
+            &self.permissions,
+            len,
+            CleanupPolicy::LastCloseRefCounted,
+            |raw| unsafe {
+                let bucket = raw as *mut Bucket;
+                (*bucket).header.init::<Bucket>(0, 0);
+                (*bucket).rate = per_second;
+                (*bucket).burst = burst.into();
+                (*bucket).epoch = time::monotonic_nanos()?;
+                (*bucket).state = AtomicU64::new(pack(0, burst.into()));
+                Ok(())
+            },
+        )?;
+
+        let bucket = map.ptr() as *const Bucket;
+        {
+            let _init_lock = map.init_lock()?;
+            unsafe {
+                (*bucket).header.validate::<Bucket>(0, 0)?;
+                (*bucket).header.attach(&map)?;
+            }
+        }
+
+        Ok(RateLimiter { map })
+    }
+}
+
+impl Drop for RateLimiter {
+    fn drop(&mut self) {
+        self.bucket().header.detach(&self.map);
+    }
+}
+
+fn pack(stamp: u64, tokens: u64) -> u64 {
+    (stamp << TOKEN_BITS) | tokens
+}
+
+fn unpack(state: u64) -> (u64, u64) {
+    (state >> TOKEN_BITS, state & TOKEN_MASK)
+}
+
+/// Microseconds from `stamp` to `now`. A stamp ahead of `now` was stored by a process that
+/// read the clock later, and counts as no time.
+fn elapsed(stamp: u64, now: u64) -> u64 {
+    let elapsed = now.wrapping_sub(stamp) & STAMP_MASK;
+    if elapsed > STAMP_MASK / 2 { 0 } else { elapsed }
+}
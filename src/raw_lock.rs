@@ -0,0 +1,514 @@
+// The process-shared mutex and condition variable behind `RMtx`, `Condvar`, `ShmMutex` and
+// `ShmArray`, picked at compile time. Robust pthread mutexes are used where available,
+// otherwise a `PidMutex`. Either way the functions report like their pthread counterparts,
+// e.g. `EOWNERDEAD` when the previous owner died. Which ones are used is decided by the
+// capability cfgs set in build.rs, see `Capabilities`.
+
+use std::time::Duration;
+
+use nix::{errno::Errno, libc::c_int};
+
+use crate::{
+    error::Result,
+    r_mtx::{MutexKind, MutexProtocol},
+};
+
+pub(crate) use imp::{RawCondvar, RawMutex};
+pub(crate) use rw_imp::RawRwLock;
+
+/// Converts the return code of a pthread function, which is the error number itself.
+pub(crate) fn check(err: c_int) -> nix::Result<()> {
+    match err {
+        0 => Ok(()),
+        err => Err(Errno::from_raw(err)),
+    }
+}
+
+/// Initializes a process-shared, robust mutex of `kind` at `ptr` using `protocol`.
+pub(crate) unsafe fn init_mutex(
+    ptr: *mut RawMutex,
+    kind: MutexKind,
+    protocol: MutexProtocol,
+) -> Result<()> {
+    unsafe { imp::init_mutex(ptr, kind, protocol) }
+}
+
+pub(crate) unsafe fn lock(ptr: *mut RawMutex) -> Result<c_int> {
+    unsafe { imp::lock(ptr) }
+}
+
+pub(crate) unsafe fn try_lock(ptr: *mut RawMutex) -> c_int {
+    unsafe { imp::try_lock(ptr) }
+}
+
+/// Locks the mutex, giving up with `ETIMEDOUT` after `timeout` on the monotonic clock.
+pub(crate) unsafe fn lock_timeout(ptr: *mut RawMutex, timeout: Duration) -> Result<c_int> {
+    unsafe { imp::lock_timeout(ptr, timeout) }
+}
+
+pub(crate) unsafe fn unlock(ptr: *mut RawMutex) -> c_int {
+    unsafe { imp::unlock(ptr) }
+}
+
+/// Marks the mutex consistent again after it was acquired with `EOWNERDEAD`.
+pub(crate) unsafe fn consistent(ptr: *mut RawMutex) -> c_int {
+    unsafe { imp::consistent(ptr) }
+}
+
+/// Initializes a process-shared condition variable at `ptr` using the monotonic clock.
+pub(crate) unsafe fn init_condvar(ptr: *mut RawCondvar) -> Result<()> {
+    unsafe { imp::init_condvar(ptr) }
+}
+
+/// Releases `mtx` and blocks until notified, then locks `mtx` again.
+pub(crate) unsafe fn wait(cond: *mut RawCondvar, mtx: *mut RawMutex) -> Result<c_int> {
+    unsafe { imp::wait(cond, mtx) }
+}
+
+/// Like `wait`, but reports `ETIMEDOUT` after `timeout` on the monotonic clock.
+pub(crate) unsafe fn wait_timeout(
+    cond: *mut RawCondvar,
+    mtx: *mut RawMutex,
+    timeout: Duration,
+) -> Result<c_int> {
+    unsafe { imp::wait_timeout(cond, mtx, timeout) }
+}
+
+pub(crate) unsafe fn notify_one(cond: *mut RawCondvar) -> c_int {
+    unsafe { imp::notify(cond, false) }
+}
+
+pub(crate) unsafe fn notify_all(cond: *mut RawCondvar) -> c_int {
+    unsafe { imp::notify(cond, true) }
+}
+
+/// Initializes a process-shared reader-writer lock at `ptr`.
+pub(crate) unsafe fn init_rwlock(ptr: *mut RawRwLock) -> Result<()> {
+    unsafe { rw_imp::init(ptr) }
+}
+
+pub(crate) unsafe fn read_lock(ptr: *mut RawRwLock) -> Result<c_int> {
+    unsafe { rw_imp::read(ptr) }
+}
+
+pub(crate) unsafe fn write_lock(ptr: *mut RawRwLock) -> Result<c_int> {
+    unsafe { rw_imp::write(ptr) }
+}
+
+pub(crate) unsafe fn unlock_rwlock(ptr: *mut RawRwLock) -> c_int {
+    unsafe { rw_imp::unlock(ptr) }
+}
+
+/// Unlocks the mutex at the pointer on drop.
+pub(crate) struct Unlock(pub(crate) *mut RawMutex);
+
+impl Drop for Unlock {
+    fn drop(&mut self) {
+        unsafe { unlock(self.0) };
+    }
+}
+
+#[cfg(robust_mutex)]
+mod imp {
+    use std::{mem::zeroed, time::Duration};
+
+    use nix::libc::{
+        CLOCK_MONOTONIC, PTHREAD_MUTEX_ERRORCHECK, PTHREAD_MUTEX_NORMAL, PTHREAD_MUTEX_RECURSIVE,
+        PTHREAD_MUTEX_ROBUST, PTHREAD_PROCESS_SHARED, c_int, pthread_cond_broadcast,
+        pthread_cond_init, pthread_cond_signal, pthread_cond_t, pthread_cond_timedwait,
+        pthread_cond_wait, pthread_condattr_destroy, pthread_condattr_init,
+        pthread_condattr_setclock, pthread_condattr_setpshared, pthread_condattr_t,
+        pthread_mutex_consistent, pthread_mutex_init, pthread_mutex_lock, pthread_mutex_t,
+        pthread_mutex_trylock, pthread_mutex_unlock, pthread_mutexattr_destroy,
+        pthread_mutexattr_init, pthread_mutexattr_setpshared, pthread_mutexattr_setrobust,
+        pthread_mutexattr_settype, pthread_mutexattr_t,
+    };
+
+    use crate::{
+        error::{Error, Result},
+        r_mtx::{MutexKind, MutexProtocol},
+        time::deadline,
+    };
+
+    pub(crate) type RawMutex = pthread_mutex_t;
+    pub(crate) type RawCondvar = pthread_cond_t;
+
+    // libc only declares these for some targets. The constants are the same on all of them.
+    const PTHREAD_PRIO_INHERIT: c_int = 1;
+    const PTHREAD_PRIO_PROTECT: c_int = 2;
+
+    unsafe extern "C" {
+        fn pthread_mutexattr_setprotocol(attr: *mut pthread_mutexattr_t, protocol: c_int) -> c_int;
+        fn pthread_mutexattr_setprioceiling(
+            attr: *mut pthread_mutexattr_t,
+            prioceiling: c_int,
+        ) -> c_int;
+    }
+
+    #[cfg(target_env = "gnu")]
+    unsafe extern "C" {
+        fn pthread_mutex_clocklock(
+            mutex: *mut pthread_mutex_t,
+            clock: nix::libc::clockid_t,
+            abstime: *const nix::libc::timespec,
+        ) -> c_int;
+    }
+
+    pub(super) unsafe fn init_mutex(
+        ptr: *mut RawMutex,
+        kind: MutexKind,
+        protocol: MutexProtocol,
+    ) -> Result<()> {
+        let kind = match kind {
+            MutexKind::Normal => PTHREAD_MUTEX_NORMAL,
+            MutexKind::ErrorCheck => PTHREAD_MUTEX_ERRORCHECK,
+            MutexKind::Recursive => PTHREAD_MUTEX_RECURSIVE,
+        };
+        let mut attr: pthread_mutexattr_t = unsafe { zeroed() };
+        unsafe {
+            super::check(pthread_mutexattr_init(&mut attr))
+                .map_err(Error::lock("pthread_mutexattr_init"))?;
+            super::check(pthread_mutexattr_setpshared(
+                &mut attr,
+                PTHREAD_PROCESS_SHARED,
+            ))
+            .map_err(Error::lock("pthread_mutexattr_setpshared"))?;
+            super::check(pthread_mutexattr_setrobust(&mut attr, PTHREAD_MUTEX_ROBUST))
+                .map_err(Error::lock("pthread_mutexattr_setrobust"))?;
+            super::check(pthread_mutexattr_settype(&mut attr, kind))
+                .map_err(Error::lock("pthread_mutexattr_settype"))?;
+            match protocol {
+                MutexProtocol::None => {}
+                MutexProtocol::Inherit => {
+                    super::check(pthread_mutexattr_setprotocol(
+                        &mut attr,
+                        PTHREAD_PRIO_INHERIT,
+                    ))
+                    .map_err(Error::lock("pthread_mutexattr_setprotocol"))?;
+                }
+                MutexProtocol::Protect { ceiling } => {
+                    super::check(pthread_mutexattr_setprotocol(
+                        &mut attr,
+                        PTHREAD_PRIO_PROTECT,
+                    ))
+                    .map_err(Error::lock("pthread_mutexattr_setprotocol"))?;
+                    super::check(pthread_mutexattr_setprioceiling(&mut attr, ceiling))
+                        .map_err(Error::lock("pthread_mutexattr_setprioceiling"))?;
+                }
+            }
+            super::check(pthread_mutex_init(ptr, &attr))
+                .map_err(Error::lock("pthread_mutex_init"))?;
+            super::check(pthread_mutexattr_destroy(&mut attr))
+                .map_err(Error::lock("pthread_mutexattr_destroy"))?;
+        }
+        Ok(())
+    }
+
+    pub(super) unsafe fn lock(ptr: *mut RawMutex) -> Result<c_int> {
+        Ok(unsafe { pthread_mutex_lock(ptr) })
+    }
+
+    pub(super) unsafe fn try_lock(ptr: *mut RawMutex) -> c_int {
+        unsafe { pthread_mutex_trylock(ptr) }
+    }
+
+    pub(super) unsafe fn lock_timeout(ptr: *mut RawMutex, timeout: Duration) -> Result<c_int> {
+        #[cfg(target_env = "gnu")]
+        let err = {
+            let abstime = deadline(CLOCK_MONOTONIC, timeout)?;
+            unsafe { pthread_mutex_clocklock(ptr, CLOCK_MONOTONIC, &abstime) }
+        };
+        // Without clocklock the deadline has to be expressed on the realtime clock.
+        #[cfg(not(target_env = "gnu"))]
+        let err = {
+            let abstime = deadline(nix::libc::CLOCK_REALTIME, timeout)?;
+            unsafe { nix::libc::pthread_mutex_timedlock(ptr, &abstime) }
+        };
+        Ok(err)
+    }
+
+    pub(super) unsafe fn unlock(ptr: *mut RawMutex) -> c_int {
+        unsafe { pthread_mutex_unlock(ptr) }
+    }
+
+    pub(super) unsafe fn consistent(ptr: *mut RawMutex) -> c_int {
+        unsafe { pthread_mutex_consistent(ptr) }
+    }
+
+    pub(super) unsafe fn init_condvar(ptr: *mut RawCondvar) -> Result<()> {
+        let mut attr: pthread_condattr_t = unsafe { zeroed() };
+        unsafe {
+            super::check(pthread_condattr_init(&mut attr))
+                .map_err(Error::lock("pthread_condattr_init"))?;
+            super::check(pthread_condattr_setpshared(
+                &mut attr,
+                PTHREAD_PROCESS_SHARED,
+            ))
+            .map_err(Error::lock("pthread_condattr_setpshared"))?;
+            super::check(pthread_condattr_setclock(&mut attr, CLOCK_MONOTONIC))
+                .map_err(Error::lock("pthread_condattr_setclock"))?;
+            super::check(pthread_cond_init(ptr, &attr))
+                .map_err(Error::lock("pthread_cond_init"))?;
+            super::check(pthread_condattr_destroy(&mut attr))
+                .map_err(Error::lock("pthread_condattr_destroy"))?;
+        }
+        Ok(())
+    }
+
+    pub(super) unsafe fn wait(cond: *mut RawCondvar, mtx: *mut RawMutex) -> Result<c_int> {
+        Ok(unsafe { pthread_cond_wait(cond, mtx) })
+    }
+
+    pub(super) unsafe fn wait_timeout(
+        cond: *mut RawCondvar,
+        mtx: *mut RawMutex,
+        timeout: Duration,
+    ) -> Result<c_int> {
+        let abstime = deadline(CLOCK_MONOTONIC, timeout)?;
+        Ok(unsafe { pthread_cond_timedwait(cond, mtx, &abstime) })
+    }
+
+    pub(super) unsafe fn notify(cond: *mut RawCondvar, all: bool) -> c_int {
+        match all {
+            true => unsafe { pthread_cond_broadcast(cond) },
+            false => unsafe { pthread_cond_signal(cond) },
+        }
+    }
+}
+
+#[cfg(not(robust_mutex))]
+mod imp {
+    use std::{
+        sync::atomic::{AtomicU32, Ordering},
+        time::Duration,
+    };
+
+    use nix::libc::{CLOCK_MONOTONIC, ETIMEDOUT, c_int, timespec};
+
+    use crate::{
+        error::{Error, Result},
+        futex,
+        pid_mutex::PidMutex,
+        r_mtx::{MutexKind, MutexProtocol},
+        time::deadline,
+    };
+
+    pub(crate) type RawMutex = PidMutex;
+
+    /// Waiters sleep on the sequence number, which notifiers bump before waking them.
+    #[repr(C)]
+    pub(crate) struct RawCondvar {
+        seq: AtomicU32,
+        waiters: AtomicU32,
+    }
+
+    // Zeroed memory is an unlocked mutex and a condition variable without waiters.
+
+    pub(super) unsafe fn init_mutex(
+        _ptr: *mut RawMutex,
+        kind: MutexKind,
+        protocol: MutexProtocol,
+    ) -> Result<()> {
+        // The owner is a process, so the threads of the owning process can't be told apart.
+        if kind != MutexKind::Normal {
+            return Err(Error::InvalidArgument(
+                "Mutex kinds other than Normal need robust pthread mutexes".to_owned(),
+            ));
+        }
+        match protocol {
+            MutexProtocol::None => Ok(()),
+            _ => Err(Error::InvalidArgument(
+                "Priority protocols need robust pthread mutexes".to_owned(),
+            )),
+        }
+    }
+
+    pub(super) unsafe fn lock(ptr: *mut RawMutex) -> Result<c_int> {
+        unsafe { (*ptr).lock(None) }
+    }
+
+    pub(super) unsafe fn try_lock(ptr: *mut RawMutex) -> c_int {
+        unsafe { (*ptr).try_lock() }
+    }
+
+    pub(super) unsafe fn lock_timeout(ptr: *mut RawMutex, timeout: Duration) -> Result<c_int> {
+        let abstime = deadline(CLOCK_MONOTONIC, timeout)?;
+        unsafe { (*ptr).lock(Some(&abstime)) }
+    }
+
+    pub(super) unsafe fn unlock(ptr: *mut RawMutex) -> c_int {
+        unsafe { (*ptr).unlock() }
+    }
+
+    pub(super) unsafe fn consistent(ptr: *mut RawMutex) -> c_int {
+        unsafe { (*ptr).consistent() }
+    }
+
+    pub(super) unsafe fn init_condvar(_ptr: *mut RawCondvar) -> Result<()> {
+        Ok(())
+    }
+
+    pub(super) unsafe fn wait(cond: *mut RawCondvar, mtx: *mut RawMutex) -> Result<c_int> {
+        unsafe { wait_until(cond, mtx, None) }
+    }
+
+    pub(super) unsafe fn wait_timeout(
+        cond: *mut RawCondvar,
+        mtx: *mut RawMutex,
+        timeout: Duration,
+    ) -> Result<c_int> {
+        let abstime = deadline(CLOCK_MONOTONIC, timeout)?;
+        unsafe { wait_until(cond, mtx, Some(&abstime)) }
+    }
+
+    unsafe fn wait_until(
+        cond: *mut RawCondvar,
+        mtx: *mut RawMutex,
+        deadline: Option<&timespec>,
+    ) -> Result<c_int> {
+        let cond = unsafe { &*cond };
+        let mtx = unsafe { &*mtx };
+
+        let seq = cond.seq.load(Ordering::SeqCst);
+        cond.waiters.fetch_add(1, Ordering::SeqCst);
+        mtx.unlock();
+        let woken = futex::wait_until(&cond.seq, seq, deadline);
+        cond.waiters.fetch_sub(1, Ordering::SeqCst);
+
+        // Like pthread_cond_timedwait, hold the mutex again before reporting anything.
+        let err = mtx.lock(None)?;
+        match woken? {
+            false if err == 0 => Ok(ETIMEDOUT),
+            _ => Ok(err),
+        }
+    }
+
+    pub(super) unsafe fn notify(cond: *mut RawCondvar, all: bool) -> c_int {
+        let cond = unsafe { &*cond };
+        cond.seq.fetch_add(1, Ordering::SeqCst);
+        if cond.waiters.load(Ordering::SeqCst) > 0 {
+            let count = if all { u32::MAX } else { 1 };
+            futex::wake(&cond.seq, count).ok();
+        }
+        0
+    }
+}
+
+#[cfg(shared_rwlock)]
+mod rw_imp {
+    use std::mem::zeroed;
+
+    use nix::libc::{
+        PTHREAD_PROCESS_SHARED, c_int, pthread_rwlock_init, pthread_rwlock_rdlock,
+        pthread_rwlock_t, pthread_rwlock_unlock, pthread_rwlock_wrlock, pthread_rwlockattr_destroy,
+        pthread_rwlockattr_init, pthread_rwlockattr_setpshared, pthread_rwlockattr_t,
+    };
+
+    use crate::error::{Error, Result};
+
+    pub(crate) type RawRwLock = pthread_rwlock_t;
+
+    pub(super) unsafe fn init(ptr: *mut RawRwLock) -> Result<()> {
+        let mut attr: pthread_rwlockattr_t = unsafe { zeroed() };
+        unsafe {
+            super::check(pthread_rwlockattr_init(&mut attr))
+                .map_err(Error::lock("pthread_rwlockattr_init"))?;
+            super::check(pthread_rwlockattr_setpshared(
+                &mut attr,
+                PTHREAD_PROCESS_SHARED,
+            ))
+            .map_err(Error::lock("pthread_rwlockattr_setpshared"))?;
+            super::check(pthread_rwlock_init(ptr, &attr))
+                .map_err(Error::lock("pthread_rwlock_init"))?;
+            super::check(pthread_rwlockattr_destroy(&mut attr))
+                .map_err(Error::lock("pthread_rwlockattr_destroy"))?;
+        }
+        Ok(())
+    }
+
+    pub(super) unsafe fn read(ptr: *mut RawRwLock) -> Result<c_int> {
+        Ok(unsafe { pthread_rwlock_rdlock(ptr) })
+    }
+
+    pub(super) unsafe fn write(ptr: *mut RawRwLock) -> Result<c_int> {
+        Ok(unsafe { pthread_rwlock_wrlock(ptr) })
+    }
+
+    pub(super) unsafe fn unlock(ptr: *mut RawRwLock) -> c_int {
+        unsafe { pthread_rwlock_unlock(ptr) }
+    }
+}
+
+#[cfg(not(shared_rwlock))]
+mod rw_imp {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use nix::libc::{EPERM, c_int};
+
+    use crate::{error::Result, futex};
+
+    /// Set in `state` while a writer holds the lock.
+    const WRITER: u32 = 1 << 31;
+
+    /// Holds the number of readers or `WRITER` in `state`. Zeroed memory is an unlocked lock.
+    #[repr(C)]
+    pub(crate) struct RawRwLock {
+        state: AtomicU32,
+        waiters: AtomicU32,
+    }
+
+    pub(super) unsafe fn init(_ptr: *mut RawRwLock) -> Result<()> {
+        Ok(())
+    }
+
+    pub(super) unsafe fn read(ptr: *mut RawRwLock) -> Result<c_int> {
+        let lock = unsafe { &*ptr };
+        acquire(lock, |state| (state & WRITER == 0).then_some(state + 1))
+    }
+
+    pub(super) unsafe fn write(ptr: *mut RawRwLock) -> Result<c_int> {
+        let lock = unsafe { &*ptr };
+        acquire(lock, |state| (state == 0).then_some(WRITER))
+    }
+
+    /// Moves `state` to what `next` returns for it, waiting while it returns `None`.
+    fn acquire(lock: &RawRwLock, next: impl Fn(u32) -> Option<u32>) -> Result<c_int> {
+        loop {
+            let state = lock.state.load(Ordering::Relaxed);
+            match next(state) {
+                Some(next) => {
+                    if lock
+                        .state
+                        .compare_exchange(state, next, Ordering::Acquire, Ordering::Relaxed)
+                        .is_ok()
+                    {
+                        return Ok(0);
+                    }
+                }
+                None => {
+                    lock.waiters.fetch_add(1, Ordering::SeqCst);
+                    let woken = futex::wait_until(&lock.state, state, None);
+                    lock.waiters.fetch_sub(1, Ordering::SeqCst);
+                    woken?;
+                }
+            }
+        }
+    }
+
+    pub(super) unsafe fn unlock(ptr: *mut RawRwLock) -> c_int {
+        let lock = unsafe { &*ptr };
+        let state = lock.state.load(Ordering::Relaxed);
+        let released = match state {
+            0 => return EPERM,
+            WRITER => {
+                lock.state.store(0, Ordering::SeqCst);
+                true
+            }
+            _ => lock.state.fetch_sub(1, Ordering::SeqCst) == 1,
+        };
+        if released && lock.waiters.load(Ordering::SeqCst) > 0 {
+            futex::wake(&lock.state, u32::MAX).ok();
+        }
+        0
+    }
+}
@@ -0,0 +1,147 @@
+use std::{
+    mem::{align_of, size_of},
+    num::NonZeroUsize,
+    os::fd::{AsFd, BorrowedFd},
+    slice,
+};
+
+use nix::fcntl::OFlag;
+
+use crate::{
+    builder::Builder,
+    cleanup::CleanupPolicy,
+    error::{Error, Result},
+    map::Mapping,
+    namespace::Namespace,
+    shm_safe::{ShmAtomic, ShmSafe},
+};
+
+/// Untyped shared memory of a fixed number of bytes, for building custom layouts on top.
+///
+/// Typed views are carved out at byte offsets with bounds and alignment checks.
+/// The object is unlinked from /dev/shm when the last handle across all processes is dropped.
+pub struct RawShm {
+    map: Mapping,
+}
+
+impl RawShm {
+    /// Opens the object in /dev/shm, creating it zero-filled with `len` bytes if it doesn't
+    /// exist. Attaching to an existing object of a different size fails.
+    pub fn new(name: &str, len: usize) -> Result<Self> {
+        Self::builder(name).build(len)
+    }
+
+    /// Returns a builder for setting the namespace and the permissions the object is created with.
+    pub fn builder(name: &str) -> Builder<Self> {
+        Builder::new(name)
+    }
+
+    /// Opens an existing object in /dev/shm with whatever size it currently has.
+    pub fn open(name: &str) -> Result<Self> {
+        Self::builder(name).open()
+    }
+
+    /// Unlinks (deletes) the object from /dev/shm.
+    pub fn unlink(name: &str) -> Result<()> {
+        Self::unlink_in(&Namespace::default(), name)
+    }
+
+    /// Unlinks the object called `name` in `namespace`.
+    pub fn unlink_in(namespace: &Namespace, name: &str) -> Result<()> {
+        namespace.path(name, "").unlink()
+    }
+
+    /// Size of the mapping in bytes.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.len() == 0
+    }
+
+    /// Base address of the mapping in this process.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.map.ptr() as *mut u8
+    }
+
+    /// Returns a pointer to a `T` at `offset` bytes into the mapping.
+    /// Fails if the value would not fit in the mapping or the address is misaligned for T.
+    pub fn ptr_at<T: ShmSafe>(&self, offset: usize) -> Result<*mut T> {
+        self.check::<T>(offset, 1)?;
+        Ok(unsafe { self.as_ptr().add(offset) as *mut T })
+    }
+
+    /// Returns a reference to a `T` at `offset` bytes into the mapping, for types that are
+    /// safe to share concurrently.
+    pub fn view_at<T: ShmAtomic>(&self, offset: usize) -> Result<&T> {
+        self.check::<T>(offset, 1)?;
+        Ok(unsafe { &*(self.as_ptr().add(offset) as *const T) })
+    }
+
+    /// Returns `count` consecutive values of T starting at `offset` bytes into the mapping,
+    /// for types that are safe to share concurrently.
+    pub fn slice_at<T: ShmAtomic>(&self, offset: usize, count: usize) -> Result<&[T]> {
+        self.check::<T>(offset, count)?;
+        Ok(unsafe { slice::from_raw_parts(self.as_ptr().add(offset) as *const T, count) })
+    }
+
+    fn check<T>(&self, offset: usize, count: usize) -> Result<()> {
+        let end = size_of::<T>()
+            .checked_mul(count)
+            .and_then(|size| size.checked_add(offset))
+            .ok_or_else(|| Error::InvalidArgument(format!("View at offset {offset} overflows")))?;
+        if end > self.len() {
+            return Err(Error::InvalidArgument(format!(
+                "View of {count} x {} bytes at offset {offset} exceeds the {} byte mapping",
+                size_of::<T>(),
+                self.len()
+            )));
+        }
+        if !(self.as_ptr() as usize + offset).is_multiple_of(align_of::<T>()) {
+            return Err(Error::InvalidArgument(format!(
+                "Offset {offset} is not aligned to {} bytes",
+                align_of::<T>()
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Builder<RawShm> {
+    /// Opens the object like `RawShm::new`.
+    pub fn build(self, len: usize) -> Result<RawShm> {
+        let len = NonZeroUsize::new(len).ok_or_else(|| {
+            Error::InvalidArgument("Cannot create an empty shared memory object".to_owned())
+        })?;
+
+        let path = self.namespace.path(&self.name, "");
+        let map = Mapping::open_init(
+            &path,
+            OFlag::O_CREAT,
+            &self.permissions,
+            len,
+            CleanupPolicy::LastCloseRefCounted,
+            |_| Ok(()),
+        )?;
+        map.inherit_in_forks(None);
+
+        Ok(RawShm { map })
+    }
+
+    /// Opens the existing object like `RawShm::open`.
+    pub fn open(self) -> Result<RawShm> {
+        let path = self.namespace.path(&self.name, "");
+        let map = Mapping::open_existing(&path, CleanupPolicy::LastCloseRefCounted)?;
+        map.inherit_in_forks(None);
+        Ok(RawShm { map })
+    }
+}
+
+impl AsFd for RawShm {
+    /// The descriptor of the backing file, which can be passed to other processes with
+    /// `send_fds`.
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.map.fd()
+    }
+}
@@ -0,0 +1,143 @@
+use std::{
+    cell::RefCell,
+    os::fd::OwnedFd,
+    sync::atomic::{AtomicU64, Ordering},
+};
+#[cfg(target_os = "linux")]
+use std::{
+    os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd},
+    process,
+};
+
+use nix::{errno::Errno, unistd::write};
+#[cfg(target_os = "linux")]
+use nix::{
+    fcntl::readlink,
+    libc::{
+        EFD_CLOEXEC, EFD_NONBLOCK, SYS_pidfd_getfd, SYS_pidfd_open, c_int, eventfd, pid_t, syscall,
+    },
+    unistd::read,
+};
+
+use crate::error::{Error, Result};
+
+/// Readiness handle of a channel, becoming readable when values arrive.
+///
+/// Register it with epoll, mio or tokio, and call `clear` before draining the channel once
+/// it reports readable. It is backed by an eventfd that senders duplicate with
+/// `pidfd_getfd`, which needs permission to ptrace the process that created it, so it
+/// stops being signaled once that process has exited.
+///
+/// Only available on Linux.
+#[cfg(target_os = "linux")]
+pub struct ReadyFd {
+    fd: OwnedFd,
+}
+
+#[cfg(target_os = "linux")]
+impl ReadyFd {
+    /// Resets the readiness, returning how many sends were signaled since the last call.
+    pub fn clear(&self) -> Result<u64> {
+        let mut buf = [0; 8];
+        match read(&self.fd, &mut buf) {
+            Ok(_) => Ok(u64::from_ne_bytes(buf)),
+            Err(Errno::EAGAIN) => Ok(0),
+            Err(e) => Err(Error::sys("read(eventfd)")(e)),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl AsFd for ReadyFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+/// Where the eventfd of a channel can be found, stored in the channel's shared memory.
+#[repr(C)]
+pub(crate) struct ReadySlot {
+    /// Pid of the process owning the eventfd in the upper half and its fd in the lower half,
+    /// or 0 if there is none.
+    owner: AtomicU64,
+}
+
+impl ReadySlot {
+    /// Returns a handle to the eventfd of the channel, creating and publishing it if there is
+    /// none yet or its owner has exited.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn ready_fd(&self) -> Result<ReadyFd> {
+        let owner = self.owner.load(Ordering::Acquire);
+        if owner != 0
+            && let Ok(fd) = open_owner(owner)
+        {
+            return Ok(ReadyFd { fd });
+        }
+
+        let raw_fd = Errno::result(unsafe { eventfd(0, EFD_NONBLOCK | EFD_CLOEXEC) })
+            .map_err(Error::sys("eventfd"))?;
+        let fd = unsafe { OwnedFd::from_raw_fd(raw_fd) };
+
+        let published = ((process::id() as u64) << 32) | fd.as_raw_fd() as u64;
+        self.owner.store(published, Ordering::Release);
+        Ok(ReadyFd { fd })
+    }
+}
+
+/// Sender-side cache of the eventfd published in a `ReadySlot`.
+#[derive(Default)]
+pub(crate) struct Signaler {
+    cached: RefCell<Option<(u64, OwnedFd)>>,
+}
+
+impl Signaler {
+    /// Signals the eventfd published in `slot`, if there is one.
+    /// Failures are ignored, since the channel itself works without readiness notification.
+    pub(crate) fn signal(&self, slot: &ReadySlot) {
+        let owner = slot.owner.load(Ordering::Acquire);
+        if owner == 0 {
+            return;
+        }
+
+        let mut cached = self.cached.borrow_mut();
+        if cached
+            .as_ref()
+            .is_none_or(|(cached_owner, _)| *cached_owner != owner)
+        {
+            *cached = open_owner(owner).ok().map(|fd| (owner, fd));
+        }
+        if let Some((_, fd)) = cached.as_ref() {
+            write(fd, &1u64.to_ne_bytes()).ok();
+        }
+    }
+}
+
+/// Duplicates the eventfd of another process with `pidfd_getfd`.
+/// Fails if the fd has since been closed or reused for anything but an eventfd.
+#[cfg(target_os = "linux")]
+fn open_owner(owner: u64) -> Result<OwnedFd> {
+    let (pid, raw_fd) = ((owner >> 32) as pid_t, owner as u32 as c_int);
+
+    let pidfd = Errno::result(unsafe { syscall(SYS_pidfd_open, pid, 0) })
+        .map_err(Error::sys("pidfd_open"))?;
+    let pidfd = unsafe { OwnedFd::from_raw_fd(pidfd as c_int) };
+
+    let fd = Errno::result(unsafe { syscall(SYS_pidfd_getfd, pidfd.as_raw_fd(), raw_fd, 0) })
+        .map_err(Error::sys("pidfd_getfd"))?;
+    let fd = unsafe { OwnedFd::from_raw_fd(fd as c_int) };
+
+    let target = readlink(format!("/proc/self/fd/{}", fd.as_raw_fd()).as_str())
+        .map_err(Error::sys("readlink"))?;
+    if target != "anon_inode:[eventfd]" {
+        return Err(Error::Validation(format!(
+            "fd {raw_fd} of process {pid} is no longer an eventfd"
+        )));
+    }
+    Ok(fd)
+}
+
+/// Nothing publishes an eventfd on other platforms, so there is never an owner to open.
+#[cfg(not(target_os = "linux"))]
+fn open_owner(_owner: u64) -> Result<OwnedFd> {
+    Err(Error::sys("pidfd_getfd")(Errno::ENOSYS))
+}
@@ -0,0 +1,237 @@
+use std::{
+    cell::UnsafeCell,
+    mem::size_of,
+    num::NonZeroUsize,
+    process, ptr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU32, Ordering},
+    },
+};
+
+use nix::fcntl::OFlag;
+
+use crate::{
+    cleanup::CleanupPolicy, error::Result, header::Header, liveness, map::Mapping,
+    namespace::Namespace, permissions::Permissions,
+};
+
+/// Number of handles the registry of a namespace can record at once.
+const SLOTS: usize = 512;
+
+/// Longest name recorded, in bytes. Longer names are cut off.
+const NAME_LEN: usize = 120;
+
+/// Marks a slot while its process writes the name into it.
+const CLAIMING: u32 = u32::MAX;
+
+#[repr(C)]
+struct Slot {
+    /// The process holding the handle, or 0 if the slot is free.
+    pid: AtomicU32,
+    len: AtomicU32,
+    name: UnsafeCell<[u8; NAME_LEN]>,
+}
+
+#[repr(C)]
+struct RegistryFile {
+    header: Header,
+    slots: [Slot; SLOTS],
+}
+
+/// The mapping of the registry of a namespace, shared by every handle of this process
+/// recorded in it.
+struct Segment {
+    path: String,
+    map: Mapping,
+}
+
+// The slots are claimed and released through their atomic pids, and names are only written
+// to slots claimed by the writer.
+unsafe impl Send for Segment {}
+unsafe impl Sync for Segment {}
+
+/// The registries opened by this process, kept open until it exits.
+static SEGMENTS: Mutex<Vec<Arc<Segment>>> = Mutex::new(Vec::new());
+
+/// A segment recording which processes have which objects of a namespace open, for
+/// diagnostics such as finding out who holds an object. Namespaces keep one if created with
+/// `Namespace::with_registry`, and handles opened through them are recorded until they are
+/// dropped.
+///
+/// Objects are recorded under their name in the namespace followed by the suffix of their
+/// kind, like `.mtx` for an `RMtx` and nothing for a `Shm`. Names are cut off after 120
+/// bytes. Up to 512 handles are recorded at a time; objects opened while the registry is
+/// full aren't. Handles a process inherits by forking aren't recorded for it, and entries
+/// of processes that died holding handles stay until they are pruned.
+pub struct ProcessRegistry {
+    segment: Arc<Segment>,
+}
+
+/// The entry of a handle in a registry, released when dropped.
+pub(crate) struct Registration {
+    segment: Arc<Segment>,
+    slot: usize,
+    pid: u32,
+}
+
+impl ProcessRegistry {
+    /// Opens the registry of `namespace`, creating it if no process has yet.
+    pub(crate) fn open(namespace: &Namespace) -> Result<Self> {
+        let path = namespace.path("nix-ipc", ".reg");
+        let mut segments = SEGMENTS.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(segment) = segments.iter().find(|s| s.path == path.to_string()) {
+            return Ok(Self {
+                segment: segment.clone(),
+            });
+        }
+
+        let data_len = size_of::<[Slot; SLOTS]>();
+        let len =
+            NonZeroUsize::new(size_of::<RegistryFile>()).expect("RegistryFile has nonzero size");
+        let map = Mapping::open_init(
+            &path,
+            OFlag::O_CREAT,
+            &Permissions::default(),
+            len,
+            CleanupPolicy::Never,
+            |raw| unsafe {
+                (*(raw as *mut RegistryFile))
+                    .header
+                    .init::<Slot>(data_len, 0);
+                Ok(())
+            },
+        )?;
+        let file = map.ptr() as *const RegistryFile;
+        {
+            let _init_lock = map.init_lock()?;
+            unsafe {
+                (*file).header.validate::<Slot>(data_len, 0)?;
+                (*file).header.attach(&map)?;
+            }
+        }
+
+        let segment = Arc::new(Segment {
+            path: path.to_string(),
+            map,
+        });
+        segments.push(segment.clone());
+        Ok(Self { segment })
+    }
+
+    /// Records that this process opened `name`, unless the registry is full.
+    pub(crate) fn register(&self, name: &str) -> Option<Registration> {
+        let pid = process::id();
+        let name = &name.as_bytes()[..name.len().min(NAME_LEN)];
+        (0..SLOTS).find_map(|index| {
+            let slot = self.segment.slot(index);
+            slot.pid
+                .compare_exchange(0, CLAIMING, Ordering::Acquire, Ordering::Relaxed)
+                .ok()?;
+            slot.len.store(name.len() as u32, Ordering::Relaxed);
+            unsafe {
+                ptr::copy_nonoverlapping(name.as_ptr(), slot.name.get() as *mut u8, name.len())
+            };
+            slot.pid.store(pid, Ordering::Release);
+            Some(Registration {
+                segment: self.segment.clone(),
+                slot: index,
+                pid,
+            })
+        })
+    }
+
+    /// Returns the processes with handles and the names of the objects they have open, one
+    /// entry per handle, after pruning the entries of dead processes.
+    pub fn entries(&self) -> Vec<(u32, String)> {
+        self.prune();
+        (0..SLOTS)
+            .filter_map(|index| self.segment.entry(index))
+            .collect()
+    }
+
+    /// Returns the live processes having the object recorded as `name` open.
+    pub fn holders(&self, name: &str) -> Vec<u32> {
+        let mut holders: Vec<u32> = self
+            .entries()
+            .into_iter()
+            .filter(|(_, entry)| is_entry_of(entry, name))
+            .map(|(pid, _)| pid)
+            .collect();
+        holders.sort_unstable();
+        holders.dedup();
+        holders
+    }
+
+    /// Returns true if any process, alive or dead, has an entry for the object recorded as
+    /// `name`. Unlike `holders`, this doesn't prune the entries of dead processes, which name
+    /// the objects they left behind.
+    pub(crate) fn records(&self, name: &str) -> bool {
+        (0..SLOTS)
+            .filter_map(|index| self.segment.entry(index))
+            .any(|(_, entry)| is_entry_of(&entry, name))
+    }
+
+    /// Frees the entries of processes that died without dropping their handles, returning
+    /// how many were freed.
+    pub fn prune(&self) -> usize {
+        (0..SLOTS)
+            .filter(|&index| {
+                let slot = self.segment.slot(index);
+                let pid = slot.pid.load(Ordering::Acquire);
+                pid != 0
+                    && pid != CLAIMING
+                    && !liveness::is_alive(pid)
+                    && slot
+                        .pid
+                        .compare_exchange(pid, 0, Ordering::AcqRel, Ordering::Relaxed)
+                        .is_ok()
+            })
+            .count()
+    }
+}
+
+impl Segment {
+    fn slot(&self, index: usize) -> &Slot {
+        unsafe { &(*(self.map.ptr() as *const RegistryFile)).slots[index] }
+    }
+
+    /// Reads the entry in slot `index`, if it holds one.
+    fn entry(&self, index: usize) -> Option<(u32, String)> {
+        let slot = self.slot(index);
+        let pid = slot.pid.load(Ordering::Acquire);
+        if pid == 0 || pid == CLAIMING {
+            return None;
+        }
+        // The slot may be released and claimed again while it is read, which the second
+        // look at the pid catches.
+        let mut name = [0; NAME_LEN];
+        let len = slot.len.load(Ordering::Relaxed) as usize;
+        unsafe {
+            ptr::copy_nonoverlapping(slot.name.get() as *const u8, name.as_mut_ptr(), NAME_LEN)
+        };
+        if slot.pid.load(Ordering::Acquire) != pid {
+            return None;
+        }
+        let name = String::from_utf8_lossy(&name[..len.min(NAME_LEN)]).into_owned();
+        Some((pid, name))
+    }
+}
+
+/// Returns true if `entry` records `name`, which is cut off like names in entries are.
+fn is_entry_of(entry: &str, name: &str) -> bool {
+    entry.as_bytes() == &name.as_bytes()[..name.len().min(NAME_LEN)]
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        // Children forked with the handle share its entry with the parent, and must not
+        // release it.
+        if self.pid == process::id() {
+            let slot = self.segment.slot(self.slot);
+            slot.pid
+                .compare_exchange(self.pid, 0, Ordering::Release, Ordering::Relaxed)
+                .ok();
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use std::{
+    mem::size_of,
+    num::NonZeroUsize,
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+use nix::fcntl::OFlag;
+
+use crate::{
+    backend::ObjectPath, builder::Builder, cleanup::CleanupPolicy, error::Result,
+    futex::EventCount, map::Mapping, namespace::Namespace, permissions::Permissions,
+};
+
+#[repr(C)]
+struct EventState {
+    /// Nonzero while the event is set.
+    signaled: AtomicU32,
+    event: EventCount,
+}
+
+/// Maps the state of the event at `path`, creating it unset with `permissions` if it doesn't
+/// exist.
+fn open(path: &ObjectPath, permissions: &Permissions) -> Result<Mapping> {
+    let len = NonZeroUsize::new(size_of::<EventState>()).expect("EventState has nonzero size");
+
+    // Zeroed memory is an unset event without waiters.
+    let map = Mapping::open_init(
+        path,
+        OFlag::O_CREAT,
+        permissions,
+        len,
+        CleanupPolicy::Never,
+        |_| Ok(()),
+    )?;
+    map.inherit_in_forks(None);
+    Ok(map)
+}
+
+fn state(map: &Mapping) -> &EventState {
+    unsafe { &*(map.ptr() as *const EventState) }
+}
+
+/// An event shared between processes that stays set until it is reset, like a Windows
+/// manual-reset event: while it is set, every `wait` returns immediately.
+pub struct ManualResetEvent {
+    map: Mapping,
+}
+
+impl ManualResetEvent {
+    /// Opens the event in /dev/shm, creating it unset if it doesn't exist.
+    pub fn new(name: &str) -> Result<Self> {
+        Self::builder(name).build()
+    }
+
+    /// Returns a builder for setting the namespace and the permissions the event is created with.
+    pub fn builder(name: &str) -> Builder<Self> {
+        Builder::new(name)
+    }
+
+    /// Unlinks (deletes) the event file from /dev/shm.
+    pub fn unlink(name: &str) -> Result<()> {
+        Self::unlink_in(&Namespace::default(), name)
+    }
+
+    /// Unlinks the event called `name` in `namespace`.
+    pub fn unlink_in(namespace: &Namespace, name: &str) -> Result<()> {
+        namespace.path(name, ".mre").unlink()
+    }
+
+    /// Sets the event, waking all waiters.
+    pub fn set(&self) {
+        let state = state(&self.map);
+        state.signaled.store(1, Ordering::Release);
+        state.event.notify_all();
+    }
+
+    /// Resets the event, so waiters block again.
+    pub fn reset(&self) {
+        state(&self.map).signaled.store(0, Ordering::Release);
+    }
+
+    /// Returns true if the event is set.
+    pub fn is_set(&self) -> bool {
+        state(&self.map).signaled.load(Ordering::Acquire) != 0
+    }
+
+    /// Blocks until the event is set.
+    pub fn wait(&self) -> Result<()> {
+        self.wait_for(None).map(drop)
+    }
+
+    /// Blocks until the event is set, returning false if `timeout` elapsed first.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<bool> {
+        self.wait_for(Some(timeout))
+    }
+
+    fn wait_for(&self, timeout: Option<Duration>) -> Result<bool> {
+        let set = state(&self.map)
+            .event
+            .wait_for(timeout, || self.is_set().then_some(()))?;
+        Ok(set.is_some())
+    }
+}
+
+impl Builder<ManualResetEvent> {
+    /// Opens the event like `ManualResetEvent::new`.
+    pub fn build(self) -> Result<ManualResetEvent> {
+        let map = open(&self.namespace.path(&self.name, ".mre"), &self.permissions)?;
+        Ok(ManualResetEvent { map })
+    }
+}
+
+/// An event shared between processes that resets itself when it releases a waiter, like a
+/// Windows auto-reset event: each `set` lets exactly one `wait` return, and setting an event
+/// that is already set has no effect.
+pub struct AutoResetEvent {
+    map: Mapping,
+}
+
+impl AutoResetEvent {
+    /// Opens the event in /dev/shm, creating it unset if it doesn't exist.
+    pub fn new(name: &str) -> Result<Self> {
+        Self::builder(name).build()
+    }
+
+    /// Returns a builder for setting the namespace and the permissions the event is created with.
+    pub fn builder(name: &str) -> Builder<Self> {
+        Builder::new(name)
+    }
+
+    /// Unlinks (deletes) the event file from /dev/shm.
+    pub fn unlink(name: &str) -> Result<()> {
+        Self::unlink_in(&Namespace::default(), name)
+    }
+
+    /// Unlinks the event called `name` in `namespace`.
+    pub fn unlink_in(namespace: &Namespace, name: &str) -> Result<()> {
+        namespace.path(name, ".are").unlink()
+    }
+
+    /// Sets the event, waking one waiter.
+    pub fn set(&self) {
+        let state = state(&self.map);
+        state.signaled.store(1, Ordering::Release);
+        state.event.notify_one();
+    }
+
+    /// Resets the event without releasing a waiter.
+    pub fn reset(&self) {
+        state(&self.map).signaled.store(0, Ordering::Release);
+    }
+
+    /// Resets the event if it is set, returning whether it was.
+    pub fn try_wait(&self) -> bool {
+        state(&self.map)
+            .signaled
+            .compare_exchange(1, 0, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    /// Blocks until the event is set, then resets it.
+    pub fn wait(&self) -> Result<()> {
+        self.wait_for(None).map(drop)
+    }
+
+    /// Blocks until the event is set, then resets it.
+    /// Returns false if `timeout` elapsed first.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<bool> {
+        self.wait_for(Some(timeout))
+    }
+
+    fn wait_for(&self, timeout: Option<Duration>) -> Result<bool> {
+        let set = state(&self.map)
+            .event
+            .wait_for(timeout, || self.try_wait().then_some(()))?;
+        Ok(set.is_some())
+    }
+}
+
+impl Builder<AutoResetEvent> {
+    /// Opens the event like `AutoResetEvent::new`.
+    pub fn build(self) -> Result<AutoResetEvent> {
+        let map = open(&self.namespace.path(&self.name, ".are"), &self.permissions)?;
+        Ok(AutoResetEvent { map })
+    }
+}
@@ -0,0 +1,433 @@
+//! Request/response calls between processes, over a unix socket or a pair of shared memory
+//! streams.
+//!
+//! A `Server` registers a handler per request type, and a `Client` calls it with
+//! `call::<Req, Resp>()`. Requests and responses are encoded with bincode and carry a
+//! correlation id, so any number of threads can have calls in flight on one client.
+
+use std::{
+    any::type_name,
+    collections::{HashMap, HashSet},
+    io::ErrorKind,
+    os::{
+        fd::{AsRawFd, OwnedFd},
+        unix::net::UnixStream,
+    },
+    sync::{
+        Condvar, Mutex, MutexGuard, PoisonError, TryLockError,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use nix::{
+    errno::Errno,
+    libc::{POLLIN, c_int, poll, pollfd},
+};
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{
+    error::{Error, Result},
+    framing::{decode, encode, read_frame, write_frame},
+    namespace::Namespace,
+    shm_safe::{FNV_OFFSET, fnv1a},
+    shm_stream::ShmStream,
+    unix_channel::UnixChannel,
+};
+
+/// Status of a response carrying the encoded result of the handler.
+const OK: u8 = 0;
+/// Status of a response carrying the message of an error the server ran into.
+const FAILED: u8 = 1;
+
+/// Request frames start with the correlation id and the method, response frames with the
+/// correlation id and the status.
+const REQUEST_HEADER: usize = 16;
+const RESPONSE_HEADER: usize = 9;
+
+/// Unlinks (deletes) the pair of streams of a shared memory transport from /dev/shm.
+pub fn unlink(name: &str) -> Result<()> {
+    unlink_in(&Namespace::default(), name)
+}
+
+/// Unlinks the pair of streams of the shared memory transport called `name` in `namespace`.
+pub fn unlink_in(namespace: &Namespace, name: &str) -> Result<()> {
+    ShmStream::unlink_in(namespace, &format!("{name}.req"))?;
+    ShmStream::unlink_in(namespace, &format!("{name}.resp"))
+}
+
+/// Identifies the handler of a call by its request and response types, so both ends must be
+/// built from the same type definitions.
+fn method<Req, Resp>() -> u64 {
+    let hash = fnv1a(FNV_OFFSET, type_name::<Req>().as_bytes());
+    fnv1a(hash, type_name::<Resp>().as_bytes())
+}
+
+/// Sending half of a transport.
+trait Sink: Send {
+    fn send_frame(&mut self, msg: &[u8]) -> Result<()>;
+}
+
+/// Receiving half of a transport.
+trait Source: Send {
+    /// Receives the next frame, or `None` if none started arriving within `timeout`.
+    fn recv_frame(&mut self, timeout: Option<Duration>) -> Result<Option<Vec<u8>>>;
+}
+
+impl Sink for UnixStream {
+    fn send_frame(&mut self, msg: &[u8]) -> Result<()> {
+        write_frame(&*self, msg)
+    }
+}
+
+impl Source for UnixStream {
+    fn recv_frame(&mut self, timeout: Option<Duration>) -> Result<Option<Vec<u8>>> {
+        if !poll_readable(self, timeout)? {
+            return Ok(None);
+        }
+        read_frame(&*self).map(Some)
+    }
+}
+
+impl Sink for ShmStream {
+    fn send_frame(&mut self, msg: &[u8]) -> Result<()> {
+        write_frame(self, msg)
+    }
+}
+
+impl Source for ShmStream {
+    fn recv_frame(&mut self, timeout: Option<Duration>) -> Result<Option<Vec<u8>>> {
+        if !self.wait_readable(timeout)? {
+            return Ok(None);
+        }
+        read_frame(self).map(Some)
+    }
+}
+
+/// Waits up to `timeout` until `fd` is readable or hung up, returning false if it isn't.
+fn poll_readable(fd: &impl AsRawFd, timeout: Option<Duration>) -> Result<bool> {
+    let timeout = match timeout {
+        Some(timeout) => timeout
+            .as_nanos()
+            .div_ceil(1_000_000)
+            .min(c_int::MAX as u128) as c_int,
+        None => -1,
+    };
+    let mut fds = [pollfd {
+        fd: fd.as_raw_fd(),
+        events: POLLIN,
+        revents: 0,
+    }];
+    match Errno::result(unsafe { poll(fds.as_mut_ptr(), 1, timeout) }) {
+        Ok(ready) => Ok(ready > 0),
+        Err(Errno::EINTR) => Ok(false),
+        Err(e) => Err(Error::sys("poll")(e)),
+    }
+}
+
+/// Splits a connected unix socket into its two halves.
+fn split_unix(channel: UnixChannel) -> Result<(Box<dyn Sink>, Box<dyn Source>)> {
+    let stream = UnixStream::from(OwnedFd::from(channel));
+    let reader = stream.try_clone()?;
+    Ok((Box::new(stream), Box::new(reader)))
+}
+
+/// Opens the streams of a shared memory transport, `outgoing` to send on and `incoming` to
+/// receive on.
+fn open_shm(
+    namespace: &Namespace,
+    outgoing: &str,
+    incoming: &str,
+    capacity: usize,
+) -> Result<(Box<dyn Sink>, Box<dyn Source>)> {
+    let sink = ShmStream::builder(outgoing)
+        .namespace(namespace)
+        .build(capacity)?;
+    let source = ShmStream::builder(incoming)
+        .namespace(namespace)
+        .build(capacity)?;
+    Ok((Box::new(sink), Box::new(source)))
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Calls in flight and the responses that arrived for them.
+#[derive(Default)]
+struct Calls {
+    waiting: HashSet<u64>,
+    /// Status and payload of each response, by correlation id.
+    responses: HashMap<u64, Vec<u8>>,
+}
+
+/// Calling end of an RPC connection.
+///
+/// Calls can be made from any number of threads at once. Whichever caller finds nobody
+/// receiving receives responses for everyone, until its own arrives, so no background thread
+/// is needed.
+pub struct Client {
+    sink: Mutex<Box<dyn Sink>>,
+    source: Mutex<Box<dyn Source>>,
+    next_id: AtomicU64,
+    calls: Mutex<Calls>,
+    /// Notified whenever a response arrived or the caller receiving them stopped.
+    answered: Condvar,
+}
+
+impl Client {
+    /// Makes calls over a connected unix socket, e.g. from `UnixChannel::connect`.
+    pub fn unix(channel: UnixChannel) -> Result<Self> {
+        let (sink, source) = split_unix(channel)?;
+        Ok(Self::with(sink, source))
+    }
+
+    /// Makes calls over a pair of shared memory streams in /dev/shm, creating them with
+    /// `capacity` bytes each if they don't exist. Only one client can use them at a time.
+    ///
+    /// Unlike a socket, the streams don't notice the server going away, so calls to a dead
+    /// server only end with their timeout.
+    pub fn shm(name: &str, capacity: usize) -> Result<Self> {
+        Self::shm_in(&Namespace::default(), name, capacity)
+    }
+
+    /// Like `shm`, with the streams of the transport called `name` in `namespace`.
+    pub fn shm_in(namespace: &Namespace, name: &str, capacity: usize) -> Result<Self> {
+        let (sink, source) = open_shm(
+            namespace,
+            &format!("{name}.req"),
+            &format!("{name}.resp"),
+            capacity,
+        )?;
+        Ok(Self::with(sink, source))
+    }
+
+    fn with(sink: Box<dyn Sink>, source: Box<dyn Source>) -> Self {
+        Self {
+            sink: Mutex::new(sink),
+            source: Mutex::new(source),
+            next_id: AtomicU64::new(0),
+            calls: Mutex::new(Calls::default()),
+            answered: Condvar::new(),
+        }
+    }
+
+    /// Calls the handler the server registered for `Req` and `Resp`, blocking until it
+    /// responded. Fails if the server has no such handler or the connection closed.
+    pub fn call<Req: Serialize, Resp: DeserializeOwned>(&self, request: &Req) -> Result<Resp> {
+        let response = self.call_until::<Req, Resp>(request, None)?;
+        Ok(response.expect("waiting without a timeout always yields a value"))
+    }
+
+    /// Like `call`, but gives up after `timeout`, returning `None`. A response arriving
+    /// after that is dropped.
+    pub fn call_timeout<Req: Serialize, Resp: DeserializeOwned>(
+        &self,
+        request: &Req,
+        timeout: Duration,
+    ) -> Result<Option<Resp>> {
+        self.call_until::<Req, Resp>(request, Some(Instant::now() + timeout))
+    }
+
+    fn call_until<Req: Serialize, Resp: DeserializeOwned>(
+        &self,
+        request: &Req,
+        deadline: Option<Instant>,
+    ) -> Result<Option<Resp>> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut msg = Vec::with_capacity(REQUEST_HEADER);
+        msg.extend_from_slice(&id.to_le_bytes());
+        msg.extend_from_slice(&method::<Req, Resp>().to_le_bytes());
+        msg.extend_from_slice(&encode(request)?);
+
+        lock(&self.calls).waiting.insert(id);
+        let _forget = Forget { client: self, id };
+        lock(&self.sink).send_frame(&msg)?;
+
+        let Some(response) = self.wait(id, deadline)? else {
+            return Ok(None);
+        };
+        match response.split_first() {
+            Some((&OK, payload)) => decode(payload).map(Some),
+            Some((_, message)) => Err(Error::Rpc(String::from_utf8_lossy(message).into_owned())),
+            None => Err(Error::Rpc("Empty response".to_owned())),
+        }
+    }
+
+    /// Waits for the response to the call `id`, receiving responses while nobody else does.
+    fn wait(&self, id: u64, deadline: Option<Instant>) -> Result<Option<Vec<u8>>> {
+        let mut calls = lock(&self.calls);
+        loop {
+            if let Some(response) = calls.responses.remove(&id) {
+                return Ok(Some(response));
+            }
+            let left = match deadline {
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return Ok(None);
+                    }
+                    Some(left)
+                }
+                None => None,
+            };
+
+            let mut source = match self.source.try_lock() {
+                Ok(source) => source,
+                Err(TryLockError::Poisoned(e)) => e.into_inner(),
+                Err(TryLockError::WouldBlock) => {
+                    calls = match left {
+                        Some(left) => {
+                            let (calls, _) = self
+                                .answered
+                                .wait_timeout(calls, left)
+                                .unwrap_or_else(PoisonError::into_inner);
+                            calls
+                        }
+                        None => self
+                            .answered
+                            .wait(calls)
+                            .unwrap_or_else(PoisonError::into_inner),
+                    };
+                    continue;
+                }
+            };
+
+            drop(calls);
+            let frame = source.recv_frame(left);
+            drop(source);
+            calls = lock(&self.calls);
+            // Hand receiving over to another caller, whether a response arrived or not.
+            self.answered.notify_all();
+            if let Some(frame) = frame? {
+                if frame.len() < RESPONSE_HEADER {
+                    return Err(Error::Rpc(format!(
+                        "Malformed response of {} bytes",
+                        frame.len()
+                    )));
+                }
+                let (response_id, response) = frame.split_at(8);
+                let response_id = u64::from_le_bytes(response_id.try_into().unwrap());
+                if calls.waiting.contains(&response_id) {
+                    calls.responses.insert(response_id, response.to_vec());
+                }
+            }
+        }
+    }
+}
+
+/// Forgets a call on every way out of it, so a response arriving late is dropped.
+struct Forget<'a> {
+    client: &'a Client,
+    id: u64,
+}
+
+impl Drop for Forget<'_> {
+    fn drop(&mut self) {
+        let mut calls = lock(&self.client.calls);
+        calls.waiting.remove(&self.id);
+        calls.responses.remove(&self.id);
+    }
+}
+
+type Handler = Box<dyn FnMut(&[u8]) -> Result<Vec<u8>>>;
+
+/// Serving end of an RPC connection, which handles one request at a time with the handler
+/// registered for its type.
+pub struct Server {
+    sink: Box<dyn Sink>,
+    source: Box<dyn Source>,
+    handlers: HashMap<u64, Handler>,
+}
+
+impl Server {
+    /// Serves calls over a connected unix socket, e.g. from `UnixChannelListener::accept`.
+    pub fn unix(channel: UnixChannel) -> Result<Self> {
+        let (sink, source) = split_unix(channel)?;
+        Ok(Self::with(sink, source))
+    }
+
+    /// Serves calls over a pair of shared memory streams in /dev/shm, creating them with
+    /// `capacity` bytes each if they don't exist.
+    pub fn shm(name: &str, capacity: usize) -> Result<Self> {
+        Self::shm_in(&Namespace::default(), name, capacity)
+    }
+
+    /// Like `shm`, with the streams of the transport called `name` in `namespace`.
+    pub fn shm_in(namespace: &Namespace, name: &str, capacity: usize) -> Result<Self> {
+        let (sink, source) = open_shm(
+            namespace,
+            &format!("{name}.resp"),
+            &format!("{name}.req"),
+            capacity,
+        )?;
+        Ok(Self::with(sink, source))
+    }
+
+    fn with(sink: Box<dyn Sink>, source: Box<dyn Source>) -> Self {
+        Self {
+            sink,
+            source,
+            handlers: HashMap::new(),
+        }
+    }
+
+    /// Registers `handler` for calls with requests of type `Req` expecting responses of type
+    /// `Resp`, replacing any handler registered for them before.
+    pub fn handle<Req, Resp, F>(&mut self, mut handler: F) -> &mut Self
+    where
+        Req: DeserializeOwned,
+        Resp: Serialize,
+        F: FnMut(Req) -> Resp + 'static,
+    {
+        let handler = move |payload: &[u8]| encode(&handler(decode(payload)?));
+        self.handlers
+            .insert(method::<Req, Resp>(), Box::new(handler));
+        self
+    }
+
+    /// Handles the next request, waiting up to `timeout` for it to arrive. Returns false if
+    /// none arrived in time.
+    pub fn serve_one(&mut self, timeout: Option<Duration>) -> Result<bool> {
+        let Some(frame) = self.source.recv_frame(timeout)? else {
+            return Ok(false);
+        };
+        if frame.len() < REQUEST_HEADER {
+            return Err(Error::Rpc(format!(
+                "Malformed request of {} bytes",
+                frame.len()
+            )));
+        }
+        let (id, method) = (&frame[..8], &frame[8..REQUEST_HEADER]);
+        let method = u64::from_le_bytes(method.try_into().unwrap());
+
+        let (status, payload) = match self.handlers.get_mut(&method) {
+            Some(handler) => match handler(&frame[REQUEST_HEADER..]) {
+                Ok(payload) => (OK, payload),
+                Err(e) => (FAILED, e.to_string().into_bytes()),
+            },
+            None => (
+                FAILED,
+                b"No handler registered for the request and response types".to_vec(),
+            ),
+        };
+        let mut msg = Vec::with_capacity(RESPONSE_HEADER + payload.len());
+        msg.extend_from_slice(id);
+        msg.push(status);
+        msg.extend_from_slice(&payload);
+        self.sink.send_frame(&msg)?;
+        Ok(true)
+    }
+
+    /// Handles requests until the client closes the connection. Over shared memory streams,
+    /// which don't notice the client going away, it only returns on errors.
+    pub fn serve(&mut self) -> Result<()> {
+        loop {
+            match self.serve_one(None) {
+                Ok(_) => {}
+                Err(Error::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
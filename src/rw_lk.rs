@@ -0,0 +1,108 @@
+use std::{mem::size_of, num::NonZeroUsize};
+
+use nix::fcntl::OFlag;
+
+use crate::{
+    builder::Builder,
+    cleanup::CleanupPolicy,
+    error::{Error, Result},
+    map::Mapping,
+    namespace::Namespace,
+    raw_lock::{self, RawRwLock},
+};
+
+/// An interprocess reader-writer lock implemented using `pthread_rwlock_t` and shared memory.
+/// Unlike `RMtx` it is not robust: a process dying while holding it leaves it locked.
+///
+/// Where `pthread_rwlock_t` can't be shared between processes, such as on NetBSD and OpenBSD,
+/// a lock built on atomics is used instead.
+pub struct RwLk {
+    map: Mapping,
+}
+
+impl RwLk {
+    pub fn new(name: &str) -> Result<Self> {
+        Self::builder(name).build()
+    }
+
+    /// Returns a builder for setting the namespace and the permissions the lock is created with.
+    pub fn builder(name: &str) -> Builder<Self> {
+        Builder::new(name)
+    }
+
+    /// Unlinks (deletes) the lock file from /dev/shm.
+    pub fn unlink(name: &str) -> Result<()> {
+        Self::unlink_in(&Namespace::default(), name)
+    }
+
+    /// Unlinks the lock called `name` in `namespace`.
+    pub fn unlink_in(namespace: &Namespace, name: &str) -> Result<()> {
+        namespace.path(name, ".rwl").unlink()
+    }
+
+    /// Acquires shared read access, returning a guard that releases it when dropped.
+    pub fn read(&self) -> Result<RwLkReadGuard<'_>> {
+        raw_lock::check(unsafe { raw_lock::read_lock(self.ptr())? })
+            .map_err(Error::lock("pthread_rwlock_rdlock"))?;
+        Ok(RwLkReadGuard { lk: self })
+    }
+
+    /// Acquires exclusive write access, returning a guard that releases it when dropped.
+    pub fn write(&self) -> Result<RwLkWriteGuard<'_>> {
+        raw_lock::check(unsafe { raw_lock::write_lock(self.ptr())? })
+            .map_err(Error::lock("pthread_rwlock_wrlock"))?;
+        Ok(RwLkWriteGuard { lk: self })
+    }
+
+    fn unlock(&self) -> Result<()> {
+        raw_lock::check(unsafe { raw_lock::unlock_rwlock(self.ptr()) })
+            .map(|_| ())
+            .map_err(Error::lock("pthread_rwlock_unlock"))
+    }
+
+    fn ptr(&self) -> *mut RawRwLock {
+        self.map.ptr() as *mut RawRwLock
+    }
+}
+
+impl Builder<RwLk> {
+    /// Opens the lock like `RwLk::new`.
+    pub fn build(self) -> Result<RwLk> {
+        let path = self.namespace.path(&self.name, ".rwl");
+        let len = NonZeroUsize::new(size_of::<RawRwLock>()).expect("RawRwLock has nonzero size");
+
+        let map = Mapping::open_init(
+            &path,
+            OFlag::O_CREAT,
+            &self.permissions,
+            len,
+            CleanupPolicy::Never,
+            |ptr| unsafe { raw_lock::init_rwlock(ptr as *mut RawRwLock) },
+        )?;
+        map.inherit_in_forks(None);
+
+        Ok(RwLk { map })
+    }
+}
+
+/// RAII guard returned by `RwLk::read`.
+pub struct RwLkReadGuard<'a> {
+    lk: &'a RwLk,
+}
+
+impl Drop for RwLkReadGuard<'_> {
+    fn drop(&mut self) {
+        self.lk.unlock().ok();
+    }
+}
+
+/// RAII guard returned by `RwLk::write`.
+pub struct RwLkWriteGuard<'a> {
+    lk: &'a RwLk,
+}
+
+impl Drop for RwLkWriteGuard<'_> {
+    fn drop(&mut self) {
+        self.lk.unlock().ok();
+    }
+}
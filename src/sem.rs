@@ -0,0 +1,248 @@
+#[cfg(target_os = "linux")]
+use std::path::Path;
+use std::{ffi::CString, time::Duration};
+
+use nix::{
+    errno::Errno,
+    libc::{
+        EAGAIN, EEXIST, EINTR, ENOENT, O_CREAT, O_EXCL, SEM_FAILED, c_uint, sem_close, sem_open,
+        sem_post, sem_t, sem_trywait, sem_unlink, sem_wait,
+    },
+};
+
+#[cfg(target_os = "linux")]
+use nix::libc::ETIMEDOUT;
+
+#[cfg(target_os = "linux")]
+use crate::time::deadline;
+use crate::{
+    builder::Builder,
+    error::{Error, Result},
+};
+
+#[cfg(target_env = "gnu")]
+unsafe extern "C" {
+    fn sem_clockwait(
+        sem: *mut sem_t,
+        clock: nix::libc::clockid_t,
+        abstime: *const nix::libc::timespec,
+    ) -> nix::libc::c_int;
+}
+
+/// How often `wait_timeout` retries where there is no `sem_timedwait`.
+#[cfg(not(target_os = "linux"))]
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// An interprocess counting semaphore implemented using named POSIX semaphores.
+pub struct Sem {
+    ptr: *mut sem_t,
+}
+
+impl Sem {
+    /// Opens the semaphore, creating it with `initial` as its value if it doesn't exist yet.
+    pub fn new(name: &str, initial: u32) -> Result<Self> {
+        Self::builder(name).build(initial)
+    }
+
+    /// Returns a builder for setting the namespace and the permissions the semaphore is created
+    /// with.
+    pub fn builder(name: &str) -> Builder<Self> {
+        Builder::new(name)
+    }
+
+    /// Removes the semaphore name; processes that have it open keep using it.
+    pub fn unlink(name: &str) -> Result<()> {
+        let path = format!("/{name}");
+        let c_name = CString::new(path.as_str())?;
+        Errno::result(unsafe { sem_unlink(c_name.as_ptr()) })
+            .map(|_| ())
+            .map_err(Error::unlink(&path))
+    }
+
+    /// Decrements the semaphore, blocking while its value is zero.
+    pub fn wait(&self) -> Result<()> {
+        loop {
+            if unsafe { sem_wait(self.ptr) } == 0 {
+                return Ok(());
+            }
+            match Errno::last_raw() {
+                EINTR => continue,
+                _ => return Err(Error::lock("sem_wait")(Errno::last())),
+            }
+        }
+    }
+
+    /// Decrements the semaphore if its value is positive, returning whether it did.
+    pub fn try_wait(&self) -> Result<bool> {
+        loop {
+            if unsafe { sem_trywait(self.ptr) } == 0 {
+                return Ok(true);
+            }
+            match Errno::last_raw() {
+                EINTR => continue,
+                EAGAIN => return Ok(false),
+                _ => return Err(Error::lock("sem_trywait")(Errno::last())),
+            }
+        }
+    }
+
+    /// Decrements the semaphore, giving up after `timeout` has elapsed.
+    /// Returns whether the semaphore was decremented.
+    #[cfg(target_os = "linux")]
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<bool> {
+        #[cfg(target_env = "gnu")]
+        let clock = nix::libc::CLOCK_MONOTONIC;
+        #[cfg(not(target_env = "gnu"))]
+        let clock = nix::libc::CLOCK_REALTIME;
+
+        let abstime = deadline(clock, timeout)?;
+        loop {
+            #[cfg(target_env = "gnu")]
+            let ret = unsafe { sem_clockwait(self.ptr, clock, &abstime) };
+            #[cfg(not(target_env = "gnu"))]
+            let ret = unsafe { nix::libc::sem_timedwait(self.ptr, &abstime) };
+
+            if ret == 0 {
+                return Ok(true);
+            }
+            match Errno::last_raw() {
+                EINTR => continue,
+                ETIMEDOUT => return Ok(false),
+                _ => return Err(Error::lock("sem_timedwait")(Errno::last())),
+            }
+        }
+    }
+
+    /// Decrements the semaphore, giving up after `timeout` has elapsed.
+    /// Returns whether the semaphore was decremented.
+    ///
+    /// Without `sem_timedwait` this retries `try_wait` until the timeout elapses.
+    #[cfg(not(target_os = "linux"))]
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<bool> {
+        let start = std::time::Instant::now();
+        loop {
+            if self.try_wait()? {
+                return Ok(true);
+            }
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                return Ok(false);
+            }
+            std::thread::sleep(POLL_INTERVAL.min(timeout - elapsed));
+        }
+    }
+
+    /// Increments the semaphore, waking up a waiter if there is one.
+    pub fn post(&self) -> Result<()> {
+        Errno::result(unsafe { sem_post(self.ptr) })
+            .map(|_| ())
+            .map_err(Error::lock("sem_post"))
+    }
+
+    /// Returns the current value of the semaphore.
+    /// Fails on macOS, which doesn't implement `sem_getvalue`.
+    pub fn value(&self) -> Result<i32> {
+        #[cfg(not(target_os = "macos"))]
+        {
+            let mut value = 0;
+            Errno::result(unsafe { nix::libc::sem_getvalue(self.ptr, &mut value) })
+                .map_err(Error::lock("sem_getvalue"))?;
+            Ok(value)
+        }
+        #[cfg(target_os = "macos")]
+        Err(Error::lock("sem_getvalue")(Errno::ENOSYS))
+    }
+}
+
+impl Builder<Sem> {
+    /// Opens the semaphore like `Sem::new`.
+    ///
+    /// Only on Linux are named semaphores files whose permissions can be changed after
+    /// creating them. Elsewhere the mode is passed to `sem_open`, so the umask applies, and
+    /// the owner and group are ignored. Only the prefix of the namespace applies.
+    pub fn build(self, initial: u32) -> Result<Sem> {
+        let name = self.namespace.object_name(&self.name);
+        let path = format!("/{name}");
+        let c_name = CString::new(path.as_str())?;
+        let mode = self.permissions.mode().bits() as c_uint;
+        loop {
+            // Creating exclusively tells whether this handle created the semaphore.
+            let flags = O_CREAT | O_EXCL;
+            let ptr = unsafe { sem_open(c_name.as_ptr(), flags, mode, initial as c_uint) };
+            if ptr != SEM_FAILED {
+                let sem = Sem { ptr };
+                #[cfg(target_os = "linux")]
+                self.permissions
+                    .apply_path(Path::new(&format!("/dev/shm/sem.{name}")))?;
+                return Ok(sem);
+            }
+            if Errno::last_raw() != EEXIST {
+                let source = Errno::last();
+                return Err(Error::Create { path, source });
+            }
+
+            let ptr = unsafe { sem_open(c_name.as_ptr(), 0) };
+            if ptr != SEM_FAILED {
+                return Ok(Sem { ptr });
+            }
+            // Not finding it means it was unlinked in the meantime, so it's created again.
+            if Errno::last_raw() != ENOENT {
+                let source = Errno::last();
+                return Err(Error::Open { path, source });
+            }
+        }
+    }
+}
+
+impl Drop for Sem {
+    fn drop(&mut self) {
+        unsafe {
+            Errno::result(sem_close(self.ptr)).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+    use crate::namespace::Namespace;
+
+    #[test]
+    fn errors_tell_creating_apart_from_unlinking() {
+        let name = format!("test-sem-missing-{}", process::id());
+        assert!(matches!(
+            Sem::unlink(&name),
+            Err(Error::Unlink {
+                source: Errno::ENOENT,
+                ..
+            })
+        ));
+        assert!(matches!(
+            Sem::new("test-sem/nested", 0),
+            Err(Error::Create { .. })
+        ));
+    }
+
+    #[test]
+    fn creates_and_opens_the_same_semaphore() {
+        let name = format!("test-sem-shared-{}", process::id());
+        let created = Sem::new(&name, 0).unwrap();
+        let opened = Sem::new(&name, 5).unwrap();
+        created.post().unwrap();
+        assert!(opened.try_wait().unwrap());
+        assert!(!opened.try_wait().unwrap());
+        Sem::unlink(&name).unwrap();
+    }
+
+    #[test]
+    fn namespace_prefixes_the_semaphore_name() {
+        let namespace = Namespace::default().with_prefix(format!("test-{}.", process::id()));
+        let created = namespace.sem("prefixed", 0).unwrap();
+        let opened = Sem::new(&format!("test-{}.prefixed", process::id()), 0).unwrap();
+        created.post().unwrap();
+        assert!(opened.try_wait().unwrap());
+        namespace.unlink_sem("prefixed").unwrap();
+    }
+}
@@ -0,0 +1,359 @@
+use std::{
+    cell::UnsafeCell,
+    marker::PhantomData,
+    mem::{MaybeUninit, size_of},
+    num::NonZeroUsize,
+    process, ptr,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering, fence},
+    thread,
+    time::{Duration, Instant},
+};
+
+use nix::fcntl::OFlag;
+
+use crate::{
+    builder::Builder,
+    cache_padded::CachePadded,
+    cleanup::CleanupPolicy,
+    error::{Error, Result},
+    header::Header,
+    liveness::is_alive,
+    map::Mapping,
+    namespace::Namespace,
+    shm_safe::ShmSafe,
+};
+
+/// Spins a reader does on a write in progress before yielding its time slice.
+const SPINS_BEFORE_YIELD: u32 = 64;
+
+#[repr(C)]
+struct SeqLockState<T> {
+    header: Header,
+    /// Odd while a write is in progress, bumped twice by every write.
+    seq: CachePadded<AtomicU64>,
+    /// The pid of the writing process, set before the counter turns odd and cleared after it
+    /// turns even again, or 0 if nobody writes.
+    writer: AtomicU32,
+    value: UnsafeCell<T>,
+}
+
+/// A value in shared memory that one process updates and any number of processes read
+/// without taking a lock.
+///
+/// The writer bumps a sequence counter before and after changing the value. Readers copy
+/// the value and retry if the counter was odd or changed meanwhile, so they never see a torn
+/// value and never slow down the writer. Reads get slower the more often the value is
+/// written, which suits telemetry snapshots updated at a steady rate.
+///
+/// Concurrent writers are serialized by spinning until the pid of the writing process is
+/// cleared. A writer dying in the middle of a write leaves the value torn and the counter
+/// odd: reads and `update` then fail with `Error::WriterDied`, until `write` replaces the
+/// value. Should its pid be reused by another process first, readers keep retrying instead.
+pub struct SeqLock<T: 'static> {
+    map: Mapping,
+    _marker: PhantomData<T>,
+}
+
+impl<T: ShmSafe + Copy> SeqLock<T> {
+    /// Opens the value in /dev/shm, creating it holding `value` if it doesn't exist.
+    /// Opening an existing one keeps its current value.
+    pub fn new(name: &str, value: T) -> Result<Self> {
+        Self::builder(name).build(value)
+    }
+
+    /// Returns a builder for setting the namespace and the permissions the seqlock is created with.
+    pub fn builder(name: &str) -> Builder<Self> {
+        Builder::new(name)
+    }
+
+    /// Unlinks (deletes) the value from /dev/shm.
+    pub fn unlink(name: &str) -> Result<()> {
+        Self::unlink_in(&Namespace::default(), name)
+    }
+
+    /// Unlinks the value called `name` in `namespace`.
+    pub fn unlink_in(namespace: &Namespace, name: &str) -> Result<()> {
+        namespace.path(name, ".seq").unlink()
+    }
+
+    /// Returns a copy of the current value, retrying while a write is in progress. Fails with
+    /// `Error::WriterDied` if the writer died in the middle of its write.
+    pub fn read(&self) -> Result<T> {
+        let mut spins = 0;
+        loop {
+            if let Some(value) = self.try_read()? {
+                return Ok(value);
+            }
+            back_off(&mut spins);
+        }
+    }
+
+    /// Like `read`, but returns `None` if writes kept the value from being read for
+    /// `timeout`.
+    pub fn read_timeout(&self, timeout: Duration) -> Result<Option<T>> {
+        let deadline = Instant::now() + timeout;
+        let mut spins = 0;
+        loop {
+            if let Some(value) = self.try_read()? {
+                return Ok(Some(value));
+            }
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+            back_off(&mut spins);
+        }
+    }
+
+    /// Returns a copy of the current value, or `None` if a write was in progress. Fails with
+    /// `Error::WriterDied` if the writer died in the middle of its write.
+    pub fn try_read(&self) -> Result<Option<T>> {
+        let state = self.state();
+        let before = state.seq.load(Ordering::Acquire);
+        if before % 2 == 1 {
+            let writer = state.writer.load(Ordering::Acquire);
+            return match writer != 0 && !is_alive(writer) {
+                true => Err(Error::WriterDied),
+                false => Ok(None),
+            };
+        }
+
+        // The copy may be torn, so it stays uninitialized until the counter proves it isn't.
+        let value = unsafe { ptr::read_volatile(state.value.get() as *const MaybeUninit<T>) };
+        fence(Ordering::Acquire);
+        let after = state.seq.load(Ordering::Relaxed);
+        Ok((before == after).then(|| unsafe { value.assume_init() }))
+    }
+
+    /// Replaces the value, taking over from a writer that died in the middle of a write.
+    pub fn write(&self, value: T) {
+        let write = self
+            .begin_write(true)
+            .expect("replacing the value always succeeds");
+        unsafe { ptr::write_volatile(self.state().value.get(), value) };
+        drop(write);
+    }
+
+    /// Changes the value in place with `f`. Readers keep retrying until `f` returns, so it
+    /// should be short. Fails with `Error::WriterDied` if a writer died in the middle of a
+    /// write, as `f` would see the value it left torn.
+    pub fn update<F>(&self, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut T),
+    {
+        let write = self.begin_write(false)?;
+        let state = self.state();
+        let mut value = unsafe { ptr::read_volatile(state.value.get()) };
+        f(&mut value);
+        unsafe { ptr::write_volatile(state.value.get(), value) };
+        drop(write);
+        Ok(())
+    }
+
+    /// Waits until no other process writes, then records this one as the writer and makes
+    /// the counter odd. A writer that died is taken over, unless it left the value torn and
+    /// `replace` is false.
+    fn begin_write(&self, replace: bool) -> Result<Write<'_, T>> {
+        let state = self.state();
+        let me = process::id();
+        loop {
+            let writer = state.writer.load(Ordering::Acquire);
+            if writer != 0 && is_alive(writer) {
+                std::hint::spin_loop();
+                continue;
+            }
+            let seq = state.seq.load(Ordering::Relaxed);
+            if writer != 0 && seq % 2 == 1 && !replace {
+                return Err(Error::WriterDied);
+            }
+            if state
+                .writer
+                .compare_exchange_weak(writer, me, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                // Stays odd if the dead writer left it odd.
+                let seq = seq | 1;
+                state.seq.store(seq, Ordering::Relaxed);
+                fence(Ordering::Release);
+                return Ok(Write { lock: self, seq });
+            }
+        }
+    }
+
+    /// The number of writes made so far, which readers can compare to skip unchanged values.
+    pub fn version(&self) -> u64 {
+        self.state().seq.load(Ordering::Acquire) / 2
+    }
+
+    fn state(&self) -> &SeqLockState<T> {
+        unsafe { &*(self.map.ptr() as *const SeqLockState<T>) }
+    }
+}
+
+/// A write in progress, which makes the counter even again and clears the writer when
+/// dropped, also if `update` panics, which leaves the value as it was.
+struct Write<'a, T: 'static> {
+    lock: &'a SeqLock<T>,
+    seq: u64,
+}
+
+impl<T: 'static> Drop for Write<'_, T> {
+    fn drop(&mut self) {
+        let state = unsafe { &*(self.lock.map.ptr() as *const SeqLockState<T>) };
+        state.seq.store(self.seq + 1, Ordering::Release);
+        state.writer.store(0, Ordering::Release);
+    }
+}
+
+impl<T: ShmSafe + Copy> Builder<SeqLock<T>> {
+    /// Opens the seqlock like `SeqLock::new`.
+    pub fn build(self, value: T) -> Result<SeqLock<T>> {
+        if size_of::<T>() == 0 {
+            return Err(Error::InvalidArgument(
+                "Cannot use zero-sized type in shared memory".to_owned(),
+            ));
+        }
+        let len =
+            NonZeroUsize::new(size_of::<SeqLockState<T>>()).expect("SeqLockState has nonzero size");
+
+        let path = self.namespace.path(&self.name, ".seq");
+        let map = Mapping::open_init(
+            &path,
+            OFlag::O_CREAT,
+            &self.permissions,
+            len,
+            CleanupPolicy::LastCloseRefCounted,
+            |raw| unsafe {
+                let state = raw as *mut SeqLockState<T>;
+                (*state).header.init::<T>(size_of::<T>(), 0);
+                (*state).value = UnsafeCell::new(value);
+                Ok(())
+            },
+        )?;
+
+        let state = map.ptr() as *const SeqLockState<T>;
+        {
+            let _init_lock = map.init_lock()?;
+            unsafe {
+                (*state).header.validate::<T>(size_of::<T>(), 0)?;
+                (*state).header.attach(&map)?;
+            }
+        }
+
+        Ok(SeqLock {
+            map,
+            _marker: PhantomData,
+        })
+    }
+}
+
+/// Spins a while on a write in progress, then yields the time slice between retries.
+fn back_off(spins: &mut u32) {
+    *spins += 1;
+    if *spins < SPINS_BEFORE_YIELD {
+        std::hint::spin_loop();
+    } else {
+        thread::yield_now();
+    }
+}
+
+impl<T: 'static> Drop for SeqLock<T> {
+    fn drop(&mut self) {
+        let state = self.map.ptr() as *const SeqLockState<T>;
+        unsafe { (*state).header.detach(&self.map) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, mem};
+
+    use nix::libc;
+
+    use super::*;
+
+    /// Opens a seqlock under a name unique to this test and process, removed again on drop.
+    struct TestLock {
+        name: String,
+        lock: SeqLock<u64>,
+    }
+
+    impl TestLock {
+        fn new(test: &str) -> Self {
+            let name = format!("test-{test}-{}", process::id());
+            SeqLock::<u64>::unlink(&name).ok();
+            let lock = SeqLock::new(&name, 1).unwrap();
+            Self { name, lock }
+        }
+    }
+
+    impl Drop for TestLock {
+        fn drop(&mut self) {
+            SeqLock::<u64>::unlink(&self.name).ok();
+        }
+    }
+
+    #[test]
+    fn read_timeout_gives_up_on_a_write_in_progress() {
+        let test = TestLock::new("seq-timeout");
+        let write = test.lock.begin_write(false).unwrap();
+        assert_eq!(test.lock.try_read().unwrap(), None);
+        let read = test.lock.read_timeout(Duration::from_millis(10));
+        assert_eq!(read.unwrap(), None);
+        drop(write);
+        assert_eq!(test.lock.read_timeout(Duration::ZERO).unwrap(), Some(1));
+    }
+
+    #[test]
+    fn writer_dying_mid_write_is_detected_until_replaced() {
+        let test = TestLock::new("seq-writer-died");
+        match unsafe { libc::fork() } {
+            -1 => panic!("fork failed"),
+            0 => {
+                mem::forget(test.lock.begin_write(false).unwrap());
+                unsafe { libc::_exit(0) }
+            }
+            child => {
+                let mut status = 0;
+                assert_eq!(unsafe { libc::waitpid(child, &mut status, 0) }, child);
+            }
+        }
+
+        assert!(matches!(test.lock.read(), Err(Error::WriterDied)));
+        assert!(matches!(
+            test.lock.read_timeout(Duration::from_secs(1)),
+            Err(Error::WriterDied)
+        ));
+        assert!(matches!(
+            test.lock.update(|value| *value += 1),
+            Err(Error::WriterDied)
+        ));
+
+        test.lock.write(2);
+        assert_eq!(test.lock.read().unwrap(), 2);
+        test.lock.update(|value| *value += 1).unwrap();
+        assert_eq!(test.lock.read().unwrap(), 3);
+    }
+
+    #[test]
+    fn builder_opens_the_lock_in_its_namespace() {
+        let dir = env::temp_dir().join(format!("nix-ipc-test-seq-namespace-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let namespace = Namespace::new(dir.display().to_string()).with_prefix("app.");
+
+        let lock = SeqLock::builder("config")
+            .namespace(&namespace)
+            .build(7u64)
+            .unwrap();
+        assert!(dir.join("app.config.seq").exists());
+        let reopened = SeqLock::<u64>::builder("config")
+            .namespace(&namespace)
+            .build(0)
+            .unwrap();
+        lock.write(8);
+        assert_eq!(reopened.read().unwrap(), 8);
+
+        SeqLock::<u64>::unlink_in(&namespace, "config").unwrap();
+        assert!(!dir.join("app.config.seq").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
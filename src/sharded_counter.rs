@@ -0,0 +1,171 @@
+use std::{
+    mem::size_of,
+    num::NonZeroUsize,
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use nix::fcntl::OFlag;
+
+use crate::{
+    builder::Builder,
+    cache_padded::CachePadded,
+    cleanup::CleanupPolicy,
+    error::{Error, Result},
+    header::Header,
+    map::Mapping,
+    namespace::Namespace,
+};
+
+#[repr(C)]
+struct CounterHeader {
+    header: Header,
+    shards: u64,
+}
+
+/// A counter in shared memory split into cache-line-padded shards, so processes incrementing
+/// it concurrently don't bounce a single cache line between their CPUs.
+///
+/// `add` bumps the shard of the CPU the caller runs on, or of its process where the CPU
+/// can't be queried, and `sum` adds up all shards. A sum taken while others add lies between
+/// the values the counter had when the call started and returned. Around as many shards as
+/// CPUs avoids contention entirely.
+pub struct ShardedCounter {
+    map: Mapping,
+    shards: usize,
+}
+
+impl ShardedCounter {
+    /// Opens the counter in /dev/shm, creating it at zero with `shards` shards if it doesn't
+    /// exist. Attaching to a counter with a different number of shards fails.
+    pub fn new(name: &str, shards: usize) -> Result<Self> {
+        Self::builder(name).build(shards)
+    }
+
+    /// Returns a builder for setting the namespace and the permissions the counter is created with.
+    pub fn builder(name: &str) -> Builder<Self> {
+        Builder::new(name)
+    }
+
+    /// Unlinks (deletes) the counter from /dev/shm.
+    pub fn unlink(name: &str) -> Result<()> {
+        Self::unlink_in(&Namespace::default(), name)
+    }
+
+    /// Unlinks the counter called `name` in `namespace`.
+    pub fn unlink_in(namespace: &Namespace, name: &str) -> Result<()> {
+        namespace.path(name, ".ctr").unlink()
+    }
+
+    /// Adds `n` to the counter.
+    pub fn add(&self, n: u64) {
+        self.shard(self.current_shard())
+            .fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Adds one to the counter.
+    pub fn increment(&self) {
+        self.add(1);
+    }
+
+    /// The sum of all shards.
+    pub fn sum(&self) -> u64 {
+        (0..self.shards).fold(0, |sum, index| {
+            sum.wrapping_add(self.shard(index).load(Ordering::Relaxed))
+        })
+    }
+
+    /// Resets the counter to zero and returns the sum it had. Adds made during the call are
+    /// either included in the returned sum or kept in the counter.
+    pub fn take(&self) -> u64 {
+        (0..self.shards).fold(0, |sum, index| {
+            sum.wrapping_add(self.shard(index).swap(0, Ordering::Relaxed))
+        })
+    }
+
+    /// The number of shards the counter is split into.
+    pub fn shards(&self) -> usize {
+        self.shards
+    }
+
+    #[cfg(target_os = "linux")]
+    fn current_shard(&self) -> usize {
+        match unsafe { nix::libc::sched_getcpu() } {
+            -1 => process::id() as usize % self.shards,
+            cpu => cpu as usize % self.shards,
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn current_shard(&self) -> usize {
+        process::id() as usize % self.shards
+    }
+
+    /// Offset of the first shard, after the header and aligned to a cache line.
+    fn data_offset() -> usize {
+        size_of::<CounterHeader>().next_multiple_of(align_of::<CachePadded<AtomicU64>>())
+    }
+
+    fn shard(&self, index: usize) -> &AtomicU64 {
+        unsafe {
+            let shards = (self.map.ptr() as *const u8).add(Self::data_offset())
+                as *const CachePadded<AtomicU64>;
+            &(*shards.add(index)).0
+        }
+    }
+}
+
+impl Builder<ShardedCounter> {
+    /// Opens the counter like `ShardedCounter::new`.
+    pub fn build(self, shards: usize) -> Result<ShardedCounter> {
+        if shards == 0 {
+            return Err(Error::InvalidArgument(
+                "Counter must have at least one shard".to_owned(),
+            ));
+        }
+        let data_len = shards
+            .checked_mul(size_of::<CachePadded<AtomicU64>>())
+            .ok_or_else(|| {
+                Error::InvalidArgument(format!("Counter of {shards} shards is too large"))
+            })?;
+        let map_len = NonZeroUsize::new(ShardedCounter::data_offset() + data_len)
+            .expect("CounterHeader has nonzero size");
+
+        let path = self.namespace.path(&self.name, ".ctr");
+        let map = Mapping::open_init(
+            &path,
+            OFlag::O_CREAT,
+            &self.permissions,
+            map_len,
+            CleanupPolicy::LastCloseRefCounted,
+            |raw| unsafe {
+                let counter = raw as *mut CounterHeader;
+                (*counter)
+                    .header
+                    .init::<CachePadded<AtomicU64>>(data_len, 0);
+                (*counter).shards = shards as u64;
+                Ok(())
+            },
+        )?;
+
+        let counter = map.ptr() as *const CounterHeader;
+        {
+            let _init_lock = map.init_lock()?;
+            unsafe {
+                (*counter)
+                    .header
+                    .validate::<CachePadded<AtomicU64>>(data_len, 0)?;
+                (*counter).header.attach(&map)?;
+            }
+        }
+
+        Ok(ShardedCounter { map, shards })
+    }
+}
+
+impl Drop for ShardedCounter {
+    fn drop(&mut self) {
+        let counter = self.map.ptr() as *const CounterHeader;
+        unsafe { (*counter).header.detach(&self.map) };
+    }
+}
@@ -16,58 +16,364 @@
 // UnsafeCell helps Rust correctly handle shared memory by preventing incorrect assumptions,
 // and synchronization (like mutexes) ensures safe, correct access.
 
-use std::{cell::UnsafeCell, ffi::c_void, mem::size_of, num::NonZeroUsize, os::fd::OwnedFd};
-
-use anyhow::{Result, anyhow};
-use nix::{
-    errno::Errno,
-    fcntl::{OFlag, open},
-    libc::{munmap, off_t},
-    sys::{
-        mman::{MapFlags, ProtFlags, mmap},
-        stat::Mode,
-    },
-    unistd::ftruncate,
+use std::{
+    cell::UnsafeCell,
+    ffi::c_void,
+    marker::PhantomData,
+    mem::size_of,
+    num::NonZeroUsize,
+    os::fd::{AsFd, BorrowedFd, OwnedFd},
+    ptr,
 };
 
+use nix::{fcntl::OFlag, sys::mman::ProtFlags};
+
+use crate::{
+    backend::ObjectPath,
+    cleanup::CleanupPolicy,
+    error::{Error, Result},
+    header::Header,
+    map::{HugePages, MapOptions, Mapping, MsyncMode},
+    namespace::Namespace,
+    permissions::Permissions,
+    shm_safe::{ShmAtomic, ShmSafe},
+};
+
+#[repr(C)]
+struct Segment<T> {
+    header: Header,
+    data: UnsafeCell<T>,
+}
+
+/// Shared memory holding a `T`.
+///
+/// By default the object is unlinked from /dev/shm when the last handle across all processes
+/// is dropped; use `ShmBuilder::persistent` to keep it around instead.
+///
+/// A child created with `fork` can keep using the handles it inherits, which count as
+/// handles of its own, like those of every other named primitive. Guards of locks held
+/// while forking must be leaked with `mem::forget` in the child, since the locks stay held
+/// by the parent's thread.
 pub struct Shm<T: 'static> {
-    _fd: OwnedFd,
+    map: Mapping,
     ptr: *mut UnsafeCell<T>,
-    len: NonZeroUsize,
+    /// How the handle was opened, to open the object again with `reattach`.
+    settings: AttachSettings,
+}
+
+/// How a `Shm` handle attaches to its object.
+#[derive(Debug, Default, Clone, Copy)]
+struct AttachSettings {
+    schema_version: u32,
+    /// `ShmSafe::FINGERPRINT` of T, or 0 where T isn't known to be `ShmSafe`.
+    fingerprint: u64,
+    options: MapOptions,
+    /// Makes the mapping only writable within `access`.
+    write_protect: bool,
 }
 
 impl<T: 'static> Shm<T> {
     /// Creates and opens object in /dev/shm and maps it.
     /// The generic type T, representing the shared data, should almost always be `#[repr(C)]`.   
-    pub fn new(name: &str) -> Result<Self> {
-        let path = format!("/dev/shm/{}", name);
-        let shm_size = size_of::<T>();
+    /// Same as `open_or_create`.
+    pub fn new(name: &str) -> Result<Self>
+    where
+        T: ShmSafe,
+    {
+        Self::open_or_create(name)
+    }
 
-        let len = NonZeroUsize::new(shm_size)
-            .ok_or_else(|| anyhow!("Cannot use zero-sized type in shared memory"))?;
+    /// Returns a builder for configuring how the object is opened and cleaned up.
+    pub fn builder(name: &str) -> ShmBuilder<T> {
+        ShmBuilder {
+            name: name.to_owned(),
+            cleanup: CleanupPolicy::LastCloseRefCounted,
+            schema_version: 0,
+            permissions: Permissions::default(),
+            namespace: Namespace::default(),
+            file: None,
+            options: MapOptions::default(),
+            write_protect: false,
+            _marker: PhantomData,
+        }
+    }
 
-        let fd = open(
-            path.as_str(),
-            OFlag::O_CREAT | OFlag::O_RDWR,
-            Mode::from_bits_truncate(0o600),
-        )?;
+    /// Returns a builder for mapping the regular file at `path`, e.g. on persistent memory or
+    /// an NVMe drive, so the data survives reboots. Unlike other objects, it is kept when the
+    /// last handle is dropped unless `cleanup` says otherwise. Use `msync` to make sure
+    /// changes reached the disk.
+    pub fn file_backed(path: impl Into<String>) -> ShmBuilder<T> {
+        let path = path.into();
+        ShmBuilder {
+            file: Some(path.clone()),
+            ..Self::builder(&path).persistent()
+        }
+    }
+
+    /// Opens the object in /dev/shm, creating it if it doesn't exist yet, and maps it.
+    pub fn open_or_create(name: &str) -> Result<Self>
+    where
+        T: ShmSafe,
+    {
+        Self::builder(name).open_or_create()
+    }
+
+    /// Creates the object in /dev/shm and maps it, failing if it already exists.
+    pub fn create_exclusive(name: &str) -> Result<Self>
+    where
+        T: ShmSafe,
+    {
+        Self::builder(name).create_exclusive()
+    }
 
-        ftruncate(&fd, shm_size as off_t)?;
-
-        let raw_ptr = unsafe {
-            mmap(
-                None,
-                len,
-                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
-                MapFlags::MAP_SHARED,
-                &fd,
-                0,
-            )?
+    /// Opens an existing object in /dev/shm and maps it, failing if it doesn't exist.
+    pub fn open(name: &str) -> Result<Self>
+    where
+        T: ShmSafe,
+    {
+        Self::builder(name).open()
+    }
+
+    /// Opens the object, creating it if it doesn't exist yet, tagged with `schema_version`.
+    /// Attaching to an object created with a different schema version fails.
+    pub fn versioned(name: &str, schema_version: u32) -> Result<Self>
+    where
+        T: ShmSafe,
+    {
+        Self::builder(name)
+            .schema_version(schema_version)
+            .open_or_create()
+    }
+
+    /// Opens an existing object in /dev/shm for reading only, mapping it without `PROT_WRITE`.
+    pub fn open_readonly(name: &str) -> Result<ShmReader<T>>
+    where
+        T: ShmSafe,
+    {
+        Self::builder(name).open_readonly()
+    }
+
+    /// Opens an existing object in /dev/shm and maps a private copy of it, see `ShmPrivate`.
+    pub fn open_private(name: &str) -> Result<ShmPrivate<T>>
+    where
+        T: ShmSafe,
+    {
+        Self::builder(name).open_private()
+    }
+
+    /// Like `open_or_create`, but without requiring `T: ShmSafe`.
+    ///
+    /// # Safety
+    ///
+    /// T must uphold the `ShmSafe` contract even though it doesn't implement the trait.
+    pub unsafe fn new_unchecked(name: &str) -> Result<Self> {
+        unsafe { Self::builder(name).open_with(OFlag::O_CREAT, 0) }
+    }
+
+    /// Opens the object in /dev/shm, creating it if it doesn't exist yet, and maps it.
+    /// `init` constructs the initial value and runs only in the process that creates the object,
+    /// while other processes opening it concurrently wait for it to finish.
+    pub fn new_with<F>(name: &str, init: F) -> Result<Self>
+    where
+        T: ShmSafe,
+        F: FnOnce() -> T,
+    {
+        Self::builder(name).open_or_create_with(init)
+    }
+
+    /// Creates a segment backed by an anonymous memfd instead of a file in /dev/shm.
+    /// It can be shared with child processes through `fork`, or with other processes by
+    /// sending its descriptor with `send_fds`, and disappears once no process uses it anymore.
+    /// Where there is no memfd, such as on macOS, an unlinked temporary file is used instead.
+    pub fn anonymous() -> Result<Self>
+    where
+        T: ShmSafe,
+    {
+        Self::anonymous_with_seals(false)
+    }
+
+    /// Like `anonymous`, but seals the size of the memfd so processes receiving its
+    /// descriptor can't shrink it and make other processes' accesses fault. Only available on Linux.
+    #[cfg(target_os = "linux")]
+    pub fn anonymous_sealed() -> Result<Self>
+    where
+        T: ShmSafe,
+    {
+        Self::anonymous_with_seals(true)
+    }
+
+    fn anonymous_with_seals(seal: bool) -> Result<Self>
+    where
+        T: ShmSafe,
+    {
+        let settings = AttachSettings {
+            fingerprint: T::FINGERPRINT,
+            ..AttachSettings::default()
         };
+        let map = Mapping::anonymous(Self::len()?, seal, |raw| {
+            Self::init_header(raw, &settings);
+            Ok(())
+        })?;
+        Self::attach(map, settings)
+    }
+
+    /// Maps a segment from a file descriptor, e.g. one received with `recv_fds` from a
+    /// process that sent the descriptor of its own `Shm`. The segment is never unlinked
+    /// through this handle.
+    pub fn from_fd(fd: OwnedFd) -> Result<Self>
+    where
+        T: ShmSafe,
+    {
+        let map = Mapping::from_fd(fd, Self::len()?)?;
+        let settings = AttachSettings {
+            fingerprint: T::FINGERPRINT,
+            ..AttachSettings::default()
+        };
+        Self::attach(map, settings)
+    }
+
+    /// Unlinks (deletes) the shared memory object from the filesystem.
+    /// Processes that have it mapped keep using it until they drop their handles.
+    pub fn unlink(name: &str) -> Result<()> {
+        Namespace::default().unlink_shm(name)
+    }
+
+    /// Writes changes to the data out to the backing file, for segments created with
+    /// `file_backed`. Segments in memory have nothing to write out.
+    pub fn msync(&self, mode: MsyncMode) -> Result<()> {
+        self.map.msync(mode)
+    }
+
+    /// Returns the number of handles currently attached to the object across all processes.
+    /// Handles of crashed processes are counted until a process attaches to the object alone.
+    pub fn attach_count(&self) -> u32 {
+        self.header().attached()
+    }
+
+    fn len() -> Result<NonZeroUsize> {
+        if size_of::<T>() == 0 {
+            return Err(Error::InvalidArgument(
+                "Cannot use zero-sized type in shared memory".to_owned(),
+            ));
+        }
+        Ok(NonZeroUsize::new(size_of::<Segment<T>>()).expect("Segment<T> has nonzero size"))
+    }
+
+    /// Writes the header of a freshly created segment.
+    fn init_header(raw: *mut c_void, settings: &AttachSettings) -> *mut Segment<T> {
+        let segment = raw as *mut Segment<T>;
+        unsafe {
+            let header = &mut (*segment).header;
+            header.init::<T>(size_of::<T>(), settings.schema_version);
+            header.set_fingerprint(settings.fingerprint);
+        }
+        segment
+    }
+
+    /// Validates the header of the mapped segment and registers the new handle in it, then
+    /// takes write access away outside of `access` if the settings ask to.
+    fn attach(map: Mapping, settings: AttachSettings) -> Result<Self> {
+        let segment = map.ptr() as *mut Segment<T>;
+        let header = unsafe { &(*segment).header };
+
+        let _init_lock = map.init_lock()?;
+        header.validate::<T>(size_of::<T>(), settings.schema_version)?;
+        header.validate_fingerprint::<T>(settings.fingerprint)?;
+        header.attach(&map)?;
+
+        let ptr = unsafe { &raw mut (*segment).data };
+        let shm = Self { map, ptr, settings };
+        if settings.write_protect {
+            shm.map.protect(ProtFlags::PROT_READ)?;
+        }
+        Ok(shm)
+    }
+
+    /// Identifies the segment among all segments created under the same name, e.g. to tell
+    /// whether two processes refer to the same one.
+    pub fn generation(&self) -> u64 {
+        self.header().generation()
+    }
+
+    /// Returns true if the object this handle maps was unlinked, and possibly created again
+    /// under the same name since. A stale handle keeps working on the old segment, which
+    /// other processes opening the name don't see. Handles of anonymous segments are never
+    /// stale.
+    pub fn is_stale(&self) -> Result<bool> {
+        let Some(object) = self.map.object() else {
+            return Ok(false);
+        };
+        let len = NonZeroUsize::new(size_of::<Header>()).expect("Header has nonzero size");
+        let Some(current) = Mapping::peek(object, len)? else {
+            return Ok(true);
+        };
+        let header = unsafe { &*(current.ptr() as *const Header) };
+        Ok(header.generation() != self.generation())
+    }
+
+    /// Attaches to the object now under the name of this handle if the handle is stale,
+    /// opening it the way this handle was opened. Returns true if the handle moved to the
+    /// new segment, and fails if the object was unlinked without being created again.
+    pub fn reattach(&mut self) -> Result<bool>
+    where
+        T: ShmSafe,
+    {
+        if !self.is_stale()? {
+            return Ok(false);
+        }
+        let object = self
+            .map
+            .object()
+            .expect("only named segments go stale")
+            .clone();
+        let map = Mapping::open_init_with(
+            &object,
+            OFlag::empty(),
+            &Permissions::default(),
+            Self::len()?,
+            self.map.cleanup(),
+            self.settings.options,
+            |_| Ok(()),
+        )?;
+        *self = Self::attach(map, self.settings)?;
+        Ok(true)
+    }
 
-        let ptr = raw_ptr.as_ptr() as *mut UnsafeCell<T>;
+    fn header(&self) -> &Header {
+        unsafe { &(*(self.map.ptr() as *const Segment<T>)).header }
+    }
 
-        Ok(Self { _fd: fd, ptr, len })
+    /// Returns a shared reference to the data, for types that are safe to share concurrently,
+    /// such as a `#[repr(C)]` struct of atomic counters.
+    pub fn get(&self) -> &T
+    where
+        T: ShmAtomic,
+    {
+        unsafe { &*(*self.ptr).get() }
+    }
+
+    /// Returns a reference to an atomic field of the data selected by `field`, e.g.
+    /// `shm.atomic(|stats| &stats.hits)`, so it can be updated without `access` while other
+    /// processes do the same. Fails if `field` returns a reference outside of the data.
+    pub fn atomic<A, F>(&self, field: F) -> Result<&A>
+    where
+        A: ShmAtomic,
+        F: for<'a> FnOnce(&'a T) -> &'a A,
+    {
+        let data = unsafe { &*(*self.ptr).get() };
+        let atomic = field(data);
+
+        let start = data as *const T as usize;
+        let offset = (atomic as *const A as usize).wrapping_sub(start);
+        if offset
+            .checked_add(size_of::<A>())
+            .is_none_or(|end| end > size_of::<T>())
+        {
+            return Err(Error::InvalidArgument(
+                "Atomic field is not part of the shared data".to_owned(),
+            ));
+        }
+        Ok(atomic)
     }
 
     /// Provides exclusive access to the shared memory data using a closure.
@@ -75,26 +381,392 @@ impl<T: 'static> Shm<T> {
     where
         F: FnOnce(&mut T) -> R,
     {
+        let _window = self
+            .settings
+            .write_protect
+            .then(|| WriteWindow::open(&self.map));
         let data = unsafe { &mut *self.ptr };
         accessor(data.get_mut())
     }
-
-    // use nix::unistd::unlink;
-    //
-    // /// Unlinks (deletes) the shared memory object from the filesystem.
-    // pub fn unlink(name: &str) -> Result<()> {
-    //     let path = format!("/dev/shm/{}", name);
-    //     println!("Attempting to unlink shared memory object: {}", path);
-    //     unlink(path.as_str())?;
-    //     Ok(())
-    // }
 }
 
 impl<T: 'static> Drop for Shm<T> {
     fn drop(&mut self) {
+        if self.settings.write_protect {
+            let prot = ProtFlags::PROT_READ | ProtFlags::PROT_WRITE;
+            self.map.protect(prot).ok();
+        }
+        self.header().detach(&self.map);
+
         unsafe {
-            std::ptr::drop_in_place(self.ptr);
-            Errno::result(munmap(self.ptr as *mut c_void, self.len.get())).ok();
+            ptr::drop_in_place(self.ptr);
+        }
+    }
+}
+
+impl<T: 'static> AsFd for Shm<T> {
+    /// The descriptor of the backing file, which can be passed to other processes with
+    /// `send_fds`.
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.map.fd()
+    }
+}
+
+/// Builder for `Shm`, created with `Shm::builder`.
+pub struct ShmBuilder<T: 'static> {
+    name: String,
+    cleanup: CleanupPolicy,
+    schema_version: u32,
+    permissions: Permissions,
+    namespace: Namespace,
+    /// Path of a regular file to map, instead of the object called `name` in `namespace`.
+    file: Option<String>,
+    options: MapOptions,
+    write_protect: bool,
+    _marker: PhantomData<T>,
+}
+
+impl<T: 'static> ShmBuilder<T> {
+    /// Sets what happens to the object in /dev/shm when the handle is dropped.
+    /// Defaults to `CleanupPolicy::LastCloseRefCounted`.
+    pub fn cleanup(mut self, cleanup: CleanupPolicy) -> Self {
+        self.cleanup = cleanup;
+        self
+    }
+
+    /// Sets the schema version stored in the header; attaching with a different one fails.
+    /// Defaults to 0.
+    pub fn schema_version(mut self, schema_version: u32) -> Self {
+        self.schema_version = schema_version;
+        self
+    }
+
+    /// Keeps the object in /dev/shm after the last handle is dropped.
+    /// Same as `cleanup(CleanupPolicy::Never)`.
+    pub fn persistent(self) -> Self {
+        self.cleanup(CleanupPolicy::Never)
+    }
+
+    /// Creates the backing file readable but not writable by other users, so they can only
+    /// attach with `open_readonly`. Only applies if this handle creates the object.
+    pub fn read_only_for_others(mut self) -> Self {
+        self.permissions = Permissions::new(0o644);
+        self
+    }
+
+    /// Sets the permissions the backing file is created with. Only applies if this handle
+    /// creates the object. Defaults to read and write access for the creating user only.
+    pub fn permissions(mut self, permissions: Permissions) -> Self {
+        self.permissions = permissions;
+        self
+    }
+
+    /// Opens the object in `namespace` instead of the default one. Has no effect on builders
+    /// created with `Shm::file_backed`.
+    pub fn namespace(mut self, namespace: &Namespace) -> Self {
+        self.namespace = namespace.clone();
+        self
+    }
+
+    /// Backs the mapping with huge pages, for objects of hundreds of megabytes or more.
+    /// Falls back to regular pages where huge pages aren't available. Defaults to
+    /// `HugePages::Never`.
+    pub fn huge_pages(mut self, huge_pages: HugePages) -> Self {
+        self.options.huge_pages = huge_pages;
+        self
+    }
+
+    /// Faults all pages of the object in while mapping it, so the first access to each page
+    /// doesn't take a page fault.
+    pub fn prefault(mut self) -> Self {
+        self.options.prefault = true;
+        self
+    }
+
+    /// Locks the pages of the object in memory while it is mapped, so they are never swapped
+    /// out and never fault. Opening fails if that exceeds `RLIMIT_MEMLOCK`, which is low for
+    /// unprivileged processes on most systems.
+    pub fn lock_in_memory(mut self) -> Self {
+        self.options.lock = true;
+        self
+    }
+
+    /// Leaves the object out of core dumps of this process, e.g. to keep secrets it holds
+    /// off disk. Only has an effect on Linux.
+    pub fn dont_dump(mut self) -> Self {
+        self.options.dont_dump = true;
+        self
+    }
+
+    /// Tells the kernel the whole object will be used soon, so it starts reading in pages
+    /// that were swapped out or live on disk, without waiting for them like `prefault`.
+    pub fn will_need(mut self) -> Self {
+        self.options.will_need = true;
+        self
+    }
+
+    /// Leaves the mapping out of children this process forks, e.g. when they exec another
+    /// program right away. A child must neither use nor drop the handle, since its mapping
+    /// is gone there. Only has an effect on Linux.
+    pub fn dont_fork(mut self) -> Self {
+        self.options.dont_fork = true;
+        self
+    }
+
+    /// Places an inaccessible guard page before and after the mapping, so stray accesses
+    /// just past either end of the object fault right away instead of corrupting whatever
+    /// else this process has mapped there. Where the object doesn't end on a page boundary,
+    /// the rest of its last page stays accessible. Makes `HugePages::Explicit` fall back to
+    /// `HugePages::Transparent`.
+    pub fn guard_pages(mut self) -> Self {
+        self.options.guard_pages = true;
+        self
+    }
+
+    /// Maps the object read-only except while `Shm::access` runs, so writes from anywhere
+    /// else in this process fault instead of silently changing the data. Costs two
+    /// `mprotect` calls per `access`. Stores through `get` and `atomic` fault too, while
+    /// writes by other threads during an `access` go through unnoticed.
+    pub fn write_protect(mut self) -> Self {
+        self.write_protect = true;
+        self
+    }
+
+    /// Allocates the pages of the object from NUMA node `node`, for objects shared by
+    /// processes pinned to that node. The pages come from other nodes only once `node` runs
+    /// out of memory. Opening fails if the node doesn't exist. Only has an effect on Linux.
+    pub fn numa_node(mut self, node: u32) -> Self {
+        self.options.numa_node = Some(node);
+        self
+    }
+
+    /// Sets whether the descriptor of the object is closed in programs started with `exec`.
+    /// Clearing it lets a re-executed worker inherit the descriptor and map the segment with
+    /// `Shm::from_fd`. Defaults to `true`, so the descriptor doesn't leak into subcommands.
+    pub fn cloexec(mut self, cloexec: bool) -> Self {
+        self.options.inheritable = !cloexec;
+        self
+    }
+
+    /// Opens the object, creating it if it doesn't exist yet.
+    pub fn open_or_create(self) -> Result<Shm<T>>
+    where
+        T: ShmSafe,
+    {
+        unsafe { self.open_with(OFlag::O_CREAT, T::FINGERPRINT) }
+    }
+
+    /// Creates the object, failing if it already exists.
+    pub fn create_exclusive(self) -> Result<Shm<T>>
+    where
+        T: ShmSafe,
+    {
+        unsafe { self.open_with(OFlag::O_CREAT | OFlag::O_EXCL, T::FINGERPRINT) }
+    }
+
+    /// Opens an existing object, failing if it doesn't exist.
+    pub fn open(self) -> Result<Shm<T>>
+    where
+        T: ShmSafe,
+    {
+        unsafe { self.open_with(OFlag::empty(), T::FINGERPRINT) }
+    }
+
+    /// Opens the object, creating it if it doesn't exist yet and running `init` only in
+    /// the process that creates it.
+    pub fn open_or_create_with<F>(self, init: F) -> Result<Shm<T>>
+    where
+        T: ShmSafe,
+        F: FnOnce() -> T,
+    {
+        let path = self.object();
+        let settings = self.settings(T::FINGERPRINT);
+        let mut map = Mapping::open_init_with(
+            &path,
+            OFlag::O_CREAT,
+            &self.permissions,
+            Shm::<T>::len()?,
+            self.cleanup,
+            self.options,
+            |raw| {
+                let segment = Shm::<T>::init_header(raw, &settings);
+                unsafe { ptr::write((*segment).data.get(), init()) };
+                Ok(())
+            },
+        )?;
+        map.record_in(&self.namespace, &self.name, "")?;
+        Shm::attach(map, settings)
+    }
+
+    /// Opens an existing object for reading only.
+    pub fn open_readonly(self) -> Result<ShmReader<T>>
+    where
+        T: ShmSafe,
+    {
+        let path = self.object();
+        let map = Mapping::open_readonly(&path, Shm::<T>::len()?)?;
+
+        let segment = map.ptr() as *const Segment<T>;
+        {
+            let _init_lock = map.init_lock()?;
+            let header = unsafe { &(*segment).header };
+            header.validate::<T>(size_of::<T>(), self.schema_version)?;
+            header.validate_fingerprint::<T>(T::FINGERPRINT)?;
         }
+
+        let ptr = unsafe { &raw const (*segment).data };
+        Ok(ShmReader { _map: map, ptr })
+    }
+
+    /// Opens an existing object and maps a private copy of it, see `ShmPrivate`.
+    pub fn open_private(self) -> Result<ShmPrivate<T>>
+    where
+        T: ShmSafe,
+    {
+        let path = self.object();
+        let map = Mapping::open_private(&path, Shm::<T>::len()?)?;
+
+        let segment = map.ptr() as *mut Segment<T>;
+        {
+            let _init_lock = map.init_lock()?;
+            let header = unsafe { &(*segment).header };
+            header.validate::<T>(size_of::<T>(), self.schema_version)?;
+            header.validate_fingerprint::<T>(T::FINGERPRINT)?;
+        }
+
+        let ptr = unsafe { &raw mut (*segment).data };
+        Ok(ShmPrivate { _map: map, ptr })
+    }
+
+    fn settings(&self, fingerprint: u64) -> AttachSettings {
+        AttachSettings {
+            schema_version: self.schema_version,
+            fingerprint,
+            options: self.options,
+            write_protect: self.write_protect,
+        }
+    }
+
+    fn object(&self) -> ObjectPath {
+        match &self.file {
+            Some(file) => ObjectPath::File(file.clone()),
+            None => self.namespace.path(&self.name, ""),
+        }
+    }
+
+    /// Opens the object with `flags`, recording or checking `fingerprint` unless it is 0.
+    unsafe fn open_with(self, flags: OFlag, fingerprint: u64) -> Result<Shm<T>> {
+        let path = self.object();
+        let settings = self.settings(fingerprint);
+        let mut map = Mapping::open_init_with(
+            &path,
+            flags,
+            &self.permissions,
+            Shm::<T>::len()?,
+            self.cleanup,
+            self.options,
+            |raw| {
+                Shm::<T>::init_header(raw, &settings);
+                Ok(())
+            },
+        )?;
+        map.record_in(&self.namespace, &self.name, "")?;
+        Shm::attach(map, settings)
+    }
+}
+
+/// Read-only view of shared memory holding a `T`, created with `Shm::open_readonly`.
+/// The data is mapped with `PROT_READ` only, so it can't be modified through this handle.
+pub struct ShmReader<T: 'static> {
+    _map: Mapping,
+    ptr: *const UnsafeCell<T>,
+}
+
+impl<T: 'static> ShmReader<T> {
+    /// Provides shared access to the shared memory data using a closure.
+    pub fn access<R, F>(&self, accessor: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        let data = unsafe { &*self.ptr };
+        accessor(unsafe { &*data.get() })
+    }
+}
+
+/// Private copy of shared memory holding a `T`, created with `Shm::open_private`.
+///
+/// The object is mapped copy-on-write and every page is copied when opening, so the data can
+/// be changed freely without other processes seeing it, and changes they make afterwards
+/// don't show up here. Copying the pages isn't atomic, so the copy is only consistent if no
+/// process writes the object while it is opened.
+pub struct ShmPrivate<T: 'static> {
+    _map: Mapping,
+    ptr: *mut UnsafeCell<T>,
+}
+
+impl<T: 'static> ShmPrivate<T> {
+    /// Returns a shared reference to the copy, which no other process can change.
+    pub fn get(&self) -> &T {
+        unsafe { &*(*self.ptr).get() }
+    }
+
+    /// Provides exclusive access to the copy using a closure.
+    pub fn access<R, F>(&mut self, accessor: F) -> R
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        let data = unsafe { &mut *self.ptr };
+        accessor(data.get_mut())
+    }
+}
+
+/// Makes a write-protected mapping writable until dropped.
+struct WriteWindow<'a> {
+    map: &'a Mapping,
+}
+
+impl<'a> WriteWindow<'a> {
+    fn open(map: &'a Mapping) -> Self {
+        let prot = ProtFlags::PROT_READ | ProtFlags::PROT_WRITE;
+        map.protect(prot)
+            .expect("protection of a whole mapping can always be changed");
+        Self { map }
+    }
+}
+
+impl Drop for WriteWindow<'_> {
+    fn drop(&mut self) {
+        self.map.protect(ProtFlags::PROT_READ).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::*;
+
+    #[test]
+    fn segments_created_with_an_initializer_are_registered() {
+        let dir = env::temp_dir().join(format!("nix-ipc-test-shm-registry-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let namespace = Namespace::new(dir.display().to_string()).with_registry();
+
+        let shm = namespace
+            .shm::<u64>("initialized")
+            .open_or_create_with(|| 7)
+            .unwrap();
+        let pid = process::id();
+        assert_eq!(namespace.registry().unwrap().holders("initialized"), [pid]);
+        drop(shm);
+        assert!(
+            namespace
+                .registry()
+                .unwrap()
+                .holders("initialized")
+                .is_empty()
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
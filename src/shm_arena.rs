@@ -0,0 +1,224 @@
+use std::{
+    marker::PhantomData,
+    mem::{align_of, size_of},
+    os::fd::{AsFd, BorrowedFd},
+    ptr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{
+    builder::Builder,
+    error::{Error, Result},
+    namespace::Namespace,
+    raw_shm::RawShm,
+    shm_safe::{FNV_OFFSET, ShmAtomic, ShmSafe, fnv1a_u64},
+};
+
+/// The first bytes of the arena hold the bump offset of the next allocation.
+const HEADER_LEN: usize = size_of::<AtomicU64>();
+
+/// A bump allocator living inside a shared memory segment.
+///
+/// Allocations are identified by `ShmBox` handles holding offsets into the segment, which stay
+/// valid in every process even though the segment is mapped at different addresses.
+/// Memory is never freed individually; it is reclaimed when the segment is removed.
+pub struct ShmArena {
+    shm: RawShm,
+}
+
+impl ShmArena {
+    /// Opens the arena in /dev/shm, creating it with `capacity` bytes if it doesn't exist.
+    pub fn new(name: &str, capacity: usize) -> Result<Self> {
+        Self::builder(name).build(capacity)
+    }
+
+    /// Returns a builder for setting the namespace and the permissions the arena is created with.
+    pub fn builder(name: &str) -> Builder<Self> {
+        Builder::new(name)
+    }
+
+    /// Opens an existing arena in /dev/shm.
+    pub fn open(name: &str) -> Result<Self> {
+        Self::builder(name).open()
+    }
+
+    /// Unlinks (deletes) the arena from /dev/shm.
+    pub fn unlink(name: &str) -> Result<()> {
+        RawShm::unlink(name)
+    }
+
+    /// Unlinks the arena called `name` in `namespace`.
+    pub fn unlink_in(namespace: &Namespace, name: &str) -> Result<()> {
+        RawShm::unlink_in(namespace, name)
+    }
+
+    /// Allocates a zeroed `T` in the arena.
+    pub fn alloc<T: ShmSafe>(&self) -> Result<ShmBox<T>> {
+        self.alloc_bytes(size_of::<T>(), align_of::<T>())
+            .map(ShmBox::from_offset)
+    }
+
+    /// Allocates a `T` in the arena and moves `value` into it.
+    pub fn alloc_with<T: ShmSafe>(&self, value: T) -> Result<ShmBox<T>> {
+        let boxed = self.alloc::<T>()?;
+        unsafe { ptr::write(self.ptr(boxed), value) };
+        Ok(boxed)
+    }
+
+    /// Returns a pointer to the allocation in this process.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the handle doesn't lie within this arena.
+    pub fn ptr<T: ShmSafe>(&self, boxed: ShmBox<T>) -> *mut T {
+        self.shm
+            .ptr_at::<T>(boxed.offset as usize)
+            .expect("ShmBox does not belong to this arena")
+    }
+
+    /// Returns a reference to the allocation, for types that are safe to share concurrently.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the handle doesn't lie within this arena.
+    pub fn get<T: ShmAtomic>(&self, boxed: ShmBox<T>) -> &T {
+        self.shm
+            .view_at::<T>(boxed.offset as usize)
+            .expect("ShmBox does not belong to this arena")
+    }
+
+    /// Number of bytes handed out so far, including the arena header.
+    pub fn used(&self) -> usize {
+        self.next().load(Ordering::Acquire) as usize
+    }
+
+    /// Total size of the arena in bytes.
+    pub fn capacity(&self) -> usize {
+        self.shm.len()
+    }
+
+    fn alloc_bytes(&self, size: usize, align: usize) -> Result<u64> {
+        let base = self.shm.as_ptr() as usize;
+        let mut current = self.next().load(Ordering::Acquire);
+        loop {
+            // Align the absolute address, since the mapping itself is only page-aligned.
+            let start = (base + current as usize).next_multiple_of(align) - base;
+            let end = start
+                .checked_add(size.max(1))
+                .filter(|&end| end <= self.capacity())
+                .ok_or_else(|| {
+                    Error::InvalidArgument(format!(
+                        "Arena out of memory: {size} bytes requested, {} of {} used",
+                        current,
+                        self.capacity()
+                    ))
+                })?;
+
+            match self.next().compare_exchange_weak(
+                current,
+                end as u64,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return Ok(start as u64),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    fn next(&self) -> &AtomicU64 {
+        self.shm
+            .view_at::<AtomicU64>(0)
+            .expect("arena header is in bounds")
+    }
+}
+
+impl Builder<ShmArena> {
+    /// Opens the arena like `ShmArena::new`.
+    pub fn build(self, capacity: usize) -> Result<ShmArena> {
+        let shm = RawShm::builder(&self.name)
+            .permissions(self.permissions)
+            .namespace(&self.namespace)
+            .build(capacity.max(HEADER_LEN))?;
+        let arena = ShmArena { shm };
+        // A fresh segment is zero-filled, so the first allocation starts after the header.
+        arena
+            .next()
+            .compare_exchange(0, HEADER_LEN as u64, Ordering::AcqRel, Ordering::Acquire)
+            .ok();
+        Ok(arena)
+    }
+
+    /// Opens the existing arena like `ShmArena::open`.
+    pub fn open(self) -> Result<ShmArena> {
+        let shm = RawShm::builder(&self.name)
+            .namespace(&self.namespace)
+            .open()?;
+        if shm.len() < HEADER_LEN {
+            return Err(Error::Validation(format!(
+                "{} is too small to hold an arena",
+                self.name
+            )));
+        }
+        Ok(ShmArena { shm })
+    }
+}
+
+impl AsFd for ShmArena {
+    /// The descriptor of the backing file, which can be passed to other processes with
+    /// `send_fds`.
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.shm.as_fd()
+    }
+}
+
+/// Handle to a `T` allocated in a `ShmArena`, stored as an offset into the segment.
+///
+/// Handles are plain data, so they can themselves be stored in shared memory and passed
+/// between processes. The zeroed handle is null and never refers to an allocation.
+#[repr(C)]
+pub struct ShmBox<T> {
+    offset: u64,
+    _marker: PhantomData<T>,
+}
+
+impl<T> ShmBox<T> {
+    /// The null handle.
+    pub const fn null() -> Self {
+        Self::from_offset(0)
+    }
+
+    pub const fn is_null(&self) -> bool {
+        self.offset == 0
+    }
+
+    /// Offset of the allocation from the start of the arena.
+    pub const fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    const fn from_offset(offset: u64) -> Self {
+        Self {
+            offset,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T> Clone for ShmBox<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for ShmBox<T> {}
+
+impl<T> std::fmt::Debug for ShmBox<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ShmBox").field(&self.offset).finish()
+    }
+}
+
+unsafe impl<T: ShmSafe> ShmSafe for ShmBox<T> {
+    const FINGERPRINT: u64 = fnv1a_u64(fnv1a_u64(FNV_OFFSET, 0x5348_4d42_4f58), T::FINGERPRINT);
+}
@@ -0,0 +1,346 @@
+use std::{
+    marker::PhantomData,
+    mem::{align_of, size_of},
+    num::NonZeroUsize,
+    ops::{Deref, DerefMut},
+    os::fd::{AsFd, BorrowedFd},
+    slice,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use nix::{fcntl::OFlag, libc::EBUSY};
+
+use crate::{
+    builder::Builder,
+    cleanup::CleanupPolicy,
+    error::{Error, Result},
+    header::Header,
+    map::Mapping,
+    namespace::Namespace,
+    r_mtx::{LockResult, MutexKind, MutexProtocol, acquired},
+    raw_lock::{self, RawMutex},
+    shm_safe::{ShmAtomic, ShmSafe},
+};
+
+#[repr(C)]
+struct ArrayHeader {
+    header: Header,
+    /// Number of elements, only modified under the init flock.
+    len: AtomicU64,
+    /// Bumped whenever the array grows so attached handles know to remap.
+    generation: AtomicU64,
+    mtx: RawMutex,
+}
+
+/// Shared memory holding a runtime-sized array of `T`.
+///
+/// Elements are reached either through `lock`, which holds a robust mutex stored in the
+/// segment, or lock-free through `as_slice` when T is an atomic type.
+/// The object is unlinked from /dev/shm when the last handle across all processes is dropped.
+///
+/// The array can be grown with `grow`. Other handles keep seeing the old length until they
+/// call `refresh`, so they never touch the extended region before it is mapped.
+pub struct ShmArray<T: 'static> {
+    map: Mapping,
+    len: usize,
+    generation: u64,
+    _marker: PhantomData<T>,
+}
+
+impl<T: 'static> ShmArray<T> {
+    /// Opens the array in /dev/shm, creating it with `len` zeroed elements if it doesn't exist.
+    /// Attaching to an existing array with a different length fails.
+    pub fn new(name: &str, len: usize) -> Result<Self>
+    where
+        T: ShmSafe,
+    {
+        Self::builder(name).build(len)
+    }
+
+    /// Returns a builder for setting the namespace and the permissions the array is created with.
+    pub fn builder(name: &str) -> Builder<Self> {
+        Builder::new(name)
+    }
+
+    /// Opens an existing array in /dev/shm with whatever length it currently has.
+    pub fn open(name: &str) -> Result<Self>
+    where
+        T: ShmSafe,
+    {
+        Self::builder(name).open()
+    }
+
+    /// Validates the header of the mapped array and registers the new handle in it.
+    fn attach(mut map: Mapping, expected_len: Option<usize>) -> Result<Self> {
+        let array = map.ptr() as *const ArrayHeader;
+        let init_lock = map.init_lock()?;
+
+        let len = unsafe { (*array).len.load(Ordering::Acquire) } as usize;
+        if let Some(expected) = expected_len
+            && len != expected
+        {
+            return Err(Error::Validation(format!(
+                "Shared array has {len} elements, expected {expected}"
+            )));
+        }
+        let data_len = len * size_of::<T>();
+        unsafe { (*array).header.validate::<T>(data_len, 0)? };
+
+        // Someone may have grown the array since the file size was read.
+        let map_len = Self::data_offset() + data_len;
+        if map.len() != map_len {
+            map.resize(NonZeroUsize::new(map_len).expect("ArrayHeader has nonzero size"))?;
+        }
+
+        let array = map.ptr() as *const ArrayHeader;
+        unsafe { (*array).header.attach(&map)? };
+        let generation = unsafe { (*array).generation.load(Ordering::Acquire) };
+        drop(init_lock);
+
+        Ok(Self {
+            map,
+            len,
+            generation,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Unlinks (deletes) the array from /dev/shm.
+    pub fn unlink(name: &str) -> Result<()> {
+        Self::unlink_in(&Namespace::default(), name)
+    }
+
+    /// Unlinks the array called `name` in `namespace`.
+    pub fn unlink_in(namespace: &Namespace, name: &str) -> Result<()> {
+        namespace.path(name, ".arr").unlink()
+    }
+
+    /// Grows the array to `new_len` elements, zero-filling the new ones.
+    /// Does nothing beyond a `refresh` if the array already has at least `new_len` elements.
+    /// Fails on macOS, where a shared memory object can't be resized once it has a size.
+    pub fn grow(&mut self, new_len: usize) -> Result<()> {
+        let data_len = new_len.checked_mul(size_of::<T>()).ok_or_else(|| {
+            Error::InvalidArgument(format!("Array of {new_len} elements is too large"))
+        })?;
+
+        let init_lock = self.map.init_lock()?;
+        if self.stored_len() >= new_len {
+            drop(init_lock);
+            return self.refresh().map(|_| ());
+        }
+
+        let map_len = NonZeroUsize::new(Self::data_offset() + data_len)
+            .expect("ArrayHeader has nonzero size");
+        self.map.resize(map_len)?;
+
+        let array = self.header();
+        unsafe {
+            (*array).header.set_size(data_len);
+            (*array).len.store(new_len as u64, Ordering::Release);
+            self.generation = (*array).generation.fetch_add(1, Ordering::AcqRel) + 1;
+        }
+        self.len = new_len;
+        Ok(())
+    }
+
+    /// Remaps the array if another process has grown it, returning whether it did.
+    pub fn refresh(&mut self) -> Result<bool> {
+        if !self.is_stale() {
+            return Ok(false);
+        }
+
+        let _init_lock = self.map.init_lock()?;
+        let array = self.header();
+        let len = self.stored_len();
+        let map_len = NonZeroUsize::new(Self::data_offset() + len * size_of::<T>())
+            .expect("ArrayHeader has nonzero size");
+        self.map.resize(map_len)?;
+
+        self.len = len;
+        self.generation = unsafe { (*array).generation.load(Ordering::Acquire) };
+        Ok(true)
+    }
+
+    /// Returns true if the array was grown by another handle since this one last mapped it.
+    pub fn is_stale(&self) -> bool {
+        unsafe { (*self.header()).generation.load(Ordering::Acquire) != self.generation }
+    }
+
+    /// Number of elements in the array.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Locks the array and returns a guard giving slice access to the elements.
+    pub fn lock(&self) -> Result<ShmArrayGuard<'_, T>> {
+        let err = unsafe { raw_lock::lock(self.mtx())? };
+        let result = acquired(self.mtx(), err, "pthread_mutex_lock")?;
+        Ok(ShmArrayGuard {
+            array: self,
+            result,
+        })
+    }
+
+    /// Attempts to lock the array without blocking, returning `None` if it is held elsewhere.
+    pub fn try_lock(&self) -> Result<Option<ShmArrayGuard<'_, T>>> {
+        let err = unsafe { raw_lock::try_lock(self.mtx()) };
+        if err == EBUSY {
+            return Ok(None);
+        }
+        let result = acquired(self.mtx(), err, "pthread_mutex_trylock")?;
+        Ok(Some(ShmArrayGuard {
+            array: self,
+            result,
+        }))
+    }
+
+    /// Returns the elements without locking, for types that are safe to share concurrently.
+    pub fn as_slice(&self) -> &[T]
+    where
+        T: ShmAtomic,
+    {
+        unsafe { slice::from_raw_parts(self.data(), self.len) }
+    }
+
+    /// Returns the element at `index` without locking, or `None` if out of bounds.
+    pub fn get(&self, index: usize) -> Option<&T>
+    where
+        T: ShmAtomic,
+    {
+        self.as_slice().get(index)
+    }
+
+    /// Offset of the first element, after the header and aligned for T.
+    fn data_offset() -> usize {
+        size_of::<ArrayHeader>().next_multiple_of(align_of::<T>())
+    }
+
+    fn stored_len(&self) -> usize {
+        unsafe { (*self.header()).len.load(Ordering::Acquire) as usize }
+    }
+
+    fn header(&self) -> *mut ArrayHeader {
+        self.map.ptr() as *mut ArrayHeader
+    }
+
+    fn mtx(&self) -> *mut RawMutex {
+        unsafe { &raw mut (*self.header()).mtx }
+    }
+
+    fn data(&self) -> *mut T {
+        unsafe { (self.map.ptr() as *mut u8).add(Self::data_offset()) as *mut T }
+    }
+
+    fn unlock(&self) -> Result<()> {
+        raw_lock::check(unsafe { raw_lock::unlock(self.mtx()) })
+            .map(|_| ())
+            .map_err(Error::lock("pthread_mutex_unlock"))
+    }
+}
+
+impl<T: ShmSafe> Builder<ShmArray<T>> {
+    /// Opens the array like `ShmArray::new`.
+    pub fn build(self, len: usize) -> Result<ShmArray<T>> {
+        if size_of::<T>() == 0 {
+            return Err(Error::InvalidArgument(
+                "Cannot use zero-sized type in shared memory".to_owned(),
+            ));
+        }
+        let data_len = len.checked_mul(size_of::<T>()).ok_or_else(|| {
+            Error::InvalidArgument(format!("Array of {len} elements is too large"))
+        })?;
+        let map_len = NonZeroUsize::new(ShmArray::<T>::data_offset() + data_len)
+            .expect("ArrayHeader has nonzero size");
+
+        let path = self.namespace.path(&self.name, ".arr");
+        let map = Mapping::open_init(
+            &path,
+            OFlag::O_CREAT,
+            &self.permissions,
+            map_len,
+            CleanupPolicy::LastCloseRefCounted,
+            |raw| unsafe {
+                let array = raw as *mut ArrayHeader;
+                (*array).header.init::<T>(data_len, 0);
+                (*array).len = AtomicU64::new(len as u64);
+                raw_lock::init_mutex(
+                    &raw mut (*array).mtx,
+                    MutexKind::Normal,
+                    MutexProtocol::None,
+                )
+            },
+        )?;
+
+        ShmArray::attach(map, Some(len))
+    }
+
+    /// Opens the existing array like `ShmArray::open`.
+    pub fn open(self) -> Result<ShmArray<T>> {
+        let path = self.namespace.path(&self.name, ".arr");
+        let map = Mapping::open_existing(&path, CleanupPolicy::LastCloseRefCounted)?;
+        if map.len() < size_of::<ArrayHeader>() {
+            return Err(Error::Validation(format!(
+                "{path} is too small to hold an array"
+            )));
+        }
+
+        ShmArray::attach(map, None)
+    }
+}
+
+impl<T: 'static> AsFd for ShmArray<T> {
+    /// The descriptor of the backing file, which can be passed to other processes with
+    /// `send_fds`.
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.map.fd()
+    }
+}
+
+impl<T: 'static> Drop for ShmArray<T> {
+    fn drop(&mut self) {
+        unsafe { (*self.header()).header.detach(&self.map) };
+    }
+}
+
+/// RAII guard returned by `ShmArray::lock`, giving slice access and unlocking on drop.
+pub struct ShmArrayGuard<'a, T: 'static> {
+    array: &'a ShmArray<T>,
+    result: LockResult,
+}
+
+impl<T: 'static> ShmArrayGuard<'_, T> {
+    /// How the mutex was acquired.
+    pub fn lock_result(&self) -> &LockResult {
+        &self.result
+    }
+
+    /// Returns true if the previous owner died while holding the mutex,
+    /// in which case elements may be partially updated.
+    pub fn owner_died_recovered(&self) -> bool {
+        matches!(self.result, LockResult::OwnerDiedRecovered)
+    }
+}
+
+impl<T: 'static> Deref for ShmArrayGuard<'_, T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.array.data(), self.array.len) }
+    }
+}
+
+impl<T: 'static> DerefMut for ShmArrayGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { slice::from_raw_parts_mut(self.array.data(), self.array.len) }
+    }
+}
+
+impl<T: 'static> Drop for ShmArrayGuard<'_, T> {
+    fn drop(&mut self) {
+        self.array.unlock().ok();
+    }
+}
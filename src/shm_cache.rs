@@ -0,0 +1,119 @@
+use std::hash::Hash;
+
+use crate::{
+    builder::Builder,
+    error::{Error, Result},
+    namespace::Namespace,
+    shm_hash_map::ShmHashMap,
+    shm_safe::ShmSafe,
+};
+
+/// Share of the buckets a cache fills before evicting, keeping probe sequences short.
+const LOAD_NUMERATOR: usize = 7;
+const LOAD_DENOMINATOR: usize = 8;
+
+/// A cache in shared memory that worker processes share instead of each keeping its own,
+/// evicting entries that haven't been used recently once it is full.
+///
+/// It is a `ShmHashMap` sized to a byte budget, which evicts with the CLOCK algorithm, an
+/// approximation of LRU: reads mark entries as used without locking, and inserting a new
+/// key into a full cache advances a hand over the buckets, clearing marks until it reaches
+/// an entry without one, which it evicts.
+pub struct ShmCache<K: 'static, V: 'static> {
+    map: ShmHashMap<K, V>,
+    limit: usize,
+}
+
+impl<K, V> ShmCache<K, V>
+where
+    K: ShmSafe + Copy + Eq + Hash,
+    V: ShmSafe + Copy,
+{
+    /// Opens the cache in /dev/shm, creating it empty in a segment of about `budget` bytes
+    /// if it doesn't exist. Attaching to a cache with a different budget fails.
+    pub fn new(name: &str, budget: usize) -> Result<Self> {
+        Self::builder(name).build(budget)
+    }
+
+    /// Returns a builder for setting the namespace and the permissions the cache is created with.
+    pub fn builder(name: &str) -> Builder<Self> {
+        Builder::new(name)
+    }
+
+    /// Unlinks (deletes) the cache from /dev/shm.
+    pub fn unlink(name: &str) -> Result<()> {
+        Self::unlink_in(&Namespace::default(), name)
+    }
+
+    /// Unlinks the cache called `name` in `namespace`.
+    pub fn unlink_in(namespace: &Namespace, name: &str) -> Result<()> {
+        namespace.path(name, ".lru").unlink()
+    }
+
+    /// Returns a copy of the value cached for `key` and marks it as used, without locking.
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.map.get_touching(key)
+    }
+
+    /// Returns true if a value is cached for `key`, without marking it as used.
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.map.contains_key(key)
+    }
+
+    /// Caches `value` for `key`, returning the entry evicted to make room for it, if any.
+    pub fn insert(&self, key: K, value: V) -> Result<Option<(K, V)>> {
+        self.map
+            .insert_evicting(key, value, self.limit)
+            .map(|inserted| inserted.evicted)
+    }
+
+    /// Removes the value cached for `key`, returning it.
+    pub fn remove(&self, key: &K) -> Result<Option<V>> {
+        self.map.remove(key)
+    }
+
+    /// Removes all entries.
+    pub fn clear(&self) -> Result<()> {
+        self.map.clear()
+    }
+
+    /// Number of entries in the cache.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Number of entries the cache holds before it starts evicting.
+    pub fn capacity(&self) -> usize {
+        self.limit
+    }
+}
+
+impl<K, V> Builder<ShmCache<K, V>>
+where
+    K: ShmSafe + Copy + Eq + Hash,
+    V: ShmSafe + Copy,
+{
+    /// Opens the cache like `ShmCache::new`.
+    pub fn build(self, budget: usize) -> Result<ShmCache<K, V>> {
+        let capacity = ShmHashMap::<K, V>::capacity_for(budget);
+        let limit = capacity * LOAD_NUMERATOR / LOAD_DENOMINATOR;
+        if limit == 0 {
+            return Err(Error::InvalidArgument(format!(
+                "Cache budget of {budget} bytes is too small to hold an entry"
+            )));
+        }
+
+        let map = ShmHashMap::open(
+            &self.namespace,
+            &self.name,
+            ".lru",
+            capacity,
+            &self.permissions,
+        )?;
+        Ok(ShmCache { map, limit })
+    }
+}
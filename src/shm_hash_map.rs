@@ -0,0 +1,532 @@
+use std::{
+    cell::UnsafeCell,
+    hash::{Hash, Hasher},
+    marker::PhantomData,
+    mem::{MaybeUninit, align_of, size_of},
+    num::NonZeroUsize,
+    os::fd::{AsFd, BorrowedFd},
+    ptr,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering, fence},
+};
+
+use nix::fcntl::OFlag;
+
+use crate::{
+    builder::Builder,
+    cleanup::CleanupPolicy,
+    error::{Error, Result},
+    header::Header,
+    map::Mapping,
+    namespace::Namespace,
+    permissions::Permissions,
+    r_mtx::{LockResult, MutexKind, MutexProtocol, acquired},
+    raw_lock::{self, RawMutex},
+    shm_safe::{FNV_OFFSET, ShmSafe, fnv1a},
+};
+
+const EMPTY: u32 = 0;
+const FULL: u32 = 1;
+const DELETED: u32 = 2;
+
+#[repr(C)]
+struct MapHeader {
+    header: Header,
+    capacity: u64,
+    /// Number of full buckets, only modified while holding the mutex.
+    len: AtomicU64,
+    /// Next bucket to consider for eviction, only used by `ShmCache`.
+    hand: AtomicU64,
+    /// Serializes writers. Readers never take it.
+    mtx: RawMutex,
+}
+
+/// A bucket is rewritten only while holding the mutex, with `seq` odd during the rewrite.
+#[repr(C)]
+struct Bucket<K, V> {
+    seq: AtomicU32,
+    state: AtomicU32,
+    /// Set when the entry is inserted or read through `ShmCache`, cleared by its eviction.
+    referenced: AtomicU32,
+    key: UnsafeCell<MaybeUninit<K>>,
+    value: UnsafeCell<MaybeUninit<V>>,
+}
+
+/// What a reader found in a bucket.
+enum Slot<K, V> {
+    Empty,
+    Deleted,
+    Full(K, V),
+}
+
+/// Hashes keys the same way in every process, unlike the randomly seeded std hasher.
+struct Fnv(u64);
+
+impl Hasher for Fnv {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.0 = fnv1a(self.0, bytes);
+    }
+}
+
+/// A fixed-capacity hash map in shared memory, for plain keys and values.
+///
+/// Buckets are probed linearly. Writers take a robust mutex stored in the segment, while
+/// readers take no lock: every bucket carries a sequence counter, and a read retries if the
+/// bucket it copied was rewritten meanwhile. A writer dying in the middle of a write leaves
+/// only the bucket it was writing behind, which the next writer deletes.
+/// The object is unlinked from /dev/shm when the last handle across all processes is dropped.
+///
+/// Keys are hashed with their `Hash` implementation fed into FNV-1a, so all processes must
+/// hash them the same way.
+pub struct ShmHashMap<K: 'static, V: 'static> {
+    map: Mapping,
+    capacity: usize,
+    _marker: PhantomData<(K, V)>,
+}
+
+impl<K, V> ShmHashMap<K, V>
+where
+    K: ShmSafe + Copy + Eq + Hash,
+    V: ShmSafe + Copy,
+{
+    /// Opens the map in /dev/shm, creating it empty with room for `capacity` entries if it
+    /// doesn't exist. Attaching to a map of a different capacity fails.
+    pub fn new(name: &str, capacity: usize) -> Result<Self> {
+        Self::builder(name).build(capacity)
+    }
+
+    /// Returns a builder for setting the namespace and the permissions the map is created with.
+    pub fn builder(name: &str) -> Builder<Self> {
+        Builder::new(name)
+    }
+
+    /// Opens the map named `name` in `namespace` with the file extension `extension`,
+    /// creating it with `permissions` if it doesn't exist.
+    pub(crate) fn open(
+        namespace: &Namespace,
+        name: &str,
+        extension: &str,
+        capacity: usize,
+        permissions: &Permissions,
+    ) -> Result<Self> {
+        if capacity == 0 {
+            return Err(Error::InvalidArgument(
+                "Map capacity must be nonzero".to_owned(),
+            ));
+        }
+        let data_len = capacity
+            .checked_mul(size_of::<Bucket<K, V>>())
+            .ok_or_else(|| {
+                Error::InvalidArgument(format!("Map of {capacity} entries is too large"))
+            })?;
+        let map_len =
+            NonZeroUsize::new(Self::data_offset() + data_len).expect("MapHeader has nonzero size");
+
+        let path = namespace.path(name, extension);
+        let map = Mapping::open_init(
+            &path,
+            OFlag::O_CREAT,
+            permissions,
+            map_len,
+            CleanupPolicy::LastCloseRefCounted,
+            |raw| unsafe {
+                let header = raw as *mut MapHeader;
+                (*header).header.init::<Bucket<K, V>>(data_len, 0);
+                (*header).capacity = capacity as u64;
+                raw_lock::init_mutex(
+                    &raw mut (*header).mtx,
+                    MutexKind::Normal,
+                    MutexProtocol::None,
+                )
+            },
+        )?;
+
+        let header = map.ptr() as *const MapHeader;
+        {
+            let _init_lock = map.init_lock()?;
+            unsafe {
+                (*header).header.validate::<Bucket<K, V>>(data_len, 0)?;
+                (*header).header.attach(&map)?;
+            }
+        }
+
+        Ok(Self {
+            map,
+            capacity,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Unlinks (deletes) the map from /dev/shm.
+    pub fn unlink(name: &str) -> Result<()> {
+        Self::unlink_in(&Namespace::default(), name)
+    }
+
+    /// Unlinks the map called `name` in `namespace`.
+    pub fn unlink_in(namespace: &Namespace, name: &str) -> Result<()> {
+        namespace.path(name, ".map").unlink()
+    }
+
+    /// Returns a copy of the value stored for `key`, without locking.
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.find(key).map(|(_, value)| value)
+    }
+
+    /// Returns true if the map holds a value for `key`, without locking.
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.find(key).is_some()
+    }
+
+    /// Stores `value` for `key`, returning the value it replaces.
+    /// Fails if the key is new and all buckets are taken.
+    pub fn insert(&self, key: K, value: V) -> Result<Option<V>> {
+        self.insert_evicting(key, value, usize::MAX)
+            .map(|inserted| inserted.previous)
+    }
+
+    /// Removes the value stored for `key`, returning it.
+    pub fn remove(&self, key: &K) -> Result<Option<V>> {
+        let _lock = self.lock()?;
+        let Probe::Found(index) = self.probe(key) else {
+            return Ok(None);
+        };
+        let previous = unsafe { (*self.bucket(index).value.get()).assume_init() };
+        self.delete(index);
+        Ok(Some(previous))
+    }
+
+    /// Removes all entries.
+    pub fn clear(&self) -> Result<()> {
+        let _lock = self.lock()?;
+        for index in 0..self.capacity {
+            if self.bucket(index).state.load(Ordering::Relaxed) != EMPTY {
+                self.set_state(index, EMPTY);
+            }
+        }
+        self.header().len.store(0, Ordering::Release);
+        Ok(())
+    }
+
+    /// Returns copies of all entries, without locking. Entries written while the call runs
+    /// may or may not be included.
+    pub fn entries(&self) -> Vec<(K, V)> {
+        (0..self.capacity)
+            .filter_map(|index| match self.read(index) {
+                Slot::Full(key, value) => Some((key, value)),
+                Slot::Empty | Slot::Deleted => None,
+            })
+            .collect()
+    }
+
+    /// Number of entries in the map.
+    pub fn len(&self) -> usize {
+        self.header().len.load(Ordering::Acquire) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Maximum number of entries the map holds.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Like `get`, but also marks the entry as recently used.
+    pub(crate) fn get_touching(&self, key: &K) -> Option<V> {
+        let (index, value) = self.find(key)?;
+        let referenced = &self.bucket(index).referenced;
+        // Skip the store if possible, so hot entries don't keep bouncing their cache line.
+        if referenced.load(Ordering::Relaxed) == 0 {
+            referenced.store(1, Ordering::Relaxed);
+        }
+        Some(value)
+    }
+
+    /// Stores `value` for `key`, first evicting an entry if the key is new and the map
+    /// already holds `limit` entries. Returns the value replaced and the entry evicted.
+    pub(crate) fn insert_evicting(&self, key: K, value: V, limit: usize) -> Result<Inserted<K, V>> {
+        let _lock = self.lock()?;
+        if let Probe::Found(index) = self.probe(&key) {
+            let previous = unsafe { (*self.bucket(index).value.get()).assume_init() };
+            self.write(index, FULL, key, value);
+            return Ok(Inserted {
+                previous: Some(previous),
+                evicted: None,
+            });
+        }
+
+        let evicted = (self.len() >= limit).then(|| self.evict());
+        match self.probe(&key) {
+            Probe::Vacant(index) => {
+                self.write(index, FULL, key, value);
+                self.header().len.fetch_add(1, Ordering::Release);
+                Ok(Inserted {
+                    previous: None,
+                    evicted,
+                })
+            }
+            Probe::Found(_) | Probe::Full => Err(Error::InvalidArgument(format!(
+                "Map is full: all {} buckets are taken",
+                self.capacity
+            ))),
+        }
+    }
+
+    /// Removes the entry the clock hand reaches first without it having been used since
+    /// the hand last passed it, and returns it. Requires the mutex and a nonempty map.
+    fn evict(&self) -> (K, V) {
+        let header = self.header();
+        loop {
+            let index =
+                (header.hand.fetch_add(1, Ordering::Relaxed) % self.capacity as u64) as usize;
+            let bucket = self.bucket(index);
+            if bucket.state.load(Ordering::Relaxed) == FULL
+                && bucket.referenced.swap(0, Ordering::Relaxed) == 0
+            {
+                let entry = unsafe {
+                    (
+                        (*bucket.key.get()).assume_init(),
+                        (*bucket.value.get()).assume_init(),
+                    )
+                };
+                self.delete(index);
+                return entry;
+            }
+        }
+    }
+
+    /// Looks up `key` without locking, returning its bucket and value.
+    fn find(&self, key: &K) -> Option<(usize, V)> {
+        let start = self.start(key);
+        for offset in 0..self.capacity {
+            let index = (start + offset) % self.capacity;
+            match self.read(index) {
+                Slot::Empty => return None,
+                Slot::Deleted => {}
+                Slot::Full(found, value) => {
+                    if found == *key {
+                        return Some((index, value));
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Looks up `key` while holding the mutex, returning its bucket or the one to insert it
+    /// into.
+    fn probe(&self, key: &K) -> Probe {
+        let start = self.start(key);
+        let mut vacant = None;
+        for offset in 0..self.capacity {
+            let index = (start + offset) % self.capacity;
+            let bucket = self.bucket(index);
+            match bucket.state.load(Ordering::Relaxed) {
+                FULL => {
+                    if unsafe { (*bucket.key.get()).assume_init() } == *key {
+                        return Probe::Found(index);
+                    }
+                }
+                DELETED => {
+                    vacant.get_or_insert(index);
+                }
+                _ => return Probe::Vacant(vacant.unwrap_or(index)),
+            }
+        }
+        vacant.map_or(Probe::Full, Probe::Vacant)
+    }
+
+    /// Copies the bucket at `index`, retrying while it is being rewritten.
+    fn read(&self, index: usize) -> Slot<K, V> {
+        let bucket = self.bucket(index);
+        loop {
+            let before = bucket.seq.load(Ordering::Acquire);
+            if before % 2 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+            let state = bucket.state.load(Ordering::Relaxed);
+            // The copies may be torn, so they stay uninitialized until the counter proves
+            // they aren't.
+            let (key, value) = unsafe {
+                (
+                    ptr::read_volatile(bucket.key.get()),
+                    ptr::read_volatile(bucket.value.get()),
+                )
+            };
+            fence(Ordering::Acquire);
+            if bucket.seq.load(Ordering::Relaxed) != before {
+                continue;
+            }
+            return match state {
+                FULL => unsafe { Slot::Full(key.assume_init(), value.assume_init()) },
+                DELETED => Slot::Deleted,
+                _ => Slot::Empty,
+            };
+        }
+    }
+
+    /// Marks the bucket at `index` deleted, and turns it and the deleted buckets before it
+    /// back into empty ones if no probe can continue past it. Requires the mutex.
+    fn delete(&self, index: usize) {
+        self.set_state(index, DELETED);
+        self.header().len.fetch_sub(1, Ordering::Release);
+
+        let next = (index + 1) % self.capacity;
+        if self.bucket(next).state.load(Ordering::Relaxed) != EMPTY {
+            return;
+        }
+        let mut index = index;
+        while self.bucket(index).state.load(Ordering::Relaxed) == DELETED {
+            self.set_state(index, EMPTY);
+            index = (index + self.capacity - 1) % self.capacity;
+        }
+    }
+
+    /// Rewrites the bucket at `index`. Requires the mutex.
+    fn write(&self, index: usize, state: u32, key: K, value: V) {
+        let bucket = self.bucket(index);
+        let seq = bucket.seq.load(Ordering::Relaxed);
+        bucket.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+        unsafe {
+            ptr::write_volatile(bucket.key.get(), MaybeUninit::new(key));
+            ptr::write_volatile(bucket.value.get(), MaybeUninit::new(value));
+        }
+        bucket.state.store(state, Ordering::Relaxed);
+        bucket.referenced.store(1, Ordering::Relaxed);
+        bucket.seq.store(seq.wrapping_add(2), Ordering::Release);
+    }
+
+    /// Changes the state of the bucket at `index`, keeping its contents. Requires the mutex.
+    fn set_state(&self, index: usize, state: u32) {
+        let bucket = self.bucket(index);
+        let seq = bucket.seq.load(Ordering::Relaxed);
+        bucket.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+        bucket.state.store(state, Ordering::Relaxed);
+        bucket.seq.store(seq.wrapping_add(2), Ordering::Release);
+    }
+
+    /// Locks the mutex, deleting the bucket a writer was rewriting when it died.
+    fn lock(&self) -> Result<WriteLock> {
+        let mtx = self.mtx();
+        let err = unsafe { raw_lock::lock(mtx)? };
+        let result = acquired(mtx, err, "pthread_mutex_lock")?;
+        let lock = WriteLock { mtx };
+        if matches!(result, LockResult::OwnerDiedRecovered) {
+            self.recover();
+        }
+        Ok(lock)
+    }
+
+    /// Deletes buckets left half-written and recounts the entries. Requires the mutex.
+    fn recover(&self) {
+        let mut len = 0;
+        for index in 0..self.capacity {
+            let bucket = self.bucket(index);
+            let seq = bucket.seq.load(Ordering::Relaxed);
+            if seq % 2 == 1 {
+                bucket.state.store(DELETED, Ordering::Relaxed);
+                bucket.seq.store(seq.wrapping_add(1), Ordering::Release);
+            }
+            if bucket.state.load(Ordering::Relaxed) == FULL {
+                len += 1;
+            }
+        }
+        self.header().len.store(len, Ordering::Release);
+    }
+
+    fn start(&self, key: &K) -> usize {
+        let mut hasher = Fnv(FNV_OFFSET);
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+        ((hash ^ (hash >> 32)) % self.capacity as u64) as usize
+    }
+
+    /// Number of buckets fitting in a segment of `bytes` bytes.
+    pub(crate) fn capacity_for(bytes: usize) -> usize {
+        bytes.saturating_sub(Self::data_offset()) / size_of::<Bucket<K, V>>()
+    }
+
+    /// Offset of the first bucket, after the header and aligned for it.
+    fn data_offset() -> usize {
+        size_of::<MapHeader>().next_multiple_of(align_of::<Bucket<K, V>>())
+    }
+
+    fn header(&self) -> &MapHeader {
+        unsafe { &*(self.map.ptr() as *const MapHeader) }
+    }
+
+    fn mtx(&self) -> *mut RawMutex {
+        unsafe { &raw mut (*(self.map.ptr() as *mut MapHeader)).mtx }
+    }
+
+    fn bucket(&self, index: usize) -> &Bucket<K, V> {
+        unsafe {
+            let buckets =
+                (self.map.ptr() as *const u8).add(Self::data_offset()) as *const Bucket<K, V>;
+            &*buckets.add(index)
+        }
+    }
+}
+
+impl<K, V> Builder<ShmHashMap<K, V>>
+where
+    K: ShmSafe + Copy + Eq + Hash,
+    V: ShmSafe + Copy,
+{
+    /// Opens the map like `ShmHashMap::new`.
+    pub fn build(self, capacity: usize) -> Result<ShmHashMap<K, V>> {
+        ShmHashMap::open(
+            &self.namespace,
+            &self.name,
+            ".map",
+            capacity,
+            &self.permissions,
+        )
+    }
+}
+
+/// What `insert_evicting` replaced and evicted.
+pub(crate) struct Inserted<K, V> {
+    pub(crate) previous: Option<V>,
+    pub(crate) evicted: Option<(K, V)>,
+}
+
+/// Where a key is, or would go, found while holding the mutex.
+enum Probe {
+    Found(usize),
+    Vacant(usize),
+    Full,
+}
+
+/// Unlocks the writer mutex on drop.
+struct WriteLock {
+    mtx: *mut RawMutex,
+}
+
+impl Drop for WriteLock {
+    fn drop(&mut self) {
+        unsafe { raw_lock::unlock(self.mtx) };
+    }
+}
+
+impl<K: 'static, V: 'static> AsFd for ShmHashMap<K, V> {
+    /// The descriptor of the backing file, which can be passed to other processes with
+    /// `send_fds`.
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.map.fd()
+    }
+}
+
+impl<K: 'static, V: 'static> Drop for ShmHashMap<K, V> {
+    fn drop(&mut self) {
+        let header = self.map.ptr() as *const MapHeader;
+        unsafe { (*header).header.detach(&self.map) };
+    }
+}
@@ -0,0 +1,338 @@
+use std::{
+    mem::size_of,
+    num::NonZeroUsize,
+    process, slice,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use nix::fcntl::OFlag;
+
+use crate::{
+    cleanup::CleanupPolicy,
+    error::{Error, Result},
+    futex::EventCount,
+    header::Header,
+    liveness::is_alive,
+    map::Mapping,
+    namespace::Namespace,
+    permissions::Permissions,
+    r_mtx::{MutexKind, MutexProtocol, acquired},
+    raw_lock::{self, RawMutex, Unlock},
+};
+
+const PENDING: u32 = 0;
+const COMMITTED: u32 = 1;
+const ABANDONED: u32 = 2;
+
+/// Records start at multiples of this, so their headers are aligned.
+const RECORD_ALIGN: usize = 8;
+
+#[repr(C)]
+struct LogHeader {
+    header: Header,
+    capacity: u64,
+    /// End of the last reserved record, only advanced while holding the mutex and after the
+    /// record header was written.
+    tail: AtomicU64,
+    /// Notified whenever a record is committed.
+    committed: EventCount,
+    /// Serializes reserving records. Readers never take it.
+    mtx: RawMutex,
+}
+
+#[repr(C)]
+struct RecordHeader {
+    len: u32,
+    /// Process appending the record, so readers can skip it if that process died.
+    pid: u32,
+    state: AtomicU32,
+    _reserved: u32,
+}
+
+/// An append-only journal of byte records in shared memory, which any number of processes
+/// append to and read while others are appending.
+///
+/// Appending reserves room for a record under a robust mutex, then copies the record and
+/// marks it committed without holding the lock. Readers walk the records in order, stopping
+/// at the first one still being written, and skipping those whose writer died before
+/// committing them. Committed records never change, so readers get them without copying.
+///
+/// The log has a fixed capacity and is never cleared. By default it stays in /dev/shm after
+/// the last handle is dropped, for post-mortem inspection. Opened in a `Namespace` on a
+/// regular filesystem, it is backed by a file and survives restarts.
+pub struct ShmLog {
+    map: Mapping,
+    capacity: usize,
+}
+
+impl ShmLog {
+    /// Opens the log in /dev/shm, creating it with room for `capacity` bytes of records,
+    /// including 16 bytes of header each, if it doesn't exist.
+    /// Attaching to a log of a different capacity fails.
+    pub fn new(name: &str, capacity: usize) -> Result<Self> {
+        Self::builder(name, capacity).build()
+    }
+
+    /// Returns a builder for configuring where the log lives and how it is cleaned up.
+    pub fn builder(name: &str, capacity: usize) -> ShmLogBuilder {
+        ShmLogBuilder {
+            name: name.to_owned(),
+            capacity,
+            cleanup: CleanupPolicy::Never,
+            namespace: Namespace::default(),
+            permissions: Permissions::default(),
+        }
+    }
+
+    /// Unlinks (deletes) the log from /dev/shm.
+    pub fn unlink(name: &str) -> Result<()> {
+        Self::unlink_in(&Namespace::default(), name)
+    }
+
+    /// Unlinks the log called `name` in `namespace`.
+    pub fn unlink_in(namespace: &Namespace, name: &str) -> Result<()> {
+        namespace.path(name, ".log").unlink()
+    }
+
+    /// Appends `record` and returns its position, from which `records_from` reads it.
+    /// Fails if the log has no room left for it.
+    pub fn append(&self, record: &[u8]) -> Result<u64> {
+        let len = u32::try_from(record.len()).map_err(|_| {
+            Error::InvalidArgument(format!("Record of {} bytes is too large", record.len()))
+        })?;
+        let size = (size_of::<RecordHeader>() + record.len()).next_multiple_of(RECORD_ALIGN);
+
+        let header = self.header();
+        let position = {
+            let err = unsafe { raw_lock::lock(self.mtx())? };
+            // Dying while holding the lock leaves nothing to repair, as the tail only moves
+            // past complete record headers.
+            acquired(self.mtx(), err, "pthread_mutex_lock")?;
+            let _unlock = Unlock(self.mtx());
+
+            let position = header.tail.load(Ordering::Relaxed);
+            if position as usize + size > self.capacity {
+                return Err(Error::InvalidArgument(format!(
+                    "Log is full: {size} bytes requested, {position} of {} used",
+                    self.capacity
+                )));
+            }
+            let record_header = self.record(position);
+            unsafe {
+                (*record_header).len = len;
+                (*record_header).pid = process::id();
+                (*record_header).state.store(PENDING, Ordering::Relaxed);
+            }
+            header.tail.store(position + size as u64, Ordering::Release);
+            position
+        };
+
+        unsafe {
+            let payload = (self.record(position) as *mut u8).add(size_of::<RecordHeader>());
+            payload.copy_from_nonoverlapping(record.as_ptr(), record.len());
+            (*self.record(position))
+                .state
+                .store(COMMITTED, Ordering::Release);
+        }
+        header.committed.notify_all();
+        Ok(position)
+    }
+
+    /// Returns an iterator over the committed records, from the first one on.
+    pub fn records(&self) -> LogRecords<'_> {
+        self.records_from(0)
+    }
+
+    /// Returns an iterator over the committed records from `position` on, which must be a
+    /// position returned by `append` or `LogRecords::position`.
+    pub fn records_from(&self, position: u64) -> LogRecords<'_> {
+        LogRecords {
+            log: self,
+            position,
+        }
+    }
+
+    /// Blocks until a record is committed at `position`, or `timeout` elapses. Returns false
+    /// if the timeout elapsed. A record whose writer died is only noticed once another
+    /// record is committed or the timeout elapses.
+    pub fn wait(&self, position: u64, timeout: Option<Duration>) -> Result<bool> {
+        let ready = self
+            .header()
+            .committed
+            .wait_for(timeout, || self.next(position).is_some().then_some(()))?;
+        Ok(ready.is_some())
+    }
+
+    /// Number of bytes taken by records so far, including their headers.
+    pub fn used(&self) -> usize {
+        self.header().tail.load(Ordering::Acquire) as usize
+    }
+
+    /// Total number of bytes the log holds.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the record at `position` and the position after it, skipping abandoned ones,
+    /// or `None` if the next record isn't committed yet.
+    fn next(&self, mut position: u64) -> Option<(&[u8], u64)> {
+        loop {
+            if position >= self.header().tail.load(Ordering::Acquire) {
+                return None;
+            }
+            let record = unsafe { &*self.record(position) };
+            let next = position
+                + (size_of::<RecordHeader>() + record.len as usize).next_multiple_of(RECORD_ALIGN)
+                    as u64;
+            match record.state.load(Ordering::Acquire) {
+                COMMITTED => {
+                    let payload = unsafe {
+                        slice::from_raw_parts(
+                            (record as *const RecordHeader as *const u8)
+                                .add(size_of::<RecordHeader>()),
+                            record.len as usize,
+                        )
+                    };
+                    return Some((payload, next));
+                }
+                PENDING if is_alive(record.pid) => return None,
+                PENDING => {
+                    record
+                        .state
+                        .compare_exchange(PENDING, ABANDONED, Ordering::AcqRel, Ordering::Acquire)
+                        .ok();
+                }
+                _ => {}
+            }
+            position = next;
+        }
+    }
+
+    fn header(&self) -> &LogHeader {
+        unsafe { &*(self.map.ptr() as *const LogHeader) }
+    }
+
+    fn mtx(&self) -> *mut RawMutex {
+        unsafe { &raw mut (*(self.map.ptr() as *mut LogHeader)).mtx }
+    }
+
+    fn record(&self, position: u64) -> *mut RecordHeader {
+        unsafe {
+            (self.map.ptr() as *mut u8).add(Self::data_offset() + position as usize)
+                as *mut RecordHeader
+        }
+    }
+
+    /// Offset of the first record, after the header and aligned for it.
+    fn data_offset() -> usize {
+        size_of::<LogHeader>().next_multiple_of(RECORD_ALIGN)
+    }
+}
+
+impl Drop for ShmLog {
+    fn drop(&mut self) {
+        self.header().header.detach(&self.map);
+    }
+}
+
+/// Iterator over the committed records of a `ShmLog`, created with `ShmLog::records`.
+///
+/// It ends at the first record that isn't committed yet. Continue later from `position`,
+/// e.g. after `ShmLog::wait`, to read records appended since.
+pub struct LogRecords<'a> {
+    log: &'a ShmLog,
+    position: u64,
+}
+
+impl LogRecords<'_> {
+    /// Position of the next record to read.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+}
+
+impl<'a> Iterator for LogRecords<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let (record, next) = self.log.next(self.position)?;
+        self.position = next;
+        Some(record)
+    }
+}
+
+/// Builder for `ShmLog`, created with `ShmLog::builder`.
+pub struct ShmLogBuilder {
+    name: String,
+    capacity: usize,
+    cleanup: CleanupPolicy,
+    namespace: Namespace,
+    permissions: Permissions,
+}
+
+impl ShmLogBuilder {
+    /// Sets what happens to the log when the handle is dropped.
+    /// Defaults to `CleanupPolicy::Never`.
+    pub fn cleanup(mut self, cleanup: CleanupPolicy) -> Self {
+        self.cleanup = cleanup;
+        self
+    }
+
+    /// Opens the log in `namespace` instead of the default one. A namespace in a directory
+    /// on disk makes the log survive restarts.
+    pub fn namespace(mut self, namespace: &Namespace) -> Self {
+        self.namespace = namespace.clone();
+        self
+    }
+
+    /// Sets the permissions the backing file is created with, see `Permissions`. Only
+    /// applies if this handle creates the log.
+    pub fn permissions(mut self, permissions: Permissions) -> Self {
+        self.permissions = permissions;
+        self
+    }
+
+    /// Creates or opens the log.
+    pub fn build(self) -> Result<ShmLog> {
+        let capacity = self.capacity;
+        if capacity < size_of::<RecordHeader>() {
+            return Err(Error::InvalidArgument(format!(
+                "Log capacity of {capacity} bytes is too small to hold a record"
+            )));
+        }
+        let map_len = NonZeroUsize::new(ShmLog::data_offset() + capacity)
+            .expect("LogHeader has nonzero size");
+
+        let path = self.namespace.path(&self.name, ".log");
+        let mut map = Mapping::open_init(
+            &path,
+            OFlag::O_CREAT,
+            &self.permissions,
+            map_len,
+            self.cleanup,
+            |raw| unsafe {
+                let header = raw as *mut LogHeader;
+                (*header).header.init::<u8>(capacity, 0);
+                (*header).capacity = capacity as u64;
+                raw_lock::init_mutex(
+                    &raw mut (*header).mtx,
+                    MutexKind::Normal,
+                    MutexProtocol::None,
+                )
+            },
+        )?;
+
+        let header = map.ptr() as *const LogHeader;
+        {
+            let _init_lock = map.init_lock()?;
+            unsafe {
+                (*header).header.validate::<u8>(capacity, 0)?;
+                (*header).header.attach(&map)?;
+            }
+        }
+        map.record_in(&self.namespace, &self.name, ".log")?;
+
+        Ok(ShmLog { map, capacity })
+    }
+}
@@ -0,0 +1,828 @@
+use std::{
+    cell::UnsafeCell,
+    hint,
+    marker::PhantomData,
+    mem::{self, MaybeUninit, size_of},
+    num::NonZeroUsize,
+    ops::{Deref, DerefMut},
+    ptr,
+    sync::atomic::{self, AtomicU32, AtomicU64, Ordering},
+};
+
+use nix::{
+    fcntl::OFlag,
+    libc::{EBUSY, EOWNERDEAD, c_int},
+};
+
+use crate::{
+    checksum::crc32c,
+    cleanup::CleanupPolicy,
+    error::{Error, Result},
+    header::Header,
+    map::Mapping,
+    namespace::Namespace,
+    permissions::Permissions,
+    r_mtx::{LockResult, MutexKind, MutexProtocol, acquired},
+    raw_lock::{self, RawMutex},
+    shm_safe::ShmSafe,
+};
+
+/// Times `ShmMutex::load_seqlock` retries a copy before falling back to the lock.
+const SEQLOCK_RETRIES: u32 = 64;
+
+#[repr(C)]
+struct Inner<T> {
+    header: Header,
+    mtx: RawMutex,
+    /// Nonzero if the mutex was created with poisoning enabled.
+    poisoning: u32,
+    /// Nonzero while the data is poisoned, only modified while holding the mutex.
+    poisoned: AtomicU32,
+    /// Nonzero if the mutex was created with checksumming enabled.
+    checksummed: u32,
+    /// CRC-32C of the data as of the last unlock, only modified while holding the mutex.
+    checksum: AtomicU32,
+    /// Odd while the mutex is held, bumped by every lock and unlock for `load_seqlock`.
+    seq: AtomicU64,
+    data: UnsafeCell<T>,
+}
+
+/// Follows `Inner` in the segments of mutexes built with `ShmMutexBuilder::transactional`.
+#[repr(C)]
+struct Staging<T> {
+    /// Nonzero while a transaction copies `staged` over the data, only modified while holding
+    /// the mutex.
+    committing: AtomicU32,
+    /// The copy of the data a transaction updates before committing it.
+    staged: UnsafeCell<T>,
+}
+
+/// The segment of a mutex built with `ShmMutexBuilder::transactional`.
+#[repr(C)]
+struct Transactional<T> {
+    inner: Inner<T>,
+    staging: Staging<T>,
+}
+
+/// Shared memory holding a `T` together with the robust mutex protecting it,
+/// so the data can only be reached through a lock guard.
+/// The generic type T should almost always be `#[repr(C)]`.
+///
+/// Mutexes built with `ShmMutexBuilder::transactional` also have room for a second copy of
+/// T, which `transaction` stages updates in.
+pub struct ShmMutex<T: 'static> {
+    map: Mapping,
+    verify_on_lock: bool,
+    transactional: bool,
+    _marker: PhantomData<T>,
+}
+
+impl<T: 'static> ShmMutex<T> {
+    /// Creates or opens the mapping in /dev/shm, initializing the mutex on creation.
+    /// The data starts out zeroed.
+    pub fn new(name: &str) -> Result<Self>
+    where
+        T: ShmSafe,
+    {
+        Self::builder(name).build()
+    }
+
+    /// Like `new`, but without requiring `T: ShmSafe`.
+    ///
+    /// # Safety
+    ///
+    /// T must uphold the `ShmSafe` contract even though it doesn't implement the trait.
+    pub unsafe fn new_unchecked(name: &str) -> Result<Self> {
+        unsafe { Self::builder(name).build_unchecked() }
+    }
+
+    /// Returns a builder for configuring how the mutex is created.
+    pub fn builder(name: &str) -> ShmMutexBuilder<T> {
+        ShmMutexBuilder {
+            name: name.to_owned(),
+            poisoning: false,
+            checksummed: false,
+            verify_on_lock: false,
+            transactional: false,
+            namespace: Namespace::default(),
+            permissions: Permissions::default(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Unlinks (deletes) the mapping file from /dev/shm.
+    pub fn unlink(name: &str) -> Result<()> {
+        Namespace::default().unlink_shm_mutex(name)
+    }
+
+    /// Locks the mutex and returns a guard giving access to the data.
+    /// With poisoning enabled, fails with `Error::Poisoned` if a previous owner died while holding
+    /// the lock and the data hasn't been repaired since.
+    pub fn lock(&self) -> Result<ShmMutexGuard<'_, T>> {
+        let err = unsafe { raw_lock::lock(self.mtx())? };
+        self.guard(err, "pthread_mutex_lock")
+    }
+
+    /// Locks the mutex, running `repair` on the data before handing out the guard if the
+    /// previous owner died while holding it, or if the data is poisoned.
+    ///
+    /// The mutex is only marked consistent once `repair` returns, so if this process dies
+    /// during the repair the next locker is asked to repair again. If `repair` panics, a
+    /// poisoning mutex is poisoned, while any other mutex is unlocked without being marked
+    /// consistent and becomes unusable.
+    pub fn lock_with_recovery<F>(&self, repair: F) -> Result<ShmMutexGuard<'_, T>>
+    where
+        F: FnOnce(&mut T),
+    {
+        let err = unsafe { raw_lock::lock(self.mtx())? };
+        self.recovering_guard(err, "pthread_mutex_lock", repair)
+    }
+
+    /// Locks the mutex, repairing the data with `Recover::recover` if the previous owner died
+    /// while holding it. See `lock_with_recovery`.
+    pub fn lock_recovering(&self) -> Result<ShmMutexGuard<'_, T>>
+    where
+        T: Recover,
+    {
+        self.lock_with_recovery(T::recover)
+    }
+
+    /// Runs `update` on a copy of the data staged in the segment while holding the lock, and
+    /// commits the copy to the data once `update` returns `Ok`. If `update` fails or panics,
+    /// the data is left as it was.
+    ///
+    /// Committing flips a flag in the segment before copying the staged value over, and if
+    /// this process dies before the copy is done, the next locker finishes it before anything
+    /// sees the data. So the data is never seen half-updated, even before robust recovery
+    /// runs, though the death is still reported like any other. Errors locking, e.g.
+    /// `Error::Poisoned`, are converted into `E`.
+    ///
+    /// Fails with `Error::InvalidArgument` unless the mutex was built with
+    /// `ShmMutexBuilder::transactional`.
+    pub fn transaction<R, E, F>(&self, update: F) -> std::result::Result<R, E>
+    where
+        F: FnOnce(&mut T) -> std::result::Result<R, E>,
+        E: From<Error>,
+    {
+        let Some(staging) = self.staging() else {
+            return Err(Error::InvalidArgument(
+                "ShmMutex wasn't built transactional, so it has no room to stage updates"
+                    .to_owned(),
+            )
+            .into());
+        };
+        let guard = self.lock()?;
+        let data = unsafe { (*self.inner()).data.get() };
+        let staged = unsafe { (*staging).staged.get() };
+        unsafe { ptr::copy_nonoverlapping(data, staged, 1) };
+        let result = update(unsafe { &mut *staged })?;
+        let committing = unsafe { &(*staging).committing };
+        committing.store(1, Ordering::Release);
+        // Keeps the copy from being moved around the flag, which a dying process must leave
+        // set until the copy is done.
+        atomic::fence(Ordering::SeqCst);
+        unsafe { ptr::copy_nonoverlapping(staged, data, 1) };
+        // Within the commit, so a checksum not matching the committed data is recomputed too.
+        self.update_checksum();
+        atomic::fence(Ordering::SeqCst);
+        committing.store(0, Ordering::Release);
+        // The checksum is up to date, so don't recompute it on unlock.
+        mem::forget(guard);
+        self.unlock()?;
+        Ok(result)
+    }
+
+    /// Returns a copy of the data, holding the lock only while copying it. Fails like `lock`,
+    /// and unlike a guard doesn't recompute the checksum on unlock, as nothing changed.
+    pub fn snapshot(&self) -> Result<T>
+    where
+        T: Copy,
+    {
+        let guard = self.lock()?;
+        let value = *guard;
+        mem::forget(guard);
+        self.unlock()?;
+        Ok(value)
+    }
+
+    /// Returns a copy of the data without taking the lock, like `SeqLock::read`: the copy is
+    /// retried if the mutex was locked meanwhile, so it is never torn. Falls back to
+    /// `snapshot` if the mutex stays locked for a few retries, which also recovers it if
+    /// its owner died. Fails with `Error::Poisoned` if the data is poisoned.
+    pub fn load_seqlock(&self) -> Result<T>
+    where
+        T: Copy,
+    {
+        let inner = self.inner();
+        let seq = unsafe { &(*inner).seq };
+        for _ in 0..SEQLOCK_RETRIES {
+            let before = seq.load(Ordering::Acquire);
+            if before % 2 == 0 {
+                // The copy may be torn, so it stays uninitialized until the counter proves
+                // it isn't.
+                let value =
+                    unsafe { ptr::read_volatile((*inner).data.get() as *const MaybeUninit<T>) };
+                atomic::fence(Ordering::Acquire);
+                if seq.load(Ordering::Relaxed) == before {
+                    if self.is_poisoned() {
+                        return Err(Error::Poisoned);
+                    }
+                    return Ok(unsafe { value.assume_init() });
+                }
+            }
+            hint::spin_loop();
+        }
+        self.snapshot()
+    }
+
+    /// Attempts to lock the mutex without blocking, returning `None` if it is held elsewhere.
+    pub fn try_lock(&self) -> Result<Option<ShmMutexGuard<'_, T>>> {
+        let err = unsafe { raw_lock::try_lock(self.mtx()) };
+        if err == EBUSY {
+            return Ok(None);
+        }
+        self.guard(err, "pthread_mutex_trylock").map(Some)
+    }
+
+    /// Like `lock_with_recovery`, but without blocking, returning `None` if the mutex is held
+    /// elsewhere.
+    pub fn try_lock_with_recovery<F>(&self, repair: F) -> Result<Option<ShmMutexGuard<'_, T>>>
+    where
+        F: FnOnce(&mut T),
+    {
+        let err = unsafe { raw_lock::try_lock(self.mtx()) };
+        if err == EBUSY {
+            return Ok(None);
+        }
+        self.recovering_guard(err, "pthread_mutex_trylock", repair)
+            .map(Some)
+    }
+
+    /// Returns true if the data is poisoned. Always false unless poisoning is enabled.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned().load(Ordering::Acquire) != 0
+    }
+
+    /// Declares the data consistent again, so `lock` stops failing with `Error::Poisoned`.
+    pub fn clear_poison(&self) -> Result<()> {
+        let err = unsafe { raw_lock::lock(self.mtx())? };
+        self.acquiring(err);
+        if err == EOWNERDEAD && self.poisoning() {
+            self.poisoned().store(1, Ordering::Release);
+        }
+        acquired(self.mtx(), err, "pthread_mutex_lock")?;
+        self.poisoned().store(0, Ordering::Release);
+        self.update_checksum();
+        self.unlock()
+    }
+
+    /// Turns the return code of a lock call into a guard, running `repair` first if the
+    /// previous owner died, or the data is poisoned or corrupted.
+    fn recovering_guard<F>(
+        &self,
+        err: c_int,
+        op: &'static str,
+        repair: F,
+    ) -> Result<ShmMutexGuard<'_, T>>
+    where
+        F: FnOnce(&mut T),
+    {
+        if err != 0 && err != EOWNERDEAD {
+            // The mutex isn't held, so the data must not be touched.
+            raw_lock::check(err).map_err(Error::lock(op))?;
+        }
+        self.acquiring(err);
+        let corrupted = self.verify_on_lock && !self.checksum_matches();
+        if err == EOWNERDEAD || self.is_poisoned() || corrupted {
+            let abort = AbortRecovery {
+                shm: self,
+                owner_died: err == EOWNERDEAD,
+            };
+            repair(unsafe { &mut *(*self.inner()).data.get() });
+            mem::forget(abort);
+            self.poisoned().store(0, Ordering::Release);
+        }
+        let result = acquired(self.mtx(), err, op)?;
+        Ok(ShmMutexGuard { shm: self, result })
+    }
+
+    /// Turns the return code of a lock call into a guard, unless the data is poisoned.
+    fn guard(&self, err: c_int, op: &'static str) -> Result<ShmMutexGuard<'_, T>> {
+        self.acquiring(err);
+        if err == EOWNERDEAD && self.poisoning() {
+            // Poison before marking the mutex consistent, so dying in between is harmless.
+            self.poisoned().store(1, Ordering::Release);
+        }
+        let result = acquired(self.mtx(), err, op)?;
+        if self.is_poisoned() {
+            self.unlock()?;
+            return Err(Error::Poisoned);
+        }
+        if self.verify_on_lock && !self.checksum_matches() {
+            self.unlock()?;
+            return Err(Error::ChecksumMismatch);
+        }
+        Ok(ShmMutexGuard { shm: self, result })
+    }
+
+    /// Prepares the data after a lock call returned `err`, if it acquired the mutex: marks it
+    /// as being written for `load_seqlock`, and finishes a commit a dead owner left behind.
+    fn acquiring(&self, err: c_int) {
+        if err != 0 && err != EOWNERDEAD {
+            return;
+        }
+        let seq = unsafe { &(*self.inner()).seq };
+        let current = seq.load(Ordering::Relaxed);
+        // Still odd if the previous owner died holding the mutex.
+        if current % 2 == 0 {
+            seq.store(current + 1, Ordering::Relaxed);
+            atomic::fence(Ordering::Release);
+        }
+        if err == EOWNERDEAD {
+            self.finish_commit();
+        }
+    }
+
+    /// Finishes the commit of a transaction whose process died while copying the staged
+    /// value over the data. Must be called holding the mutex, before looking at the data.
+    fn finish_commit(&self) {
+        let Some(staging) = self.staging() else {
+            return;
+        };
+        let committing = unsafe { &(*staging).committing };
+        if committing.load(Ordering::Acquire) == 0 {
+            return;
+        }
+        unsafe { ptr::copy_nonoverlapping((*staging).staged.get(), (*self.inner()).data.get(), 1) };
+        self.update_checksum();
+        atomic::fence(Ordering::SeqCst);
+        committing.store(0, Ordering::Release);
+    }
+
+    /// Returns false if the data changed since the mutex was last unlocked, which only
+    /// happens if it was written without holding the lock or an owner died before unlocking.
+    /// Always true unless checksumming is enabled.
+    fn checksum_matches(&self) -> bool {
+        let inner = self.inner();
+        unsafe {
+            (*inner).checksummed == 0
+                || (*inner).checksum.load(Ordering::Acquire) == self.data_checksum()
+        }
+    }
+
+    /// Stores the checksum of the data as it is now, if checksumming is enabled.
+    fn update_checksum(&self) {
+        let inner = self.inner();
+        if unsafe { (*inner).checksummed } != 0 {
+            let checksum = self.data_checksum();
+            unsafe { (*inner).checksum.store(checksum, Ordering::Release) };
+        }
+    }
+
+    fn data_checksum(&self) -> u32 {
+        let data = unsafe { (*self.inner()).data.get() };
+        unsafe { crc32c(data as *const u8, size_of::<T>()) }
+    }
+
+    fn poisoning(&self) -> bool {
+        unsafe { (*self.inner()).poisoning != 0 }
+    }
+
+    fn poisoned(&self) -> &AtomicU32 {
+        unsafe { &(*self.inner()).poisoned }
+    }
+
+    fn inner(&self) -> *mut Inner<T> {
+        self.map.ptr() as *mut Inner<T>
+    }
+
+    fn staging(&self) -> Option<*mut Staging<T>> {
+        let segment = self.map.ptr() as *mut Transactional<T>;
+        self.transactional
+            .then(|| unsafe { &raw mut (*segment).staging })
+    }
+
+    fn mtx(&self) -> *mut RawMutex {
+        unsafe { &raw mut (*self.inner()).mtx }
+    }
+
+    fn unlock(&self) -> Result<()> {
+        let seq = unsafe { &(*self.inner()).seq };
+        seq.store(seq.load(Ordering::Relaxed) + 1, Ordering::Release);
+        raw_lock::check(unsafe { raw_lock::unlock(self.mtx()) })
+            .map(|_| ())
+            .map_err(Error::lock("pthread_mutex_unlock"))
+    }
+}
+
+/// Repairs data left behind by a process that died while holding the lock protecting it.
+pub trait Recover {
+    /// Brings the data back into a consistent state, e.g. by resetting it or by rolling back
+    /// a half-finished update.
+    fn recover(&mut self);
+}
+
+/// Gives up a recovery that didn't finish when dropped, poisoning the data if enabled and
+/// unlocking the mutex.
+struct AbortRecovery<'a, T: 'static> {
+    shm: &'a ShmMutex<T>,
+    owner_died: bool,
+}
+
+impl<T: 'static> Drop for AbortRecovery<'_, T> {
+    fn drop(&mut self) {
+        if self.shm.poisoning() {
+            self.shm.poisoned().store(1, Ordering::Release);
+            if self.owner_died {
+                unsafe { raw_lock::consistent(self.shm.mtx()) };
+            }
+        }
+        self.shm.unlock().ok();
+    }
+}
+
+/// Builder for `ShmMutex`, created with `ShmMutex::builder`.
+pub struct ShmMutexBuilder<T: 'static> {
+    name: String,
+    poisoning: bool,
+    checksummed: bool,
+    verify_on_lock: bool,
+    transactional: bool,
+    namespace: Namespace,
+    permissions: Permissions,
+    _marker: PhantomData<T>,
+}
+
+impl<T: 'static> ShmMutexBuilder<T> {
+    /// Enables poisoning: once an owner dies while holding the lock, `lock` fails with
+    /// `Error::Poisoned` until the data is repaired with `lock_with_recovery` or declared
+    /// consistent with `clear_poison`. Only applies if this handle creates the mutex.
+    pub fn poisoning(mut self, poisoning: bool) -> Self {
+        self.poisoning = poisoning;
+        self
+    }
+
+    /// Enables checksumming: every unlock stores a CRC-32C of the data, so a later owner can
+    /// tell with `ShmMutexGuard::verify` whether the data was torn by an owner that died
+    /// halfway through an update, or written without holding the lock. Costs a pass over
+    /// the data per unlock. Only applies if this handle creates the mutex.
+    pub fn checksummed(mut self, checksummed: bool) -> Self {
+        self.checksummed = checksummed;
+        self
+    }
+
+    /// Verifies the checksum on every lock of this handle, failing with
+    /// `Error::ChecksumMismatch` until the data is repaired with `lock_with_recovery`, which
+    /// also repairs it on a mismatch. Costs another pass over the data per lock, and has no
+    /// effect unless the mutex was created with `checksummed`.
+    pub fn verify_on_lock(mut self, verify_on_lock: bool) -> Self {
+        self.verify_on_lock = verify_on_lock;
+        self
+    }
+
+    /// Adds room for a second copy of the data to the segment, which `ShmMutex::transaction`
+    /// stages updates in. As it changes the layout of the segment, opening a mutex fails
+    /// with `Error::Validation` unless this matches the setting it was created with.
+    pub fn transactional(mut self, transactional: bool) -> Self {
+        self.transactional = transactional;
+        self
+    }
+
+    /// Opens the mutex in `namespace` instead of the default one.
+    pub fn namespace(mut self, namespace: &Namespace) -> Self {
+        self.namespace = namespace.clone();
+        self
+    }
+
+    /// Sets the permissions the backing file is created with, see `Permissions`. Only
+    /// applies if this handle creates the mutex.
+    pub fn permissions(mut self, permissions: Permissions) -> Self {
+        self.permissions = permissions;
+        self
+    }
+
+    /// Creates or opens the mutex.
+    pub fn build(self) -> Result<ShmMutex<T>>
+    where
+        T: ShmSafe,
+    {
+        unsafe { self.open(T::FINGERPRINT) }
+    }
+
+    /// Like `build`, but without requiring `T: ShmSafe`.
+    ///
+    /// # Safety
+    ///
+    /// T must uphold the `ShmSafe` contract even though it doesn't implement the trait.
+    pub unsafe fn build_unchecked(self) -> Result<ShmMutex<T>> {
+        unsafe { self.open(0) }
+    }
+
+    /// Creates or opens the mutex, checking the `ShmSafe::FINGERPRINT` of the data against
+    /// `fingerprint` unless either is 0.
+    unsafe fn open(self, fingerprint: u64) -> Result<ShmMutex<T>> {
+        let path = self.namespace.path(&self.name, ".smx");
+        let len = match self.transactional {
+            true => size_of::<Transactional<T>>(),
+            false => size_of::<Inner<T>>(),
+        };
+        let len = NonZeroUsize::new(len).expect("Inner<T> has nonzero size");
+
+        let mut map = Mapping::open_init(
+            &path,
+            OFlag::O_CREAT,
+            &self.permissions,
+            len,
+            CleanupPolicy::Never,
+            |ptr| unsafe {
+                let inner = ptr as *mut Inner<T>;
+                let header = &mut (*inner).header;
+                match self.transactional {
+                    true => header.init::<Transactional<T>>(size_of::<T>(), 0),
+                    false => header.init::<Inner<T>>(size_of::<T>(), 0),
+                }
+                header.set_fingerprint(fingerprint);
+                (*inner).poisoning = self.poisoning as u32;
+                (*inner).checksummed = self.checksummed as u32;
+                // The data starts out zeroed, like the rest of a new file.
+                let checksum = crc32c((*inner).data.get() as *const u8, size_of::<T>());
+                (*inner).checksum = AtomicU32::new(checksum);
+                raw_lock::init_mutex(
+                    &raw mut (*inner).mtx,
+                    MutexKind::Normal,
+                    MutexProtocol::None,
+                )
+            },
+        )?;
+
+        let header = unsafe { &(*(map.ptr() as *const Inner<T>)).header };
+        {
+            let _init_lock = map.init_lock()?;
+            match self.transactional {
+                true => header.validate::<Transactional<T>>(size_of::<T>(), 0)?,
+                false => header.validate::<Inner<T>>(size_of::<T>(), 0)?,
+            }
+            header.validate_fingerprint::<T>(fingerprint)?;
+        }
+        map.record_in(&self.namespace, &self.name, ".smx")?;
+        map.inherit_in_forks(None);
+
+        Ok(ShmMutex {
+            map,
+            verify_on_lock: self.verify_on_lock,
+            transactional: self.transactional,
+            _marker: PhantomData,
+        })
+    }
+}
+
+/// RAII guard returned by `ShmMutex::lock`, giving access to the data and unlocking on drop.
+pub struct ShmMutexGuard<'a, T: 'static> {
+    shm: &'a ShmMutex<T>,
+    result: LockResult,
+}
+
+impl<T: 'static> ShmMutexGuard<'_, T> {
+    /// How the mutex was acquired.
+    pub fn lock_result(&self) -> &LockResult {
+        &self.result
+    }
+
+    /// Returns true if the previous owner died while holding the mutex,
+    /// in which case the data may be partially updated.
+    pub fn owner_died_recovered(&self) -> bool {
+        matches!(self.result, LockResult::OwnerDiedRecovered)
+    }
+
+    /// Checks the data against the checksum stored when the mutex was last unlocked, failing
+    /// with `Error::ChecksumMismatch` if it doesn't match. Only meaningful before changing the
+    /// data through this guard, and always succeeds unless the mutex was created with
+    /// `ShmMutexBuilder::checksummed`.
+    pub fn verify(&self) -> Result<()> {
+        match self.shm.checksum_matches() {
+            true => Ok(()),
+            false => Err(Error::ChecksumMismatch),
+        }
+    }
+}
+
+impl<T: 'static> Deref for ShmMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*(*self.shm.inner()).data.get() }
+    }
+}
+
+impl<T: 'static> DerefMut for ShmMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *(*self.shm.inner()).data.get() }
+    }
+}
+
+impl<T: 'static> Drop for ShmMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.shm.update_checksum();
+        self.shm.unlock().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{panic, process};
+
+    use nix::{libc, sys::stat::Mode, unistd::ftruncate};
+
+    use super::*;
+
+    /// Opens a mutex under a name unique to this test and process, removed again on drop.
+    struct TestMutex {
+        name: String,
+        shm: ShmMutex<u64>,
+    }
+
+    impl TestMutex {
+        fn new(
+            test: &str,
+            configure: impl FnOnce(ShmMutexBuilder<u64>) -> ShmMutexBuilder<u64>,
+        ) -> Self {
+            let name = format!("test-{test}-{}", process::id());
+            ShmMutex::<u64>::unlink(&name).ok();
+            let shm = configure(ShmMutex::builder(&name)).build().unwrap();
+            Self { name, shm }
+        }
+    }
+
+    impl Drop for TestMutex {
+        fn drop(&mut self) {
+            ShmMutex::<u64>::unlink(&self.name).ok();
+        }
+    }
+
+    /// Runs `f` in a forked child holding the lock, which then exits without unlocking.
+    fn die_holding_lock(shm: &ShmMutex<u64>, f: impl FnOnce(&ShmMutex<u64>, &mut u64)) {
+        match unsafe { libc::fork() } {
+            -1 => panic!("fork failed"),
+            0 => {
+                let mut guard = shm.lock().unwrap();
+                f(shm, &mut guard);
+                unsafe { libc::_exit(0) }
+            }
+            child => {
+                let mut status = 0;
+                assert_eq!(unsafe { libc::waitpid(child, &mut status, 0) }, child);
+                assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+            }
+        }
+    }
+
+    #[test]
+    fn owner_death_is_reported_and_repaired() {
+        let test = TestMutex::new("smx-owner-died", |builder| builder);
+        die_holding_lock(&test.shm, |_, data| *data = 7);
+
+        let mut repaired = false;
+        let guard = test
+            .shm
+            .lock_with_recovery(|data| {
+                repaired = true;
+                *data += 1;
+            })
+            .unwrap();
+        assert!(guard.owner_died_recovered());
+        assert_eq!(*guard, 8);
+        drop(guard);
+        assert!(repaired);
+
+        let guard = test.shm.lock().unwrap();
+        assert!(!guard.owner_died_recovered());
+    }
+
+    #[test]
+    fn owner_death_poisons_until_repaired() {
+        let test = TestMutex::new("smx-poisoned", |builder| builder.poisoning(true));
+        die_holding_lock(&test.shm, |_, _| {});
+
+        assert!(matches!(test.shm.lock(), Err(Error::Poisoned)));
+        assert!(matches!(test.shm.lock(), Err(Error::Poisoned)));
+        drop(test.shm.lock_with_recovery(|data| *data = 1).unwrap());
+        assert!(!test.shm.is_poisoned());
+        assert_eq!(*test.shm.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn transaction_commits_on_ok() {
+        let test = TestMutex::new("smx-tx-commit", |builder| {
+            builder.transactional(true).checksummed(true)
+        });
+        let result = test.shm.transaction(|data| {
+            *data = 42;
+            Ok::<_, Error>("done")
+        });
+        assert_eq!(result.unwrap(), "done");
+        let guard = test.shm.lock().unwrap();
+        assert_eq!(*guard, 42);
+        guard.verify().unwrap();
+    }
+
+    #[test]
+    fn transaction_aborts_on_error_and_panic() {
+        let test = TestMutex::new("smx-tx-abort", |builder| builder.transactional(true));
+        *test.shm.lock().unwrap() = 5;
+
+        let result = test.shm.transaction(|data| {
+            *data = 6;
+            Err::<(), _>(Error::Validation("aborted".to_owned()))
+        });
+        assert!(matches!(result, Err(Error::Validation(_))));
+        assert_eq!(*test.shm.lock().unwrap(), 5);
+
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            test.shm.transaction(|data| -> Result<()> {
+                *data = 7;
+                panic!("aborted");
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(*test.shm.lock().unwrap(), 5);
+    }
+
+    #[test]
+    fn transactions_need_a_transactional_mutex() {
+        let test = TestMutex::new("smx-tx-plain", |builder| builder);
+        let result = test.shm.transaction(|data| {
+            *data = 1;
+            Ok::<_, Error>(())
+        });
+        assert!(matches!(result, Err(Error::InvalidArgument(_))));
+        assert_eq!(*test.shm.lock().unwrap(), 0);
+
+        let reopened = ShmMutex::<u64>::builder(&test.name)
+            .transactional(true)
+            .build();
+        assert!(matches!(reopened, Err(Error::Validation(_))));
+    }
+
+    #[test]
+    fn segments_without_a_header_are_rejected() {
+        let name = format!("test-smx-headerless-{}", process::id());
+        let path = Namespace::default().path(&name, ".smx");
+        let fd = path
+            .open(
+                OFlag::O_CREAT | OFlag::O_RDWR,
+                Mode::from_bits_truncate(0o600),
+            )
+            .unwrap();
+        ftruncate(&fd, size_of::<Inner<u64>>() as i64).unwrap();
+
+        let result = ShmMutex::<u64>::new(&name);
+        ShmMutex::<u64>::unlink(&name).unwrap();
+        assert!(matches!(result, Err(Error::Validation(_))));
+    }
+
+    #[test]
+    fn interrupted_commit_is_finished_by_next_locker() {
+        let test = TestMutex::new("smx-tx-died", |builder| {
+            builder.transactional(true).checksummed(true)
+        });
+        *test.shm.lock().unwrap() = 1;
+        die_holding_lock(&test.shm, |shm, _| {
+            // What a process dying halfway through copying a staged value leaves behind.
+            let staging = shm.staging().unwrap();
+            unsafe {
+                *(*staging).staged.get() = 2;
+                (*staging).committing.store(1, Ordering::Release);
+            }
+        });
+
+        let guard = test.shm.lock().unwrap();
+        assert!(guard.owner_died_recovered());
+        assert_eq!(*guard, 2);
+        guard.verify().unwrap();
+    }
+
+    #[test]
+    fn checksum_mismatch_is_repaired() {
+        let test = TestMutex::new("smx-checksum", |builder| {
+            builder.checksummed(true).verify_on_lock(true)
+        });
+        *test.shm.lock().unwrap() = 3;
+        // Written without holding the lock.
+        unsafe { *(*test.shm.inner()).data.get() = 4 };
+
+        assert!(matches!(test.shm.lock(), Err(Error::ChecksumMismatch)));
+        assert!(matches!(test.shm.try_lock(), Err(Error::ChecksumMismatch)));
+        let mut seen = None;
+        let guard = test
+            .shm
+            .lock_with_recovery(|data| {
+                seen = Some(*data);
+                *data = 3;
+            })
+            .unwrap();
+        assert!(!guard.owner_died_recovered());
+        drop(guard);
+        assert_eq!(seen, Some(4));
+        assert_eq!(*test.shm.lock().unwrap(), 3);
+    }
+}
@@ -0,0 +1,92 @@
+use std::{
+    mem::size_of,
+    num::NonZeroUsize,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use nix::fcntl::OFlag;
+
+use crate::{
+    builder::Builder, cleanup::CleanupPolicy, error::Result, map::Mapping, namespace::Namespace,
+};
+
+/// Runs an initialization exactly once across all processes opening the same name.
+///
+/// The first caller of `call_once` runs its closure while holding the flock that also
+/// serializes the creation of shared memory objects, and later callers block on it until
+/// the closure returns. If the closure fails or its process dies while running it, the
+/// flock is released without marking the once as completed, and the next caller runs its
+/// own closure instead.
+pub struct ShmOnce {
+    map: Mapping,
+}
+
+impl ShmOnce {
+    pub fn new(name: &str) -> Result<Self> {
+        Self::builder(name).build()
+    }
+
+    /// Returns a builder for setting the namespace and the permissions the once cell is created
+    /// with.
+    pub fn builder(name: &str) -> Builder<Self> {
+        Builder::new(name)
+    }
+
+    /// Unlinks (deletes) the once file from /dev/shm, so the next opener starts over.
+    pub fn unlink(name: &str) -> Result<()> {
+        Self::unlink_in(&Namespace::default(), name)
+    }
+
+    /// Unlinks the once called `name` in `namespace`.
+    pub fn unlink_in(namespace: &Namespace, name: &str) -> Result<()> {
+        namespace.path(name, ".once").unlink()
+    }
+
+    /// Runs `f` unless it already completed in this or another process, waiting while
+    /// another process is running its closure. Errors returned by `f` are passed on.
+    pub fn call_once<F>(&self, f: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<()>,
+    {
+        if self.is_completed() {
+            return Ok(());
+        }
+
+        let _init_lock = self.map.init_lock()?;
+        if self.done().load(Ordering::Acquire) == 0 {
+            f()?;
+            self.done().store(1, Ordering::Release);
+        }
+        Ok(())
+    }
+
+    /// Returns true if a closure passed to `call_once` has completed.
+    pub fn is_completed(&self) -> bool {
+        self.done().load(Ordering::Acquire) != 0
+    }
+
+    fn done(&self) -> &AtomicU32 {
+        unsafe { &*(self.map.ptr() as *const AtomicU32) }
+    }
+}
+
+impl Builder<ShmOnce> {
+    /// Opens the once cell like `ShmOnce::new`.
+    pub fn build(self) -> Result<ShmOnce> {
+        let path = self.namespace.path(&self.name, ".once");
+        let len = NonZeroUsize::new(size_of::<AtomicU32>()).expect("AtomicU32 has nonzero size");
+
+        // Zeroed memory is a once that hasn't completed yet.
+        let map = Mapping::open_init(
+            &path,
+            OFlag::O_CREAT,
+            &self.permissions,
+            len,
+            CleanupPolicy::Never,
+            |_| Ok(()),
+        )?;
+        map.inherit_in_forks(None);
+
+        Ok(ShmOnce { map })
+    }
+}
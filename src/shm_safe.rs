@@ -0,0 +1,122 @@
+use std::{
+    cell::UnsafeCell,
+    mem::{align_of, size_of},
+    sync::atomic::{
+        AtomicBool, AtomicI8, AtomicI16, AtomicI32, AtomicI64, AtomicIsize, AtomicU8, AtomicU16,
+        AtomicU32, AtomicU64, AtomicUsize,
+    },
+};
+
+/// Marker for types that can live in shared memory mapped by several processes.
+///
+/// With the `derive` feature it can be derived for `#[repr(C)]` structs, which also checks
+/// the fields and padding at compile time.
+///
+/// # Safety
+///
+/// Implementors must be plain data: valid when all of their bytes are zero, free of
+/// pointers, references and handles that are only meaningful inside one process, and
+/// without `Drop` glue. They should almost always be `#[repr(C)]` so every process agrees
+/// on the layout.
+pub unsafe trait ShmSafe: Sized + 'static {
+    /// A hash describing the layout of the type, used to detect mismatched attaches.
+    const FINGERPRINT: u64 = {
+        let hash = fnv1a_u64(FNV_OFFSET, size_of::<Self>() as u64);
+        fnv1a_u64(hash, align_of::<Self>() as u64)
+    };
+}
+
+macro_rules! impl_shm_safe {
+    ($($t:ty),* $(,)?) => {
+        $(unsafe impl ShmSafe for $t {
+            const FINGERPRINT: u64 = fnv1a(FNV_OFFSET, stringify!($t).as_bytes());
+        })*
+    };
+}
+
+impl_shm_safe!(
+    (),
+    bool,
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    f32,
+    f64,
+    AtomicBool,
+    AtomicU8,
+    AtomicU16,
+    AtomicU32,
+    AtomicU64,
+    AtomicUsize,
+    AtomicI8,
+    AtomicI16,
+    AtomicI32,
+    AtomicI64,
+    AtomicIsize,
+);
+
+/// Marker for shm-safe types that can be read and written concurrently through shared
+/// references, such as the standard atomic types.
+///
+/// # Safety
+///
+/// All mutation through `&Self` must be free of data races across processes.
+pub unsafe trait ShmAtomic: ShmSafe {}
+
+macro_rules! impl_shm_atomic {
+    ($($t:ty),* $(,)?) => {
+        $(unsafe impl ShmAtomic for $t {})*
+    };
+}
+
+impl_shm_atomic!(
+    AtomicBool,
+    AtomicU8,
+    AtomicU16,
+    AtomicU32,
+    AtomicU64,
+    AtomicUsize,
+    AtomicI8,
+    AtomicI16,
+    AtomicI32,
+    AtomicI64,
+    AtomicIsize,
+);
+
+unsafe impl<T: ShmSafe, const N: usize> ShmSafe for [T; N] {
+    const FINGERPRINT: u64 = fnv1a_u64(T::FINGERPRINT, N as u64);
+}
+
+unsafe impl<T: ShmSafe> ShmSafe for UnsafeCell<T> {
+    const FINGERPRINT: u64 = T::FINGERPRINT;
+}
+
+unsafe impl<T: ShmAtomic, const N: usize> ShmAtomic for [T; N] {}
+
+pub const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Folds `bytes` into a running FNV-1a hash.
+pub const fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+        i += 1;
+    }
+    hash
+}
+
+/// Folds the little-endian bytes of `value` into a running FNV-1a hash.
+pub const fn fnv1a_u64(hash: u64, value: u64) -> u64 {
+    fnv1a(hash, &value.to_le_bytes())
+}
@@ -0,0 +1,265 @@
+use std::{
+    io::{self, Read, Write},
+    num::NonZeroUsize,
+    os::fd::{AsFd, BorrowedFd},
+    ptr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use nix::fcntl::OFlag;
+
+use crate::{
+    builder::Builder,
+    cache_padded::CachePadded,
+    cleanup::CleanupPolicy,
+    error::{Error, Result},
+    futex::EventCount,
+    header::Header,
+    map::Mapping,
+    namespace::Namespace,
+};
+
+#[cfg(feature = "serde")]
+use std::time::Duration;
+
+#[cfg(feature = "serde")]
+use crate::framing::{decode, encode, read_frame, write_frame};
+
+#[repr(C)]
+struct StreamHeader {
+    header: Header,
+    capacity: u64,
+    /// Total number of bytes read, only written by the reader.
+    head: CachePadded<AtomicU64>,
+    /// Total number of bytes written, only written by the writer.
+    tail: CachePadded<AtomicU64>,
+    /// Notified whenever bytes are written.
+    readable: EventCount,
+    /// Notified whenever bytes are read.
+    writable: EventCount,
+}
+
+/// Byte-oriented ring buffer in shared memory, implementing `Read` and `Write`.
+///
+/// One process writes and one process reads; transferring bytes takes no syscalls unless
+/// a blocking call has to wait. There is no end-of-stream, so a blocking read waits until
+/// more bytes are written.
+pub struct ShmStream {
+    map: Mapping,
+    capacity: u64,
+    nonblocking: bool,
+}
+
+impl ShmStream {
+    /// Opens the stream in /dev/shm, creating it with a buffer of `capacity` bytes if it
+    /// doesn't exist. Attaching to a stream of a different capacity fails.
+    pub fn new(name: &str, capacity: usize) -> Result<Self> {
+        Self::builder(name).build(capacity)
+    }
+
+    /// Returns a builder for setting the namespace and the permissions the stream is created with.
+    pub fn builder(name: &str) -> Builder<Self> {
+        Builder::new(name)
+    }
+
+    /// Unlinks (deletes) the stream from /dev/shm.
+    pub fn unlink(name: &str) -> Result<()> {
+        Self::unlink_in(&Namespace::default(), name)
+    }
+
+    /// Unlinks the stream called `name` in `namespace`.
+    pub fn unlink_in(namespace: &Namespace, name: &str) -> Result<()> {
+        namespace.path(name, ".stream").unlink()
+    }
+
+    /// In non-blocking mode, reading from an empty or writing to a full stream fails with
+    /// `ErrorKind::WouldBlock` instead of waiting.
+    pub fn set_nonblocking(&mut self, nonblocking: bool) {
+        self.nonblocking = nonblocking;
+    }
+
+    /// Number of bytes waiting to be read.
+    pub fn len(&self) -> usize {
+        let tail = self.header().tail.load(Ordering::Acquire);
+        let head = self.header().head.load(Ordering::Acquire);
+        tail.saturating_sub(head) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Size of the buffer in bytes.
+    pub fn capacity(&self) -> usize {
+        self.capacity as usize
+    }
+
+    /// Encodes `value` with bincode and writes it as one frame, prefixed with its length like
+    /// the messages of a `UnixChannel`, blocking while the buffer is full.
+    ///
+    /// Frames only stay intact in blocking mode, as a non-blocking write can stop halfway
+    /// through one.
+    #[cfg(feature = "serde")]
+    pub fn send_serialized<T: serde::Serialize>(&mut self, value: &T) -> Result<()> {
+        write_frame(self, &encode(value)?)
+    }
+
+    /// Reads the next frame written by `send_serialized` and decodes it with bincode,
+    /// blocking until it fully arrived.
+    #[cfg(feature = "serde")]
+    pub fn recv_deserialized<T: serde::de::DeserializeOwned>(&mut self) -> Result<T> {
+        decode(&read_frame(self)?)
+    }
+
+    /// Waits up to `timeout` until there are bytes to read, returning false if there are none.
+    #[cfg(feature = "serde")]
+    pub(crate) fn wait_readable(&self, timeout: Option<Duration>) -> Result<bool> {
+        let ready = self
+            .header()
+            .readable
+            .wait_for(timeout, || (!self.is_empty()).then_some(()))?;
+        Ok(ready.is_some())
+    }
+
+    fn header(&self) -> &StreamHeader {
+        unsafe { &*(self.map.ptr() as *const StreamHeader) }
+    }
+
+    fn data(&self) -> *mut u8 {
+        unsafe { (self.map.ptr() as *mut u8).add(size_of::<StreamHeader>()) }
+    }
+
+    /// Waits until `available` reports a nonzero number of bytes, unless non-blocking.
+    fn wait(&self, event: &EventCount, available: impl Fn() -> usize) -> io::Result<usize> {
+        let ready = available();
+        if ready > 0 || self.nonblocking {
+            return match ready {
+                0 => Err(io::ErrorKind::WouldBlock.into()),
+                _ => Ok(ready),
+            };
+        }
+
+        let ready = event
+            .wait_for(None, || Some(available()).filter(|&ready| ready > 0))
+            .map_err(io::Error::other)?;
+        Ok(ready.expect("waiting without a timeout always yields a value"))
+    }
+}
+
+impl Builder<ShmStream> {
+    /// Opens the stream like `ShmStream::new`.
+    pub fn build(self, capacity: usize) -> Result<ShmStream> {
+        let data_len = NonZeroUsize::new(capacity)
+            .ok_or_else(|| Error::InvalidArgument("Stream capacity must be nonzero".to_owned()))?;
+        let map_len = size_of::<StreamHeader>()
+            .checked_add(data_len.get())
+            .and_then(NonZeroUsize::new)
+            .ok_or_else(|| {
+                Error::InvalidArgument(format!("Stream of {capacity} bytes is too large"))
+            })?;
+
+        let path = self.namespace.path(&self.name, ".stream");
+        let map = Mapping::open_init(
+            &path,
+            OFlag::O_CREAT,
+            &self.permissions,
+            map_len,
+            CleanupPolicy::LastCloseRefCounted,
+            |raw| unsafe {
+                let stream = raw as *mut StreamHeader;
+                (*stream).header.init::<u8>(capacity, 0);
+                (*stream).capacity = capacity as u64;
+                Ok(())
+            },
+        )?;
+
+        let stream = map.ptr() as *const StreamHeader;
+        {
+            let _init_lock = map.init_lock()?;
+            unsafe {
+                (*stream).header.validate::<u8>(capacity, 0)?;
+                (*stream).header.attach(&map)?;
+            }
+        }
+
+        Ok(ShmStream {
+            map,
+            capacity: capacity as u64,
+            nonblocking: false,
+        })
+    }
+}
+
+impl Read for ShmStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let header = self.header();
+        let head = header.head.load(Ordering::Relaxed);
+        let available = self.wait(&header.readable, || {
+            (header.tail.load(Ordering::Acquire) - head) as usize
+        })?;
+
+        let count = available.min(buf.len());
+        let start = (head % self.capacity) as usize;
+        let first = count.min(self.capacity() - start);
+        unsafe {
+            ptr::copy_nonoverlapping(self.data().add(start), buf.as_mut_ptr(), first);
+            ptr::copy_nonoverlapping(self.data(), buf.as_mut_ptr().add(first), count - first);
+        }
+
+        header.head.store(head + count as u64, Ordering::Release);
+        header.writable.notify_all();
+        Ok(count)
+    }
+}
+
+impl Write for ShmStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let header = self.header();
+        let tail = header.tail.load(Ordering::Relaxed);
+        let free = self.wait(&header.writable, || {
+            (self.capacity - (tail - header.head.load(Ordering::Acquire))) as usize
+        })?;
+
+        let count = free.min(buf.len());
+        let start = (tail % self.capacity) as usize;
+        let first = count.min(self.capacity() - start);
+        unsafe {
+            ptr::copy_nonoverlapping(buf.as_ptr(), self.data().add(start), first);
+            ptr::copy_nonoverlapping(buf.as_ptr().add(first), self.data(), count - first);
+        }
+
+        header.tail.store(tail + count as u64, Ordering::Release);
+        header.readable.notify_all();
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsFd for ShmStream {
+    /// The descriptor of the backing file, which can be passed to other processes with
+    /// `send_fds`.
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.map.fd()
+    }
+}
+
+// The stream only points into shared memory, which is as usable from any thread as from any
+// process.
+unsafe impl Send for ShmStream {}
+
+impl Drop for ShmStream {
+    fn drop(&mut self) {
+        self.header().header.detach(&self.map);
+    }
+}
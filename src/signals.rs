@@ -0,0 +1,96 @@
+use std::{
+    mem::{size_of, zeroed},
+    os::fd::{AsFd, BorrowedFd, FromRawFd, OwnedFd},
+    ptr,
+};
+
+use nix::{
+    errno::Errno,
+    libc::{
+        SFD_CLOEXEC, SFD_NONBLOCK, SIG_BLOCK, c_int, pid_t, pthread_sigmask, sigaddset,
+        sigemptyset, signalfd, signalfd_siginfo, sigset_t, uid_t,
+    },
+    unistd::read,
+};
+
+use crate::{
+    error::{Error, Result},
+    event::wait_readable,
+};
+
+/// A signal received through `Signals`, with the process that sent it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SignalInfo {
+    pub signal: c_int,
+    pub pid: pid_t,
+    pub uid: uid_t,
+}
+
+/// Receives signals as reads from a signalfd instead of through signal handlers, so they can
+/// be polled together with the eventfds of channels, events and timers.
+///
+/// Creating it blocks the signals in the calling thread, and only threads blocking them hand
+/// them over to the signalfd. Create it before spawning threads, which inherit the mask, so
+/// no thread is left to handle them the default way. The signals stay blocked when it is
+/// dropped.
+///
+/// Only available on Linux.
+pub struct Signals {
+    fd: OwnedFd,
+}
+
+impl Signals {
+    /// Blocks `signals` in the calling thread and returns a handle receiving them.
+    pub fn new(signals: &[c_int]) -> Result<Self> {
+        let mut mask: sigset_t = unsafe { zeroed() };
+        unsafe { sigemptyset(&mut mask) };
+        for &signal in signals {
+            Errno::result(unsafe { sigaddset(&mut mask, signal) }).map_err(|_| {
+                Error::InvalidArgument(format!("{signal} is not a valid signal number"))
+            })?;
+        }
+
+        let err = unsafe { pthread_sigmask(SIG_BLOCK, &mask, ptr::null_mut()) };
+        if err != 0 {
+            return Err(Error::sys("pthread_sigmask")(Errno::from_raw(err)));
+        }
+        let raw_fd = Errno::result(unsafe { signalfd(-1, &mask, SFD_NONBLOCK | SFD_CLOEXEC) })
+            .map_err(Error::sys("signalfd"))?;
+        Ok(Self {
+            fd: unsafe { OwnedFd::from_raw_fd(raw_fd) },
+        })
+    }
+
+    /// Blocks until one of the signals arrives.
+    pub fn recv(&self) -> Result<SignalInfo> {
+        loop {
+            if let Some(info) = self.try_recv()? {
+                return Ok(info);
+            }
+            wait_readable(&self.fd)?;
+        }
+    }
+
+    /// Returns a pending signal, or `None` if there is none, without blocking.
+    pub fn try_recv(&self) -> Result<Option<SignalInfo>> {
+        let mut buf = [0; size_of::<signalfd_siginfo>()];
+        match read(&self.fd, &mut buf) {
+            Ok(_) => {
+                let info = unsafe { ptr::read_unaligned(buf.as_ptr() as *const signalfd_siginfo) };
+                Ok(Some(SignalInfo {
+                    signal: info.ssi_signo as c_int,
+                    pid: info.ssi_pid as pid_t,
+                    uid: info.ssi_uid as uid_t,
+                }))
+            }
+            Err(Errno::EAGAIN) => Ok(None),
+            Err(e) => Err(Error::sys("read(signalfd)")(e)),
+        }
+    }
+}
+
+impl AsFd for Signals {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
@@ -0,0 +1,91 @@
+use std::{
+    env, io,
+    os::{
+        fd::{AsRawFd, BorrowedFd, FromRawFd, RawFd},
+        unix::{net::UnixStream, process::CommandExt},
+    },
+    process::{Child, Command},
+};
+
+use nix::{
+    fcntl::{FcntlArg, FdFlag, fcntl},
+    libc::{F_GETFD, F_SETFD, dup2},
+    sys::stat::{SFlag, fstat},
+};
+
+use crate::{
+    error::{Error, Result},
+    unix_channel::UnixChannel,
+};
+
+/// Environment variable telling a child spawned with `spawn_with_ipc` which descriptor
+/// holds its end of the channel.
+pub const IPC_FD_ENV: &str = "NIX_IPC_FD";
+
+/// Descriptor number the child's end of the channel is inherited at.
+const CHILD_FD: RawFd = 3;
+
+/// Spawns `command` connected to this process by a unix socket pair, returning the child
+/// and the parent's end of the channel.
+///
+/// The child inherits its end at descriptor 3, announced in the `NIX_IPC_FD` environment
+/// variable, and picks it up with `UnixChannel::from_env`. No other descriptors of this
+/// crate are inherited, since they are all opened with `O_CLOEXEC`, unless that was
+/// cleared with `set_cloexec` or `ShmBuilder::cloexec`.
+pub fn spawn_with_ipc(command: &mut Command) -> Result<(Child, UnixChannel)> {
+    let (parent, child) = UnixStream::pair()?;
+    let child_fd = child.as_raw_fd();
+
+    command.env(IPC_FD_ENV, CHILD_FD.to_string());
+    unsafe {
+        command.pre_exec(move || {
+            // Only async-signal-safe calls are allowed between fork and exec.
+            if child_fd == CHILD_FD {
+                let flags = nix::libc::fcntl(child_fd, F_GETFD);
+                if flags == -1
+                    || nix::libc::fcntl(child_fd, F_SETFD, flags & !nix::libc::FD_CLOEXEC) == -1
+                {
+                    return Err(io::Error::last_os_error());
+                }
+            } else if dup2(child_fd, CHILD_FD) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+
+    let spawned = command.spawn().map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Failed to spawn {:?}: {e}", command.get_program()),
+        )
+    })?;
+    Ok((spawned, UnixChannel::from(parent)))
+}
+
+impl UnixChannel {
+    /// Takes over the channel inherited from a parent that spawned this process with
+    /// `spawn_with_ipc`. Must be called at most once per process.
+    pub fn from_env() -> Result<Self> {
+        let value = env::var(IPC_FD_ENV)
+            .map_err(|_| Error::Validation(format!("{IPC_FD_ENV} is not set")))?;
+        let fd: RawFd = value.parse().map_err(|_| {
+            Error::Validation(format!("{IPC_FD_ENV} is not a descriptor number: {value}"))
+        })?;
+
+        let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+        let stat = fstat(fd).map_err(|e| {
+            Error::Validation(format!("Inherited descriptor {value} is invalid: {e}"))
+        })?;
+        if SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT != SFlag::S_IFSOCK {
+            return Err(Error::Validation(format!(
+                "Inherited descriptor {value} is not a socket"
+            )));
+        }
+        // Don't pass the channel on to processes this one spawns in turn.
+        fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).map_err(Error::sys("fcntl(F_SETFD)"))?;
+
+        let stream = unsafe { UnixStream::from_raw_fd(fd.as_raw_fd()) };
+        Ok(Self::from(stream))
+    }
+}
@@ -0,0 +1,364 @@
+//! Single-producer, single-consumer ring buffer in shared memory.
+
+use std::{
+    marker::PhantomData,
+    mem::{align_of, size_of},
+    num::NonZeroUsize,
+    ptr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use nix::fcntl::OFlag;
+
+#[cfg(target_os = "linux")]
+use crate::ready::ReadyFd;
+use crate::{
+    builder::Builder,
+    cache_padded::CachePadded,
+    cleanup::CleanupPolicy,
+    error::{Error, Result},
+    futex::EventCount,
+    header::Header,
+    map::Mapping,
+    namespace::Namespace,
+    permissions::Permissions,
+    ready::{ReadySlot, Signaler},
+    shm_safe::ShmSafe,
+};
+
+#[repr(C)]
+struct RingHeader {
+    header: Header,
+    capacity: u64,
+    /// Total number of values received, only written by the consumer.
+    head: CachePadded<AtomicU64>,
+    /// Total number of values sent, only written by the producer.
+    tail: CachePadded<AtomicU64>,
+    /// Notified whenever a value is sent.
+    not_empty: EventCount,
+    /// Notified whenever a value is received.
+    not_full: EventCount,
+    ready: ReadySlot,
+}
+
+/// Opens the ring buffer in /dev/shm, creating it with room for `capacity` values if it
+/// doesn't exist, and returns both of its ends.
+///
+/// To use the ends in different processes, open them separately with `Producer::new` and
+/// `Consumer::new` instead. There must be at most one producer and one consumer at a time.
+pub fn channel<T: ShmSafe>(name: &str, capacity: usize) -> Result<(Producer<T>, Consumer<T>)> {
+    Ok((
+        Producer::new(name, capacity)?,
+        Consumer::new(name, capacity)?,
+    ))
+}
+
+/// Unlinks (deletes) the ring buffer from /dev/shm.
+pub fn unlink(name: &str) -> Result<()> {
+    unlink_in(&Namespace::default(), name)
+}
+
+/// Unlinks the ring buffer called `name` in `namespace`.
+pub fn unlink_in(namespace: &Namespace, name: &str) -> Result<()> {
+    namespace.path(name, ".spsc").unlink()
+}
+
+/// Mapping of the ring buffer shared by both ends.
+struct Ring<T> {
+    map: Mapping,
+    capacity: u64,
+    signaler: Signaler,
+    _marker: PhantomData<T>,
+}
+
+impl<T: ShmSafe> Ring<T> {
+    fn open(
+        namespace: &Namespace,
+        name: &str,
+        capacity: usize,
+        permissions: &Permissions,
+    ) -> Result<Self> {
+        if size_of::<T>() == 0 {
+            return Err(Error::InvalidArgument(
+                "Cannot use zero-sized type in shared memory".to_owned(),
+            ));
+        }
+        if capacity == 0 {
+            return Err(Error::InvalidArgument(
+                "Ring buffer capacity must be nonzero".to_owned(),
+            ));
+        }
+        let data_len = capacity.checked_mul(size_of::<T>()).ok_or_else(|| {
+            Error::InvalidArgument(format!("Ring buffer of {capacity} values is too large"))
+        })?;
+        let map_len =
+            NonZeroUsize::new(Self::data_offset() + data_len).expect("RingHeader has nonzero size");
+
+        let path = namespace.path(name, ".spsc");
+        let map = Mapping::open_init(
+            &path,
+            OFlag::O_CREAT,
+            permissions,
+            map_len,
+            CleanupPolicy::LastCloseRefCounted,
+            |raw| unsafe {
+                let ring = raw as *mut RingHeader;
+                (*ring).header.init::<T>(data_len, 0);
+                (*ring).capacity = capacity as u64;
+                Ok(())
+            },
+        )?;
+
+        let ring = map.ptr() as *const RingHeader;
+        {
+            let _init_lock = map.init_lock()?;
+            unsafe {
+                (*ring).header.validate::<T>(data_len, 0)?;
+                (*ring).header.attach(&map)?;
+            }
+        }
+
+        Ok(Self {
+            map,
+            capacity: capacity as u64,
+            signaler: Signaler::default(),
+            _marker: PhantomData,
+        })
+    }
+
+    /// Offset of the first slot, after the header and aligned for T.
+    fn data_offset() -> usize {
+        size_of::<RingHeader>().next_multiple_of(align_of::<T>())
+    }
+
+    fn header(&self) -> &RingHeader {
+        unsafe { &*(self.map.ptr() as *const RingHeader) }
+    }
+
+    /// Pointer to the slot used by the value at position `pos`.
+    fn slot(&self, pos: u64) -> *mut T {
+        let index = (pos % self.capacity) as usize;
+        unsafe { ((self.map.ptr() as *mut u8).add(Self::data_offset()) as *mut T).add(index) }
+    }
+
+    /// Sends `value` unless the ring buffer is full, refreshing the producer's view of the head.
+    fn try_send(&self, cached_head: &mut u64, value: T) -> Result<(), T> {
+        let header = self.header();
+        let tail = header.tail.load(Ordering::Relaxed);
+        if tail - *cached_head == self.capacity {
+            *cached_head = header.head.load(Ordering::Acquire);
+            if tail - *cached_head == self.capacity {
+                return Err(value);
+            }
+        }
+
+        unsafe { ptr::write(self.slot(tail), value) };
+        header.tail.store(tail + 1, Ordering::Release);
+        header.not_empty.notify_all();
+        self.signaler.signal(&header.ready);
+        Ok(())
+    }
+
+    /// Receives a value unless the ring buffer is empty, refreshing the consumer's view of the tail.
+    fn try_recv(&self, cached_tail: &mut u64) -> Option<T> {
+        let header = self.header();
+        let head = header.head.load(Ordering::Relaxed);
+        if head == *cached_tail {
+            *cached_tail = header.tail.load(Ordering::Acquire);
+            if head == *cached_tail {
+                return None;
+            }
+        }
+
+        let value = unsafe { ptr::read(self.slot(head)) };
+        header.head.store(head + 1, Ordering::Release);
+        header.not_full.notify_all();
+        Some(value)
+    }
+
+    fn len(&self) -> usize {
+        let tail = self.header().tail.load(Ordering::Acquire);
+        let head = self.header().head.load(Ordering::Acquire);
+        tail.saturating_sub(head) as usize
+    }
+}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        let ring = self.map.ptr() as *const RingHeader;
+        unsafe { (*ring).header.detach(&self.map) };
+    }
+}
+
+/// Sending end of a single-producer, single-consumer ring buffer.
+pub struct Producer<T: 'static> {
+    ring: Ring<T>,
+    /// Last seen value of the consumer's head, to avoid touching its cache line on every send.
+    cached_head: u64,
+}
+
+impl<T: ShmSafe> Producer<T> {
+    /// Opens the sending end of the ring buffer, creating it with room for `capacity` values
+    /// if it doesn't exist. Attaching to a ring buffer of a different capacity fails.
+    pub fn new(name: &str, capacity: usize) -> Result<Self> {
+        Self::builder(name).build(capacity)
+    }
+
+    /// Returns a builder for setting the namespace and the permissions the ring buffer is created
+    /// with.
+    pub fn builder(name: &str) -> Builder<Self> {
+        Builder::new(name)
+    }
+
+    /// Sends `value` without blocking, handing it back if the ring buffer is full.
+    pub fn try_send(&mut self, value: T) -> Result<(), T> {
+        self.ring.try_send(&mut self.cached_head, value)
+    }
+
+    /// Sends `value`, blocking while the ring buffer is full.
+    pub fn send(&mut self, value: T) -> Result<()> {
+        self.send_until(value, None).map(|_| ())
+    }
+
+    /// Sends `value`, blocking for at most `timeout` while the ring buffer is full.
+    /// Returns the value back if it could not be sent in time.
+    pub fn send_timeout(&mut self, value: T, timeout: Duration) -> Result<Option<T>> {
+        self.send_until(value, Some(timeout))
+    }
+
+    fn send_until(&mut self, value: T, timeout: Option<Duration>) -> Result<Option<T>> {
+        let Self { ring, cached_head } = self;
+        let mut value = Some(value);
+        let sent = ring.header().not_full.wait_for(timeout, || {
+            let pending = value.take().expect("value is only taken once per attempt");
+            ring.try_send(cached_head, pending)
+                .map_err(|pending| value = Some(pending))
+                .ok()
+        })?;
+        Ok(if sent.is_some() { None } else { value })
+    }
+
+    /// Number of values waiting to be received.
+    pub fn len(&self) -> usize {
+        self.ring.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Maximum number of values the ring buffer holds.
+    pub fn capacity(&self) -> usize {
+        self.ring.capacity as usize
+    }
+}
+
+impl<T: ShmSafe> Builder<Producer<T>> {
+    /// Opens the ring buffer like `Producer::new`.
+    pub fn build(self, capacity: usize) -> Result<Producer<T>> {
+        let ring = Ring::open(&self.namespace, &self.name, capacity, &self.permissions)?;
+        let cached_head = ring.header().head.load(Ordering::Acquire);
+        Ok(Producer { ring, cached_head })
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "tokio"))]
+impl<T: ShmSafe> crate::async_io::sealed::Sender for Producer<T> {
+    type Item = T;
+
+    fn try_send(&mut self, value: T) -> Result<(), T> {
+        Producer::try_send(self, value)
+    }
+
+    fn not_full(&self) -> crate::async_io::sealed::Notified<'_> {
+        crate::async_io::sealed::Notified(&self.ring.header().not_full)
+    }
+}
+
+/// Receiving end of a single-producer, single-consumer ring buffer.
+pub struct Consumer<T: 'static> {
+    ring: Ring<T>,
+    /// Last seen value of the producer's tail, to avoid touching its cache line on every receive.
+    cached_tail: u64,
+}
+
+impl<T: ShmSafe> Consumer<T> {
+    /// Opens the receiving end of the ring buffer, creating it with room for `capacity` values
+    /// if it doesn't exist. Attaching to a ring buffer of a different capacity fails.
+    pub fn new(name: &str, capacity: usize) -> Result<Self> {
+        Self::builder(name).build(capacity)
+    }
+
+    /// Returns a builder for setting the namespace and the permissions the ring buffer is created
+    /// with.
+    pub fn builder(name: &str) -> Builder<Self> {
+        Builder::new(name)
+    }
+
+    /// Receives a value without blocking, returning `None` if the ring buffer is empty.
+    pub fn try_recv(&mut self) -> Option<T> {
+        self.ring.try_recv(&mut self.cached_tail)
+    }
+
+    /// Receives a value, blocking while the ring buffer is empty.
+    pub fn recv(&mut self) -> Result<T> {
+        let value = self.recv_until(None)?;
+        Ok(value.expect("waiting without a timeout always yields a value"))
+    }
+
+    /// Receives a value, blocking for at most `timeout` while the ring buffer is empty.
+    /// Returns `None` if nothing arrived in time.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<T>> {
+        self.recv_until(Some(timeout))
+    }
+
+    /// Returns a handle that becomes readable when values are sent, for use with epoll.
+    #[cfg(target_os = "linux")]
+    pub fn ready_fd(&self) -> Result<ReadyFd> {
+        self.ring.header().ready.ready_fd()
+    }
+
+    fn recv_until(&mut self, timeout: Option<Duration>) -> Result<Option<T>> {
+        let Self { ring, cached_tail } = self;
+        ring.header()
+            .not_empty
+            .wait_for(timeout, || ring.try_recv(cached_tail))
+    }
+
+    /// Number of values waiting to be received.
+    pub fn len(&self) -> usize {
+        self.ring.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Maximum number of values the ring buffer holds.
+    pub fn capacity(&self) -> usize {
+        self.ring.capacity as usize
+    }
+}
+
+impl<T: ShmSafe> Builder<Consumer<T>> {
+    /// Opens the ring buffer like `Consumer::new`.
+    pub fn build(self, capacity: usize) -> Result<Consumer<T>> {
+        let ring = Ring::open(&self.namespace, &self.name, capacity, &self.permissions)?;
+        let cached_tail = ring.header().tail.load(Ordering::Acquire);
+        Ok(Consumer { ring, cached_tail })
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "tokio"))]
+impl<T: ShmSafe> crate::async_io::sealed::Receiver for Consumer<T> {
+    type Item = T;
+
+    fn try_recv(&mut self) -> Option<T> {
+        Consumer::try_recv(self)
+    }
+
+    fn not_empty(&self) -> crate::async_io::sealed::Notified<'_> {
+        crate::async_io::sealed::Notified(&self.ring.header().not_empty)
+    }
+}
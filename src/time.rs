@@ -0,0 +1,68 @@
+use std::time::Duration;
+
+use nix::{
+    errno::Errno,
+    libc::{CLOCK_MONOTONIC, c_long, clock_gettime, clockid_t, timespec},
+};
+
+use crate::error::{Error, Result};
+
+/// Returns the absolute `timespec` lying `timeout` after now on the given clock.
+pub(crate) fn deadline(clock: clockid_t, timeout: Duration) -> Result<timespec> {
+    let now = now(clock)?;
+    let mut nsec = now.tv_nsec + timeout.subsec_nanos() as c_long;
+    let mut sec = (now.tv_sec as i64).saturating_add(timeout.as_secs().min(i64::MAX as u64) as i64);
+    if nsec >= 1_000_000_000 {
+        nsec -= 1_000_000_000;
+        sec = sec.saturating_add(1);
+    }
+    // time_t is only 32 bits wide on some targets.
+    let max = match size_of_val(&now.tv_sec) {
+        4 => i32::MAX as i64,
+        _ => i64::MAX,
+    };
+
+    Ok(timespec {
+        tv_sec: sec.min(max) as _,
+        tv_nsec: nsec,
+    })
+}
+
+/// Returns the time left until the absolute `deadline` on the given clock, or `None` if it
+/// has passed.
+#[cfg(any(
+    not(robust_mutex),
+    target_os = "macos",
+    all(target_os = "linux", feature = "tokio")
+))]
+pub(crate) fn remaining(clock: clockid_t, deadline: &timespec) -> Result<Option<Duration>> {
+    let now = now(clock)?;
+    let left = Duration::new(deadline.tv_sec.max(0) as u64, deadline.tv_nsec as u32)
+        .checked_sub(Duration::new(now.tv_sec.max(0) as u64, now.tv_nsec as u32));
+    Ok(left.filter(|left| !left.is_zero()))
+}
+
+/// Converts `duration` to a `timespec`, saturating where `time_t` is too small.
+#[cfg(target_os = "linux")]
+pub(crate) fn to_timespec(duration: Duration) -> timespec {
+    timespec {
+        tv_sec: duration.as_secs().try_into().unwrap_or(c_long::MAX) as _,
+        tv_nsec: duration.subsec_nanos() as c_long,
+    }
+}
+
+/// Nanoseconds on the monotonic clock, which is the same in all processes.
+pub(crate) fn monotonic_nanos() -> Result<u64> {
+    let now = now(CLOCK_MONOTONIC)?;
+    Ok(now.tv_sec as u64 * 1_000_000_000 + now.tv_nsec as u64)
+}
+
+pub(crate) fn now(clock: clockid_t) -> Result<timespec> {
+    let mut now = timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    Errno::result(unsafe { clock_gettime(clock, &mut now) })
+        .map_err(Error::sys("clock_gettime"))?;
+    Ok(now)
+}
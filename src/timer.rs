@@ -0,0 +1,245 @@
+use std::{
+    mem::size_of,
+    num::NonZeroUsize,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+    ptr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use nix::{
+    errno::Errno,
+    fcntl::OFlag,
+    libc::{
+        CLOCK_MONOTONIC, TFD_CLOEXEC, TFD_NONBLOCK, TFD_TIMER_ABSTIME, c_int, itimerspec,
+        timerfd_create, timerfd_settime,
+    },
+    unistd::read,
+};
+
+use crate::{
+    builder::Builder,
+    cleanup::CleanupPolicy,
+    error::{Error, Result},
+    event::wait_readable,
+    map::Mapping,
+    namespace::Namespace,
+    time::{now, to_timespec},
+};
+
+/// A one-shot or periodic timer on the monotonic clock, backed by a timerfd that becomes
+/// readable when it expires, so it can be registered with poll or epoll.
+///
+/// Only available on Linux.
+pub struct Timer {
+    fd: OwnedFd,
+}
+
+impl Timer {
+    /// Creates a disarmed timer.
+    pub fn new() -> Result<Self> {
+        let raw_fd =
+            Errno::result(unsafe { timerfd_create(CLOCK_MONOTONIC, TFD_NONBLOCK | TFD_CLOEXEC) })
+                .map_err(Error::sys("timerfd_create"))?;
+        Ok(Self {
+            fd: unsafe { OwnedFd::from_raw_fd(raw_fd) },
+        })
+    }
+
+    /// Arms the timer to expire once, `after` from now. A zero duration disarms it.
+    pub fn set_oneshot(&self, after: Duration) -> Result<()> {
+        self.set(0, after, Duration::ZERO)
+    }
+
+    /// Arms the timer to expire every `interval`, starting `interval` from now.
+    pub fn set_periodic(&self, interval: Duration) -> Result<()> {
+        self.set(0, interval, interval)
+    }
+
+    /// Stops the timer.
+    pub fn disarm(&self) -> Result<()> {
+        self.set(0, Duration::ZERO, Duration::ZERO)
+    }
+
+    /// Arms the timer to expire first at `value`, measured from now or on the monotonic
+    /// clock depending on `flags`, then every `interval` unless it is zero.
+    fn set(&self, flags: c_int, value: Duration, interval: Duration) -> Result<()> {
+        let spec = itimerspec {
+            it_interval: to_timespec(interval),
+            it_value: to_timespec(value),
+        };
+        Errno::result(unsafe {
+            timerfd_settime(self.fd.as_raw_fd(), flags, &spec, ptr::null_mut())
+        })
+        .map_err(Error::sys("timerfd_settime"))?;
+        Ok(())
+    }
+
+    /// Blocks until the timer expires, returning the number of expirations since the last
+    /// successful wait.
+    pub fn wait(&self) -> Result<u64> {
+        loop {
+            if let Some(n) = self.try_wait()? {
+                return Ok(n);
+            }
+            wait_readable(&self.fd)?;
+        }
+    }
+
+    /// Returns the number of expirations since the last successful wait, or `None` if there
+    /// were none, without blocking.
+    pub fn try_wait(&self) -> Result<Option<u64>> {
+        let mut buf = [0; 8];
+        match read(&self.fd, &mut buf) {
+            Ok(_) => Ok(Some(u64::from_ne_bytes(buf))),
+            Err(Errno::EAGAIN) => Ok(None),
+            Err(e) => Err(Error::sys("read(timerfd)")(e)),
+        }
+    }
+}
+
+impl AsFd for Timer {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+#[repr(C)]
+struct Schedule {
+    /// First tick on the monotonic clock, in nanoseconds.
+    start: AtomicU64,
+    /// Time between ticks in nanoseconds.
+    period: AtomicU64,
+}
+
+/// A periodic schedule stored in shared memory, so timers in several processes tick at the
+/// same instants: the start of the schedule plus multiples of its period.
+///
+/// The monotonic clock is shared by all processes of a machine, so the ticks line up across
+/// processes. Only available on Linux.
+pub struct SharedSchedule {
+    map: Mapping,
+}
+
+impl SharedSchedule {
+    /// Opens the schedule in /dev/shm, creating it to start now with `period` if it doesn't
+    /// exist. Opening an existing schedule keeps its start and period.
+    pub fn new(name: &str, period: Duration) -> Result<Self> {
+        Self::builder(name).build(period)
+    }
+
+    /// Returns a builder for setting the namespace and the permissions the schedule is created
+    /// with.
+    pub fn builder(name: &str) -> Builder<Self> {
+        Builder::new(name)
+    }
+
+    /// Unlinks (deletes) the schedule file from /dev/shm.
+    pub fn unlink(name: &str) -> Result<()> {
+        Self::unlink_in(&Namespace::default(), name)
+    }
+
+    /// Unlinks the schedule called `name` in `namespace`.
+    pub fn unlink_in(namespace: &Namespace, name: &str) -> Result<()> {
+        namespace.path(name, ".sch").unlink()
+    }
+
+    /// The time between ticks.
+    pub fn period(&self) -> Duration {
+        Duration::from_nanos(self.schedule().period.load(Ordering::Relaxed))
+    }
+
+    /// Moves the schedule to start now and tick every `period`. Timers created before keep
+    /// following the old schedule until they are created again.
+    pub fn reset(&self, period: Duration) -> Result<()> {
+        let period = period_nanos(period)?;
+        let schedule = self.schedule();
+        let _init_lock = self.map.init_lock()?;
+        schedule.start.store(monotonic_nanos()?, Ordering::Relaxed);
+        schedule.period.store(period, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Returns the time left until the next tick.
+    pub fn until_next(&self) -> Result<Duration> {
+        let (next, _) = self.next_tick()?;
+        Ok(Duration::from_nanos(
+            next.saturating_sub(monotonic_nanos()?),
+        ))
+    }
+
+    /// Creates a timer expiring at every tick of the schedule, starting with the next one.
+    pub fn timer(&self) -> Result<Timer> {
+        let timer = Timer::new()?;
+        let (next, period) = self.next_tick()?;
+        timer.set(
+            TFD_TIMER_ABSTIME,
+            Duration::from_nanos(next),
+            Duration::from_nanos(period),
+        )?;
+        Ok(timer)
+    }
+
+    /// The next tick after now in nanoseconds on the monotonic clock, and the period.
+    fn next_tick(&self) -> Result<(u64, u64)> {
+        let (start, period) = {
+            let _init_lock = self.map.init_lock()?;
+            let schedule = self.schedule();
+            (
+                schedule.start.load(Ordering::Relaxed),
+                schedule.period.load(Ordering::Relaxed),
+            )
+        };
+        let now = monotonic_nanos()?;
+        if now < start {
+            return Ok((start, period));
+        }
+        let ticks = (now - start) / period + 1;
+        Ok((start.saturating_add(ticks.saturating_mul(period)), period))
+    }
+
+    fn schedule(&self) -> &Schedule {
+        unsafe { &*(self.map.ptr() as *const Schedule) }
+    }
+}
+
+impl Builder<SharedSchedule> {
+    /// Opens the schedule like `SharedSchedule::new`.
+    pub fn build(self, period: Duration) -> Result<SharedSchedule> {
+        let period = period_nanos(period)?;
+
+        let path = self.namespace.path(&self.name, ".sch");
+        let len = NonZeroUsize::new(size_of::<Schedule>()).expect("Schedule has nonzero size");
+        let start = monotonic_nanos()?;
+        let map = Mapping::open_init(
+            &path,
+            OFlag::O_CREAT,
+            &self.permissions,
+            len,
+            CleanupPolicy::Never,
+            |ptr| {
+                let schedule = unsafe { &*(ptr as *const Schedule) };
+                schedule.start.store(start, Ordering::Relaxed);
+                schedule.period.store(period, Ordering::Relaxed);
+                Ok(())
+            },
+        )?;
+        map.inherit_in_forks(None);
+
+        Ok(SharedSchedule { map })
+    }
+}
+
+fn period_nanos(period: Duration) -> Result<u64> {
+    match period.as_nanos().min(u64::MAX as u128) as u64 {
+        0 => Err(Error::InvalidArgument(
+            "Schedule period must be nonzero".to_owned(),
+        )),
+        period => Ok(period),
+    }
+}
+
+fn monotonic_nanos() -> Result<u64> {
+    let now = now(CLOCK_MONOTONIC)?;
+    Ok(now.tv_sec as u64 * 1_000_000_000 + now.tv_nsec as u64)
+}
@@ -0,0 +1,271 @@
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    broadcast::{LagPolicy, Publisher, Subscriber},
+    builder::Builder,
+    error::{Error, Result},
+    shm_safe::{ShmSafe, fnv1a_u64},
+};
+
+/// The longest topic in bytes.
+pub const MAX_TOPIC_LEN: usize = 64;
+
+/// The topic a message is published under, a string of at most `MAX_TOPIC_LEN` bytes stored
+/// inline so it can travel through shared memory.
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Topic {
+    len: u32,
+    bytes: [u8; MAX_TOPIC_LEN],
+}
+
+impl Topic {
+    /// Fails if `topic` is longer than `MAX_TOPIC_LEN` bytes.
+    pub fn new(topic: &str) -> Result<Self> {
+        if topic.len() > MAX_TOPIC_LEN {
+            return Err(Error::InvalidArgument(format!(
+                "Topic of {} bytes is longer than {MAX_TOPIC_LEN}",
+                topic.len()
+            )));
+        }
+        let mut bytes = [0; MAX_TOPIC_LEN];
+        bytes[..topic.len()].copy_from_slice(topic.as_bytes());
+        Ok(Self {
+            len: topic.len() as u32,
+            bytes,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only ever built from a `&str` by `new`, but the bytes come from shared memory.
+        let len = (self.len as usize).min(MAX_TOPIC_LEN);
+        std::str::from_utf8(&self.bytes[..len]).unwrap_or_default()
+    }
+}
+
+impl fmt::Debug for Topic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for Topic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+unsafe impl ShmSafe for Topic {}
+
+/// Which topics a `TopicSubscriber` receives messages of.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TopicFilter {
+    /// Only this very topic.
+    Exact(String),
+    /// Every topic starting with this string, e.g. `"orders."` matches `"orders.eu"`.
+    Prefix(String),
+}
+
+impl TopicFilter {
+    fn matches(&self, topic: &str) -> bool {
+        match self {
+            Self::Exact(exact) => topic == exact,
+            Self::Prefix(prefix) => topic.starts_with(prefix.as_str()),
+        }
+    }
+}
+
+/// A message on the bus together with its topic.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Envelope<T> {
+    topic: Topic,
+    value: T,
+}
+
+// Padding between the topic and the value is never read, as messages are copied whole.
+unsafe impl<T: ShmSafe> ShmSafe for Envelope<T> {
+    const FINGERPRINT: u64 = fnv1a_u64(T::FINGERPRINT, Topic::FINGERPRINT);
+}
+
+/// Publishing end of a topic-routed broadcast bus, which tags every message with a topic.
+///
+/// All topics share one `broadcast` ring, and subscribers pick the messages of the topics
+/// they're interested in. Under `LagPolicy::Block`, a subscriber interested in few topics
+/// still holds up publishers until it skipped past the messages of the others.
+pub struct TopicPublisher<T: 'static> {
+    publisher: Publisher<Envelope<T>>,
+}
+
+impl<T: ShmSafe + Copy> TopicPublisher<T> {
+    /// Opens the bus in /dev/shm for publishing, creating it with room for `capacity`
+    /// messages and the lag policy `policy` if it doesn't exist. Attaching to a bus with a
+    /// different capacity or lag policy fails.
+    pub fn new(name: &str, capacity: usize, policy: LagPolicy) -> Result<Self> {
+        Self::builder(name).build(capacity, policy)
+    }
+
+    /// Returns a builder for setting the namespace and the permissions the bus is created with.
+    pub fn builder(name: &str) -> Builder<Self> {
+        Builder::new(name)
+    }
+
+    /// Publishes `value` under `topic` without blocking, handing it back if a subscriber is
+    /// a whole ring behind under `LagPolicy::Block`.
+    pub fn try_send(&mut self, topic: &Topic, value: T) -> Result<(), T> {
+        self.publisher
+            .try_send(Envelope {
+                topic: *topic,
+                value,
+            })
+            .map_err(|envelope| envelope.value)
+    }
+
+    /// Publishes `value` under `topic`, blocking while a subscriber is a whole ring behind
+    /// under `LagPolicy::Block`.
+    pub fn send(&mut self, topic: &Topic, value: T) -> Result<()> {
+        self.publisher.send(Envelope {
+            topic: *topic,
+            value,
+        })
+    }
+
+    /// Publishes `value` under `topic`, blocking for at most `timeout` while a subscriber is
+    /// a whole ring behind. Returns the value back if it could not be published in time.
+    pub fn send_timeout(
+        &mut self,
+        topic: &Topic,
+        value: T,
+        timeout: Duration,
+    ) -> Result<Option<T>> {
+        let envelope = Envelope {
+            topic: *topic,
+            value,
+        };
+        let unsent = self.publisher.send_timeout(envelope, timeout)?;
+        Ok(unsent.map(|envelope| envelope.value))
+    }
+
+    /// Number of subscribers currently registered, whatever topics they're interested in.
+    pub fn subscribers(&self) -> usize {
+        self.publisher.subscribers()
+    }
+}
+
+impl<T: ShmSafe + Copy> Builder<TopicPublisher<T>> {
+    /// Opens the bus like `TopicPublisher::new`.
+    pub fn build(self, capacity: usize, policy: LagPolicy) -> Result<TopicPublisher<T>> {
+        Ok(TopicPublisher {
+            publisher: Publisher::builder(&self.name)
+                .permissions(self.permissions)
+                .namespace(&self.namespace)
+                .build(capacity, policy)?,
+        })
+    }
+}
+
+/// Receiving end of a topic-routed broadcast bus, which receives the messages of the topics
+/// matching any of its filters and skips the others. It receives nothing until a filter is
+/// added with `subscribe`.
+pub struct TopicSubscriber<T: 'static> {
+    subscriber: Subscriber<Envelope<T>>,
+    filters: Vec<TopicFilter>,
+}
+
+impl<T: ShmSafe + Copy> TopicSubscriber<T> {
+    /// Opens the bus in /dev/shm and subscribes to it, creating it with room for `capacity`
+    /// messages and the lag policy `policy` if it doesn't exist. Attaching to a bus with a
+    /// different capacity or lag policy fails.
+    pub fn new(name: &str, capacity: usize, policy: LagPolicy) -> Result<Self> {
+        Self::builder(name).build(capacity, policy)
+    }
+
+    /// Returns a builder for setting the namespace and the permissions the bus is created with.
+    pub fn builder(name: &str) -> Builder<Self> {
+        Builder::new(name)
+    }
+
+    /// Starts receiving the messages of the topics matching `filter`, from the next message
+    /// on.
+    pub fn subscribe(&mut self, filter: TopicFilter) {
+        if !self.filters.contains(&filter) {
+            self.filters.push(filter);
+        }
+    }
+
+    /// Stops receiving the messages of the topics matching `filter`, unless another filter
+    /// matches them too. Returns false if the filter wasn't added.
+    pub fn unsubscribe(&mut self, filter: &TopicFilter) -> bool {
+        let len = self.filters.len();
+        self.filters.retain(|added| added != filter);
+        self.filters.len() != len
+    }
+
+    /// The filters added so far.
+    pub fn filters(&self) -> &[TopicFilter] {
+        &self.filters
+    }
+
+    /// Receives the next message of a matching topic without blocking, returning `None` if
+    /// there is none.
+    pub fn try_recv(&mut self) -> Result<Option<(Topic, T)>> {
+        while let Some(envelope) = self.subscriber.try_recv()? {
+            if self.matches(&envelope.topic) {
+                return Ok(Some((envelope.topic, envelope.value)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Receives the next message of a matching topic, blocking while there is none.
+    pub fn recv(&mut self) -> Result<(Topic, T)> {
+        loop {
+            let envelope = self.subscriber.recv()?;
+            if self.matches(&envelope.topic) {
+                return Ok((envelope.topic, envelope.value));
+            }
+        }
+    }
+
+    /// Receives the next message of a matching topic, blocking for at most `timeout` while
+    /// there is none. Returns `None` if nothing matching arrived in time.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<(Topic, T)>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            let Some(envelope) = self.subscriber.recv_timeout(left)? else {
+                return Ok(None);
+            };
+            if self.matches(&envelope.topic) {
+                return Ok(Some((envelope.topic, envelope.value)));
+            }
+        }
+    }
+
+    /// Total number of messages, of any topic, this subscriber skipped because they were
+    /// overwritten.
+    pub fn missed(&self) -> u64 {
+        self.subscriber.missed()
+    }
+
+    fn matches(&self, topic: &Topic) -> bool {
+        let topic = topic.as_str();
+        self.filters.iter().any(|filter| filter.matches(topic))
+    }
+}
+
+impl<T: ShmSafe + Copy> Builder<TopicSubscriber<T>> {
+    /// Opens the bus like `TopicSubscriber::new`.
+    pub fn build(self, capacity: usize, policy: LagPolicy) -> Result<TopicSubscriber<T>> {
+        Ok(TopicSubscriber {
+            subscriber: Subscriber::builder(&self.name)
+                .permissions(self.permissions)
+                .namespace(&self.namespace)
+                .build(capacity, policy)?,
+            filters: Vec::new(),
+        })
+    }
+}
@@ -0,0 +1,181 @@
+use std::{
+    fs,
+    io::{IoSlice, Read, Write},
+    os::{
+        fd::{AsFd, BorrowedFd, OwnedFd},
+        unix::net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    credentials::{PeerCredentials, peer_credentials},
+    error::{Error, Result},
+    fd_passing::{recv_with_fds, send_with_fds},
+    framing::{frame, read_frame, read_frame_body, write_frame},
+};
+
+#[cfg(feature = "serde")]
+use crate::framing::{decode, encode};
+
+/// Message channel over a connected `SOCK_STREAM` unix socket.
+///
+/// Every message is framed with its length as a little-endian `u32`, so message boundaries
+/// survive the byte stream. With the `serde` feature, values can be sent directly and are
+/// encoded with bincode.
+pub struct UnixChannel {
+    stream: UnixStream,
+}
+
+impl UnixChannel {
+    /// Connects to a `UnixChannelListener` bound to `path`.
+    pub fn connect(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let stream = UnixStream::connect(path).map_err(|e| Error::Open {
+            path: path.display().to_string(),
+            source: Error::os_errno(&e),
+        })?;
+        Ok(Self { stream })
+    }
+
+    /// Creates a pair of connected channels, e.g. to hand one to a child process.
+    pub fn pair() -> Result<(Self, Self)> {
+        let (a, b) = UnixStream::pair()?;
+        Ok((Self { stream: a }, Self { stream: b }))
+    }
+
+    /// Sends `msg` as one frame.
+    pub fn send_bytes(&mut self, msg: &[u8]) -> Result<()> {
+        write_frame(&self.stream, msg)
+    }
+
+    /// Receives the next frame, blocking until it has fully arrived.
+    /// Fails if the peer closed the connection.
+    pub fn recv_bytes(&mut self) -> Result<Vec<u8>> {
+        read_frame(&self.stream)
+    }
+
+    /// Sends `msg` as one frame with `fds` attached using `SCM_RIGHTS`.
+    pub fn send_fds(&mut self, msg: &[u8], fds: &[BorrowedFd<'_>]) -> Result<()> {
+        let frame = frame(msg)?;
+        let sent = send_with_fds(self.stream.as_fd(), &[IoSlice::new(&frame)], fds)?;
+        // The descriptors went out with the first byte; the rest can follow normally.
+        self.stream.write_all(&frame[sent..])?;
+        Ok(())
+    }
+
+    /// Receives the next frame along with the file descriptors attached to it.
+    /// Must only be used for frames sent with `send_fds`.
+    pub fn recv_fds(&mut self) -> Result<(Vec<u8>, Vec<OwnedFd>)> {
+        let mut len = [0; 4];
+        let (received, fds) = recv_with_fds(self.stream.as_fd(), &mut len)?;
+        if received == 0 {
+            return Err(Error::closed());
+        }
+        self.stream.read_exact(&mut len[received..])?;
+
+        let msg = read_frame_body(&self.stream, len)?;
+        Ok((msg, fds))
+    }
+
+    /// Encodes `value` with bincode and sends it as one frame.
+    #[cfg(feature = "serde")]
+    pub fn send<T: serde::Serialize>(&mut self, value: &T) -> Result<()> {
+        self.send_bytes(&encode(value)?)
+    }
+
+    /// Receives the next frame and decodes it with bincode.
+    #[cfg(feature = "serde")]
+    pub fn recv<T: serde::de::DeserializeOwned>(&mut self) -> Result<T> {
+        decode(&self.recv_bytes()?)
+    }
+
+    /// Returns the credentials of the process on the other end, as of when it connected.
+    pub fn peer_credentials(&self) -> Result<PeerCredentials> {
+        peer_credentials(&self.stream)
+    }
+
+    /// The underlying socket, e.g. to set timeouts.
+    pub fn stream(&self) -> &UnixStream {
+        &self.stream
+    }
+}
+
+impl AsFd for UnixChannel {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.stream.as_fd()
+    }
+}
+
+impl From<UnixChannel> for OwnedFd {
+    fn from(channel: UnixChannel) -> Self {
+        channel.stream.into()
+    }
+}
+
+impl From<UnixStream> for UnixChannel {
+    fn from(stream: UnixStream) -> Self {
+        Self { stream }
+    }
+}
+
+/// Listening unix socket accepting `UnixChannel` connections.
+/// The socket file is removed when the listener is dropped.
+pub struct UnixChannelListener {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl UnixChannelListener {
+    /// Binds a listening socket to `path`.
+    /// A leftover socket file nobody is listening on anymore is replaced.
+    pub fn bind(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        if path.exists() && UnixStream::connect(path).is_err() {
+            fs::remove_file(path)?;
+        }
+
+        let listener = UnixListener::bind(path).map_err(|e| Error::Create {
+            path: path.display().to_string(),
+            source: Error::os_errno(&e),
+        })?;
+        Ok(Self {
+            listener,
+            path: path.to_owned(),
+        })
+    }
+
+    /// Waits for the next connection.
+    pub fn accept(&self) -> Result<UnixChannel> {
+        let (stream, _) = self.listener.accept()?;
+        Ok(UnixChannel { stream })
+    }
+
+    /// Waits for the next connection from a process whose credentials `allow` accepts,
+    /// dropping connections from any other process.
+    pub fn accept_if<F>(&self, mut allow: F) -> Result<(UnixChannel, PeerCredentials)>
+    where
+        F: FnMut(&PeerCredentials) -> bool,
+    {
+        loop {
+            let channel = self.accept()?;
+            let creds = channel.peer_credentials()?;
+            if allow(&creds) {
+                return Ok((channel, creds));
+            }
+        }
+    }
+}
+
+/// The listening socket, readable when a connection is waiting to be accepted.
+impl AsFd for UnixChannelListener {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.listener.as_fd()
+    }
+}
+
+impl Drop for UnixChannelListener {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
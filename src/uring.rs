@@ -0,0 +1,414 @@
+use std::{
+    num::NonZeroUsize,
+    os::fd::{AsRawFd, OwnedFd},
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicU16, Ordering},
+};
+
+use io_uring::{
+    IoUring, cqueue, opcode,
+    squeue::{Entry, Flags},
+    types::{BufRingEntry, Fd},
+};
+use nix::{
+    errno::Errno,
+    libc::{ENOBUFS, c_void, iovec},
+    sys::{
+        mman::{MapFlags, ProtFlags, mmap_anonymous, munmap},
+        stat::{SFlag, fstat},
+    },
+};
+
+#[cfg(feature = "serde")]
+use crate::framing::{decode, encode};
+use crate::{
+    error::{Error, Result},
+    framing::{frame, prefix},
+};
+
+/// Tags of the requests on the ring.
+const SEND: u64 = 1;
+const RECV: u64 = 2;
+
+/// The only buffer group the receive picks buffers from.
+const BUF_GROUP: u16 = 0;
+
+/// Buffers handed to the kernel for receive requests to fill, through a ring shared
+/// with it.
+struct BufRing {
+    ring: NonNull<BufRingEntry>,
+    ring_len: NonZeroUsize,
+    entries: u16,
+    buffers: Box<[u8]>,
+    buffer_len: usize,
+}
+
+impl BufRing {
+    fn new(entries: u16, buffer_len: usize) -> Result<Self> {
+        let ring_len = NonZeroUsize::new(entries as usize * size_of::<BufRingEntry>())
+            .expect("buffer ring has at least one entry");
+        let ring = unsafe {
+            mmap_anonymous(
+                None,
+                ring_len,
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_PRIVATE,
+            )
+        }
+        .map_err(Error::sys("mmap"))?;
+
+        let mut bufs = Self {
+            ring: ring.cast(),
+            ring_len,
+            entries,
+            buffers: vec![0; entries as usize * buffer_len].into_boxed_slice(),
+            buffer_len,
+        };
+        for bid in 0..entries {
+            bufs.provide(bid);
+        }
+        Ok(bufs)
+    }
+
+    /// Hands buffer `bid` to the kernel.
+    fn provide(&mut self, bid: u16) {
+        let tail = self.tail();
+        let tail_value = tail.load(Ordering::Relaxed);
+        let index = (tail_value & (self.entries - 1)) as usize;
+        let entry = unsafe { &mut *self.ring.as_ptr().add(index) };
+        entry.set_addr(self.buffers[bid as usize * self.buffer_len..].as_ptr() as u64);
+        entry.set_len(self.buffer_len as u32);
+        entry.set_bid(bid);
+        tail.store(tail_value.wrapping_add(1), Ordering::Release);
+    }
+
+    /// The first `len` bytes of buffer `bid`, filled by the kernel.
+    fn filled(&self, bid: u16, len: usize) -> &[u8] {
+        let start = bid as usize * self.buffer_len;
+        &self.buffers[start..start + len]
+    }
+
+    /// The tail of the ring, which overlaps the last field of its first entry.
+    fn tail(&self) -> &AtomicU16 {
+        unsafe { AtomicU16::from_ptr(BufRingEntry::tail(self.ring.as_ptr()) as *mut u16) }
+    }
+}
+
+impl Drop for BufRing {
+    fn drop(&mut self) {
+        unsafe { munmap(self.ring.cast::<c_void>(), self.ring_len.get()).ok() };
+    }
+}
+
+/// A message channel over a unix socket or a pipe that does its I/O through io_uring, to
+/// cut the number of system calls per message. Only available on Linux with the `uring`
+/// feature.
+///
+/// Messages use the same framing as `UnixChannel` and `Fifo`, so the other end can use
+/// either of those. Sending copies frames into a buffer registered with the ring, and
+/// `flush` writes all of them with one request. Receiving from a socket keeps a single
+/// multishot request running that fills buffers provided to the kernel, so data keeps
+/// arriving without a request per read. Pipes are read one request at a time into the same
+/// buffers.
+///
+/// Multishot receive needs Linux 6.0.
+pub struct UringChannel {
+    // The ring goes first, so it is torn down before the buffers it uses.
+    ring: IoUring,
+    fd: OwnedFd,
+    socket: bool,
+    send_buf: Box<[u8]>,
+    /// Bytes of `send_buf` holding queued frames.
+    send_len: usize,
+    bufs: BufRing,
+    /// Received bytes not yet returned as messages.
+    inbox: Vec<u8>,
+    /// Whether a receive request is running.
+    armed: bool,
+    /// Whether the peer closed its end.
+    closed: bool,
+}
+
+impl UringChannel {
+    /// Wraps a connected stream socket or one end of a pipe, e.g. a `UnixChannel` or a `Fifo`
+    /// converted into an `OwnedFd`.
+    pub fn new(fd: impl Into<OwnedFd>) -> Result<Self> {
+        Self::builder(fd).build()
+    }
+
+    /// Returns a builder for configuring the buffers of the channel.
+    pub fn builder(fd: impl Into<OwnedFd>) -> UringChannelBuilder {
+        UringChannelBuilder {
+            fd: fd.into(),
+            send_buffer: 64 * 1024,
+            recv_buffers: 64,
+            recv_buffer_len: 4096,
+        }
+    }
+
+    /// Queues `msg` as one frame, written with the next `flush`. Flushes first if the send
+    /// buffer can't hold it, and writes a frame larger than the whole buffer right away.
+    pub fn queue_bytes(&mut self, msg: &[u8]) -> Result<()> {
+        let len = prefix(msg)?;
+        let frame_len = len.len() + msg.len();
+        if self.send_len + frame_len > self.send_buf.len() {
+            self.flush()?;
+        }
+        if frame_len > self.send_buf.len() {
+            let frame = frame(msg)?;
+            return self.write_all(frame.as_ptr(), frame.len(), false);
+        }
+
+        let start = self.send_len;
+        self.send_buf[start..start + len.len()].copy_from_slice(&len);
+        self.send_buf[start + len.len()..start + frame_len].copy_from_slice(msg);
+        self.send_len += frame_len;
+        Ok(())
+    }
+
+    /// Writes all queued frames.
+    pub fn flush(&mut self) -> Result<()> {
+        if self.send_len == 0 {
+            return Ok(());
+        }
+        let len = self.send_len;
+        self.send_len = 0;
+        self.write_all(self.send_buf.as_ptr(), len, true)
+    }
+
+    /// Sends `msg` as one frame, together with any frames queued before it.
+    pub fn send_bytes(&mut self, msg: &[u8]) -> Result<()> {
+        self.queue_bytes(msg)?;
+        self.flush()
+    }
+
+    /// Receives the next frame, blocking until it has fully arrived.
+    /// Fails if the peer closed the connection.
+    pub fn recv_bytes(&mut self) -> Result<Vec<u8>> {
+        loop {
+            if let Some(msg) = self.try_recv_bytes()? {
+                return Ok(msg);
+            }
+            self.enter(1)?;
+        }
+    }
+
+    /// Receives the next frame if it has fully arrived, without blocking.
+    /// Fails if the peer closed the connection.
+    pub fn try_recv_bytes(&mut self) -> Result<Option<Vec<u8>>> {
+        self.reap()?;
+        if let Some(msg) = self.next_frame() {
+            return Ok(Some(msg));
+        }
+        if self.closed {
+            return Err(Error::closed());
+        }
+        // Rearmed after reaping, as the completions may have ended the request.
+        self.arm()?;
+        Ok(None)
+    }
+
+    /// Encodes `value` with bincode and sends it as one frame.
+    #[cfg(feature = "serde")]
+    pub fn send<T: serde::Serialize>(&mut self, value: &T) -> Result<()> {
+        self.send_bytes(&encode(value)?)
+    }
+
+    /// Receives the next frame and decodes it with bincode.
+    #[cfg(feature = "serde")]
+    pub fn recv<T: serde::de::DeserializeOwned>(&mut self) -> Result<T> {
+        decode(&self.recv_bytes()?)
+    }
+
+    /// Writes `len` bytes at `buf`, from the registered send buffer if `fixed` is set.
+    fn write_all(&mut self, buf: *const u8, len: usize, fixed: bool) -> Result<()> {
+        let fd = Fd(self.fd.as_raw_fd());
+        let mut written = 0;
+        while written < len {
+            let (buf, left) = (unsafe { buf.add(written) }, (len - written) as u32);
+            let entry = match fixed {
+                true => opcode::WriteFixed::new(fd, buf, left, 0).build(),
+                false => opcode::Write::new(fd, buf, left).build(),
+            };
+            self.push(entry.user_data(SEND));
+
+            let res = loop {
+                self.enter(1)?;
+                if let Some(res) = self.reap()? {
+                    break res;
+                }
+            };
+            if res < 0 {
+                return Err(Error::sys("write(io_uring)")(Errno::from_raw(-res)));
+            }
+            written += res as usize;
+        }
+        Ok(())
+    }
+
+    /// Starts receiving unless a request is running or the peer closed its end. Pipes get a
+    /// plain read instead of a multishot one, which doesn't report the writer closing.
+    fn arm(&mut self) -> Result<()> {
+        if self.armed || self.closed {
+            return Ok(());
+        }
+        let fd = Fd(self.fd.as_raw_fd());
+        let entry = match self.socket {
+            true => opcode::RecvMulti::new(fd, BUF_GROUP).build(),
+            false => opcode::Read::new(fd, ptr::null_mut(), self.bufs.buffer_len as u32)
+                .buf_group(BUF_GROUP)
+                .build()
+                .flags(Flags::BUFFER_SELECT),
+        };
+        self.push(entry.user_data(RECV));
+        self.enter(0)?;
+        self.armed = true;
+        Ok(())
+    }
+
+    /// Handles all completions, moving received data into the inbox.
+    /// Returns the result of a write if one completed.
+    fn reap(&mut self) -> Result<Option<i32>> {
+        let mut sent = None;
+        while let Some(cqe) = self.ring.completion().next() {
+            let (res, flags) = (cqe.result(), cqe.flags());
+            if cqe.user_data() == SEND {
+                sent = Some(res);
+                continue;
+            }
+
+            if !cqueue::more(flags) {
+                self.armed = false;
+            }
+            match res {
+                0 => self.closed = true,
+                // Running out of buffers ends the request, but they are back by now.
+                _ if res == -ENOBUFS => {}
+                _ if res < 0 => {
+                    let op = match self.socket {
+                        true => "recv(io_uring)",
+                        false => "read(io_uring)",
+                    };
+                    return Err(Error::sys(op)(Errno::from_raw(-res)));
+                }
+                _ => {
+                    let bid = cqueue::buffer_select(flags).expect("receive picked a buffer");
+                    self.inbox
+                        .extend_from_slice(self.bufs.filled(bid, res as usize));
+                    self.bufs.provide(bid);
+                }
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Removes the first frame from the inbox if it has fully arrived.
+    fn next_frame(&mut self) -> Option<Vec<u8>> {
+        let len: [u8; 4] = self.inbox.get(..4)?.try_into().expect("slice has 4 bytes");
+        let end = 4 + u32::from_le_bytes(len) as usize;
+        if self.inbox.len() < end {
+            return None;
+        }
+        let msg = self.inbox[4..end].to_vec();
+        self.inbox.drain(..end);
+        Some(msg)
+    }
+
+    fn push(&mut self, entry: Entry) {
+        // At most one write and one receive are ever in flight, far below the ring's size.
+        unsafe { self.ring.submission().push(&entry) }
+            .expect("submission queue has room for every request");
+    }
+
+    /// Submits queued requests and waits for `want` completions.
+    fn enter(&mut self, want: usize) -> Result<()> {
+        loop {
+            match self.ring.submit_and_wait(want) {
+                Ok(_) => return Ok(()),
+                Err(e) if Error::os_errno(&e) == Errno::EINTR => continue,
+                Err(e) => return Err(Error::sys("io_uring_enter")(Error::os_errno(&e))),
+            }
+        }
+    }
+}
+
+/// Builder for `UringChannel`, created with `UringChannel::builder`.
+pub struct UringChannelBuilder {
+    fd: OwnedFd,
+    send_buffer: usize,
+    recv_buffers: u16,
+    recv_buffer_len: usize,
+}
+
+impl UringChannelBuilder {
+    /// Sets the size of the registered buffer frames are queued in, 64 KiB by default.
+    pub fn send_buffer(mut self, len: usize) -> Self {
+        self.send_buffer = len;
+        self
+    }
+
+    /// Sets how many buffers of what size the kernel fills with received data, 64 buffers of
+    /// 4 KiB by default. The count must be a power of two of at most 32768.
+    pub fn recv_buffers(mut self, count: u16, len: usize) -> Self {
+        self.recv_buffers = count;
+        self.recv_buffer_len = len;
+        self
+    }
+
+    /// Sets up the ring and registers the buffers with it.
+    pub fn build(self) -> Result<UringChannel> {
+        if self.send_buffer == 0 || self.recv_buffer_len == 0 {
+            return Err(Error::InvalidArgument(
+                "io_uring buffers must be nonempty".to_owned(),
+            ));
+        }
+        if !self.recv_buffers.is_power_of_two() || self.recv_buffers > 32768 {
+            return Err(Error::InvalidArgument(format!(
+                "{} receive buffers is not a power of two of at most 32768",
+                self.recv_buffers
+            )));
+        }
+        if self.recv_buffer_len > u32::MAX as usize {
+            return Err(Error::InvalidArgument(format!(
+                "Receive buffers of {} bytes are too large",
+                self.recv_buffer_len
+            )));
+        }
+
+        let stat = fstat(&self.fd).map_err(Error::sys("fstat"))?;
+        let socket = SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT == SFlag::S_IFSOCK;
+
+        let ring =
+            IoUring::new(8).map_err(|e| Error::sys("io_uring_setup")(Error::os_errno(&e)))?;
+        let mut send_buf = vec![0; self.send_buffer].into_boxed_slice();
+        let iov = iovec {
+            iov_base: send_buf.as_mut_ptr().cast(),
+            iov_len: send_buf.len(),
+        };
+        unsafe { ring.submitter().register_buffers(&[iov]) }
+            .map_err(|e| Error::sys("io_uring_register")(Error::os_errno(&e)))?;
+
+        let bufs = BufRing::new(self.recv_buffers, self.recv_buffer_len)?;
+        unsafe {
+            ring.submitter().register_buf_ring_with_flags(
+                bufs.ring.as_ptr() as u64,
+                bufs.entries,
+                BUF_GROUP,
+                0,
+            )
+        }
+        .map_err(|e| Error::sys("io_uring_register")(Error::os_errno(&e)))?;
+
+        Ok(UringChannel {
+            ring,
+            fd: self.fd,
+            socket,
+            send_buf,
+            send_len: 0,
+            bufs,
+            inbox: Vec::new(),
+            armed: false,
+            closed: false,
+        })
+    }
+}
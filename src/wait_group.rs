@@ -0,0 +1,231 @@
+use std::{
+    mem::size_of,
+    num::NonZeroUsize,
+    process,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+
+use nix::fcntl::OFlag;
+
+use crate::{
+    builder::Builder,
+    cleanup::CleanupPolicy,
+    error::{Error, Result},
+    futex::EventCount,
+    liveness::{LIVENESS_POLL, is_alive},
+    map::Mapping,
+    namespace::Namespace,
+};
+
+/// The most processes that can have work outstanding in one group at the same time.
+const MAX_MEMBERS: usize = 256;
+
+/// The work outstanding in one member process, or a free slot if `pid` is 0.
+#[repr(C)]
+struct Slot {
+    pid: AtomicU32,
+    count: AtomicU32,
+}
+
+#[repr(C)]
+struct Group {
+    /// Sum of the counts of all slots.
+    total: AtomicU32,
+    event: EventCount,
+    slots: [Slot; MAX_MEMBERS],
+}
+
+/// A Go-style wait group shared between processes: workers `add` to it when they start work
+/// and call `done` when they finish it, while a supervisor `wait`s for all work to finish.
+///
+/// The work is counted per process, so the work of a process that dies before calling
+/// `done` is dropped from the group once a waiter notices its death, checking every 10
+/// milliseconds. An exited child process counts as alive until its parent reaps it, and a
+/// recycled pid keeps the work of the dead process counted until the new process exits.
+/// At most 256 processes can have work outstanding at the same time.
+///
+/// Slots are only modified while holding the flock that also serializes initialization,
+/// which the kernel releases when its holder dies.
+pub struct WaitGroup {
+    map: Mapping,
+}
+
+impl WaitGroup {
+    /// Opens the wait group in /dev/shm, creating it without any work if it doesn't exist.
+    pub fn new(name: &str) -> Result<Self> {
+        Self::builder(name).build()
+    }
+
+    /// Returns a builder for setting the namespace and the permissions the wait group is created
+    /// with.
+    pub fn builder(name: &str) -> Builder<Self> {
+        Builder::new(name)
+    }
+
+    /// Unlinks (deletes) the wait group file from /dev/shm.
+    pub fn unlink(name: &str) -> Result<()> {
+        Self::unlink_in(&Namespace::default(), name)
+    }
+
+    /// Unlinks the wait group called `name` in `namespace`.
+    pub fn unlink_in(namespace: &Namespace, name: &str) -> Result<()> {
+        namespace.path(name, ".wgr").unlink()
+    }
+
+    /// Adds `n` units of work owned by the calling process.
+    pub fn add(&self, n: u32) -> Result<()> {
+        let group = self.group();
+        let me = process::id();
+        let _lock = self.map.init_lock()?;
+
+        let slot = match group.slots.iter().find(|slot| slot.pid() == me) {
+            Some(slot) => slot,
+            None => {
+                let slot = group
+                    .slots
+                    .iter()
+                    .find(|slot| slot.pid() == 0)
+                    .ok_or_else(|| {
+                        Error::InvalidArgument(format!(
+                            "Wait group already has {MAX_MEMBERS} processes with work"
+                        ))
+                    })?;
+                slot.pid.store(me, Ordering::Relaxed);
+                slot
+            }
+        };
+        let count = slot.count.load(Ordering::Relaxed).checked_add(n);
+        let total = group.total.load(Ordering::Relaxed).checked_add(n);
+        let (Some(count), Some(total)) = (count, total) else {
+            return Err(Error::InvalidArgument(format!(
+                "Adding {n} overflows the wait group"
+            )));
+        };
+        slot.count.store(count, Ordering::Relaxed);
+        group.total.store(total, Ordering::Release);
+        Ok(())
+    }
+
+    /// Finishes one unit of work owned by the calling process, waking the waiters if it was
+    /// the last one in the group. Fails if the process has no work outstanding.
+    pub fn done(&self) -> Result<()> {
+        let group = self.group();
+        let me = process::id();
+        let _lock = self.map.init_lock()?;
+
+        let slot = group
+            .slots
+            .iter()
+            .find(|slot| slot.pid() == me)
+            .ok_or_else(|| {
+                Error::InvalidArgument("Process has no work in the wait group".to_owned())
+            })?;
+        slot.release(group, 1);
+        Ok(())
+    }
+
+    /// Blocks until no work is outstanding.
+    pub fn wait(&self) -> Result<()> {
+        self.wait_until(None).map(drop)
+    }
+
+    /// Blocks until no work is outstanding, returning false if `timeout` elapsed first.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<bool> {
+        self.wait_until(Some(Instant::now() + timeout))
+    }
+
+    /// The number of units of work outstanding, including those of dead processes that
+    /// haven't been noticed yet.
+    pub fn count(&self) -> u32 {
+        self.group().total.load(Ordering::Acquire)
+    }
+
+    fn wait_until(&self, deadline: Option<Instant>) -> Result<bool> {
+        let group = self.group();
+        loop {
+            let poll = match deadline {
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return Ok(self.count() == 0);
+                    }
+                    left.min(LIVENESS_POLL)
+                }
+                None => LIVENESS_POLL,
+            };
+            let finished = group
+                .event
+                .wait_for(Some(poll), || (self.count() == 0).then_some(()))?;
+            if finished.is_some() {
+                return Ok(true);
+            }
+            self.reap()?;
+        }
+    }
+
+    /// Drops the work of member processes that died.
+    fn reap(&self) -> Result<()> {
+        let group = self.group();
+        if !group.slots.iter().any(|slot| slot.is_dead()) {
+            return Ok(());
+        }
+
+        let _lock = self.map.init_lock()?;
+        for slot in group.slots.iter().filter(|slot| slot.is_dead()) {
+            slot.release(group, slot.count.load(Ordering::Relaxed));
+        }
+        Ok(())
+    }
+
+    fn group(&self) -> &Group {
+        unsafe { &*(self.map.ptr() as *const Group) }
+    }
+}
+
+impl Builder<WaitGroup> {
+    /// Opens the wait group like `WaitGroup::new`.
+    pub fn build(self) -> Result<WaitGroup> {
+        let path = self.namespace.path(&self.name, ".wgr");
+        let len = NonZeroUsize::new(size_of::<Group>()).expect("Group has nonzero size");
+
+        // Zeroed memory is a group without work or members.
+        let map = Mapping::open_init(
+            &path,
+            OFlag::O_CREAT,
+            &self.permissions,
+            len,
+            CleanupPolicy::Never,
+            |_| Ok(()),
+        )?;
+        map.inherit_in_forks(None);
+
+        Ok(WaitGroup { map })
+    }
+}
+
+impl Slot {
+    fn pid(&self) -> u32 {
+        self.pid.load(Ordering::Relaxed)
+    }
+
+    fn is_dead(&self) -> bool {
+        let pid = self.pid();
+        pid != 0 && !is_alive(pid)
+    }
+
+    /// Removes `n` units of work from the slot, freeing it once it has none left.
+    /// Must be called while holding the lock.
+    fn release(&self, group: &Group, n: u32) {
+        let count = self.count.load(Ordering::Relaxed) - n;
+        self.count.store(count, Ordering::Relaxed);
+        if count == 0 {
+            self.pid.store(0, Ordering::Relaxed);
+        }
+        let total = group.total.load(Ordering::Relaxed) - n;
+        group.total.store(total, Ordering::Release);
+        if total == 0 {
+            group.event.notify_all();
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use std::{
+    mem::zeroed,
+    os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd},
+    sync::atomic::AtomicU32,
+    time::Duration,
+};
+
+use nix::{
+    errno::Errno,
+    libc::{
+        EPOLL_CLOEXEC, EPOLL_CTL_ADD, EPOLL_CTL_DEL, EPOLLIN, c_int, epoll_create1, epoll_ctl,
+        epoll_event, epoll_wait,
+    },
+};
+
+use crate::{
+    error::{Error, Result},
+    futex_bridge::FutexBridge,
+};
+
+/// Most sources reported by a single `epoll_wait` call.
+const MAX_EVENTS: usize = 64;
+
+/// A futex word watched by a `WaitSet`, with the token reported for it.
+struct Bridge {
+    token: u64,
+    bridge: FutexBridge,
+}
+
+/// Blocks until any of a set of IPC primitives becomes ready, reporting which ones did.
+///
+/// Sources with a descriptor are watched with epoll: the `ready_fd` of channels, `Event`
+/// (in semaphore mode for a semaphore that can be polled), `Timer`, `Signals`, sockets and
+/// pipes. Each is registered with a token of the caller's choosing, which `wait` returns
+/// when it is readable. Sources are level-triggered, so one stays ready until it is drained.
+///
+/// Futex words in shared memory have no descriptor. `add_futex` bridges one with a helper
+/// thread that signals an eventfd whenever the word changes.
+///
+/// Only available on Linux.
+pub struct WaitSet {
+    epoll: OwnedFd,
+    bridges: Vec<Bridge>,
+}
+
+impl WaitSet {
+    /// Creates an empty wait set.
+    pub fn new() -> Result<Self> {
+        let raw_fd = Errno::result(unsafe { epoll_create1(EPOLL_CLOEXEC) })
+            .map_err(Error::sys("epoll_create1"))?;
+        Ok(Self {
+            epoll: unsafe { OwnedFd::from_raw_fd(raw_fd) },
+            bridges: Vec::new(),
+        })
+    }
+
+    /// Watches `source` for readability, reporting it as `token`.
+    /// The source must stay open while it is in the set.
+    pub fn add(&mut self, source: &impl AsFd, token: u64) -> Result<()> {
+        let mut event = epoll_event {
+            events: EPOLLIN as u32,
+            u64: token,
+        };
+        self.ctl(EPOLL_CTL_ADD, source.as_fd().as_raw_fd(), &mut event)
+    }
+
+    /// Stops watching `source`.
+    pub fn remove(&mut self, source: &impl AsFd) -> Result<()> {
+        let mut event = epoll_event { events: 0, u64: 0 };
+        self.ctl(EPOLL_CTL_DEL, source.as_fd().as_raw_fd(), &mut event)
+    }
+
+    /// Watches the futex word `word`, reporting it as `token` whenever its value changes from
+    /// the one it had when last reported, starting with `expected`.
+    ///
+    /// Changes are noticed by a helper thread waiting on the word, which `futex::wake` on it
+    /// wakes. Changing the word without waking its waiters is noticed within 100 milliseconds.
+    ///
+    /// # Safety
+    ///
+    /// `word` must stay mapped until it is removed with `remove_futex` or the set is dropped,
+    /// and the set must not be leaked, as the helper thread keeps reading the word until then.
+    pub unsafe fn add_futex(&mut self, word: &AtomicU32, expected: u32, token: u64) -> Result<()> {
+        let bridge = unsafe { FutexBridge::new(word, expected, None, None)? };
+        self.add(bridge.event(), token)?;
+        self.bridges.push(Bridge { token, bridge });
+        Ok(())
+    }
+
+    /// Stops watching the futex word `word`, waiting for its helper thread to exit.
+    pub fn remove_futex(&mut self, word: &AtomicU32) -> Result<()> {
+        let Some(index) = self
+            .bridges
+            .iter()
+            .position(|bridge| bridge.bridge.watches(word))
+        else {
+            return Err(Error::InvalidArgument(
+                "Futex word is not in the wait set".to_owned(),
+            ));
+        };
+        let bridge = self.bridges.swap_remove(index);
+        self.remove(bridge.bridge.event())
+    }
+
+    /// Blocks until at least one source is ready or `timeout` elapses, returning the tokens
+    /// of the ready sources. Returns no tokens if the timeout elapsed.
+    pub fn wait(&self, timeout: Option<Duration>) -> Result<Vec<u64>> {
+        let timeout = match timeout {
+            Some(timeout) => timeout.as_millis().min(c_int::MAX as u128) as c_int,
+            None => -1,
+        };
+        let mut events: [epoll_event; MAX_EVENTS] = unsafe { zeroed() };
+        let count = loop {
+            let ret = unsafe {
+                epoll_wait(
+                    self.epoll.as_raw_fd(),
+                    events.as_mut_ptr(),
+                    MAX_EVENTS as c_int,
+                    timeout,
+                )
+            };
+            match Errno::result(ret) {
+                Ok(count) => break count as usize,
+                Err(Errno::EINTR) => continue,
+                Err(e) => return Err(Error::sys("epoll_wait")(e)),
+            }
+        };
+
+        let tokens: Vec<u64> = events[..count].iter().map(|event| event.u64).collect();
+        // Reported changes of futex words are consumed, unlike readable descriptors.
+        for bridge in &self.bridges {
+            if tokens.contains(&bridge.token) {
+                bridge.bridge.event().try_wait()?;
+            }
+        }
+        Ok(tokens)
+    }
+
+    fn ctl(&self, op: c_int, fd: c_int, event: &mut epoll_event) -> Result<()> {
+        Errno::result(unsafe { epoll_ctl(self.epoll.as_raw_fd(), op, fd, event) })
+            .map_err(Error::sys("epoll_ctl"))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,124 @@
+use std::{
+    sync::mpsc::{self, RecvTimeoutError, Sender, SyncSender},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::{
+    error::{Error, Result},
+    namespace::Namespace,
+    r_mtx::{RMtx, RMtxBuilder},
+    shm_mutex::{ShmMutex, ShmMutexBuilder},
+};
+
+/// Looks at a watched mutex once, recovering it if its owner died.
+type Check = Box<dyn FnMut() -> Result<()>>;
+
+/// Opens a mutex on the watchdog thread, returning its check.
+type Open = Box<dyn FnOnce(&Namespace) -> Result<Check> + Send>;
+
+/// The thread behind `IpcContext::start_watchdog`. Handles can't be sent to other threads,
+/// so it opens its own handles to the mutexes it watches.
+pub(crate) struct Watchdog {
+    opens: Option<Sender<(Open, SyncSender<Result<()>>)>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Watchdog {
+    /// Starts the thread, checking the mutexes opened in `namespace` every `interval`.
+    pub(crate) fn start(namespace: Namespace, interval: Duration) -> Result<Self> {
+        let (opens, opened) = mpsc::channel::<(Open, SyncSender<Result<()>>)>();
+        let thread = thread::Builder::new()
+            .name("nix-ipc-watchdog".to_owned())
+            .spawn(move || {
+                let mut checks = Vec::new();
+                loop {
+                    match opened.recv_timeout(interval) {
+                        Ok((open, reply)) => {
+                            let result = open(&namespace).map(|check| checks.push(check));
+                            reply.send(result).ok();
+                        }
+                        // Nobody can be told about failures, and the next round tries again.
+                        Err(RecvTimeoutError::Timeout) => {
+                            for check in &mut checks {
+                                check().ok();
+                            }
+                        }
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+            })
+            .map_err(Error::Io)?;
+        Ok(Self {
+            opens: Some(opens),
+            thread: Some(thread),
+        })
+    }
+
+    /// Watches the `RMtx` opened with `open`, running `repair` whenever its owner died.
+    pub(crate) fn watch_mtx<O, R>(&self, name: &str, open: O, mut repair: R) -> Result<()>
+    where
+        O: FnOnce(RMtxBuilder) -> Result<RMtx> + Send + 'static,
+        R: FnMut() + Send + 'static,
+    {
+        let name = name.to_owned();
+        self.watch(Box::new(move |namespace| {
+            let mtx = open(namespace.mtx(&name))?;
+            Ok(Box::new(move || {
+                if let Some(guard) = mtx.try_lock()?
+                    && guard.owner_died_recovered()
+                {
+                    repair();
+                }
+                Ok(())
+            }))
+        }))
+    }
+
+    /// Watches the `ShmMutex` opened with `open`, running `repair` on its data whenever its
+    /// owner died or the data is poisoned or corrupted.
+    pub(crate) fn watch_shm_mutex<T, O, R>(&self, name: &str, open: O, mut repair: R) -> Result<()>
+    where
+        T: 'static,
+        O: FnOnce(ShmMutexBuilder<T>) -> Result<ShmMutex<T>> + Send + 'static,
+        R: FnMut(&mut T) + Send + 'static,
+    {
+        let name = name.to_owned();
+        self.watch(Box::new(move |namespace| {
+            let shm = open(namespace.shm_mutex(&name))?;
+            Ok(Box::new(move || {
+                match shm.try_lock() {
+                    Ok(Some(mut guard)) if guard.owner_died_recovered() => repair(&mut guard),
+                    Ok(_) => {}
+                    // The guard was refused, leaving the mutex unlocked for a recovering lock.
+                    // Someone else may take it first, and then the next check repairs it.
+                    Err(Error::Poisoned | Error::ChecksumMismatch) => {
+                        drop(shm.try_lock_with_recovery(&mut repair)?);
+                    }
+                    Err(e) => return Err(e),
+                }
+                Ok(())
+            }))
+        }))
+    }
+
+    fn watch(&self, open: Open) -> Result<()> {
+        let (reply, replied) = mpsc::sync_channel(1);
+        let stopped = || Error::Validation("Watchdog thread has stopped".to_owned());
+        self.opens
+            .as_ref()
+            .and_then(|opens| opens.send((open, reply)).ok())
+            .ok_or_else(stopped)?;
+        replied.recv().map_err(|_| stopped())?
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        // Disconnecting stops the thread at its next wakeup, which is immediate.
+        self.opens = None;
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}